@@ -69,6 +69,7 @@ struct MachineState {
     modules: ModuleLookup,
     ip_field: DataFieldRef,
     pid_field: DataFieldRef,
+    time_field: DataFieldRef,
     callchain_field: DataFieldRef,
     regs_user_field: DataFieldRef,
     stack_user_field: DataFieldRef,
@@ -86,6 +87,7 @@ impl MachineState {
             modules: ModuleLookup::new(),
             ip_field: empty.clone(),
             pid_field: empty.clone(),
+            time_field: empty.clone(),
             callchain_field: empty.clone(),
             regs_user_field: empty.clone(),
             stack_user_field: empty.clone(),
@@ -194,9 +196,11 @@ impl MachineState {
 
 pub struct UnwindRequest<'a> {
     pid: u32,
+    time: Option<u64>,
     rip: u64,
     rbp: u64,
     rsp: u64,
+    link: Option<u64>,
     machine: &'a mut Machine,
     unwinder: &'a mut dyn MachineUnwinder,
     modules: &'a dyn ModuleAccessor,
@@ -207,6 +211,7 @@ pub struct UnwindRequest<'a> {
 impl<'a> UnwindRequest<'a> {
     pub fn new(
         pid: u32,
+        time: Option<u64>,
         rip: u64,
         rbp: u64,
         rsp: u64,
@@ -217,9 +222,11 @@ impl<'a> UnwindRequest<'a> {
         frames: &'a mut Vec<u64>) -> Self {
         Self {
             pid,
+            time,
             rip,
             rbp,
             rsp,
+            link: None,
             machine,
             unwinder,
             modules,
@@ -230,10 +237,39 @@ impl<'a> UnwindRequest<'a> {
 
     pub fn pid(&self) -> u32 { self.pid }
 
+    /* Only known when samples include PERF_SAMPLE_TIME */
+    pub fn time(&self) -> Option<u64> { self.time }
+
+    pub fn rip(&self) -> u64 { self.rip }
+
+    pub fn rbp(&self) -> u64 { self.rbp }
+
+    pub fn rsp(&self) -> u64 { self.rsp }
+
+    pub fn link_register(&self) -> Option<u64> { self.link }
+
+    pub fn stack_data(&self) -> &[u8] { self.stack_data }
+
+    pub fn push_user_ip(&mut self) {
+        /* Unwinding is deferred, only the user IP is known now */
+        self.frames.push(self.rip);
+    }
+
+    pub fn set_link_register(
+        &mut self,
+        lr: u64) {
+        /* Return address of leaf frames on ARM64 */
+        self.link = Some(lr);
+    }
+
     pub fn machine(&mut self) -> &Machine { self.machine }
 
     pub fn unwind_machine(
         &mut self) -> UnwindResult {
+        if let Some(lr) = self.link {
+            self.unwinder.set_link_register(lr);
+        }
+
         self.machine.unwind_process(
             self.pid,
             self.unwinder,
@@ -256,6 +292,10 @@ impl<'a> UnwindRequest<'a> {
             self.rbp,
             self.rsp);
 
+        if let Some(lr) = self.link {
+            self.unwinder.set_link_register(lr);
+        }
+
         self.frames.push(self.rip);
         result.frames_pushed += 1;
 
@@ -316,8 +356,8 @@ impl CallstackReader {
                 /* Registers */
                 let data = state.regs_user_field.get_data(full_data);
 
-                /* Expected 3 registers on x64 */
-                if data.len() != 24 {
+                /* Expected a u64 per-register, in bit order */
+                if data.len() != abi::PERF_REGS_UNWIND.count_ones() as usize * 8 {
                     return;
                 }
 
@@ -327,15 +367,28 @@ impl CallstackReader {
                     None => { return; },
                 }
 
-                let rbp = u64::from_ne_bytes(data[0..8].try_into().unwrap());
-                let rsp = u64::from_ne_bytes(data[8..16].try_into().unwrap());
-                let rip = u64::from_ne_bytes(data[16..24].try_into().unwrap());
+                /* Time, optional */
+                let time = state.time_field.try_get_u64(full_data);
+
+                let reg = |index: usize| {
+                    let start = index * 8;
+                    u64::from_ne_bytes(data[start..start+8].try_into().unwrap())
+                };
+
+                /* X64: RBP, RSP, RIP */
+                #[cfg(target_arch = "x86_64")]
+                let (rbp, rsp, rip, link) = (reg(0), reg(1), reg(2), None);
+
+                /* ARM64: X29, LR, SP, PC */
+                #[cfg(target_arch = "aarch64")]
+                let (rbp, rsp, rip, link) = (reg(0), reg(2), reg(3), Some(reg(1)));
 
                 /* Stack data */
                 let data = state.stack_user_field.get_data(full_data);
 
                 let mut request = UnwindRequest::new(
                     pid,
+                    time,
                     rip,
                     rbp,
                     rsp,
@@ -345,6 +398,10 @@ impl CallstackReader {
                     data,
                     frames);
 
+                if let Some(lr) = link {
+                    request.set_link_register(lr);
+                }
+
                 (state.unwind)(&mut request);
             }
         });
@@ -398,7 +455,6 @@ impl CallstackHelper {
 
     pub fn has_unwinder(&self) -> bool { self.unwinder.is_some() }
 
-    #[cfg(target_arch = "x86_64")]
     pub fn with_dwarf_unwinding(&mut self) -> Self {
         let mut clone = self.clone_mut();
 
@@ -407,9 +463,12 @@ impl CallstackHelper {
         clone
     }
 
-    #[cfg(target_arch = "aarch64")]
-    pub fn with_dwarf_unwinding(&mut self) -> Self {
-        self.clone_mut()
+    pub fn with_frame_pointer_unwinding(&mut self) -> Self {
+        let mut clone = self.clone_mut();
+
+        clone.unwinder = Some(Box::new(frame_pointer_unwinder()));
+
+        clone
     }
 
     pub fn with_stack_size(
@@ -488,6 +547,22 @@ impl CallstackHelp for RingBufSessionBuilder {
                             .with_ip());
                     }
 
+                    if let Some(pmu) = builder.take_pmu_events() {
+                        builder.replace_pmu_events(
+                            pmu
+                            .iter()
+                            .map(|pmu| pmu.with_ip())
+                            .collect());
+                    }
+
+                    if let Some(groups) = builder.take_pmu_groups() {
+                        builder.replace_pmu_groups(
+                            groups
+                            .iter()
+                            .map(|group| group.with_ip())
+                            .collect());
+                    }
+
                     return;
                 }
 
@@ -517,6 +592,22 @@ impl CallstackHelp for RingBufSessionBuilder {
                             .with_callchain_data());
                     }
 
+                    if let Some(pmu) = builder.take_pmu_events() {
+                        builder.replace_pmu_events(
+                            pmu
+                            .iter()
+                            .map(|pmu| pmu.with_callchain_data())
+                            .collect());
+                    }
+
+                    if let Some(groups) = builder.take_pmu_groups() {
+                        builder.replace_pmu_groups(
+                            groups
+                            .iter()
+                            .map(|group| group.with_callchain_data())
+                            .collect());
+                    }
+
                     return;
                 }
 
@@ -539,10 +630,7 @@ impl CallstackHelp for RingBufSessionBuilder {
                         profiling
                         .with_callchain_data()
                         .without_user_callchain_data()
-                        .with_user_regs_data(
-                            abi::PERF_REG_BP |
-                            abi::PERF_REG_SP |
-                            abi::PERF_REG_IP)
+                        .with_user_regs_data(abi::PERF_REGS_UNWIND)
                         .with_user_stack_data(stack_size));
                 }
 
@@ -551,10 +639,7 @@ impl CallstackHelp for RingBufSessionBuilder {
                         tp
                         .with_callchain_data()
                         .without_user_callchain_data()
-                        .with_user_regs_data(
-                            abi::PERF_REG_BP |
-                            abi::PERF_REG_SP |
-                            abi::PERF_REG_IP)
+                        .with_user_regs_data(abi::PERF_REGS_UNWIND)
                         .with_user_stack_data(stack_size));
                 }
 
@@ -563,10 +648,7 @@ impl CallstackHelp for RingBufSessionBuilder {
                         cswitch
                         .with_callchain_data()
                         .without_user_callchain_data()
-                        .with_user_regs_data(
-                            abi::PERF_REG_BP |
-                            abi::PERF_REG_SP |
-                            abi::PERF_REG_IP)
+                        .with_user_regs_data(abi::PERF_REGS_UNWIND)
                         .with_user_stack_data(stack_size));
                 }
 
@@ -575,12 +657,37 @@ impl CallstackHelp for RingBufSessionBuilder {
                         bpf
                         .with_callchain_data()
                         .without_user_callchain_data()
-                        .with_user_regs_data(
-                            abi::PERF_REG_BP |
-                            abi::PERF_REG_SP |
-                            abi::PERF_REG_IP)
+                        .with_user_regs_data(abi::PERF_REGS_UNWIND)
                         .with_user_stack_data(stack_size));
                 }
+
+                if let Some(pmu) = builder.take_pmu_events() {
+                    builder.replace_pmu_events(
+                        pmu
+                        .iter()
+                        .map(|pmu| {
+                            pmu
+                            .with_callchain_data()
+                            .without_user_callchain_data()
+                            .with_user_regs_data(abi::PERF_REGS_UNWIND)
+                            .with_user_stack_data(stack_size)
+                        })
+                        .collect());
+                }
+
+                if let Some(groups) = builder.take_pmu_groups() {
+                    builder.replace_pmu_groups(
+                        groups
+                        .iter()
+                        .map(|group| {
+                            group
+                            .with_callchain_data()
+                            .without_user_callchain_data()
+                            .with_user_regs_data(abi::PERF_REGS_UNWIND)
+                            .with_user_stack_data(stack_size)
+                        })
+                        .collect());
+                }
             },
 
             move |session| {
@@ -598,6 +705,7 @@ impl CallstackHelp for RingBufSessionBuilder {
                 /* DWARF needs a few more fields and hooks */
                 session_state.write(|state| {
                     state.pid_field = session.pid_field_ref();
+                    state.time_field = session.time_data_ref();
                     state.regs_user_field = session.regs_user_data_ref();
                     state.stack_user_field = session.stack_user_data_ref();
                 });
@@ -0,0 +1,434 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::collections::{HashMap, HashSet};
+
+use crate::helpers::exporting::*;
+use crate::helpers::exporting::graph::*;
+use crate::helpers::exporting::span::ExportSpan;
+use super::json::write_json_string;
+use super::frames::frame_name;
+
+const UNKNOWN: &str = "Unknown";
+
+pub trait ChromeTraceFormat {
+    fn to_chrome_trace(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut impl Write) -> anyhow::Result<()>;
+
+    fn to_chrome_trace_file(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.to_chrome_trace(
+            predicate,
+            &mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+struct ChromeTraceWriter<'a, W: Write> {
+    writer: &'a mut W,
+    first: bool,
+    freq: u64,
+    names: Vec<String>,
+    name_lookup: HashMap<String, usize>,
+    ip_lookup: HashMap<(u32, usize, u64), usize>,
+    /* (Parent, name ID) per stack frame */
+    frames: Vec<(Option<usize>, usize)>,
+    frame_lookup: HashMap<(Option<usize>, usize), usize>,
+    processes: HashSet<u32>,
+    threads: HashSet<(u32, u32)>,
+    ips: Vec<u64>,
+}
+
+impl<'a, W: Write> ChromeTraceWriter<'a, W> {
+    fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            first: true,
+            freq: ExportMachine::qpc_freq(),
+            names: Vec::new(),
+            name_lookup: HashMap::new(),
+            ip_lookup: HashMap::new(),
+            frames: Vec::new(),
+            frame_lookup: HashMap::new(),
+            processes: HashSet::new(),
+            threads: HashSet::new(),
+            ips: Vec::new(),
+        }
+    }
+
+    fn begin_event(&mut self) -> anyhow::Result<()> {
+        if !self.first {
+            write!(self.writer, ",")?;
+        }
+
+        self.first = false;
+
+        Ok(())
+    }
+
+    fn write_ts(
+        &mut self,
+        time: u64) -> anyhow::Result<()> {
+        /* Trace event timestamps are in microseconds */
+        let ns = ExportMachine::qpc_to_ns(self.freq, time);
+
+        write!(self.writer, ",\"ts\":{}.{:03}", ns / 1000, ns % 1000)?;
+
+        Ok(())
+    }
+
+    fn write_metadata(
+        &mut self,
+        kind: &str,
+        pid: u32,
+        tid: u32,
+        name: &str) -> anyhow::Result<()> {
+        self.begin_event()?;
+
+        write!(
+            self.writer,
+            "{{\"name\":\"{}\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":",
+            kind,
+            pid,
+            tid)?;
+
+        write_json_string(self.writer, name)?;
+        write!(self.writer, "}}}}")?;
+
+        Ok(())
+    }
+
+    fn comm<'b>(
+        machine: &'b ExportMachine,
+        process: &ExportProcess) -> &'b str {
+        match process.comm_id() {
+            Some(id) => { machine.strings().from_id(id).unwrap_or(UNKNOWN) },
+            None => { UNKNOWN },
+        }
+    }
+
+    fn add_process(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess) -> anyhow::Result<()> {
+        let pid = process.pid();
+
+        if self.processes.insert(pid) {
+            self.write_metadata(
+                "process_name",
+                pid,
+                pid,
+                Self::comm(machine, process))?;
+        }
+
+        Ok(())
+    }
+
+    fn add_thread(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        tid: u32) -> anyhow::Result<()> {
+        let pid = process.pid();
+
+        if self.threads.insert((pid, tid)) {
+            self.write_metadata(
+                "thread_name",
+                pid,
+                tid,
+                Self::comm(machine, process))?;
+        }
+
+        Ok(())
+    }
+
+    fn name_id(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        time: u64,
+        ip: u64) -> usize {
+        let mapping = process.find_mapping(ip, Some(time));
+        let mapping_id = mapping.map_or(usize::MAX, |mapping| mapping.id());
+        let key = (process.pid(), mapping_id, ip);
+
+        if let Some(id) = self.ip_lookup.get(&key) {
+            return *id;
+        }
+
+        let name = frame_name(machine, mapping, ip);
+
+        let id = match self.name_lookup.get(&name) {
+            Some(id) => { *id },
+            None => {
+                let id = self.names.len();
+                self.names.push(name.clone());
+                self.name_lookup.insert(name, id);
+                id
+            },
+        };
+
+        self.ip_lookup.insert(key, id);
+
+        id
+    }
+
+    fn stack_frame(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        sample: &ExportProcessSample) -> anyhow::Result<Option<usize>> {
+        let time = sample.time();
+        let mut ips = std::mem::take(&mut self.ips);
+
+        machine.callstacks().from_id(
+            sample.callstack_id(),
+            &mut ips)?;
+
+        ips.insert(0, sample.ip());
+
+        /* Callstacks are leaf first, stack frames link leaf to root */
+        let mut parent = None;
+
+        for ip in ips.iter().rev() {
+            let name_id = self.name_id(machine, process, time, *ip);
+            let key = (parent, name_id);
+
+            let id = match self.frame_lookup.get(&key) {
+                Some(id) => { *id },
+                None => {
+                    let id = self.frames.len();
+                    self.frames.push(key);
+                    self.frame_lookup.insert(key, id);
+                    id
+                },
+            };
+
+            parent = Some(id);
+        }
+
+        self.ips = ips;
+
+        Ok(parent)
+    }
+
+    fn write_span(
+        &mut self,
+        machine: &ExportMachine,
+        pid: u32,
+        tid: u32,
+        span: &ExportSpan) -> anyhow::Result<()> {
+        self.begin_event()?;
+        write!(self.writer, "{{\"name\":")?;
+        write_json_string(self.writer, span.name(machine.strings()))?;
+        write!(self.writer, ",\"cat\":\"span\",\"ph\":\"B\",\"pid\":{},\"tid\":{}", pid, tid)?;
+        self.write_ts(span.start_time())?;
+        write!(self.writer, "}}")?;
+
+        for child in span.children() {
+            self.write_span(machine, pid, tid, child)?;
+        }
+
+        self.begin_event()?;
+        write!(self.writer, "{{\"ph\":\"E\",\"pid\":{},\"tid\":{}", pid, tid)?;
+        self.write_ts(span.end_time())?;
+        write!(self.writer, "}}")?;
+
+        Ok(())
+    }
+
+    fn write_sample(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        sample: &ExportProcessSample,
+        converter: &dyn ExportGraphMetricValueConverter) -> anyhow::Result<()> {
+        let pid = process.pid();
+        let tid = sample.tid();
+
+        self.add_thread(machine, process, tid)?;
+
+        /* Spans become nested duration events */
+        if let Some(span) = machine.sample_span(sample) {
+            return self.write_span(machine, pid, tid, span);
+        }
+
+        let kind = match machine.sample_kinds().get(sample.kind() as usize) {
+            Some(kind) => { kind.as_str() },
+            None => { UNKNOWN },
+        };
+
+        let frame = self.stack_frame(machine, process, sample)?;
+        let value = converter.convert(machine, sample.value());
+
+        self.begin_event()?;
+        write!(self.writer, "{{\"name\":")?;
+        write_json_string(self.writer, kind)?;
+        write!(self.writer, ",\"cat\":\"sample\",\"ph\":\"i\",\"s\":\"t\",\"pid\":{},\"tid\":{}", pid, tid)?;
+        self.write_ts(sample.time())?;
+
+        if let Some(frame) = frame {
+            write!(self.writer, ",\"sf\":{}", frame)?;
+        }
+
+        write!(
+            self.writer,
+            ",\"args\":{{\"cpu\":{},\"value\":{}}}}}",
+            sample.cpu(),
+            value)?;
+
+        Ok(())
+    }
+
+    fn write_stack_frames(&mut self) -> anyhow::Result<()> {
+        write!(self.writer, "\"stackFrames\":{{")?;
+
+        for (id, (parent, name_id)) in self.frames.iter().enumerate() {
+            if id != 0 {
+                write!(self.writer, ",")?;
+            }
+
+            write!(self.writer, "\"{}\":{{\"name\":", id)?;
+            write_json_string(self.writer, &self.names[*name_id])?;
+
+            if let Some(parent) = parent {
+                write!(self.writer, ",\"parent\":\"{}\"", parent)?;
+            }
+
+            write!(self.writer, "}}")?;
+        }
+
+        write!(self.writer, "}}")?;
+
+        Ok(())
+    }
+}
+
+impl ChromeTraceFormat for ExportMachine {
+    fn to_chrome_trace(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let converter = DefaultExportGraphMetricValueConverter::default();
+        let mut trace = ChromeTraceWriter::new(writer);
+
+        write!(trace.writer, "{{\"traceEvents\":[")?;
+
+        self.replay_by_time(
+            predicate,
+            |machine, replay| {
+                let process = replay.process();
+
+                trace.add_process(machine, process)?;
+
+                if let Some(sample) = replay.sample_event() {
+                    trace.write_sample(
+                        machine,
+                        process,
+                        sample,
+                        &converter)?;
+                }
+
+                Ok(())
+            })?;
+
+        write!(trace.writer, "],")?;
+        trace.write_stack_frames()?;
+
+        /* Lost samples let viewers know the trace is incomplete */
+        write!(
+            trace.writer,
+            ",\"metadata\":{{\"lost_samples\":{}}}",
+            self.lost_samples())?;
+
+        write!(trace.writer, ",\"displayTimeUnit\":\"ns\"}}")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::exporting::formats::testing::*;
+
+    #[test]
+    fn it_works() {
+        /* Samples are 1us apart on a thread apart from the spans */
+        let mut exporter = stepped_machine(|exporter, cpu, i, frames| {
+            exporter.add_sample(
+                i as u64 * 1000,
+                MetricValue::Count(1),
+                1,
+                2,
+                0,
+                cpu,
+                frames).unwrap();
+        });
+
+        /* Span tree: outer with 2 inner children */
+        let outer_id = exporter.intern("outer");
+        let inner_id = exporter.intern("inner");
+
+        let mut outer = ExportSpan::start(outer_id, 100, 2);
+
+        for i in 0..2 {
+            let mut inner = ExportSpan::start(inner_id, 200 + i * 100, 0);
+            inner.mark_end(250 + i * 100);
+            outer.add_child(inner);
+        }
+
+        outer.mark_end(500);
+
+        let span = exporter.span_to_value(outer);
+        let timeline = exporter.sample_kind("timeline");
+
+        exporter.add_sample(
+            100,
+            span,
+            1,
+            3,
+            0,
+            timeline,
+            &[]).unwrap();
+
+        exporter.add_lost_samples(0, 3);
+
+        let mut output = Vec::new();
+        exporter.to_chrome_trace(|_| true, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("{\"traceEvents\":["));
+        assert!(output.ends_with(",\"displayTimeUnit\":\"ns\"}"));
+        assert!(output.contains(",\"metadata\":{\"lost_samples\":3},"));
+
+        /* Process and thread metadata */
+        assert!(output.contains("{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":1,\"tid\":1,\"args\":{\"name\":\"test\"}}"));
+        assert_eq!(2, output.matches("\"thread_name\"").count());
+
+        /* Samples */
+        assert_eq!(16, output.matches("\"ph\":\"i\"").count());
+        assert!(output.contains("\"ts\":15.000,\"sf\":0,"));
+
+        /* Spans */
+        assert_eq!(3, output.matches("\"ph\":\"B\"").count());
+        assert_eq!(3, output.matches("\"ph\":\"E\"").count());
+        assert!(output.contains("{\"name\":\"outer\",\"cat\":\"span\",\"ph\":\"B\",\"pid\":1,\"tid\":3,\"ts\":0.100}"));
+        assert!(output.contains("{\"ph\":\"E\",\"pid\":1,\"tid\":3,\"ts\":0.500}"));
+
+        /* Stack frames link to parents */
+        assert!(output.contains("\"0\":{\"name\":\"15!15\"}"));
+        assert!(output.contains("\"15\":{\"name\":\"0!0\",\"parent\":\"14\"}"));
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry::{Vacant, Occupied};
+
+use crate::helpers::exporting::*;
+use crate::helpers::exporting::graph::*;
+
+pub trait FoldedFormat {
+    type Options<'a>;
+
+    fn to_folded(
+        &self,
+        options: Self::Options<'_>,
+        writer: &mut impl Write) -> anyhow::Result<()>;
+
+    fn to_folded_file(
+        &self,
+        options: Self::Options<'_>,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.to_folded(
+            options,
+            &mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+pub struct FoldedMachineOptions<'a> {
+    predicate: Box<dyn Fn(&ExportProcess) -> bool + 'a>,
+    kind: u16,
+    converter: Option<&'a dyn ExportGraphMetricValueConverter>,
+    lost_samples: bool,
+}
+
+impl<'a> FoldedMachineOptions<'a> {
+    pub fn new(kind: u16) -> Self {
+        Self {
+            predicate: Box::new(|_| true),
+            kind,
+            converter: None,
+            lost_samples: false,
+        }
+    }
+
+    pub fn with_predicate(
+        mut self,
+        predicate: impl Fn(&ExportProcess) -> bool + 'a) -> Self {
+        self.predicate = Box::new(predicate);
+        self
+    }
+
+    pub fn with_converter(
+        mut self,
+        converter: &'a dyn ExportGraphMetricValueConverter) -> Self {
+        self.converter = Some(converter);
+        self
+    }
+
+    /*
+     * Notes lost samples in a leading "#" comment line. This is off by
+     * default, flamegraph.pl and inferno do not accept the line.
+     */
+    pub fn with_lost_samples(mut self) -> Self {
+        self.lost_samples = true;
+        self
+    }
+}
+
+fn push_frame_name(
+    name: &mut String,
+    value: &str) {
+    /* Frames are split by ';' and lines by '\n' */
+    for c in value.chars() {
+        match c {
+            ';' => { name.push(':'); },
+            '\n' | '\r' => { name.push(' '); },
+            _ => { name.push(c); },
+        }
+    }
+}
+
+fn frame_name(
+    graph: &ExportGraph,
+    target: &Target) -> anyhow::Result<String> {
+    let mut name = String::new();
+
+    push_frame_name(&mut name, &graph.frame_name(target)?);
+
+    Ok(name)
+}
+
+fn write_lost_samples(
+    lost_samples: u64,
+    writer: &mut impl Write) -> anyhow::Result<()> {
+    if lost_samples != 0 {
+        writeln!(writer, "# lost_samples={}", lost_samples)?;
+    }
+
+    Ok(())
+}
+
+fn write_folded(
+    graph: &ExportGraph,
+    prefix: Option<&str>,
+    writer: &mut impl Write) -> anyhow::Result<()> {
+    let nodes = graph.nodes();
+    let root = graph.root_node();
+
+    let mut names: HashMap<Target, String> = HashMap::new();
+    let mut path: Vec<usize> = Vec::new();
+    let mut line = String::new();
+
+    for (id, node) in nodes.iter().enumerate() {
+        if id == root || node.exclusive() == 0 {
+            continue;
+        }
+
+        /* Walk up to the root, then emit root first */
+        path.clear();
+
+        let mut current = id;
+
+        while current != root {
+            path.push(current);
+            current = nodes[current].parent();
+        }
+
+        line.clear();
+
+        if let Some(prefix) = prefix {
+            push_frame_name(&mut line, prefix);
+            line.push(';');
+        }
+
+        for id in path.iter().rev() {
+            let target = nodes[*id].target();
+
+            let name = match names.entry(target) {
+                Occupied(entry) => { entry.into_mut() },
+                Vacant(entry) => {
+                    let name = frame_name(graph, entry.key())?;
+                    entry.insert(name)
+                },
+            };
+
+            line.push_str(name);
+            line.push(';');
+        }
+
+        line.pop();
+
+        writeln!(writer, "{} {}", line, node.exclusive())?;
+    }
+
+    Ok(())
+}
+
+impl FoldedFormat for ExportGraph {
+    type Options<'a> = ();
+
+    fn to_folded(
+        &self,
+        _options: (),
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        /* Lost samples are left to the caller via lost_samples() */
+        write_folded(
+            self,
+            None,
+            writer)
+    }
+}
+
+/*
+ * Differential folded format, as used by difffolded.pl:
+ * "frame;frame;frame baseline comparison"
+ */
+impl FoldedFormat for ExportGraphDiff {
+    type Options<'a> = ();
+
+    fn to_folded(
+        &self,
+        _options: (),
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let strings = self.strings();
+        let nodes = self.nodes();
+        let root = self.root_node();
+
+        let mut path: Vec<usize> = Vec::new();
+        let mut line = String::new();
+
+        for (id, node) in nodes.iter().enumerate() {
+            if id == root {
+                continue;
+            }
+
+            if node.baseline_exclusive() == 0 && node.comparison_exclusive() == 0 {
+                continue;
+            }
+
+            /* Walk up to the root, then emit root first */
+            path.clear();
+
+            let mut current = id;
+
+            while current != root {
+                path.push(current);
+                current = nodes[current].parent();
+            }
+
+            line.clear();
+
+            for id in path.iter().rev() {
+                push_frame_name(&mut line, strings.from_id(nodes[*id].name())?);
+                line.push(';');
+            }
+
+            line.pop();
+
+            writeln!(
+                writer,
+                "{} {} {}",
+                line,
+                node.baseline_exclusive(),
+                node.comparison_exclusive())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FoldedFormat for ExportMachine {
+    type Options<'a> = FoldedMachineOptions<'a>;
+
+    fn to_folded(
+        &self,
+        options: FoldedMachineOptions<'_>,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let mut graph = ExportGraph::new();
+
+        if options.lost_samples {
+            write_lost_samples(
+                self.lost_samples(),
+                writer)?;
+        }
+
+        for process in self.processes() {
+            if !(options.predicate)(process) {
+                continue;
+            }
+
+            graph.reset();
+
+            graph.add_samples(
+                self,
+                process,
+                options.kind,
+                options.converter);
+
+            /* Root each process by comm name to merge alike processes */
+            let comm = match process.comm_id() {
+                Some(id) => { self.strings().from_id(id)? },
+                None => { "Unknown" },
+            };
+
+            write_folded(
+                &graph,
+                Some(comm),
+                writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::exporting::formats::testing::*;
+
+    #[test]
+    fn it_works() {
+        let mut exporter = stepped_cpu_machine();
+        let cpu = exporter.sample_kind("cpu");
+
+        let process = exporter.find_process(1).unwrap();
+
+        let mut graph = ExportGraph::new();
+
+        graph.add_samples(
+            &exporter,
+            &process,
+            cpu,
+            None);
+
+        let mut output = Vec::new();
+        graph.to_folded((), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        /* Each sample ends at a unique frame */
+        assert_eq!(16, lines.len());
+
+        for line in &lines {
+            assert!(line.ends_with(" 1"));
+        }
+
+        /* Deepest stack is rooted at the last frame */
+        assert!(lines.contains(&"15!15;14!14;13!13;12!12;11!11;10!10;9!9;8!8;7!7;6!6;5!5;4!4;3!3;2!2;1!1;0!0 1"));
+        assert!(lines.contains(&"15!15 1"));
+
+        let mut output = Vec::new();
+        exporter.to_folded(FoldedMachineOptions::new(cpu), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(16, output.lines().count());
+        assert!(output.lines().all(|line| line.starts_with("test;15!15")));
+
+        /* Lost samples are only noted up front when asked for */
+        exporter.add_lost_samples(0, 5);
+
+        let mut output = Vec::new();
+        exporter.to_folded(FoldedMachineOptions::new(cpu), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(16, output.lines().count());
+        assert!(!output.contains('#'));
+
+        let mut output = Vec::new();
+        exporter.to_folded(FoldedMachineOptions::new(cpu).with_lost_samples(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(17, output.lines().count());
+        assert_eq!(Some("# lost_samples=5"), output.lines().next());
+    }
+
+    #[test]
+    fn diff() {
+        let mut exporter = machine();
+
+        for pid in 1..3 {
+            exporter.add_comm_exec(pid, "test", 0).unwrap();
+
+            exporter.add_mmap_exec(
+                0,
+                pid,
+                0,
+                16,
+                0,
+                0,
+                0,
+                0,
+                "mod").unwrap();
+        }
+
+        let cpu = exporter.sample_kind("cpu");
+
+        /* Baseline only, comparison only and shared stacks */
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[1, 0]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[2, 0]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[2, 0]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[2, 0]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[3, 0]).unwrap();
+
+        let mut baseline = ExportGraph::new();
+        let mut comparison = ExportGraph::new();
+
+        baseline.add_samples(&exporter, exporter.find_process(1).unwrap(), cpu, None);
+        comparison.add_samples(&exporter, exporter.find_process(2).unwrap(), cpu, None);
+
+        let diff = ExportGraphDiff::new(&baseline, &comparison).unwrap();
+
+        let mut output = Vec::new();
+        diff.to_folded((), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(3, lines.len());
+        assert!(lines.contains(&"mod!0x0;mod!0x1 1 0"));
+        assert!(lines.contains(&"mod!0x0;mod!0x2 1 2"));
+        assert!(lines.contains(&"mod!0x0;mod!0x3 0 1"));
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::helpers::exporting::*;
+
+const UNKNOWN: &str = "Unknown";
+
+pub(crate) fn find_symbol(
+    mapping: &ExportMapping,
+    ip: u64) -> Option<&ExportSymbol> {
+    mapping.symbols().iter().find(|symbol| {
+        ip >= symbol.start() && ip <= symbol.end()
+    })
+}
+
+pub(crate) fn frame_name(
+    machine: &ExportMachine,
+    mapping: Option<&ExportMapping>,
+    ip: u64) -> String {
+    /* '/' on Linux and '\\' on Windows */
+    const SLASH: char = std::path::MAIN_SEPARATOR;
+
+    let strings = machine.strings();
+
+    let mapping = match mapping {
+        Some(mapping) => { mapping },
+        None => { return format!("{}!0x{:x}", UNKNOWN, ip); },
+    };
+
+    let mut name = match strings.from_id(mapping.filename_id()) {
+        Ok(name) => { name },
+        Err(_) => { UNKNOWN },
+    };
+
+    /* Trim file name to the short name, not full path */
+    if let Some(short_name) = name.rsplit(SLASH).next() {
+        name = short_name;
+    }
+
+    if let Some(symbol) = find_symbol(mapping, ip) {
+        let sym_name = match strings.from_id(symbol.name_id()) {
+            Ok(name) => { name },
+            Err(_) => { UNKNOWN },
+        };
+
+        return format!("{}!{}", name, sym_name);
+    }
+
+    /* Use the file address, unless anonymous */
+    let address = if mapping.anon() || ip > KERNEL_START {
+        ip
+    } else {
+        (ip - mapping.start()) + mapping.file_offset()
+    };
+
+    format!("{}!0x{:x}", name, address)
+}
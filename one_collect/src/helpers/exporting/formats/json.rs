@@ -0,0 +1,46 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::io::Write;
+
+pub(crate) fn write_json_string(
+    writer: &mut impl Write,
+    value: &str) -> anyhow::Result<()> {
+    let mut escaped = String::with_capacity(value.len() + 2);
+
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => { escaped.push_str("\\\""); },
+            '\\' => { escaped.push_str("\\\\"); },
+            '\n' => { escaped.push_str("\\n"); },
+            '\r' => { escaped.push_str("\\r"); },
+            '\t' => { escaped.push_str("\\t"); },
+            c if (c as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32));
+            },
+            _ => { escaped.push(c); },
+        }
+    }
+
+    escaped.push('"');
+
+    writer.write_all(escaped.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        let mut output = Vec::new();
+
+        write_json_string(&mut output, "a\"b\\c\nd\u{1}").unwrap();
+
+        assert_eq!("\"a\\\"b\\\\c\\nd\\u0001\"", String::from_utf8(output).unwrap());
+    }
+}
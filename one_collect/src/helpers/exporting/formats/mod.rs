@@ -4,3 +4,90 @@
 pub mod perf_view;
 pub mod pprof;
 pub mod nettrace;
+pub mod perf_data;
+pub mod folded;
+pub mod speedscope;
+pub mod chrome_trace;
+pub mod perfetto;
+pub mod otlp;
+
+mod json;
+mod frames;
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use crate::helpers::exporting::*;
+
+    /* Empty machine that ignores process FS to avoid permissions, etc */
+    pub(crate) fn machine() -> ExportMachine {
+        let callstacks = CallstackHelper::new();
+        let settings = ExportSettings::new(callstacks);
+
+        #[cfg(target_os = "linux")]
+        let settings = settings.without_process_fs();
+
+        ExportMachine::new(settings)
+    }
+
+    /*
+     * Machine with a "test" process (pid 1) that maps 16 one byte
+     * modules, each with a local symbol. The sampler is called once
+     * per step with the cpu kind, the step and frames stepping down.
+     */
+    pub(crate) fn stepped_machine(
+        mut sampler: impl FnMut(&mut ExportMachine, u16, usize, &[u64])) -> ExportMachine {
+        let mut exporter = machine();
+
+        exporter.add_comm_exec(1, "test", 0).unwrap();
+
+        let mut frames = Vec::new();
+
+        for i in 0..16 {
+            exporter.add_mmap_exec(
+                0,
+                1,
+                i,
+                1,
+                0,
+                0,
+                0,
+                0,
+                &i.to_string()).unwrap();
+
+            /* Add local symbol */
+            let mappings = exporter.process_mut(1).mappings_mut();
+            let len = mappings.len();
+            let last = &mut mappings[len-1];
+
+            last.add_symbol(
+                ExportSymbol::new(
+                    last.filename_id(),
+                    i,
+                    i+1));
+
+            frames.push(i);
+        }
+
+        let cpu = exporter.sample_kind("cpu");
+
+        for i in 0..16 {
+            sampler(&mut exporter, cpu, i, &frames[i..16]);
+        }
+
+        exporter
+    }
+
+    /* Stepped machine with a single cpu sample per step on tid 1 */
+    pub(crate) fn stepped_cpu_machine() -> ExportMachine {
+        stepped_machine(|exporter, cpu, i, frames| {
+            exporter.add_sample(
+                i as u64,
+                MetricValue::Count(1),
+                1,
+                1,
+                0,
+                cpu,
+                frames).unwrap();
+        })
+    }
+}
@@ -0,0 +1,942 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+/*
+ * OTLP profiles signal, as defined by opentelemetry-proto v1.5.0
+ * (opentelemetry.proto.profiles.v1development). ProfilesData and
+ * ExportProfilesServiceRequest share the same wire format, so the
+ * same bytes are used for files and OTLP/HTTP requests.
+ */
+
+use std::fs::File;
+use std::io::{Read, Write, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry::{Vacant, Occupied};
+
+use crate::helpers::exporting::*;
+use super::frames::find_symbol;
+
+use protobuf::CodedOutputStream;
+
+pub const OTLP_DEFAULT_ENDPOINT: &str = "http://localhost:4318/v1development/profiles";
+
+const OTLP_PROFILES_PATH: &str = "/v1development/profiles";
+const OTLP_DEFAULT_PORT: u16 = 4318;
+const OTLP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const OTLP_IO_TIMEOUT: Duration = Duration::from_secs(30);
+const UNKNOWN: &str = "Unknown";
+
+pub trait OtlpFormat {
+    fn to_otlp(
+        &self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut impl Write) -> anyhow::Result<()>;
+
+    fn to_otlp_file(
+        &self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.to_otlp(
+            predicate,
+            &mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn to_otlp_endpoint(
+        &self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        endpoint: &str) -> anyhow::Result<()> {
+        let mut body = Vec::new();
+
+        self.to_otlp(
+            predicate,
+            &mut body)?;
+
+        post_otlp(endpoint, &body)
+    }
+}
+
+fn parse_endpoint(endpoint: &str) -> anyhow::Result<(String, String)> {
+    let rest = match endpoint.strip_prefix("http://") {
+        Some(rest) => { rest },
+        None => { anyhow::bail!("Only http:// OTLP endpoints are supported."); },
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(index) => { (&rest[..index], &rest[index..]) },
+        None => { (rest, OTLP_PROFILES_PATH) },
+    };
+
+    if authority.is_empty() {
+        anyhow::bail!("OTLP endpoint is missing a host.");
+    }
+
+    let authority = match authority.contains(':') {
+        true => { authority.to_owned() },
+        false => { format!("{}:{}", authority, OTLP_DEFAULT_PORT) },
+    };
+
+    Ok((authority, path.to_owned()))
+}
+
+fn post_otlp(
+    endpoint: &str,
+    body: &[u8]) -> anyhow::Result<()> {
+    let (authority, path) = parse_endpoint(endpoint)?;
+
+    /* Don't hang forever on a dead collector */
+    let mut stream = None;
+    let mut error = None;
+
+    for addr in authority.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, OTLP_CONNECT_TIMEOUT) {
+            Ok(connected) => { stream = Some(connected); break; },
+            Err(err) => { error = Some(err); },
+        }
+    }
+
+    let mut stream = match (stream, error) {
+        (Some(stream), _) => { stream },
+        (None, Some(err)) => { return Err(err.into()); },
+        (None, None) => { anyhow::bail!("OTLP endpoint \"{}\" did not resolve.", authority); },
+    };
+
+    stream.set_read_timeout(Some(OTLP_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(OTLP_IO_TIMEOUT))?;
+
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        body.len())?;
+
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+
+    /* "HTTP/1.1 200 OK" */
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => { Ok(()) },
+        _ => { anyhow::bail!("OTLP endpoint returned \"{}\".", status) },
+    }
+}
+
+fn append(
+    field_number: u32,
+    input: &mut Vec<u8>,
+    output: &mut Vec<u8>) -> anyhow::Result<()> {
+    let mut stream = CodedOutputStream::vec(output);
+
+    stream.write_bytes(field_number, input)?;
+    stream.flush()?;
+    drop(stream);
+
+    input.clear();
+
+    Ok(())
+}
+
+fn write_key_value(
+    key: &str,
+    value: ExportAttributeValue,
+    strings: &InternedStrings,
+    output: &mut Vec<u8>) -> anyhow::Result<()> {
+    let mut any = Vec::new();
+    let mut stream = CodedOutputStream::vec(&mut any);
+
+    match value {
+        ExportAttributeValue::Label(id) => {
+            stream.write_string(1, strings.from_id(id).unwrap_or(UNKNOWN))?;
+        },
+        ExportAttributeValue::Value(value) => {
+            stream.write_int64(3, value as i64)?;
+        },
+    }
+
+    stream.flush()?;
+    drop(stream);
+
+    let mut stream = CodedOutputStream::vec(output);
+
+    stream.write_string(1, key)?;
+    stream.write_bytes(2, &any)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct OtlpSample {
+    value: u64,
+    locations_start: usize,
+    locations_len: usize,
+    attribute_indices: Vec<i32>,
+}
+
+#[derive(Default)]
+struct OtlpProfileBuilder {
+    strings: Vec<u8>,
+    string_lookup: HashMap<String, i32>,
+    mappings: Vec<u8>,
+    mapping_lookup: HashMap<usize, i32>,
+    locations: Vec<u8>,
+    location_lookup: HashMap<(usize, u64), i32>,
+    functions: Vec<u8>,
+    function_lookup: HashMap<(usize, usize), i32>,
+    attributes: Vec<u8>,
+    attribute_lookup: HashMap<(usize, ExportAttributeValueKey), i32>,
+    location_indices: Vec<i32>,
+    samples: Vec<OtlpSample>,
+    sample_lookup: HashMap<(u64, usize, usize), usize>,
+    buffer: Vec<u8>,
+    ips: Vec<u64>,
+}
+
+/* ExportAttributeValue is not hashable, so key it locally */
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ExportAttributeValueKey {
+    Label(usize),
+    Value(u64),
+}
+
+impl From<ExportAttributeValue> for ExportAttributeValueKey {
+    fn from(value: ExportAttributeValue) -> Self {
+        match value {
+            ExportAttributeValue::Label(id) => { Self::Label(id) },
+            ExportAttributeValue::Value(value) => { Self::Value(value) },
+        }
+    }
+}
+
+impl OtlpProfileBuilder {
+    fn new() -> Self {
+        let mut builder = Self::default();
+
+        /* string_table[0] must be "" */
+        builder.string_index("");
+
+        builder
+    }
+
+    fn string_index(
+        &mut self,
+        value: &str) -> i32 {
+        if let Some(index) = self.string_lookup.get(value) {
+            return *index;
+        }
+
+        let index = self.string_lookup.len() as i32;
+
+        let mut stream = CodedOutputStream::vec(&mut self.strings);
+        let _ = stream.write_string(10, value);
+        let _ = stream.flush();
+        drop(stream);
+
+        self.string_lookup.insert(value.to_owned(), index);
+
+        index
+    }
+
+    fn machine_string_index(
+        &mut self,
+        machine: &ExportMachine,
+        id: usize) -> i32 {
+        let value = machine.strings().from_id(id).unwrap_or(UNKNOWN);
+
+        self.string_index(value)
+    }
+
+    fn mapping_index(
+        &mut self,
+        machine: &ExportMachine,
+        mapping: &ExportMapping) -> anyhow::Result<i32> {
+        if let Some(index) = self.mapping_lookup.get(&mapping.id()) {
+            return Ok(*index);
+        }
+
+        let filename = self.machine_string_index(machine, mapping.filename_id());
+        let index = self.mapping_lookup.len() as i32;
+
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint64(1, mapping.start())?;
+        stream.write_uint64(2, mapping.end())?;
+        stream.write_uint64(3, mapping.file_offset())?;
+        stream.write_int32(4, filename)?;
+        stream.write_bool(6, !mapping.symbols().is_empty())?;
+        stream.flush()?;
+        drop(stream);
+
+        append(3, &mut self.buffer, &mut self.mappings)?;
+        self.mapping_lookup.insert(mapping.id(), index);
+
+        Ok(index)
+    }
+
+    fn function_index(
+        &mut self,
+        machine: &ExportMachine,
+        mapping: &ExportMapping,
+        name_id: usize) -> anyhow::Result<i32> {
+        let key = (mapping.filename_id(), name_id);
+
+        if let Some(index) = self.function_lookup.get(&key) {
+            return Ok(*index);
+        }
+
+        let name = self.machine_string_index(machine, name_id);
+        let filename = self.machine_string_index(machine, mapping.filename_id());
+        let index = self.function_lookup.len() as i32;
+
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_int32(1, name)?;
+        stream.write_int32(2, name)?;
+        stream.write_int32(3, filename)?;
+        stream.flush()?;
+        drop(stream);
+
+        append(6, &mut self.buffer, &mut self.functions)?;
+        self.function_lookup.insert(key, index);
+
+        Ok(index)
+    }
+
+    fn location_index(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        time: u64,
+        ip: u64) -> anyhow::Result<i32> {
+        let mapping = process.find_mapping(ip, Some(time));
+        let mapping_id = mapping.map_or(usize::MAX, |mapping| mapping.id());
+        let key = (mapping_id, ip);
+
+        if let Some(index) = self.location_lookup.get(&key) {
+            return Ok(*index);
+        }
+
+        let mut mapping_index = None;
+        let mut function_index = None;
+
+        if let Some(mapping) = mapping {
+            mapping_index = Some(self.mapping_index(machine, mapping)?);
+
+            if let Some(symbol) = find_symbol(mapping, ip) {
+                function_index = Some(self.function_index(machine, mapping, symbol.name_id())?);
+            }
+        }
+
+        let index = self.location_lookup.len() as i32;
+
+        let mut line = Vec::new();
+
+        if let Some(function_index) = function_index {
+            let mut stream = CodedOutputStream::vec(&mut line);
+            stream.write_int32(1, function_index)?;
+            stream.flush()?;
+        }
+
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        if let Some(mapping_index) = mapping_index {
+            stream.write_int32(1, mapping_index)?;
+        }
+
+        stream.write_uint64(2, ip)?;
+
+        if function_index.is_some() {
+            stream.write_bytes(3, &line)?;
+        }
+
+        stream.flush()?;
+        drop(stream);
+
+        append(4, &mut self.buffer, &mut self.locations)?;
+        self.location_lookup.insert(key, index);
+
+        Ok(index)
+    }
+
+    fn attribute_index(
+        &mut self,
+        machine: &ExportMachine,
+        attribute: &ExportAttributePair) -> anyhow::Result<i32> {
+        let key = (attribute.name(), attribute.attribute_value().into());
+
+        if let Some(index) = self.attribute_lookup.get(&key) {
+            return Ok(*index);
+        }
+
+        let strings = machine.strings();
+        let name = attribute.name_str(strings).unwrap_or(UNKNOWN);
+        let index = self.attribute_lookup.len() as i32;
+
+        write_key_value(
+            name,
+            attribute.attribute_value(),
+            strings,
+            &mut self.buffer)?;
+
+        append(7, &mut self.buffer, &mut self.attributes)?;
+        self.attribute_lookup.insert(key, index);
+
+        Ok(index)
+    }
+
+    fn add_sample(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        sample: &ExportProcessSample,
+        value: u64,
+        attributes: &[ExportAttributePair]) -> anyhow::Result<()> {
+        let key = (sample.ip(), sample.callstack_id(), sample.attributes_id());
+
+        let index = match self.sample_lookup.entry(key) {
+            Occupied(entry) => { *entry.get() },
+            Vacant(entry) => {
+                entry.insert(self.samples.len());
+
+                let mut otlp_sample = OtlpSample {
+                    locations_start: self.location_indices.len(),
+                    ..Default::default()
+                };
+
+                let mut ips = std::mem::take(&mut self.ips);
+
+                machine.callstacks().from_id(
+                    sample.callstack_id(),
+                    &mut ips)?;
+
+                ips.insert(0, sample.ip());
+
+                /* Locations are leaf first, like our callstacks */
+                for ip in &ips {
+                    let location = self.location_index(machine, process, sample.time(), *ip)?;
+                    self.location_indices.push(location);
+                }
+
+                self.ips = ips;
+
+                otlp_sample.locations_len = self.location_indices.len() - otlp_sample.locations_start;
+
+                for attribute in attributes {
+                    let attribute = self.attribute_index(machine, attribute)?;
+                    otlp_sample.attribute_indices.push(attribute);
+                }
+
+                self.samples.push(otlp_sample);
+                self.samples.len() - 1
+            },
+        };
+
+        self.samples[index].value += value;
+
+        Ok(())
+    }
+
+    fn write_profile(
+        &mut self,
+        machine: &ExportMachine,
+        kind: &str,
+        unit: &str,
+        output: &mut Vec<u8>) -> anyhow::Result<()> {
+        let kind = self.string_index(kind);
+        let unit = self.string_index(unit);
+
+        /* Lost samples and counter totals are machine wide, so every profile notes them */
+        let mut comments = Vec::new();
+
+        let lost_samples = machine.lost_samples();
+
+        if lost_samples != 0 {
+            comments.push(self.string_index(&format!("lost_samples={}", lost_samples)));
+        }
+
+        for (name_id, value) in machine.counter_totals() {
+            let name = machine.strings().from_id(*name_id)?;
+
+            comments.push(self.string_index(&format!("counter:{}={}", name, value)));
+        }
+
+        let mut profile = Vec::new();
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_int32(1, kind)?;
+        stream.write_int32(2, unit)?;
+        stream.flush()?;
+        drop(stream);
+
+        append(1, &mut self.buffer, &mut profile)?;
+
+        for sample in &self.samples {
+            let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+            stream.write_int32(1, sample.locations_start as i32)?;
+            stream.write_int32(2, sample.locations_len as i32)?;
+            stream.write_repeated_packed_int64(3, &[sample.value as i64])?;
+
+            if !sample.attribute_indices.is_empty() {
+                stream.write_repeated_packed_int32(4, &sample.attribute_indices)?;
+            }
+
+            stream.flush()?;
+            drop(stream);
+
+            append(2, &mut self.buffer, &mut profile)?;
+        }
+
+        profile.extend_from_slice(&self.mappings);
+        profile.extend_from_slice(&self.locations);
+
+        let mut stream = CodedOutputStream::vec(&mut profile);
+
+        stream.write_repeated_packed_int32(5, &self.location_indices)?;
+        stream.flush()?;
+        drop(stream);
+
+        profile.extend_from_slice(&self.functions);
+        profile.extend_from_slice(&self.attributes);
+        profile.extend_from_slice(&self.strings);
+
+        let mut stream = CodedOutputStream::vec(&mut profile);
+
+        if let Some(start_date) = machine.start_date() {
+            if let Some(time) = start_date.timestamp_nanos_opt() {
+                stream.write_int64(11, time)?;
+            }
+        }
+
+        if let Some(duration) = machine.duration() {
+            stream.write_int64(12, duration.as_nanos() as i64)?;
+        }
+
+        if !comments.is_empty() {
+            stream.write_repeated_packed_int32(15, &comments)?;
+        }
+
+        stream.flush()?;
+        drop(stream);
+
+        append(2, &mut profile, output)
+    }
+}
+
+fn sample_value(
+    machine: &ExportMachine,
+    value: MetricValue) -> (u64, &'static str) {
+    let freq = ExportMachine::qpc_freq();
+
+    match value {
+        MetricValue::Count(value) => { (value, "count") },
+        MetricValue::Bytes(value) => { (value, "bytes") },
+        MetricValue::Duration(qpc) => {
+            (ExportMachine::qpc_to_ns(freq, qpc), "nanoseconds")
+        },
+        MetricValue::Span(_) => {
+            let qpc = match machine.span_from_value(value) {
+                Some(span) => { span.qpc_duration() },
+                None => { 0 },
+            };
+
+            (ExportMachine::qpc_to_ns(freq, qpc), "nanoseconds")
+        },
+    }
+}
+
+fn write_resource_profiles(
+    machine: &ExportMachine,
+    process: &ExportProcess,
+    output: &mut Vec<u8>) -> anyhow::Result<()> {
+    let strings = machine.strings();
+    let mut walker = ExportAttributeWalker::default();
+
+    /* Labels shared by every sample of the process describe the resource */
+    let mut attributes_ids = HashSet::new();
+    let mut shared: Option<Vec<ExportAttributePair>> = None;
+
+    for sample in process.samples() {
+        if !attributes_ids.insert(sample.attributes_id()) {
+            continue;
+        }
+
+        machine.sample_attributes(sample, &mut walker);
+        let attributes = walker.attributes();
+
+        shared = Some(match shared {
+            Some(mut shared) => {
+                shared.retain(|pair| attributes.contains(pair));
+                shared
+            },
+            None => { attributes.to_vec() },
+        });
+    }
+
+    let shared = shared.unwrap_or_default();
+
+    /* Resource */
+    let comm = match process.comm_id() {
+        Some(id) => { strings.from_id(id).unwrap_or(UNKNOWN) },
+        None => { UNKNOWN },
+    };
+
+    let mut resource = Vec::new();
+    let mut buffer = Vec::new();
+
+    write_key_value(
+        "process.pid",
+        ExportAttributeValue::Value(process.pid() as u64),
+        strings,
+        &mut buffer)?;
+
+    append(1, &mut buffer, &mut resource)?;
+
+    /* Write comm directly, it may not be interned as a label */
+    let mut any = Vec::new();
+    let mut stream = CodedOutputStream::vec(&mut any);
+    stream.write_string(1, comm)?;
+    stream.flush()?;
+    drop(stream);
+
+    let mut stream = CodedOutputStream::vec(&mut buffer);
+    stream.write_string(1, "process.executable.name")?;
+    stream.write_bytes(2, &any)?;
+    stream.flush()?;
+    drop(stream);
+
+    append(1, &mut buffer, &mut resource)?;
+
+    for attribute in &shared {
+        write_key_value(
+            attribute.name_str(strings).unwrap_or(UNKNOWN),
+            attribute.attribute_value(),
+            strings,
+            &mut buffer)?;
+
+        append(1, &mut buffer, &mut resource)?;
+    }
+
+    /* Scope */
+    let mut scope = Vec::new();
+    let mut stream = CodedOutputStream::vec(&mut buffer);
+    stream.write_string(1, "one_collect")?;
+    stream.write_string(2, env!("CARGO_PKG_VERSION"))?;
+    stream.flush()?;
+    drop(stream);
+
+    append(1, &mut buffer, &mut scope)?;
+
+    /* One profile per sample kind */
+    for (kind, name) in machine.sample_kinds().iter().enumerate() {
+        let mut builder = OtlpProfileBuilder::new();
+        let mut unit = "count";
+
+        for sample in process.samples() {
+            if sample.kind() as usize != kind {
+                continue;
+            }
+
+            let value;
+            (value, unit) = sample_value(machine, sample.value());
+
+            machine.sample_attributes(sample, &mut walker);
+
+            let unique: Vec<ExportAttributePair> = walker.attributes()
+                .iter()
+                .filter(|pair| !shared.contains(pair))
+                .copied()
+                .collect();
+
+            builder.add_sample(
+                machine,
+                process,
+                sample,
+                value,
+                &unique)?;
+        }
+
+        if builder.samples.is_empty() {
+            continue;
+        }
+
+        builder.write_profile(
+            machine,
+            name,
+            unit,
+            &mut scope)?;
+    }
+
+    let mut resource_profiles = Vec::new();
+
+    append(1, &mut resource, &mut resource_profiles)?;
+    append(2, &mut scope, &mut resource_profiles)?;
+    append(1, &mut resource_profiles, output)
+}
+
+impl OtlpFormat for ExportMachine {
+    fn to_otlp(
+        &self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let mut output = Vec::new();
+
+        for process in self.processes() {
+            if !predicate(process) || process.samples().is_empty() {
+                continue;
+            }
+
+            write_resource_profiles(
+                self,
+                process,
+                &mut output)?;
+
+            writer.write_all(&output)?;
+            output.clear();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use protobuf::CodedInputStream;
+    use crate::helpers::exporting::formats::testing::stepped_machine;
+
+    /* Splits a message into (field, wire value or bytes) */
+    fn fields(data: &[u8]) -> Vec<(u32, u64, Vec<u8>)> {
+        let mut input = CodedInputStream::from_bytes(data);
+        let mut fields = Vec::new();
+
+        while !input.eof().unwrap() {
+            let tag = input.read_raw_varint32().unwrap();
+            let field = tag >> 3;
+
+            match tag & 7 {
+                0 => { fields.push((field, input.read_raw_varint64().unwrap(), Vec::new())); },
+                2 => { fields.push((field, 0, input.read_bytes().unwrap())); },
+                _ => { panic!("Unexpected wire type"); },
+            }
+        }
+
+        fields
+    }
+
+    fn count(
+        fields: &[(u32, u64, Vec<u8>)],
+        number: u32) -> usize {
+        fields.iter().filter(|field| field.0 == number).count()
+    }
+
+    fn field(
+        fields: &[(u32, u64, Vec<u8>)],
+        number: u32) -> Vec<(u32, u64, Vec<u8>)> {
+        super::tests::fields(&fields.iter().find(|field| field.0 == number).unwrap().2)
+    }
+
+    fn machine() -> ExportMachine {
+        let mut labels = None;
+
+        stepped_machine(|exporter, cpu, i, frames| {
+            /* Shared and unique labels */
+            let (shared, unique) = *labels.get_or_insert_with(|| {
+                let mut shared = ExportAttributes::default();
+                shared.push(exporter.label_attribute("service", "unit"));
+                let shared = exporter.push_unique_attributes(shared);
+
+                let mut unique = ExportAttributes::default();
+                unique.push(exporter.label_attribute("thread.kind", "worker"));
+                unique.push_association(shared);
+                let unique = exporter.push_unique_attributes(unique);
+
+                (shared, unique)
+            });
+
+            let attributes = match i {
+                0 => { unique },
+                _ => { shared },
+            };
+
+            let mut sample = exporter.make_sample(
+                0,
+                MetricValue::Count(1),
+                1,
+                0,
+                cpu,
+                frames);
+
+            sample.attach_attributes(attributes);
+            exporter.process_mut(1).add_sample(sample);
+        })
+    }
+
+    #[test]
+    fn it_works() {
+        let exporter = machine();
+
+        let mut output = Vec::new();
+        exporter.to_otlp(|_| true, &mut output).unwrap();
+
+        let data = fields(&output);
+        assert_eq!(1, count(&data, 1));
+
+        let resource_profiles = field(&data, 1);
+        let resource = field(&resource_profiles, 1);
+
+        /* pid, comm and shared label */
+        assert_eq!(3, count(&resource, 1));
+
+        let label = fields(&resource[2].2);
+        assert_eq!(b"service".to_vec(), label[0].2);
+
+        let scope = field(&resource_profiles, 2);
+        assert_eq!(1, count(&scope, 2));
+
+        let profile = field(&scope, 2);
+
+        /* Each sample is unique */
+        assert_eq!(16, count(&profile, 2));
+        assert_eq!(16, count(&profile, 3));
+        assert_eq!(16, count(&profile, 4));
+        assert_eq!(16, count(&profile, 6));
+
+        /* Only the unique label is in the table */
+        assert_eq!(1, count(&profile, 7));
+
+        let strings: Vec<&Vec<u8>> = profile.iter()
+            .filter(|field| field.0 == 10)
+            .map(|field| &field.2)
+            .collect();
+
+        assert!(strings[0].is_empty());
+        assert!(strings.contains(&&b"cpu".to_vec()));
+        assert!(strings.contains(&&b"count".to_vec()));
+    }
+
+    #[test]
+    fn lost_samples() {
+        let mut exporter = machine();
+
+        let mut output = Vec::new();
+        exporter.to_otlp(|_| true, &mut output).unwrap();
+
+        let data = fields(&output);
+        let profile = field(&field(&field(&data, 1), 2), 2);
+        assert_eq!(0, count(&profile, 15));
+
+        exporter.add_lost_samples(0, 7);
+
+        let mut output = Vec::new();
+        exporter.to_otlp(|_| true, &mut output).unwrap();
+
+        let data = fields(&output);
+        let profile = field(&field(&field(&data, 1), 2), 2);
+
+        let strings: Vec<&Vec<u8>> = profile.iter()
+            .filter(|field| field.0 == 10)
+            .map(|field| &field.2)
+            .collect();
+
+        /* Packed comment index points at the lost samples string */
+        let comments = &profile.iter().find(|field| field.0 == 15).unwrap().2;
+        assert_eq!(1, comments.len());
+        assert_eq!(b"lost_samples=7".to_vec(), *strings[comments[0] as usize]);
+
+        /* Counter totals follow as further comments */
+        exporter.add_counter_total("cycles", 1234);
+
+        let mut output = Vec::new();
+        exporter.to_otlp(|_| true, &mut output).unwrap();
+
+        let data = fields(&output);
+        let profile = field(&field(&field(&data, 1), 2), 2);
+
+        let strings: Vec<&Vec<u8>> = profile.iter()
+            .filter(|field| field.0 == 10)
+            .map(|field| &field.2)
+            .collect();
+
+        let comments = &profile.iter().find(|field| field.0 == 15).unwrap().2;
+        assert_eq!(2, comments.len());
+        assert_eq!(b"lost_samples=7".to_vec(), *strings[comments[0] as usize]);
+        assert_eq!(b"counter:cycles=1234".to_vec(), *strings[comments[1] as usize]);
+    }
+
+    #[test]
+    fn endpoint() {
+        assert!(parse_endpoint("https://localhost").is_err());
+
+        let (authority, path) = parse_endpoint("http://localhost").unwrap();
+        assert_eq!("localhost:4318", authority);
+        assert_eq!(OTLP_PROFILES_PATH, path);
+
+        let (authority, path) = parse_endpoint("http://127.0.0.1:1234/custom").unwrap();
+        assert_eq!("127.0.0.1:1234", authority);
+        assert_eq!("/custom", path);
+
+        let (authority, path) = parse_endpoint(OTLP_DEFAULT_ENDPOINT).unwrap();
+        assert_eq!("localhost:4318", authority);
+        assert_eq!(OTLP_PROFILES_PATH, path);
+
+        /* Local collector stand-in */
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let collector = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+
+            /* Read until the full body has arrived */
+            loop {
+                let len = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..len]);
+
+                let text = String::from_utf8_lossy(&request).to_string();
+
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let length: usize = text.lines()
+                        .find_map(|line| line.strip_prefix("Content-Length: "))
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+
+                    if request.len() >= header_end + 4 + length {
+                        break;
+                    }
+                }
+            }
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+
+            request
+        });
+
+        let exporter = machine();
+        let endpoint = format!("http://127.0.0.1:{}", port);
+
+        exporter.to_otlp_endpoint(|_| true, &endpoint).unwrap();
+
+        let request = collector.join().unwrap();
+        let text = String::from_utf8_lossy(&request);
+
+        assert!(text.starts_with("POST /v1development/profiles HTTP/1.1\r\n"));
+        assert!(text.contains("Content-Type: application/x-protobuf\r\n"));
+    }
+}
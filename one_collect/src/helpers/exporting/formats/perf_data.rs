@@ -0,0 +1,751 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write, BufWriter};
+
+use crate::helpers::exporting::*;
+use crate::helpers::exporting::graph::*;
+
+use crate::perf_event::abi::{
+    PERF_FILE_MAGIC,
+    PERF_FILE_HEADER_SIZE,
+    PERF_ATTR_SIZE_VER4,
+    PERF_TYPE_HARDWARE,
+    PERF_TYPE_SOFTWARE,
+    PERF_COUNT_HW_CPU_CYCLES,
+    PERF_COUNT_HW_INSTRUCTIONS,
+    PERF_COUNT_SW_CPU_CLOCK,
+    PERF_COUNT_SW_TASK_CLOCK,
+    PERF_COUNT_SW_PAGE_FAULTS,
+    PERF_COUNT_SW_CONTEXT_SWITCHES,
+    PERF_COUNT_SW_CPU_MIGRATIONS,
+    PERF_COUNT_SW_PAGE_FAULTS_MIN,
+    PERF_COUNT_SW_PAGE_FAULTS_MAJ,
+    PERF_COUNT_SW_ALIGNMENT_FAULTS,
+    PERF_COUNT_SW_DUMMY,
+    PERF_SAMPLE_IP,
+    PERF_SAMPLE_TID,
+    PERF_SAMPLE_TIME,
+    PERF_SAMPLE_CALLCHAIN,
+    PERF_SAMPLE_CPU,
+    PERF_SAMPLE_PERIOD,
+    PERF_SAMPLE_IDENTIFIER,
+    FLAG_DISABLED,
+    FLAG_MMAP,
+    FLAG_COMM,
+    FLAG_TASK,
+    FLAG_SAMPLE_ID_ALL,
+    FLAG_MMAP2,
+    PERF_RECORD_MMAP,
+    PERF_RECORD_LOST,
+    PERF_RECORD_COMM,
+    PERF_RECORD_EXIT,
+    PERF_RECORD_SAMPLE,
+    PERF_RECORD_MMAP2,
+    PERF_RECORD_MISC_KERNEL,
+    PERF_RECORD_MISC_USER,
+    PERF_CONTEXT_KERNEL,
+    PERF_CONTEXT_USER,
+    HEADER_ARCH,
+    HEADER_NRCPUS,
+    HEADER_EVENT_DESC,
+};
+
+const PERF_ATTR_SIZE: u64 = PERF_ATTR_SIZE_VER4 as u64;
+const PERF_ATTR_ENTRY_SIZE: u64 = PERF_ATTR_SIZE + 16;
+
+const SAMPLE_TYPE: u64 =
+    PERF_SAMPLE_IDENTIFIER |
+    PERF_SAMPLE_IP |
+    PERF_SAMPLE_TID |
+    PERF_SAMPLE_TIME |
+    PERF_SAMPLE_CPU |
+    PERF_SAMPLE_PERIOD |
+    PERF_SAMPLE_CALLCHAIN;
+
+/* Header strings are padded to this alignment */
+const NAME_ALIGN: usize = 64;
+
+const PROT_EXEC: u32 = 4;
+const MAP_PRIVATE: u32 = 2;
+
+/* Keep records within the u16 header size */
+const MAX_CALLCHAIN: usize = 4096;
+
+pub trait PerfDataFormat {
+    fn to_perf_data<W: Write + Seek>(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut W) -> anyhow::Result<()>;
+
+    fn to_perf_data_file(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.to_perf_data(
+            predicate,
+            &mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn kind_config(kind: &str) -> (u32, u64) {
+    match kind {
+        "cpu" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_CLOCK) },
+        "cswitch" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CONTEXT_SWITCHES) },
+        "page_fault" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS) },
+        "minor_fault" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS_MIN) },
+        "major_fault" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS_MAJ) },
+        "alignment_fault" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_ALIGNMENT_FAULTS) },
+        "cpu_migration" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_MIGRATIONS) },
+        "task_clock" => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_TASK_CLOCK) },
+        "cycles" => { (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES) },
+        "instructions" => { (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS) },
+        /* No perf equivalent, perf names these via EVENT_DESC */
+        _ => { (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_DUMMY) },
+    }
+}
+
+const fn kind_id(kind: u16) -> u64 {
+    /* perf treats ID 0 as unset */
+    kind as u64 + 1
+}
+
+/* Non-sample records are attributed to the first kind */
+const SIDEBAND_ID: u64 = kind_id(0);
+
+fn is_kernel(ip: u64) -> bool {
+    ip >= KERNEL_START
+}
+
+fn dev_parts(dev: u64) -> (u32, u32) {
+    /* Kernel dev_t encoding, 12-bit major and 20-bit minor */
+    let maj = (dev >> 8) & 0xfff;
+    let min = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+
+    (maj as u32, min as u32)
+}
+
+fn write_attr(
+    kind: &str,
+    output: &mut Vec<u8>) {
+    let start = output.len();
+    let (event_type, config) = kind_config(kind);
+
+    output.extend_from_slice(&event_type.to_ne_bytes());
+    output.extend_from_slice(&(PERF_ATTR_SIZE as u32).to_ne_bytes());
+    output.extend_from_slice(&config.to_ne_bytes());
+    /* sample_period */
+    output.extend_from_slice(&1u64.to_ne_bytes());
+    output.extend_from_slice(&SAMPLE_TYPE.to_ne_bytes());
+    /* read_format */
+    output.extend_from_slice(&0u64.to_ne_bytes());
+
+    let flags =
+        FLAG_DISABLED |
+        FLAG_MMAP |
+        FLAG_COMM |
+        FLAG_TASK |
+        FLAG_SAMPLE_ID_ALL |
+        FLAG_MMAP2;
+
+    output.extend_from_slice(&flags.to_ne_bytes());
+
+    /* Remaining fields are unused */
+    output.resize(start + PERF_ATTR_SIZE as usize, 0);
+}
+
+fn write_string(
+    value: &str,
+    output: &mut Vec<u8>) {
+    let len = (value.len() + 1).div_ceil(NAME_ALIGN) * NAME_ALIGN;
+
+    output.extend_from_slice(&(len as u32).to_ne_bytes());
+    output.extend_from_slice(value.as_bytes());
+    output.resize(output.len() + len - value.len(), 0);
+}
+
+fn write_section(
+    offset: u64,
+    size: u64,
+    output: &mut Vec<u8>) {
+    output.extend_from_slice(&offset.to_ne_bytes());
+    output.extend_from_slice(&size.to_ne_bytes());
+}
+
+struct PerfDataWriter<'a, W: Write + Seek> {
+    output: &'a mut W,
+    buffer: Vec<u8>,
+    frames: Vec<u64>,
+    data_size: u64,
+}
+
+impl<'a, W: Write + Seek> PerfDataWriter<'a, W> {
+    fn new(output: &'a mut W) -> Self {
+        Self {
+            output,
+            buffer: Vec::new(),
+            frames: Vec::new(),
+            data_size: 0,
+        }
+    }
+
+    fn begin_record(&mut self) {
+        self.buffer.clear();
+
+        /* Header is filled in by end_record() */
+        self.buffer.extend_from_slice(&[0u8; 8]);
+    }
+
+    fn write_u32(
+        &mut self,
+        value: u32) {
+        self.buffer.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn write_u64(
+        &mut self,
+        value: u64) {
+        self.buffer.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    fn write_str(
+        &mut self,
+        value: &str) {
+        self.buffer.extend_from_slice(value.as_bytes());
+
+        /* NULL terminate and pad to u64 */
+        let len = self.buffer.len() + 1;
+        self.buffer.resize(len.div_ceil(8) * 8, 0);
+    }
+
+    fn write_sample_id(
+        &mut self,
+        pid: u32,
+        tid: u32,
+        time: u64,
+        cpu: u32,
+        id: u64) {
+        /* TID, TIME, CPU, IDENTIFIER */
+        self.write_u32(pid);
+        self.write_u32(tid);
+        self.write_u64(time);
+        self.write_u32(cpu);
+        self.write_u32(0);
+        self.write_u64(id);
+    }
+
+    fn end_record(
+        &mut self,
+        entry_type: u32,
+        misc: u16) -> anyhow::Result<()> {
+        let size = self.buffer.len();
+
+        if size > u16::MAX as usize {
+            anyhow::bail!("Record is too large for perf.data.");
+        }
+
+        self.buffer[0..4].copy_from_slice(&entry_type.to_ne_bytes());
+        self.buffer[4..6].copy_from_slice(&misc.to_ne_bytes());
+        self.buffer[6..8].copy_from_slice(&(size as u16).to_ne_bytes());
+
+        self.output.write_all(&self.buffer)?;
+        self.data_size += size as u64;
+
+        Ok(())
+    }
+
+    fn write_comm(
+        &mut self,
+        pid: u32,
+        comm: &str,
+        time: u64) -> anyhow::Result<()> {
+        self.begin_record();
+        self.write_u32(pid);
+        self.write_u32(pid);
+        self.write_str(comm);
+        self.write_sample_id(pid, pid, time, 0, SIDEBAND_ID);
+        self.end_record(PERF_RECORD_COMM, 0)
+    }
+
+    fn write_exit(
+        &mut self,
+        pid: u32,
+        time: u64) -> anyhow::Result<()> {
+        self.begin_record();
+        self.write_u32(pid);
+        self.write_u32(0);
+        self.write_u32(pid);
+        self.write_u32(0);
+        self.write_u64(time);
+        self.write_sample_id(pid, pid, time, 0, SIDEBAND_ID);
+        self.end_record(PERF_RECORD_EXIT, 0)
+    }
+
+    fn write_lost(
+        &mut self,
+        cpu: u32,
+        lost: u64,
+        time: u64) -> anyhow::Result<()> {
+        self.begin_record();
+        self.write_u64(SIDEBAND_ID);
+        self.write_u64(lost);
+        self.write_sample_id(u32::MAX, u32::MAX, time, cpu, SIDEBAND_ID);
+        self.end_record(PERF_RECORD_LOST, 0)
+    }
+
+    fn write_kernel_mmap(&mut self) -> anyhow::Result<()> {
+        /* Let perf resolve the kernel via kallsyms */
+        self.begin_record();
+        self.write_u32(u32::MAX);
+        self.write_u32(0);
+        self.write_u64(KERNEL_START);
+        self.write_u64(KERNEL_END - KERNEL_START);
+        self.write_u64(0);
+        self.write_str("[kernel.kallsyms]_text");
+        self.write_sample_id(u32::MAX, 0, 0, 0, SIDEBAND_ID);
+        self.end_record(PERF_RECORD_MMAP, PERF_RECORD_MISC_KERNEL)
+    }
+
+    fn write_mapping(
+        &mut self,
+        machine: &ExportMachine,
+        pid: u32,
+        mapping: &ExportMapping) -> anyhow::Result<()> {
+        /* Kernel is covered by write_kernel_mmap() */
+        if is_kernel(mapping.start()) {
+            return Ok(());
+        }
+
+        let mut filename = machine.strings().from_id(mapping.filename_id())?;
+
+        if filename.is_empty() {
+            filename = "//anon";
+        }
+
+        let (dev, ino) = match mapping.node() {
+            Some(node) => { (node.dev(), node.ino()) },
+            None => { (0, 0) },
+        };
+
+        let (maj, min) = dev_parts(dev);
+
+        self.begin_record();
+        self.write_u32(pid);
+        self.write_u32(pid);
+        self.write_u64(mapping.start());
+        self.write_u64(mapping.len() + 1);
+        self.write_u64(mapping.file_offset());
+        self.write_u32(maj);
+        self.write_u32(min);
+        self.write_u64(ino);
+        self.write_u64(0);
+        self.write_u32(PROT_EXEC);
+        self.write_u32(MAP_PRIVATE);
+        self.write_str(filename);
+        self.write_sample_id(pid, pid, mapping.time(), 0, SIDEBAND_ID);
+        self.end_record(PERF_RECORD_MMAP2, PERF_RECORD_MISC_USER)
+    }
+
+    fn write_sample(
+        &mut self,
+        machine: &ExportMachine,
+        pid: u32,
+        sample: &ExportProcessSample,
+        converter: &dyn ExportGraphMetricValueConverter) -> anyhow::Result<()> {
+        machine.callstacks().from_id(
+            sample.callstack_id(),
+            &mut self.frames)?;
+
+        let ip = sample.ip();
+        let period = converter.convert(machine, sample.value());
+
+        let misc = match is_kernel(ip) {
+            true => { PERF_RECORD_MISC_KERNEL },
+            false => { PERF_RECORD_MISC_USER },
+        };
+
+        self.begin_record();
+        self.write_u64(kind_id(sample.kind()));
+        self.write_u64(ip);
+        self.write_u32(pid);
+        self.write_u32(sample.tid());
+        self.write_u64(sample.time());
+        self.write_u32(sample.cpu() as u32);
+        self.write_u32(0);
+        self.write_u64(period);
+
+        /* Callchain with context markers, reserve count */
+        let count_offset = self.buffer.len();
+        self.write_u64(0);
+
+        let mut count = 0u64;
+        let mut kernel = None;
+
+        let frames = std::mem::take(&mut self.frames);
+
+        for frame in std::iter::once(&ip).chain(frames.iter()).take(MAX_CALLCHAIN) {
+            let frame_kernel = is_kernel(*frame);
+
+            if kernel != Some(frame_kernel) {
+                match frame_kernel {
+                    true => { self.write_u64(PERF_CONTEXT_KERNEL); },
+                    false => { self.write_u64(PERF_CONTEXT_USER); },
+                }
+
+                kernel = Some(frame_kernel);
+                count += 1;
+            }
+
+            self.write_u64(*frame);
+            count += 1;
+        }
+
+        self.frames = frames;
+
+        self.buffer[count_offset..count_offset+8].copy_from_slice(&count.to_ne_bytes());
+
+        self.end_record(PERF_RECORD_SAMPLE, misc)
+    }
+
+    fn write_replay_event(
+        &mut self,
+        machine: &ExportMachine,
+        replay: &ExportProcessReplay,
+        converter: &dyn ExportGraphMetricValueConverter) -> anyhow::Result<()> {
+        let process = replay.process();
+        let pid = process.pid();
+
+        if let Some(mapping) = replay.mapping_event() {
+            self.write_mapping(machine, pid, mapping)?;
+        }
+
+        if let Some(sample) = replay.sample_event() {
+            self.write_sample(machine, pid, sample, converter)?;
+        }
+
+        if replay.exited_event() {
+            self.write_exit(pid, replay.time())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn write_features(
+    data_end: u64,
+    kinds: &[String],
+    output: &mut Vec<u8>) -> [u8; 32] {
+    let mut bits = [0u8; 32];
+    let mut sections = Vec::new();
+
+    /* ARCH */
+    let mut arch = Vec::new();
+    write_string(std::env::consts::ARCH, &mut arch);
+    sections.push((HEADER_ARCH, arch));
+
+    /* NRCPUS */
+    let mut cpus = Vec::new();
+    let cpu_count = ExportMachine::cpu_count();
+    cpus.extend_from_slice(&cpu_count.to_ne_bytes());
+    cpus.extend_from_slice(&cpu_count.to_ne_bytes());
+    sections.push((HEADER_NRCPUS, cpus));
+
+    /* EVENT_DESC */
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&(kinds.len() as u32).to_ne_bytes());
+    desc.extend_from_slice(&(PERF_ATTR_SIZE as u32).to_ne_bytes());
+
+    for (i, kind) in kinds.iter().enumerate() {
+        write_attr(kind, &mut desc);
+        desc.extend_from_slice(&1u32.to_ne_bytes());
+        write_string(kind, &mut desc);
+        desc.extend_from_slice(&kind_id(i as u16).to_ne_bytes());
+    }
+
+    sections.push((HEADER_EVENT_DESC, desc));
+
+    /* Section table is first, then section data */
+    let mut offset = data_end + (sections.len() * 16) as u64;
+
+    for (bit, data) in &sections {
+        bits[bit / 8] |= 1 << (bit % 8);
+        write_section(offset, data.len() as u64, output);
+        offset += data.len() as u64;
+    }
+
+    for (_, data) in &sections {
+        output.extend_from_slice(data);
+    }
+
+    bits
+}
+
+impl PerfDataFormat for ExportMachine {
+    fn to_perf_data<W: Write + Seek>(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut W) -> anyhow::Result<()> {
+        let kinds = self.sample_kinds().clone();
+
+        if kinds.is_empty() {
+            anyhow::bail!("No sample kinds to export.");
+        }
+
+        let start = writer.stream_position()?;
+        let attr_offset = start + PERF_FILE_HEADER_SIZE;
+        let attr_size = kinds.len() as u64 * PERF_ATTR_ENTRY_SIZE;
+        let ids_offset = attr_offset + attr_size;
+        let data_offset = ids_offset + kinds.len() as u64 * 8;
+
+        /* Header is written last, once sizes are known */
+        writer.write_all(&[0u8; PERF_FILE_HEADER_SIZE as usize])?;
+
+        /* Attributes, one per sample kind with a single ID each */
+        let mut buffer = Vec::new();
+
+        for (i, kind) in kinds.iter().enumerate() {
+            write_attr(kind, &mut buffer);
+            write_section(ids_offset + i as u64 * 8, 8, &mut buffer);
+        }
+
+        for i in 0..kinds.len() {
+            buffer.extend_from_slice(&kind_id(i as u16).to_ne_bytes());
+        }
+
+        writer.write_all(&buffer)?;
+
+        /* Data */
+        let mut data = PerfDataWriter::new(writer);
+        let converter = DefaultExportGraphMetricValueConverter::default();
+
+        data.write_kernel_mmap()?;
+
+        /* Emit comms up front, processes may pre-date the capture */
+        for process in self.processes() {
+            if !predicate(process) {
+                continue;
+            }
+
+            if let Some(comm_id) = process.comm_id() {
+                let comm = self.strings().from_id(comm_id)?;
+                let time = process.create_time_qpc().unwrap_or(0);
+
+                data.write_comm(process.pid(), comm, time)?;
+            }
+        }
+
+        self.replay_by_time(
+            predicate,
+            |machine, replay| {
+                data.write_replay_event(machine, replay, &converter)
+            })?;
+
+        /* Lost samples are reported per-CPU at the end of the capture */
+        let end_time = self.end_qpc().unwrap_or(0);
+
+        for (cpu, lost) in self.lost_samples_per_cpu().iter().enumerate() {
+            if *lost != 0 {
+                data.write_lost(cpu as u32, *lost, end_time)?;
+            }
+        }
+
+        let data_size = data.data_size;
+
+        /* Features */
+        buffer.clear();
+
+        let features = write_features(
+            data_offset + data_size,
+            &kinds,
+            &mut buffer);
+
+        writer.write_all(&buffer)?;
+
+        let end = writer.stream_position()?;
+
+        /* Header */
+        buffer.clear();
+        buffer.extend_from_slice(&PERF_FILE_MAGIC.to_ne_bytes());
+        buffer.extend_from_slice(&PERF_FILE_HEADER_SIZE.to_ne_bytes());
+        buffer.extend_from_slice(&PERF_ATTR_ENTRY_SIZE.to_ne_bytes());
+        write_section(attr_offset - start, attr_size, &mut buffer);
+        write_section(data_offset - start, data_size, &mut buffer);
+        write_section(0, 0, &mut buffer);
+        buffer.extend_from_slice(&features);
+
+        writer.seek(SeekFrom::Start(start))?;
+        writer.write_all(&buffer)?;
+        writer.seek(SeekFrom::Start(end))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::helpers::exporting::formats::testing::*;
+
+    #[test]
+    fn it_works() {
+        let mut exporter = stepped_cpu_machine();
+
+        exporter.add_comm_exit(1, 100).unwrap();
+        exporter.add_lost_samples(1, 3);
+        exporter.add_lost_samples(2, 4);
+
+        let mut output = Cursor::new(Vec::new());
+
+        exporter.to_perf_data(
+            |_| true,
+            &mut output).unwrap();
+
+        let data = output.into_inner();
+
+        assert_eq!(PERF_FILE_MAGIC.to_ne_bytes(), data[0..8]);
+
+        /* Ensure perf_event can read back what we wrote */
+        #[cfg(target_os = "linux")]
+        {
+            use std::rc::Rc;
+            use std::cell::RefCell;
+            use crate::perf_event::{PerfSession, PerfFileDataSource};
+
+            let source = PerfFileDataSource::new(Cursor::new(data)).unwrap();
+
+            assert_eq!(1, source.attributes().len());
+            assert_eq!(Some(std::env::consts::ARCH), source.features().arch());
+
+            let mut session = PerfSession::new(Box::new(source));
+            let counts = Rc::new(RefCell::new((0, 0, 0, 0)));
+
+            let count = counts.clone();
+            session.comm_event().add_callback(move |_| {
+                count.borrow_mut().0 += 1;
+                Ok(())
+            });
+
+            let count = counts.clone();
+            session.mmap_event().add_callback(move |_| {
+                count.borrow_mut().1 += 1;
+                Ok(())
+            });
+
+            let count = counts.clone();
+            session.cpu_profile_event().add_callback(move |_| {
+                count.borrow_mut().2 += 1;
+                Ok(())
+            });
+
+            let count = counts.clone();
+            session.exit_event().add_callback(move |_| {
+                count.borrow_mut().3 += 1;
+                Ok(())
+            });
+
+            let lost = Rc::new(RefCell::new(0));
+            let lost_count = lost.clone();
+            let lost_field = session.lost_event().format().get_field_ref_unchecked("lost");
+
+            session.lost_event().add_callback(move |data| {
+                let fmt = data.format();
+                *lost_count.borrow_mut() += fmt.get_u64(lost_field, data.event_data())?;
+                Ok(())
+            });
+
+            session.parse_all().unwrap();
+
+            assert_eq!((1, 16, 16, 1), *counts.borrow());
+            assert_eq!(7, *lost.borrow());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kinds_and_devs() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        use crate::perf_event::{PerfSession, PerfFileDataSource};
+
+        let mut exporter = machine();
+
+        exporter.add_comm_exec(1, "test", 0).unwrap();
+        exporter.add_mmap_exec(1, 1, 0x1000, 0x1000, 0, 8, 1, 42, "/a").unwrap();
+
+        /* Minors past 8 bits are split around the major */
+        let maj: u64 = 259;
+        let min: u64 = 0x12345;
+        let dev = (maj << 8) | (min & 0xff) | ((min & !0xff) << 12);
+        let filename = exporter.intern("/b");
+
+        let mut mapping = ExportMapping::new(
+            2, filename, 0x2000, 0x2fff, 0, false, 1, UnwindType::DWARF);
+
+        mapping.set_node(ExportDevNode::new(dev, 43));
+        exporter.process_mut(1).add_mapping(mapping);
+
+        let cpu = exporter.sample_kind("cpu");
+        let cswitch = exporter.sample_kind("cswitch");
+
+        exporter.add_sample(3, MetricValue::Count(1), 1, 1, 0, cswitch, &[0x1000]).unwrap();
+        exporter.add_sample(4, MetricValue::Count(1), 1, 1, 0, cpu, &[0x1000]).unwrap();
+        exporter.add_sample(5, MetricValue::Count(1), 1, 1, 0, cswitch, &[0x2000]).unwrap();
+
+        let mut output = Cursor::new(Vec::new());
+
+        exporter.to_perf_data(
+            |_| true,
+            &mut output).unwrap();
+
+        let source = PerfFileDataSource::new(Cursor::new(output.into_inner())).unwrap();
+
+        assert_eq!(2, source.attributes().len());
+
+        let mut session = PerfSession::new(Box::new(source));
+
+        let devs = Rc::new(RefCell::new(Vec::new()));
+        let mmap_devs = devs.clone();
+        let fmt = session.mmap_event().format();
+        let maj_field = fmt.get_field_ref_unchecked("maj");
+        let min_field = fmt.get_field_ref_unchecked("min");
+
+        session.mmap_event().add_callback(move |data| {
+            let fmt = data.format();
+            let data = data.event_data();
+
+            mmap_devs.borrow_mut().push((
+                fmt.get_u32(maj_field, data)?,
+                fmt.get_u32(min_field, data)?));
+
+            Ok(())
+        });
+
+        /* Each sample is attributed to the attr of its kind */
+        let counts = Rc::new(RefCell::new((0, 0)));
+
+        let count = counts.clone();
+        session.cpu_profile_event().add_callback(move |_| {
+            count.borrow_mut().0 += 1;
+            Ok(())
+        });
+
+        let count = counts.clone();
+        session.cswitch_profile_event().add_callback(move |_| {
+            count.borrow_mut().1 += 1;
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(vec![(8, 1), (259, 0x12345)], *devs.borrow());
+        assert_eq!((1, 2), *counts.borrow());
+    }
+}
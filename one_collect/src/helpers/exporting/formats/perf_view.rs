@@ -5,7 +5,7 @@ use std::fs::File;
 use std::io::{Write, BufWriter};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Vacant, Occupied};
-use crate::helpers::exporting::graph::{Target, ExportGraph};
+use crate::helpers::exporting::graph::{Target, ExportGraph, ExportGraphDiff};
 
 pub trait PerfViewXmlFormat {
     fn to_perf_view_xml(
@@ -50,6 +50,11 @@ impl PerfViewXmlFormat for ExportGraph {
         let mut writer = BufWriter::new(file);
 
         write!(writer, "<StackWindow>\n")?;
+
+        if self.lost_samples() != 0 {
+            writeln!(writer, "<!-- lost_samples={} -->", self.lost_samples())?;
+        }
+
         write!(writer, "<StackSource>\n")?;
         write!(writer, "<Frames Count=\"{}\">\n", frames.len())?;
 
@@ -161,3 +166,192 @@ impl PerfViewXmlFormat for ExportGraph {
         Ok(())
     }
 }
+
+fn escape_name(name: &str) -> String {
+    let mut name = name.to_string();
+
+    if name.contains('<') || name.contains('>') || name.contains('&') {
+        name = name
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+    }
+
+    name
+}
+
+/*
+ * PerfView cannot show negative metrics, so instead of deltas the
+ * baseline and comparison stacks are each rooted under their own
+ * frame with their own positive metrics.
+ */
+impl PerfViewXmlFormat for ExportGraphDiff {
+    fn to_perf_view_xml(
+        &self,
+        path: &str) -> anyhow::Result<()> {
+        let strings = self.strings();
+        let nodes = self.nodes();
+        let root = self.root_node();
+
+        /* Sort by name so IDs are stable across runs */
+        let mut names: Vec<(&str, usize)> = Vec::new();
+
+        for node in nodes.iter().skip(1) {
+            names.push((strings.from_id(node.name())?, node.name()));
+        }
+
+        names.sort();
+        names.dedup();
+
+        let frames: HashMap<usize, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(id, (_, name_id))| (*name_id, id))
+            .collect();
+
+        let baseline_frame = names.len();
+        let comparison_frame = baseline_frame + 1;
+
+        /* Comparison stacks follow the baseline stacks */
+        let stack_count = nodes.len();
+
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+
+        writeln!(writer, "<StackWindow>")?;
+        writeln!(writer, "<StackSource>")?;
+        writeln!(writer, "<Frames Count=\"{}\">", names.len() + 2)?;
+
+        for (id, (name, _)) in names.iter().enumerate() {
+            writeln!(writer, "<Frame ID=\"{}\">{}</Frame>", id, escape_name(name))?;
+        }
+
+        writeln!(writer, "<Frame ID=\"{}\">Baseline</Frame>", baseline_frame)?;
+        writeln!(writer, "<Frame ID=\"{}\">Comparison</Frame>", comparison_frame)?;
+
+        writeln!(writer, "</Frames>")?;
+        writeln!(writer, "<Stacks Count=\"{}\">", stack_count * 2)?;
+
+        for (offset, group_frame) in [(0, baseline_frame), (stack_count, comparison_frame)] {
+            /* Root stack is the group frame */
+            writeln!(
+                writer,
+                "<Stack ID=\"{}\" CallerID=\"-1\" FrameID=\"{}\"/>",
+                offset + root,
+                group_frame)?;
+
+            for (id, node) in nodes.iter().enumerate() {
+                if id == root {
+                    continue;
+                }
+
+                writeln!(
+                    writer,
+                    "<Stack ID=\"{}\" CallerID=\"{}\" FrameID=\"{}\"/>",
+                    offset + id,
+                    offset + node.parent(),
+                    frames[&node.name()])?;
+            }
+        }
+
+        writeln!(writer, "</Stacks>")?;
+
+        let mut samples = Vec::new();
+
+        for (id, node) in nodes.iter().enumerate() {
+            if id == root {
+                continue;
+            }
+
+            if node.baseline_exclusive() != 0 {
+                samples.push((id, node.baseline_exclusive()));
+            }
+
+            if node.comparison_exclusive() != 0 {
+                samples.push((stack_count + id, node.comparison_exclusive()));
+            }
+        }
+
+        writeln!(writer, "<Samples Count=\"{}\">", samples.len())?;
+
+        for (id, (stack_id, metric)) in samples.iter().enumerate() {
+            writeln!(
+                writer,
+                "<Sample ID=\"{}\" Count=\"1\" StackID=\"{}\" Metric=\"{}\"/>",
+                id,
+                stack_id,
+                metric)?;
+        }
+
+        writeln!(writer, "</Samples>")?;
+        writeln!(writer, "</StackSource>")?;
+        writeln!(writer, "</StackWindow>")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::exporting::*;
+    use crate::helpers::exporting::formats::testing::machine;
+
+    #[test]
+    fn diff() {
+        let mut exporter = machine();
+
+        for pid in 1..3 {
+            exporter.add_comm_exec(pid, "test", 0).unwrap();
+
+            for name in ["b", "a", "c"] {
+                let start = (name.as_bytes()[0] - b'a') as u64 * 16;
+
+                exporter.add_mmap_exec(0, pid, start, 16, 0, 0, 0, 0, name).unwrap();
+            }
+        }
+
+        let cpu = exporter.sample_kind("cpu");
+
+        /* Baseline: b -> a x2, comparison: b -> a x1, b -> c x1 */
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[0, 16]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[0, 16]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[0, 16]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[32, 16]).unwrap();
+
+        let mut baseline = ExportGraph::new();
+        let mut comparison = ExportGraph::new();
+
+        baseline.add_samples(&exporter, exporter.find_process(1).unwrap(), cpu, None);
+        comparison.add_samples(&exporter, exporter.find_process(2).unwrap(), cpu, None);
+
+        let diff = ExportGraphDiff::new(&baseline, &comparison).unwrap();
+
+        let path = std::env::temp_dir().join(
+            format!("one_collect_diff_{}.PerfView.xml", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        diff.to_perf_view_xml(path).unwrap();
+        let first = std::fs::read_to_string(path).unwrap();
+
+        diff.to_perf_view_xml(path).unwrap();
+        let second = std::fs::read_to_string(path).unwrap();
+
+        std::fs::remove_file(path).unwrap();
+
+        /* Output is stable and frames are in name order */
+        assert_eq!(first, second);
+        assert!(first.contains("<Frame ID=\"0\">a!0x0</Frame>"));
+        assert!(first.contains("<Frame ID=\"1\">b!0x0</Frame>"));
+        assert!(first.contains("<Frame ID=\"2\">c!0x0</Frame>"));
+        assert!(first.contains("<Frame ID=\"3\">Baseline</Frame>"));
+        assert!(first.contains("<Frame ID=\"4\">Comparison</Frame>"));
+
+        /* Baseline and comparison are separate positive metrics */
+        assert!(!first.contains("Metric=\"-"));
+        assert!(first.contains("<Samples Count=\"3\">"));
+        assert!(first.contains("StackID=\"2\" Metric=\"2\""));
+        assert!(first.contains("StackID=\"6\" Metric=\"1\""));
+        assert!(first.contains("StackID=\"7\" Metric=\"1\""));
+    }
+}
@@ -0,0 +1,767 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::collections::{HashMap, HashSet};
+
+use crate::helpers::exporting::*;
+use crate::helpers::exporting::graph::*;
+use super::frames::find_symbol;
+
+use protobuf::CodedOutputStream;
+
+/* Trace */
+const TRACE_PACKET: u32 = 1;
+
+/* TracePacket */
+const PACKET_FTRACE_EVENTS: u32 = 1;
+const PACKET_PROCESS_TREE: u32 = 2;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_INTERNED_DATA: u32 = 12;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_PERF_SAMPLE: u32 = 66;
+
+/* PerfSample */
+const PERF_SAMPLE_KERNEL_RECORDS_LOST: u32 = 17;
+
+const SEQ_INCREMENTAL_STATE_CLEARED: u32 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u32 = 2;
+
+/* InternedData */
+const INTERNED_FUNCTION_NAMES: u32 = 5;
+const INTERNED_FRAMES: u32 = 6;
+const INTERNED_CALLSTACKS: u32 = 7;
+const INTERNED_MAPPING_PATHS: u32 = 17;
+const INTERNED_MAPPINGS: u32 = 19;
+
+/* Profiling.CpuMode */
+const CPU_MODE_KERNEL: i32 = 1;
+const CPU_MODE_USER: i32 = 2;
+
+/* Linux task states */
+const TASK_RUNNING: i64 = 0;
+const TASK_INTERRUPTIBLE: i64 = 1;
+
+const SEQUENCE_ID: u32 = 1;
+const SCHED_EVENTS_PER_BUNDLE: usize = 4096;
+const UNKNOWN: &str = "Unknown";
+
+pub trait PerfettoFormat {
+    fn to_perfetto(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut impl Write) -> anyhow::Result<()>;
+
+    fn to_perfetto_file(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.to_perfetto(
+            predicate,
+            &mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+struct PerfettoWriter<'a> {
+    output: CodedOutputStream<'a>,
+    freq: u64,
+    flags: u32,
+    packet: Vec<u8>,
+    interned: Vec<u8>,
+    buffer: Vec<u8>,
+    function_names: HashMap<usize, u64>,
+    mapping_paths: HashMap<String, u64>,
+    mappings: HashMap<(u32, usize), u64>,
+    frames: HashMap<(u32, usize, u64), u64>,
+    callstacks: HashMap<(u32, usize, u64), u64>,
+    path_ids: Vec<u64>,
+    frame_ids: Vec<u64>,
+    ips: Vec<u64>,
+}
+
+impl<'a> PerfettoWriter<'a> {
+    fn new(writer: &'a mut dyn Write) -> Self {
+        Self {
+            output: CodedOutputStream::new(writer),
+            freq: ExportMachine::qpc_freq(),
+            flags: SEQ_INCREMENTAL_STATE_CLEARED,
+            packet: Vec::new(),
+            interned: Vec::new(),
+            buffer: Vec::new(),
+            function_names: HashMap::new(),
+            mapping_paths: HashMap::new(),
+            mappings: HashMap::new(),
+            frames: HashMap::new(),
+            callstacks: HashMap::new(),
+            path_ids: Vec::new(),
+            frame_ids: Vec::new(),
+            ips: Vec::new(),
+        }
+    }
+
+    fn to_ns(
+        &self,
+        qpc: u64) -> u64 {
+        ExportMachine::qpc_to_ns(self.freq, qpc)
+    }
+
+    fn append(
+        field_number: u32,
+        input: &mut Vec<u8>,
+        output: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut stream = CodedOutputStream::vec(output);
+
+        stream.write_bytes(field_number, input)?;
+        stream.flush()?;
+        drop(stream);
+
+        input.clear();
+
+        Ok(())
+    }
+
+    fn write_packet(
+        &mut self,
+        time: u64) -> anyhow::Result<()> {
+        let mut packet = Vec::new();
+        let mut stream = CodedOutputStream::vec(&mut packet);
+
+        stream.write_uint64(PACKET_TIMESTAMP, time)?;
+        stream.write_uint32(PACKET_SEQUENCE_ID, SEQUENCE_ID)?;
+        stream.write_uint32(PACKET_SEQUENCE_FLAGS, self.flags)?;
+
+        if !self.interned.is_empty() {
+            stream.write_bytes(PACKET_INTERNED_DATA, &self.interned)?;
+        }
+
+        stream.flush()?;
+        drop(stream);
+
+        packet.extend_from_slice(&self.packet);
+
+        self.output.write_bytes(TRACE_PACKET, &packet)?;
+
+        /* Only the first packet clears state */
+        self.flags = SEQ_NEEDS_INCREMENTAL_STATE;
+        self.interned.clear();
+        self.packet.clear();
+
+        Ok(())
+    }
+
+    fn write_interned_string(
+        &mut self,
+        field_number: u32,
+        iid: u64,
+        value: &str) -> anyhow::Result<()> {
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint64(1, iid)?;
+        stream.write_bytes(2, value.as_bytes())?;
+        stream.flush()?;
+        drop(stream);
+
+        Self::append(field_number, &mut self.buffer, &mut self.interned)
+    }
+
+    fn intern_function_name(
+        &mut self,
+        machine: &ExportMachine,
+        name_id: usize) -> anyhow::Result<u64> {
+        if let Some(iid) = self.function_names.get(&name_id) {
+            return Ok(*iid);
+        }
+
+        let iid = self.function_names.len() as u64 + 1;
+        let name = machine.strings().from_id(name_id).unwrap_or(UNKNOWN);
+
+        self.write_interned_string(INTERNED_FUNCTION_NAMES, iid, name)?;
+        self.function_names.insert(name_id, iid);
+
+        Ok(iid)
+    }
+
+    fn intern_mapping_path(
+        &mut self,
+        component: &str) -> anyhow::Result<u64> {
+        if let Some(iid) = self.mapping_paths.get(component) {
+            return Ok(*iid);
+        }
+
+        let iid = self.mapping_paths.len() as u64 + 1;
+
+        self.write_interned_string(INTERNED_MAPPING_PATHS, iid, component)?;
+        self.mapping_paths.insert(component.to_owned(), iid);
+
+        Ok(iid)
+    }
+
+    fn intern_mapping(
+        &mut self,
+        machine: &ExportMachine,
+        pid: u32,
+        mapping: Option<&ExportMapping>) -> anyhow::Result<u64> {
+        /* Unknown mappings are shared by all processes */
+        let key = match mapping {
+            Some(mapping) => { (pid, mapping.id()) },
+            None => { (0, usize::MAX) },
+        };
+
+        if let Some(iid) = self.mappings.get(&key) {
+            return Ok(*iid);
+        }
+
+        let path = match mapping {
+            Some(mapping) => { machine.strings().from_id(mapping.filename_id()).unwrap_or(UNKNOWN) },
+            None => { UNKNOWN },
+        };
+
+        /* Paths are interned per-component and joined by '/' */
+        self.path_ids.clear();
+
+        for component in path.split(['/', '\\']) {
+            if component.is_empty() {
+                continue;
+            }
+
+            let id = self.intern_mapping_path(component)?;
+            self.path_ids.push(id);
+        }
+
+        let iid = self.mappings.len() as u64 + 1;
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint64(1, iid)?;
+
+        if let Some(mapping) = mapping {
+            stream.write_uint64(3, mapping.file_offset())?;
+            stream.write_uint64(4, mapping.start())?;
+            stream.write_uint64(5, mapping.end())?;
+        }
+
+        for id in &self.path_ids {
+            stream.write_uint64(7, *id)?;
+        }
+
+        stream.flush()?;
+        drop(stream);
+
+        Self::append(INTERNED_MAPPINGS, &mut self.buffer, &mut self.interned)?;
+        self.mappings.insert(key, iid);
+
+        Ok(iid)
+    }
+
+    fn intern_frame(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        time: u64,
+        ip: u64) -> anyhow::Result<u64> {
+        let pid = process.pid();
+        let mapping = process.find_mapping(ip, Some(time));
+        let mapping_id = mapping.map_or(usize::MAX, |mapping| mapping.id());
+        let key = (pid, mapping_id, ip);
+
+        if let Some(iid) = self.frames.get(&key) {
+            return Ok(*iid);
+        }
+
+        let mapping_iid = self.intern_mapping(machine, pid, mapping)?;
+
+        let mut function_iid = None;
+        let mut rel_pc = ip;
+
+        if let Some(mapping) = mapping {
+            if let Some(symbol) = find_symbol(mapping, ip) {
+                function_iid = Some(self.intern_function_name(machine, symbol.name_id())?);
+            }
+
+            /* Use the file address, unless anonymous or kernel */
+            if mapping.anon() {
+                rel_pc = ip - mapping.start();
+            } else if ip <= KERNEL_START {
+                rel_pc = (ip - mapping.start()) + mapping.file_offset();
+            }
+        }
+
+        let iid = self.frames.len() as u64 + 1;
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint64(1, iid)?;
+
+        if let Some(function_iid) = function_iid {
+            stream.write_uint64(2, function_iid)?;
+        }
+
+        stream.write_uint64(3, mapping_iid)?;
+        stream.write_uint64(4, rel_pc)?;
+        stream.flush()?;
+        drop(stream);
+
+        Self::append(INTERNED_FRAMES, &mut self.buffer, &mut self.interned)?;
+        self.frames.insert(key, iid);
+
+        Ok(iid)
+    }
+
+    fn intern_callstack(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        sample: &ExportProcessSample) -> anyhow::Result<u64> {
+        let ip = sample.ip();
+        let time = sample.time();
+
+        /* Perfetto callstacks include the IP, ours do not */
+        let key = (process.pid(), sample.callstack_id(), ip);
+
+        if let Some(iid) = self.callstacks.get(&key) {
+            return Ok(*iid);
+        }
+
+        let mut ips = std::mem::take(&mut self.ips);
+        let mut frame_ids = std::mem::take(&mut self.frame_ids);
+
+        machine.callstacks().from_id(
+            sample.callstack_id(),
+            &mut ips)?;
+
+        ips.insert(0, ip);
+
+        /* Perfetto wants the root frame first */
+        frame_ids.clear();
+
+        for ip in ips.iter().rev() {
+            frame_ids.push(self.intern_frame(machine, process, time, *ip)?);
+        }
+
+        let iid = self.callstacks.len() as u64 + 1;
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint64(1, iid)?;
+
+        for id in &frame_ids {
+            stream.write_uint64(2, *id)?;
+        }
+
+        stream.flush()?;
+        drop(stream);
+
+        self.ips = ips;
+        self.frame_ids = frame_ids;
+
+        Self::append(INTERNED_CALLSTACKS, &mut self.buffer, &mut self.interned)?;
+        self.callstacks.insert(key, iid);
+
+        Ok(iid)
+    }
+
+    fn write_process_tree(
+        &mut self,
+        machine: &ExportMachine,
+        predicate: &impl Fn(&ExportProcess) -> bool) -> anyhow::Result<()> {
+        let mut tree = Vec::new();
+        let mut threads = HashSet::new();
+        let mut start = u64::MAX;
+
+        for process in machine.processes() {
+            if !predicate(process) {
+                continue;
+            }
+
+            let pid = process.pid();
+            let comm = match process.comm_id() {
+                Some(id) => { machine.strings().from_id(id).unwrap_or(UNKNOWN) },
+                None => { UNKNOWN },
+            };
+
+            let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+            stream.write_int32(1, pid as i32)?;
+            stream.write_string(3, comm)?;
+            stream.flush()?;
+            drop(stream);
+
+            Self::append(1, &mut self.buffer, &mut tree)?;
+
+            threads.clear();
+
+            for sample in process.samples() {
+                start = start.min(sample.time());
+
+                if !threads.insert(sample.tid()) {
+                    continue;
+                }
+
+                let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+                stream.write_int32(1, sample.tid() as i32)?;
+                stream.write_string(2, comm)?;
+                stream.write_int32(5, pid as i32)?;
+                stream.flush()?;
+                drop(stream);
+
+                Self::append(2, &mut self.buffer, &mut tree)?;
+            }
+        }
+
+        Self::append(PACKET_PROCESS_TREE, &mut tree, &mut self.packet)?;
+
+        if start == u64::MAX {
+            start = 0;
+        }
+
+        let time = self.to_ns(start);
+
+        self.write_packet(time)
+    }
+
+    fn write_sample(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        sample: &ExportProcessSample,
+        converter: &dyn ExportGraphMetricValueConverter) -> anyhow::Result<()> {
+        let callstack_iid = self.intern_callstack(machine, process, sample)?;
+
+        let cpu_mode = match sample.ip() > KERNEL_START {
+            true => { CPU_MODE_KERNEL },
+            false => { CPU_MODE_USER },
+        };
+
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint32(1, sample.cpu() as u32)?;
+        stream.write_uint32(2, process.pid())?;
+        stream.write_uint32(3, sample.tid())?;
+        stream.write_uint64(4, callstack_iid)?;
+        stream.write_enum(5, cpu_mode)?;
+        stream.write_uint64(6, converter.convert(machine, sample.value()))?;
+        stream.flush()?;
+        drop(stream);
+
+        Self::append(PACKET_PERF_SAMPLE, &mut self.buffer, &mut self.packet)?;
+
+        let time = self.to_ns(sample.time());
+
+        self.write_packet(time)
+    }
+
+    fn switch_comm(
+        machine: &ExportMachine,
+        pid: u32,
+        tid: u32,
+        cpu: u16) -> String {
+        if tid == 0 {
+            return format!("swapper/{}", cpu);
+        }
+
+        let comm_id = machine
+            .find_process(pid)
+            .and_then(|process| process.comm_id());
+
+        match comm_id {
+            Some(id) => { machine.strings().from_id(id).unwrap_or(UNKNOWN).to_string() },
+            None => { UNKNOWN.to_string() },
+        }
+    }
+
+    fn write_sched_switch(
+        &mut self,
+        machine: &ExportMachine,
+        switch: &ExportSchedSwitch,
+        events: &mut Vec<u8>) -> anyhow::Result<()> {
+        let cpu = switch.cpu();
+        let prev_comm = Self::switch_comm(machine, switch.prev_pid(), switch.prev_tid(), cpu);
+        let next_comm = Self::switch_comm(machine, switch.next_pid(), switch.next_tid(), cpu);
+
+        /* perf only reports preemption, otherwise the task blocked */
+        let prev_state = match switch.preempted() || switch.prev_tid() == 0 {
+            true => { TASK_RUNNING },
+            false => { TASK_INTERRUPTIBLE },
+        };
+
+        let mut sched = Vec::new();
+        let mut stream = CodedOutputStream::vec(&mut sched);
+
+        stream.write_string(1, &prev_comm)?;
+        stream.write_int32(2, switch.prev_tid() as i32)?;
+        stream.write_int64(4, prev_state)?;
+        stream.write_string(5, &next_comm)?;
+        stream.write_int32(6, switch.next_tid() as i32)?;
+        stream.flush()?;
+        drop(stream);
+
+        let time = self.to_ns(switch.time());
+        let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+        stream.write_uint64(1, time)?;
+        stream.write_uint32(2, switch.prev_tid())?;
+        stream.write_bytes(4, &sched)?;
+        stream.flush()?;
+        drop(stream);
+
+        Self::append(2, &mut self.buffer, events)
+    }
+
+    fn write_lost_samples(
+        &mut self,
+        machine: &ExportMachine) -> anyhow::Result<()> {
+        /* Data loss packets carry only the CPU and lost count */
+        let time = self.to_ns(machine.end_qpc().unwrap_or(0));
+
+        for (cpu, lost) in machine.lost_samples_per_cpu().iter().enumerate() {
+            if *lost == 0 {
+                continue;
+            }
+
+            let mut stream = CodedOutputStream::vec(&mut self.buffer);
+
+            stream.write_uint32(1, cpu as u32)?;
+            stream.write_uint64(PERF_SAMPLE_KERNEL_RECORDS_LOST, *lost)?;
+            stream.flush()?;
+            drop(stream);
+
+            Self::append(PACKET_PERF_SAMPLE, &mut self.buffer, &mut self.packet)?;
+
+            self.write_packet(time)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_sched_switches(
+        &mut self,
+        machine: &ExportMachine,
+        predicate: &impl Fn(&ExportProcess) -> bool) -> anyhow::Result<()> {
+        let included = |pid: u32, tid: u32| -> bool {
+            tid != 0 && machine.find_process(pid).is_some_and(predicate)
+        };
+
+        let mut switches: Vec<&ExportSchedSwitch> = machine
+            .sched_switches()
+            .iter()
+            .filter(|switch| {
+                included(switch.prev_pid(), switch.prev_tid()) ||
+                included(switch.next_pid(), switch.next_tid())
+            })
+            .collect();
+
+        switches.sort_by_key(|switch| (switch.cpu(), switch.time()));
+
+        for chunk in switches.chunk_by(|a, b| a.cpu() == b.cpu()) {
+            for bundle in chunk.chunks(SCHED_EVENTS_PER_BUNDLE) {
+                let mut events = Vec::new();
+                let mut stream = CodedOutputStream::vec(&mut events);
+
+                stream.write_uint32(1, bundle[0].cpu() as u32)?;
+                stream.flush()?;
+                drop(stream);
+
+                for switch in bundle {
+                    self.write_sched_switch(machine, switch, &mut events)?;
+                }
+
+                Self::append(PACKET_FTRACE_EVENTS, &mut events, &mut self.packet)?;
+
+                let time = self.to_ns(bundle[0].time());
+
+                self.write_packet(time)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PerfettoFormat for ExportMachine {
+    fn to_perfetto(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let converter = DefaultExportGraphMetricValueConverter::default();
+        let cpu = self.find_sample_kind("cpu");
+        let mut trace = PerfettoWriter::new(writer);
+
+        trace.write_process_tree(self, &predicate)?;
+
+        self.replay_by_time(
+            &predicate,
+            |machine, replay| {
+                if let Some(sample) = replay.sample_event() {
+                    let kind = Some(sample.kind());
+
+                    if kind == cpu {
+                        trace.write_sample(
+                            machine,
+                            replay.process(),
+                            sample,
+                            &converter)?;
+                    }
+                }
+
+                Ok(())
+            })?;
+
+        trace.write_sched_switches(self, &predicate)?;
+        trace.write_lost_samples(self)?;
+        trace.output.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::exporting::formats::testing::*;
+    use protobuf::CodedInputStream;
+
+    /* Splits a message into (field, wire value or bytes) */
+    fn fields(data: &[u8]) -> Vec<(u32, u64, Vec<u8>)> {
+        let mut input = CodedInputStream::from_bytes(data);
+        let mut fields = Vec::new();
+
+        while !input.eof().unwrap() {
+            let tag = input.read_raw_varint32().unwrap();
+            let field = tag >> 3;
+
+            match tag & 7 {
+                0 => { fields.push((field, input.read_raw_varint64().unwrap(), Vec::new())); },
+                2 => { fields.push((field, 0, input.read_bytes().unwrap())); },
+                _ => { panic!("Unexpected wire type"); },
+            }
+        }
+
+        fields
+    }
+
+    fn field<'a>(
+        fields: &'a [(u32, u64, Vec<u8>)],
+        number: u32) -> Option<&'a (u32, u64, Vec<u8>)> {
+        fields.iter().find(|field| field.0 == number)
+    }
+
+    #[test]
+    fn it_works() {
+        let mut exporter = stepped_cpu_machine();
+        let cpu = exporter.sample_kind("cpu");
+        let frames: Vec<u64> = (0..16).collect();
+
+        /* Same stack again should re-use the callstack */
+        exporter.add_sample(
+            16,
+            MetricValue::Count(1),
+            1,
+            1,
+            0,
+            cpu,
+            &frames[0..16]).unwrap();
+
+        /* Blocks on CPU 1 at 20, then preempts idle at 30 */
+        exporter.add_sched_switch(ExportSchedSwitch::new(20, 1, 1, 1, 0, 0, false));
+        exporter.add_sched_switch(ExportSchedSwitch::new(30, 1, 0, 0, 1, 1, false));
+
+        /* Switches between untracked tasks are dropped */
+        exporter.add_sched_switch(ExportSchedSwitch::new(40, 1, 5, 5, 0, 0, false));
+
+        let mut output = Vec::new();
+        exporter.to_perfetto(|_| true, &mut output).unwrap();
+
+        let packets: Vec<Vec<(u32, u64, Vec<u8>)>> = fields(&output)
+            .iter()
+            .map(|packet| {
+                assert_eq!(TRACE_PACKET, packet.0);
+                fields(&packet.2)
+            })
+            .collect();
+
+        /* Process tree, 17 samples, 1 sched bundle */
+        assert_eq!(19, packets.len());
+
+        let tree = fields(&field(&packets[0], PACKET_PROCESS_TREE).unwrap().2);
+        assert_eq!(2, tree.len());
+        assert_eq!(SEQ_INCREMENTAL_STATE_CLEARED as u64, field(&packets[0], PACKET_SEQUENCE_FLAGS).unwrap().1);
+
+        /* First sample interns all frames, mappings and the callstack */
+        let interned = fields(&field(&packets[1], PACKET_INTERNED_DATA).unwrap().2);
+        assert_eq!(16, interned.iter().filter(|f| f.0 == INTERNED_FRAMES).count());
+        assert_eq!(16, interned.iter().filter(|f| f.0 == INTERNED_MAPPINGS).count());
+        assert_eq!(16, interned.iter().filter(|f| f.0 == INTERNED_FUNCTION_NAMES).count());
+        assert_eq!(1, interned.iter().filter(|f| f.0 == INTERNED_CALLSTACKS).count());
+
+        let callstack = fields(&field(&interned, INTERNED_CALLSTACKS).unwrap().2);
+        assert_eq!(17, callstack.len());
+
+        let sample = fields(&field(&packets[1], PACKET_PERF_SAMPLE).unwrap().2);
+        assert_eq!(1, field(&sample, 2).unwrap().1);
+        assert_eq!(1, field(&sample, 4).unwrap().1);
+
+        /* Repeated stack is not re-interned */
+        assert!(field(&packets[17], PACKET_INTERNED_DATA).is_none());
+        let sample = fields(&field(&packets[17], PACKET_PERF_SAMPLE).unwrap().2);
+        assert_eq!(1, field(&sample, 4).unwrap().1);
+        assert_eq!(16, field(&packets[17], PACKET_TIMESTAMP).unwrap().1);
+
+        /* Switch out at 20 and back in at 30 on CPU 1 */
+        let bundle = fields(&field(&packets[18], PACKET_FTRACE_EVENTS).unwrap().2);
+        assert_eq!(1, field(&bundle, 1).unwrap().1);
+
+        let events: Vec<_> = bundle.iter().filter(|f| f.0 == 2).collect();
+        assert_eq!(2, events.len());
+
+        let out = fields(&events[0].2);
+        let into = fields(&events[1].2);
+        assert_eq!(20, field(&out, 1).unwrap().1);
+        assert_eq!(30, field(&into, 1).unwrap().1);
+
+        let out = fields(&field(&out, 4).unwrap().2);
+        assert_eq!(b"test".to_vec(), field(&out, 1).unwrap().2);
+        assert_eq!(1, field(&out, 2).unwrap().1);
+        assert_eq!(TASK_INTERRUPTIBLE as u64, field(&out, 4).unwrap().1);
+        assert_eq!(b"swapper/1".to_vec(), field(&out, 5).unwrap().2);
+        assert_eq!(0, field(&out, 6).unwrap().1);
+
+        let into = fields(&field(&into, 4).unwrap().2);
+        assert_eq!(b"swapper/1".to_vec(), field(&into, 1).unwrap().2);
+        assert_eq!(b"test".to_vec(), field(&into, 5).unwrap().2);
+        assert_eq!(1, field(&into, 6).unwrap().1);
+    }
+
+    #[test]
+    fn lost_samples() {
+        let mut exporter = machine();
+
+        exporter.add_lost_samples(0, 2);
+        exporter.add_lost_samples(3, 5);
+
+        let mut output = Vec::new();
+        exporter.to_perfetto(|_| true, &mut output).unwrap();
+
+        let packets: Vec<Vec<(u32, u64, Vec<u8>)>> = fields(&output)
+            .iter()
+            .map(|packet| fields(&packet.2))
+            .collect();
+
+        /* Process tree and a loss packet per CPU with loss */
+        assert_eq!(3, packets.len());
+
+        let lost = fields(&field(&packets[1], PACKET_PERF_SAMPLE).unwrap().2);
+        assert_eq!(0, field(&lost, 1).unwrap().1);
+        assert_eq!(2, field(&lost, PERF_SAMPLE_KERNEL_RECORDS_LOST).unwrap().1);
+        assert!(field(&lost, 4).is_none());
+
+        let lost = fields(&field(&packets[2], PACKET_PERF_SAMPLE).unwrap().2);
+        assert_eq!(3, field(&lost, 1).unwrap().1);
+        assert_eq!(5, field(&lost, PERF_SAMPLE_KERNEL_RECORDS_LOST).unwrap().1);
+    }
+}
@@ -280,15 +280,19 @@ fn write_functions(
 
 fn write_strings(
     output: &mut CodedOutputStream,
-    strings: &InternedStrings) -> anyhow::Result<()> {
+    strings: &InternedStrings) -> anyhow::Result<usize> {
+    let mut count = 0;
+
     for i in 0..usize::MAX {
         match strings.from_id(i) {
             Ok(value) => { output.write_string(6, value)?; },
             Err(_) => { break; },
         }
+
+        count += 1;
     }
 
-    Ok(())
+    Ok(count)
 }
 
 fn proto_append(
@@ -375,10 +379,26 @@ impl PprofFormat for ExportGraph {
             root)?;
 
         /* Strings */
-        write_strings(
+        let string_count = write_strings(
             &mut output,
             strings)?;
 
+        /* Lost samples and counter totals are appended as comment strings */
+        let mut comments = Vec::new();
+
+        if self.lost_samples() != 0 {
+            comments.push(format!("lost_samples={}", self.lost_samples()));
+        }
+
+        for (name, value) in self.counter_totals() {
+            comments.push(format!("counter:{}={}", name, value));
+        }
+
+        for (i, comment) in comments.iter().enumerate() {
+            output.write_string(6, comment)?;
+            output.write_int64(13, (string_count + i) as i64)?;
+        }
+
         /* Done */
         output.flush()?;
         drop(output);
@@ -0,0 +1,474 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::fs::File;
+use std::io::{Write, BufWriter};
+use std::collections::HashMap;
+
+use crate::helpers::exporting::*;
+use crate::helpers::exporting::graph::*;
+use super::json::write_json_string;
+use super::frames::frame_name;
+
+const SPEEDSCOPE_SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+const UNKNOWN: &str = "Unknown";
+
+pub trait SpeedscopeFormat {
+    fn to_speedscope(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        converter: Option<&dyn ExportGraphMetricValueConverter>,
+        writer: &mut impl Write) -> anyhow::Result<()>;
+
+    fn to_speedscope_file(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        converter: Option<&dyn ExportGraphMetricValueConverter>,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.to_speedscope(
+            predicate,
+            converter,
+            &mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+struct SpeedscopeThread {
+    name: String,
+    /* (Nanoseconds since start, stack ID) in time order */
+    samples: Vec<(u64, usize)>,
+}
+
+#[derive(Default)]
+struct SpeedscopeSampled {
+    stacks: Vec<usize>,
+    weights: Vec<u64>,
+    lookup: HashMap<usize, usize>,
+    total: u64,
+}
+
+#[derive(Default)]
+struct SpeedscopeBuilder {
+    frames: Vec<String>,
+    frame_lookup: HashMap<String, usize>,
+    ip_lookup: HashMap<(u32, usize, u64), usize>,
+    stacks: Vec<Vec<usize>>,
+    stack_lookup: HashMap<Vec<usize>, usize>,
+    threads: Vec<SpeedscopeThread>,
+    thread_lookup: HashMap<(u32, u32), usize>,
+    kinds: Vec<SpeedscopeSampled>,
+    ips: Vec<u64>,
+    stack: Vec<usize>,
+    start: Option<u64>,
+}
+
+impl SpeedscopeBuilder {
+    fn frame_id(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        time: u64,
+        ip: u64) -> usize {
+        let mapping = process.find_mapping(ip, Some(time));
+        let mapping_id = mapping.map_or(usize::MAX, |mapping| mapping.id());
+        let key = (process.pid(), mapping_id, ip);
+
+        if let Some(id) = self.ip_lookup.get(&key) {
+            return *id;
+        }
+
+        let name = frame_name(machine, mapping, ip);
+
+        let id = match self.frame_lookup.get(&name) {
+            Some(id) => { *id },
+            None => {
+                let id = self.frames.len();
+                self.frames.push(name.clone());
+                self.frame_lookup.insert(name, id);
+                id
+            },
+        };
+
+        self.ip_lookup.insert(key, id);
+
+        id
+    }
+
+    fn stack_id(&mut self) -> usize {
+        match self.stack_lookup.get(&self.stack) {
+            Some(id) => { *id },
+            None => {
+                let id = self.stacks.len();
+                self.stacks.push(self.stack.clone());
+                self.stack_lookup.insert(self.stack.clone(), id);
+                id
+            },
+        }
+    }
+
+    fn thread_id(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        tid: u32) -> anyhow::Result<usize> {
+        let key = (process.pid(), tid);
+
+        if let Some(id) = self.thread_lookup.get(&key) {
+            return Ok(*id);
+        }
+
+        let comm = match process.comm_id() {
+            Some(id) => { machine.strings().from_id(id)? },
+            None => { UNKNOWN },
+        };
+
+        let id = self.threads.len();
+
+        self.threads.push(
+            SpeedscopeThread {
+                name: format!("{} ({}/{})", comm, process.pid(), tid),
+                samples: Vec::new(),
+            });
+
+        self.thread_lookup.insert(key, id);
+
+        Ok(id)
+    }
+
+    fn add_sample(
+        &mut self,
+        machine: &ExportMachine,
+        process: &ExportProcess,
+        sample: &ExportProcessSample,
+        converter: &dyn ExportGraphMetricValueConverter) -> anyhow::Result<()> {
+        let time = sample.time();
+        let mut ips = std::mem::take(&mut self.ips);
+
+        machine.callstacks().from_id(
+            sample.callstack_id(),
+            &mut ips)?;
+
+        ips.insert(0, sample.ip());
+
+        /* Callstacks are leaf first, speedscope wants root first */
+        self.stack.clear();
+
+        for ip in ips.iter().rev() {
+            let frame = self.frame_id(machine, process, time, *ip);
+            self.stack.push(frame);
+        }
+
+        self.ips = ips;
+
+        let stack = self.stack_id();
+
+        /* Evented, time ordered per-thread */
+        let start = *self.start.get_or_insert(time);
+        let freq = ExportMachine::qpc_freq();
+        let at = ExportMachine::qpc_to_ns(freq, time - start);
+        let thread = self.thread_id(machine, process, sample.tid())?;
+
+        self.threads[thread].samples.push((at, stack));
+
+        /* Sampled, aggregated per-kind */
+        let kind = sample.kind() as usize;
+
+        while self.kinds.len() <= kind {
+            self.kinds.push(SpeedscopeSampled::default());
+        }
+
+        let weight = converter.convert(machine, sample.value());
+        let sampled = &mut self.kinds[kind];
+
+        match sampled.lookup.get(&stack) {
+            Some(index) => { sampled.weights[*index] += weight; },
+            None => {
+                sampled.lookup.insert(stack, sampled.stacks.len());
+                sampled.stacks.push(stack);
+                sampled.weights.push(weight);
+            },
+        }
+
+        sampled.total += weight;
+
+        Ok(())
+    }
+
+    fn write_event(
+        writer: &mut impl Write,
+        first: &mut bool,
+        open: bool,
+        frame: usize,
+        at: u64) -> anyhow::Result<()> {
+        if !*first {
+            write!(writer, ",")?;
+        }
+
+        *first = false;
+
+        let kind = match open {
+            true => { "O" },
+            false => { "C" },
+        };
+
+        write!(writer, "{{\"type\":\"{}\",\"frame\":{},\"at\":{}}}", kind, frame, at)?;
+
+        Ok(())
+    }
+
+    fn write_evented(
+        &self,
+        thread: &SpeedscopeThread,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let samples = &thread.samples;
+
+        let start_value = samples.first().map_or(0, |sample| sample.0);
+        let last = samples.last().map_or(0, |sample| sample.0);
+
+        /*
+         * Samples run until the next sample on the thread. The last
+         * sample is given the same width as the one before it.
+         */
+        let gap = match samples.len() {
+            0 | 1 => { 1 },
+            len => { (last - samples[len - 2].0).max(1) },
+        };
+
+        let end_value = last + gap;
+
+        write!(writer, "{{\"type\":\"evented\",\"name\":")?;
+        write_json_string(writer, &thread.name)?;
+        write!(
+            writer,
+            ",\"unit\":\"nanoseconds\",\"startValue\":{},\"endValue\":{},\"events\":[",
+            start_value,
+            end_value)?;
+
+        let mut first = true;
+        let mut open: &[usize] = &[];
+
+        for (at, stack) in samples {
+            let frames = &self.stacks[*stack];
+
+            let common = open.iter()
+                .zip(frames.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            /* Close what is no longer on the stack, leaf first */
+            for frame in open[common..].iter().rev() {
+                Self::write_event(writer, &mut first, false, *frame, *at)?;
+            }
+
+            /* Open what is new on the stack, root first */
+            for frame in &frames[common..] {
+                Self::write_event(writer, &mut first, true, *frame, *at)?;
+            }
+
+            open = frames;
+        }
+
+        for frame in open.iter().rev() {
+            Self::write_event(writer, &mut first, false, *frame, end_value)?;
+        }
+
+        write!(writer, "]}}")?;
+
+        Ok(())
+    }
+
+    fn write_sampled(
+        &self,
+        name: &str,
+        sampled: &SpeedscopeSampled,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        write!(writer, "{{\"type\":\"sampled\",\"name\":")?;
+        write_json_string(writer, name)?;
+        write!(
+            writer,
+            ",\"unit\":\"none\",\"startValue\":0,\"endValue\":{},\"samples\":[",
+            sampled.total)?;
+
+        for (i, stack) in sampled.stacks.iter().enumerate() {
+            if i != 0 {
+                write!(writer, ",")?;
+            }
+
+            write!(writer, "[")?;
+
+            for (j, frame) in self.stacks[*stack].iter().enumerate() {
+                if j != 0 {
+                    write!(writer, ",")?;
+                }
+
+                write!(writer, "{}", frame)?;
+            }
+
+            write!(writer, "]")?;
+        }
+
+        write!(writer, "],\"weights\":[")?;
+
+        for (i, weight) in sampled.weights.iter().enumerate() {
+            if i != 0 {
+                write!(writer, ",")?;
+            }
+
+            write!(writer, "{}", weight)?;
+        }
+
+        write!(writer, "]}}")?;
+
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        kinds: &[String],
+        lost_samples: u64,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        write!(writer, "{{\"$schema\":")?;
+        write_json_string(writer, SPEEDSCOPE_SCHEMA)?;
+        write!(writer, ",\"exporter\":\"one_collect\"")?;
+
+        /* Not part of the schema, viewers ignore unknown fields */
+        write!(writer, ",\"lostSamples\":{}", lost_samples)?;
+
+        write!(writer, ",\"shared\":{{\"frames\":[")?;
+
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i != 0 {
+                write!(writer, ",")?;
+            }
+
+            write!(writer, "{{\"name\":")?;
+            write_json_string(writer, frame)?;
+            write!(writer, "}}")?;
+        }
+
+        write!(writer, "]}},\"profiles\":[")?;
+
+        let mut first = true;
+
+        for thread in &self.threads {
+            if !first {
+                write!(writer, ",")?;
+            }
+
+            first = false;
+
+            self.write_evented(thread, writer)?;
+        }
+
+        for (kind, sampled) in self.kinds.iter().enumerate() {
+            if sampled.stacks.is_empty() {
+                continue;
+            }
+
+            if !first {
+                write!(writer, ",")?;
+            }
+
+            first = false;
+
+            let name = match kinds.get(kind) {
+                Some(name) => { name.as_str() },
+                None => { UNKNOWN },
+            };
+
+            self.write_sampled(name, sampled, writer)?;
+        }
+
+        write!(writer, "],\"activeProfileIndex\":0}}")?;
+
+        Ok(())
+    }
+}
+
+impl SpeedscopeFormat for ExportMachine {
+    fn to_speedscope(
+        &mut self,
+        predicate: impl Fn(&ExportProcess) -> bool,
+        converter: Option<&dyn ExportGraphMetricValueConverter>,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        let default_converter = DefaultExportGraphMetricValueConverter::default();
+        let converter = converter.unwrap_or(&default_converter);
+        let mut builder = SpeedscopeBuilder::default();
+
+        self.replay_by_time(
+            predicate,
+            |machine, replay| {
+                if let Some(sample) = replay.sample_event() {
+                    builder.add_sample(
+                        machine,
+                        replay.process(),
+                        sample,
+                        converter)?;
+                }
+
+                Ok(())
+            })?;
+
+        builder.write(
+            self.sample_kinds(),
+            self.lost_samples(),
+            writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::exporting::formats::testing::*;
+
+    #[test]
+    fn it_works() {
+        /* Sample each frame on 2 threads */
+        let mut exporter = stepped_machine(|exporter, cpu, i, frames| {
+            for tid in 1..3 {
+                exporter.add_sample(
+                    i as u64 * 10,
+                    MetricValue::Count(2),
+                    1,
+                    tid,
+                    0,
+                    cpu,
+                    frames).unwrap();
+            }
+        });
+
+        exporter.add_lost_samples(1, 4);
+
+        let mut output = Vec::new();
+        exporter.to_speedscope(|_| true, None, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("{\"$schema\":"));
+        assert!(output.ends_with("\"activeProfileIndex\":0}"));
+        assert!(output.contains(",\"lostSamples\":4,"));
+
+        /* One frame per symbol */
+        assert_eq!(16, output.matches("{\"name\":\"").count());
+        assert!(output.contains("{\"name\":\"15!15\"}"));
+
+        /* One evented per thread, one sampled per kind */
+        assert_eq!(2, output.matches("\"type\":\"evented\"").count());
+        assert_eq!(1, output.matches("\"type\":\"sampled\"").count());
+        assert!(output.contains("\"name\":\"test (1/1)\""));
+        assert!(output.contains("\"name\":\"test (1/2)\""));
+        assert!(output.contains("\"name\":\"cpu\""));
+
+        /* Each thread opens and closes each frame once */
+        assert_eq!(32, output.matches("\"type\":\"O\"").count());
+        assert_eq!(32, output.matches("\"type\":\"C\"").count());
+
+        /* Sampled weights are aggregated across threads */
+        assert!(output.contains("\"endValue\":64,\"samples\""));
+        assert!(output.contains("\"weights\":[4,4,4,4,4,4,4,4,4,4,4,4,4,4,4,4]"));
+    }
+}
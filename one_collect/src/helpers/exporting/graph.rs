@@ -107,6 +107,8 @@ pub struct ExportGraph {
     resolvables: Vec<Resolvable>,
     nodes: Vec<Node>,
     frames: Vec<u64>,
+    lost_samples: u64,
+    counter_totals: Vec<(String, u64)>,
 }
 
 const UNKNOWN: &str = "Unknown";
@@ -118,6 +120,8 @@ impl ExportGraph {
             resolvables: Vec::new(),
             nodes: Vec::new(),
             frames: Vec::new(),
+            lost_samples: 0,
+            counter_totals: Vec::new(),
         };
 
         new.reset();
@@ -134,11 +138,40 @@ impl ExportGraph {
 
     pub fn resolvables(&self) -> &[Resolvable] { &self.resolvables }
 
+    pub fn lost_samples(&self) -> u64 { self.lost_samples }
+
+    pub fn counter_totals(&self) -> &[(String, u64)] { &self.counter_totals }
+
+    pub fn frame_name(
+        &self,
+        target: &Target) -> anyhow::Result<String> {
+        let mut name = String::new();
+
+        if target.has_resolvable() {
+            let resolvable = &self.resolvables[target.resolvable()];
+            name.push_str(self.strings.from_id(resolvable.name())?);
+        } else {
+            name.push_str(UNKNOWN);
+        }
+
+        name.push('!');
+
+        if target.has_method() {
+            name.push_str(self.strings.from_id(target.method())?);
+        } else {
+            name.push_str(&format!("0x{:x}", target.address()));
+        }
+
+        Ok(name)
+    }
+
     pub fn reset(
         &mut self) {
         self.strings = InternedStrings::new(128);
         self.nodes.clear();
         self.resolvables.clear();
+        self.lost_samples = 0;
+        self.counter_totals.clear();
 
         /* 0 should always be empty/undefined */
         self.strings.to_id("");
@@ -161,6 +194,11 @@ impl ExportGraph {
         &mut self,
         parent_id: usize,
         value: u64) {
+        /* Root is charged by the top frame merge */
+        if parent_id == 0 {
+            return;
+        }
+
         let mut id = self.nodes[parent_id].parent_id;
 
         loop {
@@ -314,6 +352,18 @@ impl ExportGraph {
             None => { &default_converter },
         };
 
+        /* Lost samples have no process, so carry the machine total */
+        self.lost_samples = exporter.lost_samples();
+
+        /* Counter totals are machine wide as well */
+        self.counter_totals.clear();
+
+        for (name_id, value) in exporter.counter_totals() {
+            if let Ok(name) = exporter.strings().from_id(*name_id) {
+                self.counter_totals.push((name.to_owned(), *value));
+            }
+        }
+
         for sample in process.samples() {
             if sample.kind() != kind {
                 continue;
@@ -390,10 +440,140 @@ impl ExportGraph {
     }
 }
 
+#[derive(Default)]
+pub struct DiffNode {
+    name_id: usize,
+    parent_id: usize,
+    child_ids: Vec<usize>,
+    baseline_exclusive: u64,
+    baseline_total: u64,
+    comparison_exclusive: u64,
+    comparison_total: u64,
+}
+
+impl DiffNode {
+    pub fn name(&self) -> usize { self.name_id }
+
+    pub fn parent(&self) -> usize { self.parent_id }
+
+    pub fn children(&self) -> &[usize] { &self.child_ids }
+
+    pub fn baseline_exclusive(&self) -> u64 { self.baseline_exclusive }
+
+    pub fn baseline_total(&self) -> u64 { self.baseline_total }
+
+    pub fn comparison_exclusive(&self) -> u64 { self.comparison_exclusive }
+
+    pub fn comparison_total(&self) -> u64 { self.comparison_total }
+
+    pub fn exclusive_delta(&self) -> i64 {
+        self.comparison_exclusive as i64 - self.baseline_exclusive as i64
+    }
+
+    pub fn total_delta(&self) -> i64 {
+        self.comparison_total as i64 - self.baseline_total as i64
+    }
+}
+
+pub struct ExportGraphDiff {
+    strings: InternedStrings,
+    nodes: Vec<DiffNode>,
+    lookup: HashMap<(usize, usize), usize>,
+}
+
+impl ExportGraphDiff {
+    pub fn new(
+        baseline: &ExportGraph,
+        comparison: &ExportGraph) -> anyhow::Result<Self> {
+        let mut diff = Self {
+            strings: InternedStrings::new(128),
+            nodes: Vec::new(),
+            lookup: HashMap::new(),
+        };
+
+        /* 0 should always be empty/undefined */
+        diff.strings.to_id("");
+
+        /* Always have a root node */
+        diff.nodes.push(DiffNode::default());
+
+        diff.add_graph(baseline, false)?;
+        diff.add_graph(comparison, true)?;
+
+        Ok(diff)
+    }
+
+    pub fn root_node(&self) -> usize { 0 }
+
+    pub fn strings(&self) -> &InternedStrings { &self.strings }
+
+    pub fn nodes(&self) -> &[DiffNode] { &self.nodes }
+
+    fn add_graph(
+        &mut self,
+        graph: &ExportGraph,
+        comparison: bool) -> anyhow::Result<()> {
+        let nodes = graph.nodes();
+        let root = graph.root_node();
+
+        /* Graph node ID to diff node ID */
+        let mut ids = vec![0; nodes.len()];
+
+        /* Parents are always added before their children */
+        for (id, node) in nodes.iter().enumerate() {
+            let diff_id = match id == root {
+                true => { self.root_node() },
+                false => {
+                    /* Match by name, different addresses can share a name */
+                    let name = graph.frame_name(&node.target)?;
+                    let name_id = self.strings.to_id(&name);
+                    let parent_id = ids[node.parent()];
+
+                    match self.lookup.entry((parent_id, name_id)) {
+                        Occupied(entry) => { *entry.get() },
+                        Vacant(entry) => {
+                            let diff_id = self.nodes.len();
+
+                            self.nodes.push(
+                                DiffNode {
+                                    name_id,
+                                    parent_id,
+                                    ..Default::default()
+                                });
+
+                            self.nodes[parent_id].child_ids.push(diff_id);
+
+                            *entry.insert(diff_id)
+                        },
+                    }
+                },
+            };
+
+            ids[id] = diff_id;
+
+            let diff_node = &mut self.nodes[diff_id];
+
+            match comparison {
+                true => {
+                    diff_node.comparison_exclusive += node.exclusive();
+                    diff_node.comparison_total += node.total();
+                },
+                false => {
+                    diff_node.baseline_exclusive += node.exclusive();
+                    diff_node.baseline_total += node.total();
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::helpers::exporting::formats::perf_view::PerfViewXmlFormat;
+    use crate::helpers::exporting::formats::testing::machine;
 
     #[test]
     fn it_works() {
@@ -493,4 +673,121 @@ mod tests {
 
         graph.to_perf_view_xml("t.UnitTest.PerfView.xml").unwrap();
     }
+
+    #[test]
+    fn top_frame_only() {
+        let mut exporter = machine();
+
+        exporter.add_comm_exec(1, "test", 0).unwrap();
+        exporter.add_mmap_exec(0, 1, 0, 16, 0, 0, 0, 0, "0").unwrap();
+
+        let cpu = exporter.sample_kind("cpu");
+
+        /*
+         * Samples with only a top frame share the empty callstack, which
+         * imports as the root itself. Repeats must not charge the root
+         * again on top of the top frame merge.
+         */
+        for _ in 0..3 {
+            exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[1]).unwrap();
+        }
+
+        let mut graph = ExportGraph::new();
+
+        graph.add_samples(
+            &exporter,
+            exporter.find_process(1).unwrap(),
+            cpu,
+            None);
+
+        let nodes = graph.nodes();
+        let root = &nodes[graph.root_node()];
+
+        assert_eq!(2, nodes.len());
+        assert_eq!(3, root.total);
+        assert_eq!(3, nodes[root.child_ids[0]].total);
+        assert_eq!(3, nodes[root.child_ids[0]].exclusive);
+    }
+
+    #[test]
+    fn diff() {
+        let mut exporter = machine();
+
+        /* Baseline and comparison load the same modules */
+        for pid in 1..3 {
+            exporter.add_comm_exec(pid, "test", 0).unwrap();
+
+            for i in 0..2 {
+                exporter.add_mmap_exec(
+                    0,
+                    pid,
+                    i * 16,
+                    16,
+                    0,
+                    0,
+                    0,
+                    0,
+                    &i.to_string()).unwrap();
+
+                let name_id = exporter.intern(&format!("func{}", i));
+                let mappings = exporter.process_mut(pid).mappings_mut();
+                let len = mappings.len();
+
+                mappings[len-1].add_symbol(
+                    ExportSymbol::new(
+                        name_id,
+                        i * 16,
+                        i * 16 + 15));
+            }
+        }
+
+        let cpu = exporter.sample_kind("cpu");
+
+        /* Baseline: func0 x2, func0 -> func1 x1 */
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[1]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[1]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 1, 1, 0, cpu, &[17, 1]).unwrap();
+
+        /* Comparison: func0 x1, func0 -> func1 x3 at different addresses */
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[5]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[20, 2]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[20, 2]).unwrap();
+        exporter.add_sample(0, MetricValue::Count(1), 2, 2, 0, cpu, &[21, 3]).unwrap();
+
+        let mut baseline = ExportGraph::new();
+        let mut comparison = ExportGraph::new();
+
+        baseline.add_samples(&exporter, exporter.find_process(1).unwrap(), cpu, None);
+        comparison.add_samples(&exporter, exporter.find_process(2).unwrap(), cpu, None);
+
+        /* Addresses differ, so comparison has more nodes */
+        assert_eq!(3, baseline.nodes().len());
+        assert_eq!(6, comparison.nodes().len());
+
+        let diff = ExportGraphDiff::new(&baseline, &comparison).unwrap();
+        let nodes = diff.nodes();
+        let strings = diff.strings();
+
+        /* Matched by name: root, func0, func1 */
+        assert_eq!(3, nodes.len());
+
+        let root = &nodes[diff.root_node()];
+        assert_eq!(3, root.baseline_total());
+        assert_eq!(4, root.comparison_total());
+        assert_eq!(1, root.total_delta());
+        assert_eq!(1, root.children().len());
+
+        let func0 = &nodes[root.children()[0]];
+        assert_eq!("0!func0", strings.from_id(func0.name()).unwrap());
+        assert_eq!(-1, func0.exclusive_delta());
+        assert_eq!(1, func0.total_delta());
+        assert_eq!(1, func0.children().len());
+
+        let func1 = &nodes[func0.children()[0]];
+        assert_eq!("1!func1", strings.from_id(func1.name()).unwrap());
+        assert_eq!(1, func1.baseline_exclusive());
+        assert_eq!(3, func1.comparison_exclusive());
+        assert_eq!(2, func1.exclusive_delta());
+        assert_eq!(2, func1.total_delta());
+    }
 }
@@ -22,7 +22,7 @@ use crate::helpers::callstack::CallstackHelper;
 use modulemetadata::ModuleMetadata;
 use pe_file::PEModuleMetadata;
 use process::MetricValue::{self, Span};
-use ruwind::UnwindType;
+use ruwind::{CodeSection, UnwindType};
 use chrono::{DateTime, Utc};
 
 mod lookup;
@@ -31,6 +31,7 @@ pub mod record;
 use record::ExportRecordType;
 use record::ExportRecordData;
 use record::ExportRecord;
+use record::ExportMemoryAccess;
 
 pub mod attributes;
 use attributes::ExportAttributes;
@@ -41,6 +42,11 @@ use attributes::ExportAttributeValue;
 pub mod span;
 use span::ExportSpan;
 
+mod snapshot;
+
+pub mod userstack;
+use userstack::{ExportUserStack, ExportUserStacks};
+
 pub mod os;
 use os::OSExportMachine;
 use os::OSExportSampler;
@@ -50,6 +56,9 @@ use os::OSExportSettings;
 #[cfg(target_os = "linux")]
 pub use os::linux::ExportSettingsLinuxExt;
 
+#[cfg(target_os = "linux")]
+use crate::perf_event::BranchEntry;
+
 pub const KERNEL_START:u64 = 0xFFFF800000000000;
 pub const KERNEL_END:u64 = 0xFFFFFFFFFFFFFFFF;
 
@@ -57,6 +66,12 @@ const NANOS_IN_SEC:u64 = 1000000000;
 
 pub type ExportDevNode = ruwind::ModuleKey;
 
+pub use ruwind::{
+    UnwindStats,
+    UnwindStop,
+    ModuleUnwindStats,
+};
+
 pub mod graph;
 pub mod formats;
 pub mod modulemetadata;
@@ -95,6 +110,56 @@ struct ExportCSwitch {
     sample: Option<ExportProcessSample>,
 }
 
+/*
+ * A scheduler switch on a CPU with both sides known. Idle is pid/tid 0.
+ * perf only tells us if the prev task was preempted, not its full state.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExportSchedSwitch {
+    time: u64,
+    cpu: u16,
+    prev_pid: u32,
+    prev_tid: u32,
+    next_pid: u32,
+    next_tid: u32,
+    preempted: bool,
+}
+
+impl ExportSchedSwitch {
+    pub fn new(
+        time: u64,
+        cpu: u16,
+        prev_pid: u32,
+        prev_tid: u32,
+        next_pid: u32,
+        next_tid: u32,
+        preempted: bool) -> Self {
+        Self {
+            time,
+            cpu,
+            prev_pid,
+            prev_tid,
+            next_pid,
+            next_tid,
+            preempted,
+        }
+    }
+
+    pub fn time(&self) -> u64 { self.time }
+
+    pub fn cpu(&self) -> u16 { self.cpu }
+
+    pub fn prev_pid(&self) -> u32 { self.prev_pid }
+
+    pub fn prev_tid(&self) -> u32 { self.prev_tid }
+
+    pub fn next_pid(&self) -> u32 { self.next_pid }
+
+    pub fn next_tid(&self) -> u32 { self.next_tid }
+
+    pub fn preempted(&self) -> bool { self.preempted }
+}
+
 #[derive(Default)]
 struct ExportProxy {
     errors: Vec<anyhow::Error>,
@@ -802,6 +867,7 @@ pub struct ExportSettings {
     cpu_profiling: bool,
     cpu_freq: u64,
     cswitches: bool,
+    sched_switches: bool,
     unwinder: bool,
     callstack_helper: Option<CallstackHelper>,
     os: OSExportSettings,
@@ -828,6 +894,7 @@ impl ExportSettings {
             cpu_profiling: false,
             cpu_freq: 1000,
             cswitches: false,
+            sched_switches: false,
             callstack_helper: Some(callstack_helper.with_external_lookup()),
             unwinder,
             os: OSExportSettings::new(),
@@ -932,6 +999,12 @@ impl ExportSettings {
         clone
     }
 
+    pub fn with_sched_switches(self) -> Self {
+        let mut clone = self.with_cswitches();
+        clone.sched_switches = true;
+        clone
+    }
+
     pub fn with_target_pid(
         self,
         pid: i32) -> Self {
@@ -971,6 +1044,11 @@ pub struct ExportMachine {
     start_qpc: Option<u64>,
     end_qpc: Option<u64>,
     duration: Option<Duration>,
+    counter_totals: Vec<(usize, u64)>,
+    lost_samples: Vec<u64>,
+    sched_switches: Vec<ExportSchedSwitch>,
+    user_stacks: ExportUserStacks,
+    unwind_stats: UnwindStats,
     sample_hooks: Vec<Box<dyn Fn(&ExportSampleFilterContext) -> ExportFilterAction>>,
 }
 
@@ -990,6 +1068,8 @@ pub trait ExportMachineOSHooks {
 
     fn os_resolve_local_anon_symbols(&mut self);
 
+    fn os_unwind_user_stacks(&mut self) -> usize;
+
     fn os_add_mmap_exec(
         &mut self,
         pid: u32,
@@ -1054,6 +1134,11 @@ impl ExportMachine {
             start_qpc: None,
             end_qpc: None,
             duration: None,
+            counter_totals: Vec::new(),
+            lost_samples: Vec::new(),
+            sched_switches: Vec::new(),
+            user_stacks: ExportUserStacks::new(),
+            unwind_stats: UnwindStats::new(),
             sample_hooks,
         }
     }
@@ -1074,19 +1159,13 @@ impl ExportMachine {
 
     pub fn qpc_to_ns(
         freq: u64,
-        mut qpc: u64) -> u64 {
-        let mut ns: u64 = 0;
-
-        if freq != 0 {
-            while qpc >= freq {
-                ns += NANOS_IN_SEC;
-                qpc -= freq;
-            }
-
-            ns += qpc * NANOS_IN_SEC / freq;
+        qpc: u64) -> u64 {
+        if freq == 0 {
+            return 0;
         }
 
-        ns
+        /* Widen to avoid overflow without looping per second */
+        (qpc as u128 * NANOS_IN_SEC as u128 / freq as u128) as u64
     }
 
     pub fn qpc_to_duration(
@@ -1097,6 +1176,22 @@ impl ExportMachine {
 
     pub fn cpu_count() -> u32 { Self::os_cpu_count() }
 
+    pub fn unwind_stats(&self) -> &UnwindStats { &self.unwind_stats }
+
+    pub fn dev_node_filename(
+        &self,
+        node: &ExportDevNode) -> Option<&str> {
+        for proc in self.procs.values() {
+            for mapping in proc.mappings() {
+                if mapping.node().as_ref() == Some(node) {
+                    return self.strings.from_id(mapping.filename_id()).ok();
+                }
+            }
+        }
+
+        None
+    }
+
     pub fn get_mapping_metadata(
         &self,
         mapping: &ExportMapping) -> Option<&ModuleMetadata> {
@@ -1256,6 +1351,34 @@ impl ExportMachine {
             &self.record_data[record.start()..record.end()])
     }
 
+    pub fn sample_memory_access<'a>(
+        &'a self,
+        process: &'a ExportProcess,
+        sample: &ExportProcessSample) -> Option<ExportMemoryAccess<'a>> {
+        let data = self.sample_record_data(sample);
+
+        if !data.record_type().is_memory_access() {
+            return None;
+        }
+
+        /* Records are address, weight, data_src */
+        let data = data.record_data();
+
+        if data.len() < 24 {
+            return None;
+        }
+
+        let address = u64::from_ne_bytes(data[0..8].try_into().unwrap());
+        let weight = u64::from_ne_bytes(data[8..16].try_into().unwrap());
+        let data_src = u64::from_ne_bytes(data[16..24].try_into().unwrap());
+
+        Some(ExportMemoryAccess::new(
+            address,
+            weight,
+            data_src,
+            process.find_data_mapping(address, Some(sample.time()))))
+    }
+
     pub fn sample_span(
         &self,
         sample: &ExportProcessSample) -> Option<&ExportSpan> {
@@ -1296,6 +1419,179 @@ impl ExportMachine {
         None
     }
 
+    pub fn add_counter_total(
+        &mut self,
+        name: &str,
+        value: u64) {
+        let name_id = self.intern(name);
+
+        for (id, total) in &mut self.counter_totals {
+            if *id == name_id {
+                *total += value;
+                return;
+            }
+        }
+
+        self.counter_totals.push((name_id, value));
+    }
+
+    pub fn counter_totals(&self) -> &[(usize, u64)] { &self.counter_totals }
+
+    pub fn add_lost_samples(
+        &mut self,
+        cpu: u16,
+        count: u64) {
+        let cpu = cpu as usize;
+
+        if cpu >= self.lost_samples.len() {
+            self.lost_samples.resize(cpu + 1, 0);
+        }
+
+        self.lost_samples[cpu] += count;
+    }
+
+    pub fn lost_samples(&self) -> u64 { self.lost_samples.iter().sum() }
+
+    pub fn lost_samples_per_cpu(&self) -> &[u64] { &self.lost_samples }
+
+    pub fn add_sched_switch(
+        &mut self,
+        switch: ExportSchedSwitch) {
+        self.sched_switches.push(switch);
+    }
+
+    pub fn sched_switches(&self) -> &[ExportSchedSwitch] { &self.sched_switches }
+
+    pub fn sample_count(&self) -> u64 {
+        self.procs
+            .values()
+            .map(|proc| proc.samples().len() as u64)
+            .sum()
+    }
+
+    pub fn lost_sample_ratio(&self) -> f64 {
+        /* Lost out of everything the kernel tried to deliver */
+        let lost = self.lost_samples();
+        let total = lost + self.sample_count();
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        lost as f64 / total as f64
+    }
+
+    pub fn callstack_metric_ratios(
+        &self,
+        numerator_kind: &str,
+        denominator_kind: &str) -> HashMap<usize, f64> {
+        /*
+         * Sums two sample kinds per-callstack and returns their ratio,
+         * IE: instructions / cycles for IPC from counter groups.
+         */
+        let mut ratios = HashMap::new();
+
+        let numerator_kind = match self.find_sample_kind(numerator_kind) {
+            Some(kind) => { kind },
+            None => { return ratios; },
+        };
+
+        let denominator_kind = match self.find_sample_kind(denominator_kind) {
+            Some(kind) => { kind },
+            None => { return ratios; },
+        };
+
+        let mut totals: HashMap<usize, (u64, u64)> = HashMap::new();
+
+        for process in self.procs.values() {
+            for sample in process.samples() {
+                let value = match sample.value() {
+                    MetricValue::Count(value) => { value },
+                    MetricValue::Duration(value) => { value },
+                    MetricValue::Bytes(value) => { value },
+                    Span(_) => { continue; },
+                };
+
+                let kind = sample.kind();
+
+                if kind == numerator_kind {
+                    totals.entry(sample.callstack_id()).or_default().0 += value;
+                } else if kind == denominator_kind {
+                    totals.entry(sample.callstack_id()).or_default().1 += value;
+                }
+            }
+        }
+
+        for (callstack_id, (numerator, denominator)) in totals {
+            if denominator != 0 {
+                ratios.insert(callstack_id, numerator as f64 / denominator as f64);
+            }
+        }
+
+        ratios
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn branch_edges(&self) -> HashMap<(u64, u64), u64> {
+        /* Counts each (from, to) taken branch for hot-edge profiles */
+        let mut edges = HashMap::new();
+
+        self.walk_branch_stacks(|entries| {
+            for entry in entries {
+                *edges.entry((entry.from_ip(), entry.to_ip())).or_default() += 1;
+            }
+        });
+
+        edges
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn branch_blocks(&self) -> HashMap<(u64, u64), u64> {
+        /*
+         * Branch stacks are newest first, so the target of each older
+         * branch ran straight through to the source of the newer one.
+         * Counts each (start, end) basic block for block profiles.
+         */
+        let mut blocks = HashMap::new();
+
+        self.walk_branch_stacks(|entries| {
+            for pair in entries.windows(2) {
+                let start = pair[1].to_ip();
+                let end = pair[0].from_ip();
+
+                if start <= end {
+                    *blocks.entry((start, end)).or_default() += 1;
+                }
+            }
+        });
+
+        blocks
+    }
+
+    #[cfg(target_os = "linux")]
+    fn walk_branch_stacks(
+        &self,
+        mut callback: impl FnMut(&[BranchEntry])) {
+        let mut entries = Vec::new();
+
+        for process in self.procs.values() {
+            for sample in process.samples() {
+                let data = self.sample_record_data(sample);
+
+                if !data.record_type().is_branch_stack() {
+                    continue;
+                }
+
+                /* Truncated records are skipped */
+                if BranchEntry::decode(data.record_data(), &mut entries).is_err() {
+                    continue;
+                }
+
+                callback(&entries);
+            }
+        }
+    }
+
     pub fn find_process(
         &self,
         pid: u32) -> Option<&ExportProcess> {
@@ -1500,6 +1796,41 @@ impl ExportMachine {
         Ok(())
     }
 
+    pub fn add_mmap_data(
+        &mut self,
+        time: u64,
+        pid: u32,
+        addr: u64,
+        len: u64,
+        pgoffset: u64,
+        filename: &str) -> anyhow::Result<()> {
+        /* Data mappings are only used to resolve data addresses */
+        if pid == 0 {
+            return Ok(());
+        }
+
+        let anon = filename.is_empty() ||
+            filename.starts_with('[') ||
+            filename.starts_with("/memfd:") ||
+            filename.starts_with("//anon");
+
+        let mapping = ExportMapping::new(
+            time,
+            self.intern(filename),
+            addr,
+            addr + len - 1,
+            pgoffset,
+            anon,
+            self.map_index,
+            UnwindType::Prolog);
+
+        self.map_index += 1;
+
+        self.process_mut(pid).add_data_mapping(mapping);
+
+        Ok(())
+    }
+
     pub fn add_comm_exec(
         &mut self,
         pid: u32,
@@ -1580,7 +1911,7 @@ impl ExportMachine {
         cpu: u16,
         kind: u16,
         frames: &[u64]) -> anyhow::Result<()> {
-        let sample = self.make_sample(
+        let mut sample = self.make_sample(
             time,
             value,
             tid,
@@ -1588,10 +1919,15 @@ impl ExportMachine {
             kind,
             frames);
 
+        let user_stack = self.user_stacks.take_pending(pid, sample.time());
         let proc = self.procs.entry(pid).or_insert_with(|| ExportProcess::new(pid));
 
         filter_sample_ret_on_drop!(self, &proc, &sample, 0, None);
 
+        if let Some(user_stack) = user_stack {
+            self.user_stacks.attach(user_stack, &mut sample);
+        }
+
         proc.add_sample(sample);
 
         Ok(())
@@ -1607,10 +1943,15 @@ impl ExportMachine {
             anyhow::bail!("Record is already attached.");
         }
 
+        let user_stack = self.user_stacks.take_pending(pid, sample.time());
         let proc = self.procs.entry(pid).or_insert_with(|| ExportProcess::new(pid));
 
         filter_sample_ret_on_drop!(self, &proc, &sample, record_type, Some(record_data));
 
+        if let Some(user_stack) = user_stack {
+            self.user_stacks.attach(user_stack, &mut sample);
+        }
+
         /*
          * Add record data to global data slice:
          * Instead of having many vecs (IE: each process) we keep
@@ -1666,11 +2007,16 @@ impl ExportMachine {
     pub fn add_custom_sample(
         &mut self,
         pid: u32,
-        sample: ExportProcessSample) -> anyhow::Result<()> {
+        mut sample: ExportProcessSample) -> anyhow::Result<()> {
+        let user_stack = self.user_stacks.take_pending(pid, sample.time());
         let proc = self.procs.entry(pid).or_insert_with(|| ExportProcess::new(pid));
 
         filter_sample_ret_on_drop!(self, &proc, &sample, 0, None);
 
+        if let Some(user_stack) = user_stack {
+            self.user_stacks.attach(user_stack, &mut sample);
+        }
+
         proc.add_sample(sample);
 
         Ok(())
@@ -1689,6 +2035,468 @@ impl ExportMachine {
             record_data)
     }
 
+    fn merge_span(
+        span: &ExportSpan,
+        string_map: &[usize],
+        time_delta: i128) -> ExportSpan {
+        let mut merged = ExportSpan::start(
+            string_map.get(span.name_id()).copied().unwrap_or(0),
+            Self::rebase_time(span.start_time(), time_delta),
+            span.children().len());
+
+        for child in span.children() {
+            let child = Self::merge_span(child, string_map, time_delta);
+
+            merged.add_child(child);
+        }
+
+        merged.mark_end(Self::rebase_time(span.end_time(), time_delta));
+
+        merged
+    }
+
+    fn rebase_time(
+        time: u64,
+        time_delta: i128) -> u64 {
+        /* Time 0 means always present (IE: kernel mappings) */
+        if time == 0 {
+            return 0;
+        }
+
+        (time as i128 + time_delta).clamp(0, u64::MAX as i128) as u64
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &ExportMachine) -> anyhow::Result<()> {
+        /*
+         * Captures from other machines have unrelated QPC values, so we
+         * rebase them onto our start time. This lets samples from both
+         * machines interleave as if they were captured together.
+         */
+        let time_delta = match (self.start_qpc, other.start_qpc) {
+            (Some(start), Some(other_start)) => { start as i128 - other_start as i128 },
+            _ => { 0 },
+        };
+
+        match (self.start_qpc, other.start_qpc) {
+            (None, Some(_)) => {
+                self.start_date = other.start_date;
+                self.start_qpc = other.start_qpc;
+                self.end_qpc = other.end_qpc;
+                self.duration = other.duration;
+            },
+            (Some(_), Some(_)) => {
+                if let Some(end_qpc) = other.end_qpc {
+                    let end_qpc = Self::rebase_time(end_qpc, time_delta);
+
+                    if self.end_qpc.map_or(true, |existing| end_qpc > existing) {
+                        self.end_qpc = Some(end_qpc);
+                    }
+                }
+
+                if other.duration > self.duration {
+                    self.duration = other.duration;
+                }
+            },
+            _ => {},
+        }
+
+        /* Re-intern strings */
+        let mut string_map = Vec::new();
+
+        other.strings.for_each(|id, value| {
+            if id >= string_map.len() {
+                string_map.resize(id + 1, 0);
+            }
+
+            string_map[id] = self.strings.to_id(value);
+        });
+
+        let remap_string = |id: usize| -> usize {
+            string_map.get(id).copied().unwrap_or(0)
+        };
+
+        /* Counter totals are summed by name */
+        for (name_id, value) in &other.counter_totals {
+            let name_id = remap_string(*name_id);
+
+            match self.counter_totals.iter_mut().find(|(id, _)| *id == name_id) {
+                Some((_, total)) => { *total += value; },
+                None => { self.counter_totals.push((name_id, *value)); },
+            }
+        }
+
+        /* Lost samples are summed by CPU */
+        for (cpu, count) in other.lost_samples.iter().enumerate() {
+            if *count != 0 {
+                self.add_lost_samples(cpu as u16, *count);
+            }
+        }
+
+        /*
+         * dev/ino is only unique within a machine, the other machine may
+         * use the same dev/ino for a different binary. Re-key any of its
+         * modules that collide with ours so metadata can't be mixed up.
+         */
+        let mut used_nodes = HashSet::new();
+
+        for (node, _) in self.module_metadata.iter() {
+            used_nodes.insert(*node);
+        }
+
+        for proc in self.procs.values() {
+            for mapping in proc.mappings() {
+                if let Some(node) = mapping.node() {
+                    used_nodes.insert(*node);
+                }
+            }
+        }
+
+        for (node, _) in self.unwind_stats.modules() {
+            if let Some(node) = node {
+                used_nodes.insert(node);
+            }
+        }
+
+        let mut other_nodes: Vec<ExportDevNode> = other.module_metadata
+            .iter()
+            .map(|(node, _)| *node)
+            .collect();
+
+        for proc in other.procs.values() {
+            for mapping in proc.mappings() {
+                if let Some(node) = mapping.node() {
+                    other_nodes.push(*node);
+                }
+            }
+        }
+
+        for (node, _) in other.unwind_stats.modules() {
+            if let Some(node) = node {
+                other_nodes.push(node);
+            }
+        }
+
+        let mut node_map = HashMap::new();
+        let mut next_dev = u64::MAX;
+
+        for node in other_nodes {
+            if node_map.contains_key(&node) {
+                continue;
+            }
+
+            let mut key = node;
+
+            while used_nodes.contains(&key) {
+                key = ExportDevNode::new(next_dev, node.ino());
+                next_dev -= 1;
+            }
+
+            used_nodes.insert(key);
+            node_map.insert(node, key);
+        }
+
+        let remap_node = |node: ExportDevNode| -> ExportDevNode {
+            node_map.get(&node).copied().unwrap_or(node)
+        };
+
+        /* Unwind stats are summed by module */
+        self.unwind_stats.merge_with(&other.unwind_stats, remap_node);
+
+        /*
+         * Sched switches are not merged, CPUs and tids from another
+         * machine would interleave with ours as if they ran together.
+         */
+
+        /* Re-intern sample kinds and record types */
+        let mut kind_map = Vec::new();
+
+        for kind in &other.kinds {
+            kind_map.push(self.sample_kind(kind));
+        }
+
+        let mut record_type_map = Vec::new();
+
+        for (i, record_type) in other.record_types.iter().enumerate() {
+            /* Record type 0 is always the empty/default type */
+            if i == 0 {
+                record_type_map.push(0);
+            } else {
+                let mut record_type = record_type.clone();
+                let kind = record_type.kind_mut();
+
+                if let Some(new_kind) = kind_map.get(*kind as usize) {
+                    *kind = *new_kind;
+                }
+
+                record_type_map.push(self.record_type(record_type));
+            }
+        }
+
+        /* Copy records and their data, record 0 is the default */
+        let record_base = self.records.len() - 1;
+
+        for record in other.records.iter().skip(1) {
+            let offset = self.record_data.len();
+            let data = &other.record_data[record.start()..record.end()];
+
+            self.records.push(
+                ExportRecord::new(
+                    record_type_map[record.record_type() as usize],
+                    offset,
+                    data.len() as u32));
+
+            self.record_data.extend_from_slice(data);
+        }
+
+        /*
+         * Copy attributes, attribute 0 is the default. Associations may
+         * point at any attribute ID, so IDs are computed up front.
+         */
+        let attribute_base = self.attributes.len() - 1;
+
+        let remap_attributes = |id: usize| -> usize {
+            if id == 0 { 0 } else { attribute_base + id }
+        };
+
+        for attributes in other.attributes.iter().skip(1) {
+            let mut merged = ExportAttributes::default();
+
+            for pair in attributes.attributes() {
+                let value = match pair.attribute_value() {
+                    ExportAttributeValue::Label(id) => {
+                        ExportAttributeValue::Label(remap_string(id))
+                    },
+                    value => { value },
+                };
+
+                merged.push(ExportAttributePair::new(remap_string(pair.name()), value));
+            }
+
+            for associated_id in attributes.associated_ids() {
+                merged.push_association(remap_attributes(*associated_id));
+            }
+
+            self.push_unique_attributes(merged);
+        }
+
+        /* Copy spans */
+        let span_base = self.spans.len();
+
+        for span in &other.spans {
+            let span = Self::merge_span(span, &string_map, time_delta);
+
+            self.spans.push(span);
+        }
+
+        /* Copy module metadata under the re-keyed nodes */
+        for (node, metadata) in other.module_metadata.iter() {
+            if let Vacant(entry) = self.module_metadata.entry(remap_node(*node)) {
+                let mut metadata = metadata.clone();
+
+                metadata.remap_strings(remap_string);
+
+                entry.insert(metadata);
+            }
+        }
+
+        /*
+         * Union processes: A process is the same when the comm matches
+         * and either the pid or ns_pid matches. Otherwise the process is
+         * added, under a new pid if the pid is already taken.
+         */
+        let mut ns_pids = HashMap::new();
+
+        for proc in self.procs.values() {
+            if let Some(ns_pid) = proc.ns_pid() {
+                ns_pids.insert((ns_pid, proc.comm_id()), proc.pid());
+            }
+        }
+
+        let mut callstack_map = HashMap::new();
+        let mut frames = Vec::new();
+
+        for other_proc in other.procs.values() {
+            let comm_id = other_proc.comm_id().map(remap_string);
+
+            let mut pid = other_proc.pid();
+
+            let matched = match self.procs.get(&pid) {
+                Some(proc) => { proc.comm_id() == comm_id },
+                None => { false },
+            };
+
+            if !matched {
+                let ns_match = match other_proc.ns_pid() {
+                    Some(ns_pid) => { ns_pids.get(&(ns_pid, comm_id)).copied() },
+                    None => { None },
+                };
+
+                match ns_match {
+                    Some(ns_match) => { pid = ns_match; },
+                    None => {
+                        while self.procs.contains_key(&pid) {
+                            pid = pid.wrapping_add(1).max(1);
+                        }
+
+                        let mut proc = ExportProcess::new(pid);
+
+                        *proc.ns_pid_mut() = other_proc.ns_pid();
+
+                        if let Some(comm_id) = comm_id {
+                            proc.set_comm_id(comm_id);
+                        }
+
+                        if let Some(ns_pid) = other_proc.ns_pid() {
+                            ns_pids.insert((ns_pid, comm_id), pid);
+                        }
+
+                        self.procs.insert(pid, proc);
+                    }
+                }
+            }
+
+            /* Copy mappings with new IDs */
+            let mut mappings = Vec::new();
+
+            for mapping in other_proc.mappings() {
+                let mut merged = ExportMapping::new(
+                    Self::rebase_time(mapping.time(), time_delta),
+                    remap_string(mapping.filename_id()),
+                    mapping.start(),
+                    mapping.end(),
+                    mapping.file_offset(),
+                    mapping.anon(),
+                    self.map_index,
+                    mapping.unwind_type());
+
+                if let Some(node) = mapping.node() {
+                    merged.set_node(remap_node(*node));
+                }
+
+                for symbol in mapping.symbols() {
+                    merged.add_symbol(
+                        ExportSymbol::new(
+                            remap_string(symbol.name_id()),
+                            symbol.start(),
+                            symbol.end()));
+                }
+
+                self.map_index += 1;
+
+                mappings.push(merged);
+            }
+
+            let mut data_mappings = Vec::new();
+
+            for mapping in other_proc.data_mappings() {
+                let merged = ExportMapping::new(
+                    Self::rebase_time(mapping.time(), time_delta),
+                    remap_string(mapping.filename_id()),
+                    mapping.start(),
+                    mapping.end(),
+                    mapping.file_offset(),
+                    mapping.anon(),
+                    self.map_index,
+                    mapping.unwind_type());
+
+                self.map_index += 1;
+
+                data_mappings.push(merged);
+            }
+
+            /* Copy samples with re-interned IDs */
+            let mut samples = Vec::new();
+
+            for sample in other_proc.samples() {
+                let callstack_id = match callstack_map.entry(sample.callstack_id()) {
+                    Occupied(entry) => { *entry.get() },
+                    Vacant(entry) => {
+                        other.callstacks.from_id(sample.callstack_id(), &mut frames)?;
+
+                        *entry.insert(self.callstacks.to_id(&frames))
+                    },
+                };
+
+                let value = match sample.value() {
+                    Span(id) => { Span(span_base + id) },
+                    value => { value },
+                };
+
+                let mut merged = ExportProcessSample::new(
+                    Self::rebase_time(sample.time(), time_delta),
+                    value,
+                    sample.cpu(),
+                    kind_map[sample.kind() as usize],
+                    sample.tid(),
+                    sample.ip(),
+                    callstack_id);
+
+                if sample.has_record() {
+                    merged.attach_record(record_base + sample.record_id());
+                }
+
+                if sample.has_attributes() {
+                    merged.attach_attributes(remap_attributes(sample.attributes_id()));
+                }
+
+                if let Some((stack, stack_data)) = other.user_stack(sample) {
+                    let callstack_id = match callstack_map.entry(stack.callstack_id()) {
+                        Occupied(entry) => { *entry.get() },
+                        Vacant(entry) => {
+                            other.callstacks.from_id(stack.callstack_id(), &mut frames)?;
+
+                            *entry.insert(self.callstacks.to_id(&frames))
+                        },
+                    };
+
+                    merged.attach_user_stack(
+                        self.user_stacks.push_data(
+                            stack,
+                            stack_data,
+                            callstack_id));
+                }
+
+                samples.push(merged);
+            }
+
+            let proc = self.process_mut(pid);
+
+            for mapping in mappings {
+                proc.add_mapping(mapping);
+            }
+
+            for mapping in data_mappings {
+                proc.add_data_mapping(mapping);
+            }
+
+            for sample in samples {
+                proc.add_sample(sample);
+            }
+
+            if proc.comm_id().is_none() {
+                if let Some(comm_id) = comm_id {
+                    proc.set_comm_id(comm_id);
+                }
+            }
+
+            if proc.create_time_qpc().is_none() {
+                if let Some(qpc) = other_proc.create_time_qpc() {
+                    proc.set_create_time_qpc(Self::rebase_time(qpc, time_delta));
+                }
+            }
+
+            if proc.exit_time_qpc().is_none() {
+                if let Some(qpc) = other_proc.exit_time_qpc() {
+                    proc.set_exit_time_qpc(Self::rebase_time(qpc, time_delta));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn load_pe_metadata(
         &mut self) {
         for proc in self.procs.values() {
@@ -1754,6 +2562,21 @@ mod tests {
     use super::*;
     use crate::event::*;
 
+    #[test]
+    fn qpc_to_ns() {
+        assert_eq!(0, ExportMachine::qpc_to_ns(0, 1234));
+        assert_eq!(1500, ExportMachine::qpc_to_ns(NANOS_IN_SEC, 1500));
+        assert_eq!(200, ExportMachine::qpc_to_ns(10_000_000, 2));
+
+        /* Long uptimes must not overflow */
+        let freq = 10_000_000;
+        let secs = 60 * 60 * 24 * 365 * 10;
+
+        assert_eq!(
+            secs * NANOS_IN_SEC + 100,
+            ExportMachine::qpc_to_ns(freq, secs * freq + 1));
+    }
+
     #[test]
     fn sample_records() {
         let mut machine = ExportMachine::new(ExportSettings::default());
@@ -1906,6 +2729,132 @@ mod tests {
             }).expect("Should work");
     }
 
+    #[test]
+    fn merge() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+        let mut other = ExportMachine::new(ExportSettings::default());
+        let mut frames = Vec::new();
+
+        machine.mark_start_direct(Utc::now(), 100);
+        other.mark_start_direct(Utc::now(), 1000);
+
+        /* Ensure IDs differ between machines */
+        other.intern("Unrelated");
+        other.sample_kind("alloc");
+
+        machine.add_counter_total("cycles", 100);
+        other.add_counter_total("instructions", 50);
+        other.add_counter_total("cycles", 200);
+
+        machine.add_lost_samples(0, 5);
+        other.add_lost_samples(0, 2);
+        other.add_lost_samples(2, 3);
+
+        let cpu = machine.sample_kind("cpu");
+        machine.add_comm_exec(1, "app", 100).unwrap();
+        machine.add_comm_exec(3, "first", 100).unwrap();
+        machine.add_sample(150, MetricValue::Count(1), 1, 1, 0, cpu, &[1, 2]).unwrap();
+
+        let other_cpu = other.sample_kind("cpu");
+        other.add_comm_exec(1, "app", 1000).unwrap();
+        other.add_comm_exec(3, "second", 1000).unwrap();
+
+        /* Sample with attributes, record and span */
+        let mut e = Event::new(1, "test".into());
+
+        e.format_mut().add_field(
+            EventField::new(
+                "1".into(), "unsigned char".into(),
+                LocationType::Static, 0, 1));
+
+        let record_type = other.record_type(ExportRecordType::from_event(other_cpu, &e));
+        let mut attributes = ExportAttributes::default();
+        attributes.push(other.label_attribute("Host", "other"));
+        let attributes_id = other.push_unique_attributes(attributes);
+
+        let mut span = ExportSpan::start(other.intern("Span"), 1010, 0);
+        span.mark_end(1020);
+        let value = other.span_to_value(span);
+
+        let mut sample = other.make_sample(1050, value, 1, 0, other_cpu, &[3, 4, 5]);
+        sample.attach_attributes(attributes_id);
+
+        other.add_custom_sample_with_record(1, sample, record_type, &[b'Z']).unwrap();
+        other.add_sample(1060, MetricValue::Count(1), 3, 3, 0, other_cpu, &[6]).unwrap();
+
+        /* Same dev/ino on both machines, but different binaries */
+        let node = ExportDevNode::from_parts(8, 1, 42);
+
+        for (machine, time, build_id) in [(&mut machine, 100, 1), (&mut other, 1000, 2)] {
+            machine.add_mmap_exec(time, 1, 0x1000, 0x1000, 0, 8, 1, 42, "/usr/bin/app").unwrap();
+
+            let mut elf = modulemetadata::ElfModuleMetadata::new();
+            elf.set_build_id(Some(&[build_id; 20]));
+            machine.module_metadata.entry(node).or_insert(ModuleMetadata::Elf(elf));
+        }
+
+        machine.merge(&other).unwrap();
+
+        /* Same pid and comm are unioned */
+        let proc = machine.find_process(1).unwrap();
+        assert_eq!(2, proc.samples().len());
+
+        let sample = &proc.samples()[1];
+        assert_eq!(150, sample.time());
+        assert_eq!(cpu, sample.kind());
+        assert_eq!(3, sample.ip());
+
+        machine.callstacks().from_id(sample.callstack_id(), &mut frames).unwrap();
+        assert_eq!(vec![4, 5], frames);
+
+        let mut walker = ExportAttributeWalker::default();
+        machine.sample_attributes(sample, &mut walker);
+        assert_eq!(1, walker.attributes().len());
+        assert_eq!(Some("Host"), walker.attributes()[0].name_str(machine.strings()));
+        assert_eq!(Some("other"), walker.attributes()[0].label_str(machine.strings()));
+
+        let data = machine.sample_record_data(sample);
+        assert_eq!("test", data.record_type().name());
+        assert_eq!(cpu, data.record_type().kind());
+        assert_eq!(&[b'Z'], data.record_data());
+
+        let span = machine.sample_span(sample).unwrap();
+        assert_eq!("Span", span.name(machine.strings()));
+        assert_eq!(110, span.start_time());
+        assert_eq!(120, span.end_time());
+
+        /* Same pid with a different comm gets a new pid */
+        let first = machine.find_process(3).unwrap();
+        assert_eq!(Some("first"), first.comm_id().map(|id| machine.strings().from_id(id).unwrap()));
+        assert_eq!(0, first.samples().len());
+
+        let second = machine.find_process(4).unwrap();
+        assert_eq!(Some("second"), second.comm_id().map(|id| machine.strings().from_id(id).unwrap()));
+        assert_eq!(1, second.samples().len());
+        assert_eq!(160, second.samples()[0].time());
+        assert_eq!(2, machine.sample_kinds().len());
+
+        /* Counter totals are summed by name */
+        let cycles = machine.intern("cycles");
+        let instructions = machine.intern("instructions");
+        assert_eq!(&[(cycles, 300), (instructions, 50)], machine.counter_totals());
+        assert_eq!(&[7, 0, 3], machine.lost_samples_per_cpu());
+
+        /* Colliding dev/ino keep their own metadata */
+        let build_ids: Vec<_> = machine.find_process(1).unwrap()
+            .mappings()
+            .iter()
+            .map(|mapping| {
+                match machine.get_mapping_metadata(mapping) {
+                    Some(ModuleMetadata::Elf(elf)) => { elf.build_id().unwrap()[0] },
+                    _ => { 0 },
+                }
+            })
+            .collect();
+
+        assert_eq!(vec![1, 2], build_ids);
+    }
+
     #[cfg(target_os = "windows")]
     #[test]
     fn proxy() {
@@ -2046,4 +2995,156 @@ mod tests {
         assert_eq!(parent_str_id, attributes[1].name());
         assert_eq!(true_str_id, attributes[1].label().expect("Should be label attribute"));
     }
+
+    #[test]
+    fn callstack_metric_ratios() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        let cycles = machine.sample_kind("cycles");
+        let instructions = machine.sample_kind("instructions");
+        machine.add_comm_exec(1, "app", 0).unwrap();
+
+        /* Two group samples on one callstack, one on another */
+        machine.add_sample(1, MetricValue::Count(1000), 1, 1, 0, cycles, &[1, 2]).unwrap();
+        machine.add_sample(1, MetricValue::Count(2000), 1, 1, 0, instructions, &[1, 2]).unwrap();
+        machine.add_sample(2, MetricValue::Count(1000), 1, 1, 0, cycles, &[1, 2]).unwrap();
+        machine.add_sample(2, MetricValue::Count(1000), 1, 1, 0, instructions, &[1, 2]).unwrap();
+        machine.add_sample(3, MetricValue::Count(4000), 1, 1, 0, cycles, &[3]).unwrap();
+        machine.add_sample(3, MetricValue::Count(1000), 1, 1, 0, instructions, &[3]).unwrap();
+
+        let ipc = machine.callstack_metric_ratios("instructions", "cycles");
+        assert_eq!(2, ipc.len());
+
+        let process = machine.find_process(1).unwrap();
+        let first = process.samples()[0].callstack_id();
+        let second = process.samples()[4].callstack_id();
+
+        assert_eq!(1.5, ipc[&first]);
+        assert_eq!(0.25, ipc[&second]);
+
+        /* Unknown kinds give back nothing */
+        assert!(machine.callstack_metric_ratios("cache-misses", "cycles").is_empty());
+    }
+
+    #[test]
+    fn memory_access() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        let kind = machine.sample_kind("memory_access");
+        let record_type = machine.record_type(ExportRecordType::for_memory_access(kind));
+        machine.add_comm_exec(1, "app", 0).unwrap();
+        machine.add_mmap_data(0, 1, 0x10000, 0x1000, 0, "[heap]").unwrap();
+
+        /* Kernel PID is never tracked */
+        machine.add_mmap_data(0, 0, 0x10000, 0x1000, 0, "[heap]").unwrap();
+        assert!(machine.find_process(0).is_none());
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&0x10100u64.to_ne_bytes());
+        data.extend_from_slice(&250u64.to_ne_bytes());
+        data.extend_from_slice(&0x1234u64.to_ne_bytes());
+
+        machine.add_sample_with_record(
+            1, MetricValue::Count(1), 1, 1, 0,
+            kind, record_type, &data, &[1]).unwrap();
+
+        /* Outside of any mapping */
+        data[0..8].copy_from_slice(&0x90000u64.to_ne_bytes());
+
+        machine.add_sample_with_record(
+            2, MetricValue::Count(1), 1, 1, 0,
+            kind, record_type, &data, &[1]).unwrap();
+
+        machine.add_sample(3, MetricValue::Count(1), 1, 1, 0, kind, &[1]).unwrap();
+
+        let process = machine.find_process(1).unwrap();
+        assert_eq!(1, process.data_mappings().len());
+        assert!(process.mappings().is_empty());
+
+        let samples = process.samples();
+
+        let access = machine.sample_memory_access(process, &samples[0]).unwrap();
+        assert_eq!(0x10100, access.address());
+        assert_eq!(250, access.weight());
+        assert_eq!(0x1234, access.data_src());
+
+        let mapping = access.mapping().unwrap();
+        assert!(mapping.anon());
+        assert_eq!("[heap]", machine.strings().from_id(mapping.filename_id()).unwrap());
+
+        let access = machine.sample_memory_access(process, &samples[1]).unwrap();
+        assert!(access.mapping().is_none());
+
+        assert!(machine.sample_memory_access(process, &samples[2]).is_none());
+
+        /* Data mappings carry over on fork */
+        let fork = process.fork(2);
+        assert_eq!(1, fork.data_mappings().len());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn branch_profiles() {
+        use crate::perf_event::BranchEntry;
+
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        let kind = machine.sample_kind("cpu");
+        let record_type = machine.record_type(ExportRecordType::for_branch_stack(kind));
+        machine.add_comm_exec(1, "app", 0).unwrap();
+
+        /* Newest first: 0x1000 -> 0x1080, then 0x1100 -> 0x2000 */
+        let mut data = Vec::new();
+        BranchEntry::new(0x1100, 0x2000, 0).write(&mut data);
+        BranchEntry::new(0x1000, 0x1080, 0).write(&mut data);
+
+        machine.add_sample_with_record(
+            1, MetricValue::Count(1), 1, 1, 0,
+            kind, record_type, &data, &[1]).unwrap();
+
+        machine.add_sample_with_record(
+            2, MetricValue::Count(1), 1, 1, 0,
+            kind, record_type, &data, &[1]).unwrap();
+
+        /* Samples without branch stacks are ignored */
+        machine.add_sample(3, MetricValue::Count(1), 1, 1, 0, kind, &[1]).unwrap();
+
+        let edges = machine.branch_edges();
+        assert_eq!(2, edges.len());
+        assert_eq!(2, edges[&(0x1100, 0x2000)]);
+        assert_eq!(2, edges[&(0x1000, 0x1080)]);
+
+        let blocks = machine.branch_blocks();
+        assert_eq!(1, blocks.len());
+        assert_eq!(2, blocks[&(0x1080, 0x1100)]);
+
+        let process = machine.find_process(1).unwrap();
+        let record = machine.sample_record_data(&process.samples()[0]);
+        assert!(record.record_type().is_branch_stack());
+        assert_eq!(3, record.record_type().format().fields().len());
+    }
+
+    #[test]
+    fn lost_samples() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        assert_eq!(0, machine.lost_samples());
+        assert_eq!(0.0, machine.lost_sample_ratio());
+
+        let kind = machine.sample_kind("cpu");
+        machine.add_comm_exec(1, "app", 0).unwrap();
+
+        for i in 0..6 {
+            machine.add_sample(i, MetricValue::Count(1), 1, 1, 0, kind, &[1]).unwrap();
+        }
+
+        machine.add_lost_samples(1, 1);
+        machine.add_lost_samples(3, 2);
+        machine.add_lost_samples(1, 1);
+
+        assert_eq!(&[0, 2, 0, 2], machine.lost_samples_per_cpu());
+        assert_eq!(4, machine.lost_samples());
+        assert_eq!(6, machine.sample_count());
+        assert_eq!(0.4, machine.lost_sample_ratio());
+    }
 }
@@ -2,17 +2,62 @@
 // Licensed under the MIT license.
 
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+use std::io::{Read, Write};
+use std::collections::hash_map::{Entry, Iter};
 use crate::helpers::exporting::ExportDevNode;
 use super::InternedStrings;
 use super::pe_file::PEModuleMetadata;
+use super::snapshot::{SnapshotRead, SnapshotWrite};
 
+#[derive(Clone)]
 pub enum ModuleMetadata {
     Elf(ElfModuleMetadata),
     PE(PEModuleMetadata),
 }
 
+const METADATA_ELF: u8 = 0;
+const METADATA_PE: u8 = 1;
+
 impl ModuleMetadata {
+    pub(crate) fn write_snapshot(
+        &self,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        match self {
+            ModuleMetadata::Elf(elf) => {
+                writer.write_u8(METADATA_ELF)?;
+                elf.write_snapshot(writer)
+            },
+
+            ModuleMetadata::PE(pe) => {
+                writer.write_u8(METADATA_PE)?;
+                pe.write_snapshot(writer)
+            }
+        }
+    }
+
+    pub(crate) fn read_snapshot(
+        reader: &mut impl Read) -> anyhow::Result<Self> {
+        match reader.read_u8()? {
+            METADATA_ELF => { Ok(ModuleMetadata::Elf(ElfModuleMetadata::read_snapshot(reader)?)) },
+            METADATA_PE => { Ok(ModuleMetadata::PE(PEModuleMetadata::read_snapshot(reader)?)) },
+            kind => { anyhow::bail!("Unknown module metadata kind {}.", kind) },
+        }
+    }
+
+    pub(crate) fn remap_strings(
+        &mut self,
+        remap: impl Fn(usize) -> usize) {
+        match self {
+            ModuleMetadata::Elf(elf) => {
+                elf.remap_strings(remap);
+            },
+
+            ModuleMetadata::PE(pe) => {
+                pe.remap_strings(remap);
+            }
+        }
+    }
+
     pub fn to_symbol_metadata(
         &self,
         strings: &InternedStrings,
@@ -44,6 +89,7 @@ impl ModuleMetadata {
     }
 }
 
+#[derive(Clone)]
 pub struct ElfModuleMetadata {
     build_id: Option<[u8; 20]>,
     debug_link_id: usize,
@@ -102,6 +148,46 @@ impl ElfModuleMetadata {
         }
     }
 
+    pub(crate) fn write_snapshot(
+        &self,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        match &self.build_id {
+            Some(build_id) => {
+                writer.write_u8(1)?;
+                writer.write_all(build_id)?;
+            },
+            None => { writer.write_u8(0)?; },
+        }
+
+        writer.write_u64(self.debug_link_id as u64)?;
+        writer.write_u64(self.version_metadata_id as u64)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn read_snapshot(
+        reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut metadata = Self::new();
+
+        if reader.read_u8()? != 0 {
+            let mut build_id = [0; 20];
+            reader.read_exact(&mut build_id)?;
+            metadata.build_id = Some(build_id);
+        }
+
+        metadata.debug_link_id = reader.read_u64()? as usize;
+        metadata.version_metadata_id = reader.read_u64()? as usize;
+
+        Ok(metadata)
+    }
+
+    pub(crate) fn remap_strings(
+        &mut self,
+        remap: impl Fn(usize) -> usize) {
+        self.debug_link_id = remap(self.debug_link_id);
+        self.version_metadata_id = remap(self.version_metadata_id);
+    }
+
     pub fn build_id(&self) -> Option<&[u8; 20]> {
         self.build_id.as_ref()
     }
@@ -173,6 +259,10 @@ impl ModuleMetadataLookup {
         key: &ExportDevNode) -> Option<&ModuleMetadata> {
         self.metadata.get(key)
     }
+
+    pub fn iter(&self) -> Iter<'_, ExportDevNode, ModuleMetadata> {
+        self.metadata.iter()
+    }
 }
 
 #[cfg(test)]
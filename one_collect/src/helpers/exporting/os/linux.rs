@@ -4,6 +4,8 @@
 use std::collections::hash_map::Entry;
 use std::collections::hash_map::Entry::{Vacant, Occupied};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use std::fs::File;
 use std::fmt::Write;
@@ -15,8 +17,10 @@ use crate::PathBufInteger;
 use crate::openat::{OpenAt, DupFd};
 use crate::procfs;
 use crate::perf_event::{AncillaryData, PerfSession};
-use crate::perf_event::{RingBufSessionBuilder, RingBufBuilder};
+use crate::perf_event::{RingBufSessionBuilder, RingBufBuilder, RingBufGroupBuilder, RingBufOptions, Pmu};
+use crate::perf_event::{GroupReadDecoder, LaunchedProcess, PmuEventKind, PerfCountersBuilder};
 use crate::perf_event::abi::PERF_RECORD_MISC_SWITCH_OUT;
+use crate::perf_event::abi::PERF_RECORD_MISC_SWITCH_OUT_PREEMPT;
 use crate::helpers::callstack::{CallstackHelp, CallstackReader};
 use crate::helpers::exporting::*;
 use crate::helpers::exporting::process::{ExportProcessOSHooks, MetricValue};
@@ -556,38 +560,112 @@ impl ExportProcessOSHooks for ExportProcess {
 }
 
 pub(crate) fn default_export_settings() -> ExportSettings {
-        let helper = if cfg!(target_arch="x86_64") {
-            /* X64 Linux */
-
-            /*
-             * TODO:
-             * When SFRAME is supported, we should query to see
-             * if SFRAME is being supported. If so, we don't need
-             * to use DWARF anymore.
-             */
-            CallstackHelper::new().with_dwarf_unwinding()
-        } else {
-            /* Non-X64 Linux */
-            CallstackHelper::new()
-        };
+        /* X64 and ARM64 Linux */
+
+        /*
+         * TODO:
+         * When SFRAME is supported, we should query to see
+         * if SFRAME is being supported. If so, we don't need
+         * to use DWARF anymore.
+         */
+        let helper = CallstackHelper::new().with_dwarf_unwinding();
 
         ExportSettings::new(helper)
 }
 
 pub(crate) struct OSExportSettings {
     process_fs: bool,
+    pmu_events: Vec<(String, RingBufBuilder<Pmu>)>,
+    pmu_groups: Vec<(Vec<String>, RingBufGroupBuilder)>,
+    branch_sample_type: u64,
+    memory_events: Vec<RingBufBuilder<Pmu>>,
+    launched_process: Option<LaunchedProcess>,
+    cgroup_labels: bool,
+    target_cgroup: Option<String>,
+    wakeup_watermark: u32,
+    max_cpu_buf_bytes: usize,
+    deferred_unwinding: bool,
+    counters: Vec<(String, RingBufBuilder<Pmu>)>,
 }
 
 impl OSExportSettings {
     pub fn new() -> Self {
         Self {
             process_fs: true,
+            pmu_events: Vec::new(),
+            pmu_groups: Vec::new(),
+            branch_sample_type: 0,
+            memory_events: Vec::new(),
+            launched_process: None,
+            cgroup_labels: false,
+            target_cgroup: None,
+            wakeup_watermark: 0,
+            max_cpu_buf_bytes: 0,
+            deferred_unwinding: false,
+            counters: Vec::new(),
         }
     }
 }
 
 pub trait ExportSettingsLinuxExt {
     fn without_process_fs(self) -> Self;
+
+    fn with_pmu_sampling(
+        self,
+        kind: &str,
+        builder: RingBufBuilder<Pmu>) -> Self;
+
+    fn with_pmu_group_sampling(
+        self,
+        kinds: &[&str],
+        group: RingBufGroupBuilder) -> Self;
+
+    fn with_page_faults(self) -> Self;
+
+    fn with_minor_faults(self) -> Self;
+
+    fn with_major_faults(self) -> Self;
+
+    fn with_alignment_faults(self) -> Self;
+
+    fn with_cpu_migrations(self) -> Self;
+
+    fn with_task_clock(
+        self,
+        frequency: u64) -> Self;
+
+    fn with_branch_stacks(
+        self,
+        branch_sample_type: u64) -> Self;
+
+    fn with_memory_access_sampling(
+        self,
+        builder: RingBufBuilder<Pmu>) -> Self;
+
+    fn with_launched_process(
+        self,
+        process: LaunchedProcess) -> Self;
+
+    fn with_cgroup_labels(self) -> Self;
+
+    fn with_target_cgroup(
+        self,
+        path: &str) -> Self;
+
+    fn with_ring_buffer_watermark(
+        self,
+        bytes: u32) -> Self;
+
+    fn with_auto_ring_buffer_bytes(
+        self,
+        max_bytes: usize) -> Self;
+
+    fn with_deferred_unwinding(self) -> Self;
+
+    fn with_counter(
+        self,
+        name: &str,
+        builder: RingBufBuilder<Pmu>) -> Self;
 }
 
 impl ExportSettingsLinuxExt for ExportSettings {
@@ -596,6 +674,145 @@ impl ExportSettingsLinuxExt for ExportSettings {
         clone.os.process_fs = false;
         clone
     }
+
+    fn with_pmu_sampling(
+        self,
+        kind: &str,
+        builder: RingBufBuilder<Pmu>) -> Self {
+        let mut clone = self;
+        clone.os.pmu_events.push((kind.to_owned(), builder));
+        clone
+    }
+
+    fn with_pmu_group_sampling(
+        self,
+        kinds: &[&str],
+        group: RingBufGroupBuilder) -> Self {
+        /* Kinds are in group order, leader first */
+        let kinds = kinds
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect();
+
+        let mut clone = self;
+        clone.os.pmu_groups.push((kinds, group));
+        clone
+    }
+
+    fn with_page_faults(self) -> Self {
+        self.with_pmu_sampling(
+            "page_fault",
+            RingBufBuilder::for_page_faults())
+    }
+
+    fn with_minor_faults(self) -> Self {
+        self.with_pmu_sampling(
+            "minor_fault",
+            RingBufBuilder::for_minor_faults())
+    }
+
+    fn with_major_faults(self) -> Self {
+        self.with_pmu_sampling(
+            "major_fault",
+            RingBufBuilder::for_major_faults())
+    }
+
+    fn with_alignment_faults(self) -> Self {
+        self.with_pmu_sampling(
+            "alignment_fault",
+            RingBufBuilder::for_alignment_faults())
+    }
+
+    fn with_cpu_migrations(self) -> Self {
+        self.with_pmu_sampling(
+            "cpu_migration",
+            RingBufBuilder::for_cpu_migrations())
+    }
+
+    fn with_task_clock(
+        self,
+        frequency: u64) -> Self {
+        self.with_pmu_sampling(
+            "task_clock",
+            RingBufBuilder::for_task_clock(frequency))
+    }
+
+    fn with_branch_stacks(
+        self,
+        branch_sample_type: u64) -> Self {
+        /* Applies to CPU and PMU samples, dropped if CPU lacks LBR */
+        let mut clone = self;
+        clone.os.branch_sample_type = branch_sample_type;
+        clone
+    }
+
+    fn with_memory_access_sampling(
+        self,
+        builder: RingBufBuilder<Pmu>) -> Self {
+        let mut clone = self;
+        clone.os.memory_events.push(builder.with_memory_access_data());
+        clone
+    }
+
+    fn with_launched_process(
+        self,
+        process: LaunchedProcess) -> Self {
+        /* Started once capture is enabled, stops capture on exit */
+        let mut clone = self;
+        clone.os.launched_process = Some(process);
+        clone
+    }
+
+    fn with_cgroup_labels(self) -> Self {
+        /* Samples get cgroup path and container ID attributes */
+        let mut clone = self;
+        clone.os.cgroup_labels = true;
+        clone
+    }
+
+    fn with_target_cgroup(
+        self,
+        path: &str) -> Self {
+        /* Relative paths are from the cgroup v2 mount */
+        let mut clone = self.with_cgroup_labels();
+        clone.os.target_cgroup = Some(path.to_owned());
+        clone
+    }
+
+    fn with_ring_buffer_watermark(
+        self,
+        bytes: u32) -> Self {
+        /* Reads wake up once a CPU buffer holds this many bytes */
+        let mut clone = self;
+        clone.os.wakeup_watermark = bytes;
+        clone
+    }
+
+    fn with_auto_ring_buffer_bytes(
+        self,
+        max_bytes: usize) -> Self {
+        /* CPU buffers that lose data grow, up to max_bytes each */
+        let mut clone = self;
+        clone.os.max_cpu_buf_bytes = max_bytes;
+        clone
+    }
+
+    fn with_deferred_unwinding(self) -> Self {
+        /* Raw user stacks are kept, see ExportMachine::unwind_user_stacks() */
+        let mut clone = self;
+        clone.os.deferred_unwinding = true;
+        clone
+    }
+
+    fn with_counter(
+        self,
+        name: &str,
+        builder: RingBufBuilder<Pmu>) -> Self {
+        /* Counted for the whole capture, see ExportMachine::counter_totals() */
+        let mut clone = self;
+        clone.os.counters.push((name.to_owned(), builder));
+        clone
+    }
 }
 
 pub(crate) struct OSExportSampler {
@@ -671,6 +888,32 @@ pub(crate) struct OSExportMachine {
     cswitches: HashMap<u32, ExportCSwitch>,
     dev_nodes: ExportDevNodeLookup,
     path_buf: Writable<PathBuf>,
+    cgroups: HashMap<u64, usize>,
+}
+
+/*
+ * Container runtimes name the cgroup after the 64 hex char container
+ * ID, IE: docker-<id>.scope, cri-containerd-<id>.scope or /docker/<id>.
+ */
+fn cgroup_container_id(path: &str) -> Option<&str> {
+    const PREFIXES: [&str; 5] = ["docker-", "cri-containerd-", "crio-", "libpod-", "containerd-"];
+
+    for name in path.rsplit('/') {
+        let mut id = name.strip_suffix(".scope").unwrap_or(name);
+
+        for prefix in PREFIXES {
+            if let Some(stripped) = id.strip_prefix(prefix) {
+                id = stripped;
+                break;
+            }
+        }
+
+        if id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Some(id);
+        }
+    }
+
+    None
 }
 
 impl OSExportMachine {
@@ -679,6 +922,55 @@ impl OSExportMachine {
             cswitches: HashMap::new(),
             dev_nodes: ExportDevNodeLookup::new(),
             path_buf: Writable::new(PathBuf::new()),
+            cgroups: HashMap::new(),
+        }
+    }
+
+    fn cgroup_attributes(
+        &self,
+        id: u64) -> usize {
+        self.cgroups.get(&id).copied().unwrap_or(0)
+    }
+
+    fn add_cgroup(
+        machine: &mut ExportMachine,
+        id: u64,
+        path: &str) {
+        let mut attributes = ExportAttributes::default();
+
+        attributes.push(machine.label_attribute("cgroup", path));
+
+        if let Some(container_id) = cgroup_container_id(path) {
+            attributes.push(machine.label_attribute("container.id", container_id));
+        }
+
+        let attributes_id = machine.push_unique_attributes(attributes);
+
+        machine.os.cgroups.insert(id, attributes_id);
+    }
+
+    fn capture_cgroups(
+        machine: &mut ExportMachine,
+        root: &Path,
+        dir: &Path) {
+        use std::os::unix::fs::MetadataExt;
+
+        /* Cgroup IDs are the inode numbers within cgroupfs */
+        if let Ok(metadata) = std::fs::metadata(dir) {
+            let path = match dir.strip_prefix(root) {
+                Ok(path) => { format!("/{}", path.display()) },
+                Err(_) => { return; },
+            };
+
+            Self::add_cgroup(machine, metadata.ino(), &path);
+        }
+
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    Self::capture_cgroups(machine, root, &entry.path());
+                }
+            }
         }
     }
 
@@ -697,7 +989,15 @@ impl OSExportMachine {
         session: &mut PerfSession) -> anyhow::Result<Writable<ExportMachine>> {
         let cpu_profiling = machine.settings.cpu_profiling;
         let cswitches = machine.settings.cswitches;
+        let sched_switches = machine.settings.sched_switches;
         let events = machine.settings.events.take();
+        let pmu_events = std::mem::take(&mut machine.settings.os.pmu_events);
+        let pmu_groups = std::mem::take(&mut machine.settings.os.pmu_groups);
+        let branch_stacks = machine.settings.os.branch_sample_type != 0;
+        let memory_events = std::mem::take(&mut machine.settings.os.memory_events);
+        let memory_access = !memory_events.is_empty();
+        let cgroup_labels = machine.settings.os.cgroup_labels;
+        let deferred_unwinding = machine.settings.os.deferred_unwinding;
 
         let callstack_reader = match machine.settings.callstack_helper.take() {
             Some(callstack_helper) => { callstack_helper.to_reader() },
@@ -712,12 +1012,38 @@ impl OSExportMachine {
 
         let callstack_reader = callstack_reader.with_unwind(
             move |request| {
-                let machine = callstack_machine.borrow_mut();
+                let mut machine = callstack_machine.borrow_mut();
+
+                if deferred_unwinding {
+                    /* Deferred stacks are matched to samples by time */
+                    if let Some(time) = request.time() {
+                        /* Keep the raw user registers and stack for later */
+                        request.push_user_ip();
+
+                        let stack = ExportUserStack::from_regs(
+                            request.rip(),
+                            request.rbp(),
+                            request.rsp(),
+                            request.link_register());
+
+                        machine.user_stacks.defer(
+                            request.pid(),
+                            time,
+                            stack,
+                            request.stack_data());
+
+                        return;
+                    }
+
+                    /* Without sample times, fall back to unwinding now */
+                }
 
                 if let Some(process) = machine.find_process(request.pid()) {
-                    request.unwind_process(
+                    let result = request.unwind_process(
                         process,
                         &machine.os.dev_nodes);
+
+                    machine.unwind_stats.add(&result);
                 }
             });
 
@@ -796,9 +1122,15 @@ impl OSExportMachine {
             let tid_field = session.tid_data_ref();
             let reader = callstack_reader.clone();
 
+            let branch_field = session.branch_stack_data_ref();
+            let cgroup_field = session.cgroup_data_ref();
+
             /* Get sample kind for CPU */
             let kind = machine.borrow_mut().sample_kind("cpu");
 
+            let branch_record_type = machine.borrow_mut().record_type(
+                ExportRecordType::for_branch_stack(kind));
+
             /* Hook cpu profile event */
             let event = session.cpu_profile_event();
             let event_machine = machine.clone();
@@ -820,14 +1152,258 @@ impl OSExportMachine {
                     full_data,
                     &mut frames);
 
-                event_machine.borrow_mut().add_sample(
+                let branches = branch_field.get_data(full_data);
+
+                let mut machine = event_machine.borrow_mut();
+
+                let mut sample = machine.make_sample(
                     time,
                     MetricValue::Count(1),
+                    tid,
+                    cpu,
+                    kind,
+                    &frames);
+
+                if let Some(cgroup) = cgroup_field.try_get_u64(full_data) {
+                    sample.attach_attributes(machine.os.cgroup_attributes(cgroup));
+                }
+
+                if branch_stacks && !branches.is_empty() {
+                    return machine.add_custom_sample_with_record(
+                        pid,
+                        sample,
+                        branch_record_type,
+                        branches);
+                }
+
+                machine.add_custom_sample(
                     pid,
+                    sample)
+            });
+        }
+
+        for (kind, pmu) in pmu_events {
+            let ancillary = session.ancillary_data();
+            let time_field = session.time_data_ref();
+            let pid_field = session.pid_field_ref();
+            let tid_field = session.tid_data_ref();
+            let period_field = session.period_data_ref();
+            let branch_field = session.branch_stack_data_ref();
+            let cgroup_field = session.cgroup_data_ref();
+            let reader = callstack_reader.clone();
+
+            /* Get sample kind for PMU event */
+            let kind = machine.borrow_mut().sample_kind(&kind);
+
+            let branch_record_type = machine.borrow_mut().record_type(
+                ExportRecordType::for_branch_stack(kind));
+
+            /* Hook PMU profile event */
+            let event = session.pmu_profile_event(
+                pmu.event_type(),
+                pmu.config(),
+                PmuEventKind::Sample);
+
+            let event_machine = machine.clone();
+            let mut frames: Vec<u64> = Vec::new();
+
+            event.add_callback(move |data| {
+                let full_data = data.full_data();
+
+                let ancillary = ancillary.borrow();
+
+                let cpu = ancillary.cpu() as u16;
+                let time = time_field.get_u64(full_data)?;
+                let pid = pid_field.get_u32(full_data)?;
+                let tid = tid_field.get_u32(full_data)?;
+
+                /* Each sample represents period events */
+                let period = period_field.try_get_u64(full_data).unwrap_or(1);
+
+                frames.clear();
+
+                reader.read_frames(
+                    full_data,
+                    &mut frames);
+
+                let branches = branch_field.get_data(full_data);
+
+                let mut machine = event_machine.borrow_mut();
+
+                let mut sample = machine.make_sample(
+                    time,
+                    MetricValue::Count(period),
                     tid,
                     cpu,
                     kind,
-                    &frames)
+                    &frames);
+
+                if let Some(cgroup) = cgroup_field.try_get_u64(full_data) {
+                    sample.attach_attributes(machine.os.cgroup_attributes(cgroup));
+                }
+
+                if branch_stacks && !branches.is_empty() {
+                    return machine.add_custom_sample_with_record(
+                        pid,
+                        sample,
+                        branch_record_type,
+                        branches);
+                }
+
+                machine.add_custom_sample(
+                    pid,
+                    sample)
+            });
+        }
+
+        for pmu in memory_events {
+            let ancillary = session.ancillary_data();
+            let time_field = session.time_data_ref();
+            let pid_field = session.pid_field_ref();
+            let tid_field = session.tid_data_ref();
+            let period_field = session.period_data_ref();
+            let address_field = session.address_data_ref();
+            let weight_field = session.weight_data_ref();
+            let data_src_field = session.data_src_data_ref();
+            let cgroup_field = session.cgroup_data_ref();
+            let reader = callstack_reader.clone();
+
+            /* Get sample kind and record type for memory access */
+            let kind = machine.borrow_mut().sample_kind("memory_access");
+
+            let record_type = machine.borrow_mut().record_type(
+                ExportRecordType::for_memory_access(kind));
+
+            /* Hook memory access profile event */
+            let event = session.pmu_profile_event(
+                pmu.event_type(),
+                pmu.config(),
+                PmuEventKind::Memory);
+
+            let event_machine = machine.clone();
+            let mut frames: Vec<u64> = Vec::new();
+            let mut record: Vec<u8> = Vec::new();
+
+            event.add_callback(move |data| {
+                let full_data = data.full_data();
+
+                let ancillary = ancillary.borrow();
+
+                let cpu = ancillary.cpu() as u16;
+                let time = time_field.get_u64(full_data)?;
+                let pid = pid_field.get_u32(full_data)?;
+                let tid = tid_field.get_u32(full_data)?;
+                let period = period_field.try_get_u64(full_data).unwrap_or(1);
+                let address = address_field.get_u64(full_data)?;
+                let weight = weight_field.try_get_u64(full_data).unwrap_or(0);
+                let data_src = data_src_field.try_get_u64(full_data).unwrap_or(0);
+
+                record.clear();
+                record.extend_from_slice(&address.to_ne_bytes());
+                record.extend_from_slice(&weight.to_ne_bytes());
+                record.extend_from_slice(&data_src.to_ne_bytes());
+
+                frames.clear();
+
+                reader.read_frames(
+                    full_data,
+                    &mut frames);
+
+                let mut machine = event_machine.borrow_mut();
+
+                let mut sample = machine.make_sample(
+                    time,
+                    MetricValue::Count(period),
+                    tid,
+                    cpu,
+                    kind,
+                    &frames);
+
+                if let Some(cgroup) = cgroup_field.try_get_u64(full_data) {
+                    sample.attach_attributes(machine.os.cgroup_attributes(cgroup));
+                }
+
+                machine.add_custom_sample_with_record(
+                    pid,
+                    sample,
+                    record_type,
+                    &record)
+            });
+        }
+
+        for (kinds, group) in pmu_groups {
+            let ancillary = session.ancillary_data();
+            let time_field = session.time_data_ref();
+            let pid_field = session.pid_field_ref();
+            let tid_field = session.tid_data_ref();
+            let read_field = session.read_data_ref();
+            let reader = callstack_reader.clone();
+
+            /* Get sample kinds for each counter in the group */
+            let kinds: Vec<u16> = kinds
+                .iter()
+                .map(|kind| machine.borrow_mut().sample_kind(kind))
+                .collect();
+
+            /* Hook group leader profile event */
+            let leader = group.leader();
+
+            let event = session.pmu_profile_event(
+                leader.event_type(),
+                leader.config(),
+                PmuEventKind::Group);
+
+            let event_machine = machine.clone();
+            let mut decoder = GroupReadDecoder::new();
+            let mut deltas: Vec<u64> = Vec::new();
+            let mut frames: Vec<u64> = Vec::new();
+
+            event.add_callback(move |data| {
+                let full_data = data.full_data();
+
+                let ancillary = ancillary.borrow();
+
+                let cpu = ancillary.cpu() as u16;
+                let time = time_field.get_u64(full_data)?;
+                let pid = pid_field.get_u32(full_data)?;
+                let tid = tid_field.get_u32(full_data)?;
+
+                decoder.decode(
+                    ancillary.read_format(),
+                    read_field.get_data(full_data),
+                    &mut deltas)?;
+
+                frames.clear();
+
+                reader.read_frames(
+                    full_data,
+                    &mut frames);
+
+                let mut machine = event_machine.borrow_mut();
+
+                /*
+                 * The read should hold one value per configured counter.
+                 * If it doesn't, we cannot tell which delta belongs to
+                 * which kind, so drop the sample and count it as lost.
+                 */
+                if deltas.len() != kinds.len() {
+                    machine.add_lost_samples(cpu, 1);
+                    return Ok(());
+                }
+
+                /* Each counter delta is a sample on the same callstack */
+                for (kind, delta) in kinds.iter().zip(deltas.iter()) {
+                    machine.add_sample(
+                        time,
+                        MetricValue::Count(*delta),
+                        pid,
+                        tid,
+                        cpu,
+                        *kind,
+                        &frames)?;
+                }
+
+                Ok(())
             });
         }
 
@@ -869,7 +1445,7 @@ impl OSExportMachine {
 
                 let mut machine = event_machine.borrow_mut();
 
-                let sample = machine.make_sample(
+                let mut sample = machine.make_sample(
                     time,
                     MetricValue::Duration(0),
                     tid,
@@ -877,12 +1453,18 @@ impl OSExportMachine {
                     kind,
                     &frames);
 
+                /* Other records arrive before switch-in, attach now */
+                if let Some(user_stack) = machine.user_stacks.take_pending(pid, time) {
+                    machine.user_stacks.attach(user_stack, &mut sample);
+                }
+
                 /* Stash away the sample until switch-in */
                 machine.os.cswitches.entry(tid).or_default().sample = Some(sample);
 
                 Ok(())
             });
 
+            let ancillary = session.ancillary_data();
             let misc_field = session.misc_data_ref();
             let time_field = session.time_data_ref();
             let pid_field = session.pid_field_ref();
@@ -891,22 +1473,63 @@ impl OSExportMachine {
             /* Hook cswitch swap event */
             let event = session.cswitch_event();
             let event_machine = machine.clone();
+            let fmt = event.format();
+            let next_prev_pid = fmt.get_field_ref_unchecked("next_prev_pid");
+            let next_prev_tid = fmt.get_field_ref_unchecked("next_prev_tid");
 
             event.add_callback(move |data| {
+                let fmt = data.format();
                 let full_data = data.full_data();
+                let data = data.event_data();
 
                 let misc = misc_field.get_u16(full_data)?;
                 let time = time_field.get_u64(full_data)?;
                 let pid = pid_field.get_u32(full_data)?;
                 let tid = tid_field.get_u32(full_data)?;
 
+                let mut machine = event_machine.borrow_mut();
+
+                /*
+                 * CPU wide switch records carry the other side of the
+                 * switch. Each switch is seen from both sides, so only
+                 * keep switch outs, and switch ins from idle since idle
+                 * is not reported as switching out.
+                 */
+                if sched_switches {
+                    let other_pid = fmt.get_u32(next_prev_pid, data)?;
+                    let other_tid = fmt.get_u32(next_prev_tid, data)?;
+                    let cpu = ancillary.borrow().cpu() as u16;
+
+                    if misc & PERF_RECORD_MISC_SWITCH_OUT != 0 {
+                        if tid != 0 {
+                            machine.add_sched_switch(
+                                ExportSchedSwitch::new(
+                                    time,
+                                    cpu,
+                                    pid,
+                                    tid,
+                                    other_pid,
+                                    other_tid,
+                                    misc & PERF_RECORD_MISC_SWITCH_OUT_PREEMPT != 0));
+                        }
+                    } else if other_tid == 0 && tid != 0 {
+                        machine.add_sched_switch(
+                            ExportSchedSwitch::new(
+                                time,
+                                cpu,
+                                0,
+                                0,
+                                pid,
+                                tid,
+                                false));
+                    }
+                }
+
                 /* Ignore scheduler switches */
                 if pid == 0 || tid == 0 {
                     return Ok(());
                 }
 
-                let mut machine = event_machine.borrow_mut();
-
                 match machine.os.cswitches.entry(tid) {
                     Occupied(mut entry) => {
                         let entry = entry.get_mut();
@@ -978,8 +1601,18 @@ impl OSExportMachine {
 
             let prot = fmt.get_u32(prot, data)?;
 
-            /* Skip non-executable mmaps */
+            /* Non-executable mmaps only resolve data addresses */
             if prot & PROT_EXEC != PROT_EXEC {
+                if memory_access {
+                    return event_machine.borrow_mut().add_mmap_data(
+                        time_field.get_u64(full_data)?,
+                        fmt.get_u32(pid, data)?,
+                        fmt.get_u64(addr, data)?,
+                        fmt.get_u64(len, data)?,
+                        fmt.get_u64(pgoffset, data)?,
+                        fmt.get_str(filename, data)?);
+                }
+
                 return Ok(());
             }
 
@@ -1066,6 +1699,71 @@ impl OSExportMachine {
                 fmt.get_u32(ppid, data)?)
         });
 
+        /* Hook lost records, the ring buffer was full */
+        let ancillary = session.ancillary_data();
+        let event = session.lost_event();
+        let event_machine = machine.clone();
+        let fmt = event.format();
+        let lost = fmt.get_field_ref_unchecked("lost");
+
+        event.add_callback(move |data| {
+            let fmt = data.format();
+            let data = data.event_data();
+
+            event_machine.borrow_mut().add_lost_samples(
+                ancillary.borrow().cpu() as u16,
+                fmt.get_u64(lost, data)?);
+
+            Ok(())
+        });
+
+        /* Hook lost samples, dropped before reaching the ring buffer */
+        let ancillary = session.ancillary_data();
+        let event = session.lost_samples_event();
+        let event_machine = machine.clone();
+        let fmt = event.format();
+        let lost = fmt.get_field_ref_unchecked("lost");
+
+        event.add_callback(move |data| {
+            let fmt = data.format();
+            let data = data.event_data();
+
+            event_machine.borrow_mut().add_lost_samples(
+                ancillary.borrow().cpu() as u16,
+                fmt.get_u64(lost, data)?);
+
+            Ok(())
+        });
+
+        if cgroup_labels {
+            /* Existing cgroups, new ones come from cgroup records */
+            let root = procfs::cgroup2_mount();
+
+            Self::capture_cgroups(
+                &mut machine.borrow_mut(),
+                &root,
+                &root);
+
+            /* Hook cgroup records */
+            let event = session.cgroup_event();
+            let event_machine = machine.clone();
+            let fmt = event.format();
+            let id = fmt.get_field_ref_unchecked("id");
+            let path = fmt.get_field_ref_unchecked("path[]");
+
+            event.add_callback(move |data| {
+                let fmt = data.format();
+                let data = data.event_data();
+
+                Self::add_cgroup(
+                    &mut event_machine.borrow_mut(),
+                    fmt.get_u64(id, data)?,
+                    fmt.get_str(path, data)?);
+
+                Ok(())
+            });
+        }
+
         Ok(machine)
     }
 
@@ -1363,6 +2061,20 @@ impl ExportMachineOSHooks for ExportMachine {
         OSExportMachine::resolve_perf_map_symbols(self);
     }
 
+    fn os_unwind_user_stacks(&mut self) -> usize {
+        /* Modules are opened via the dev nodes seen during capture */
+        let dev_nodes = std::mem::replace(
+            &mut self.os.dev_nodes,
+            ExportDevNodeLookup::new());
+
+        let mut unwinder = ruwind::default_unwinder();
+        let unwound = self.unwind_user_stacks_with(&mut unwinder, &dev_nodes);
+
+        self.os.dev_nodes = dev_nodes;
+
+        unwound
+    }
+
     fn os_qpc_time() -> u64 {
         let mut t = libc::timespec {
             tv_sec: 0,
@@ -1411,8 +2123,19 @@ impl ExportBuilderHelp for RingBufSessionBuilder {
             .with_comm_records()
             .with_task_records();
 
+        let branch_sample_type = settings.os.branch_sample_type;
+        let cgroup_labels = settings.os.cgroup_labels;
+
         if settings.cpu_profiling {
-            let profiling = RingBufBuilder::for_profiling(settings.cpu_freq);
+            let mut profiling = RingBufBuilder::for_profiling(settings.cpu_freq);
+
+            if branch_sample_type != 0 {
+                profiling = profiling.with_branch_stack_data(branch_sample_type);
+            }
+
+            if cgroup_labels {
+                profiling = profiling.with_cgroup_data();
+            }
 
             builder = builder.with_profiling_events(profiling);
         }
@@ -1424,6 +2147,42 @@ impl ExportBuilderHelp for RingBufSessionBuilder {
             kernel = kernel.with_cswitch_records();
         }
 
+        for (_, pmu) in &settings.os.pmu_events {
+            let mut pmu = pmu.clone_options();
+
+            if branch_sample_type != 0 {
+                pmu = pmu.with_branch_stack_data(branch_sample_type);
+            }
+
+            if cgroup_labels {
+                pmu = pmu.with_cgroup_data();
+            }
+
+            builder = builder.with_pmu_events(pmu);
+        }
+
+        for (_, group) in &settings.os.pmu_groups {
+            builder = builder.with_pmu_group(group.clone_options());
+        }
+
+        for pmu in &settings.os.memory_events {
+            let mut pmu = pmu.clone_options();
+
+            if cgroup_labels {
+                pmu = pmu.with_cgroup_data();
+            }
+
+            builder = builder.with_pmu_events(pmu);
+        }
+
+        if !settings.os.memory_events.is_empty() {
+            kernel = kernel.with_mmap_data_records();
+        }
+
+        if cgroup_labels {
+            kernel = kernel.with_cgroup_records();
+        }
+
         if settings.events.is_some() {
             let tracepoint = RingBufBuilder::for_tracepoint();
 
@@ -1457,14 +2216,20 @@ impl UniversalExporterOSHooks for UniversalExporter {
         mut self,
         _name: &str,
         until: impl Fn() -> bool + Send + 'static) -> anyhow::Result<Writable<ExportMachine>> {
-        let settings = self.settings()?;
+        let mut settings = self.settings()?;
 
         let page_size = unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) as usize };
 
         let page_count = self.cpu_buf_bytes() / page_size;
 
+        let launched_process = settings.os.launched_process.take();
+
+        let max_page_count = settings.os.max_cpu_buf_bytes / page_size;
+
         let mut builder = RingBufSessionBuilder::new()
             .with_page_count(page_count)
+            .with_wakeup_watermark(settings.os.wakeup_watermark)
+            .with_auto_page_count(max_page_count)
             .with_exporter_events(&settings);
 
         if let Some(target_pids) = &settings.target_pids {
@@ -1473,6 +2238,37 @@ impl UniversalExporterOSHooks for UniversalExporter {
             }
         }
 
+        if let Some(process) = &launched_process {
+            builder = builder.with_launched_process(process);
+        }
+
+        if let Some(path) = &settings.os.target_cgroup {
+            builder = builder.with_target_cgroup(path);
+        }
+
+        /* Counters follow the same targets as the session */
+        if !settings.os.counters.is_empty() && settings.os.target_cgroup.is_some() {
+            anyhow::bail!("Counters cannot be used with a target cgroup.");
+        }
+
+        let mut counters = PerfCountersBuilder::new();
+
+        if let Some(target_pids) = &settings.target_pids {
+            for pid in target_pids {
+                counters = counters.with_target_pid(*pid);
+            }
+        }
+
+        if let Some(process) = &launched_process {
+            counters = counters.with_target_pid(process.pid());
+        }
+
+        for (name, counter) in settings.os.counters.drain(..) {
+            counters = counters.with_counter(&name, counter);
+        }
+
+        let mut counters = counters.build()?;
+
         let mut builder = self.run_build_hooks(builder)?;
 
         let mut session = builder.build()?;
@@ -1481,14 +2277,77 @@ impl UniversalExporterOSHooks for UniversalExporter {
 
         self.run_export_hooks(&exporter)?;
 
-        session.capture_environment();
+        /* Launched processes have no environment until exec */
+        if launched_process.is_none() {
+            session.capture_environment();
+        }
 
         exporter.borrow_mut().mark_start();
         session.enable()?;
-        session.parse_until(until)?;
-        session.disable()?;
+        counters.enable()?;
+
+        match launched_process {
+            Some(mut process) => {
+                if let Err(e) = process.start() {
+                    /* Exec failed, reap the child before bailing */
+                    let _ = process.wait();
+                    return Err(e.into());
+                }
+
+                /* Stop once the process exits or when asked */
+                let exited = Arc::new(AtomicBool::new(false));
+                let thread_exited = exited.clone();
+                let parse_exited = exited.clone();
+                let wait_exit = process.exit_waiter();
+
+                let waiter = std::thread::spawn(move || {
+                    let result = wait_exit();
+
+                    thread_exited.store(true, Ordering::SeqCst);
+
+                    result
+                });
+
+                let parsed = session.parse_until(move || {
+                    parse_exited.load(Ordering::SeqCst) || until()
+                });
+
+                let exited = exited.load(Ordering::SeqCst);
+
+                /* Stopped early, don't leave the process running */
+                if exited {
+                    process.wait()?;
+                } else {
+                    process.kill()?;
+                }
+
+                let _ = waiter.join();
+
+                parsed?;
+
+                session.disable()?;
+                counters.disable()?;
+
+                /* Drain anything written before the exit */
+                if exited {
+                    session.parse_all()?;
+                }
+            },
+            None => {
+                session.parse_until(until)?;
+                session.disable()?;
+                counters.disable()?;
+            },
+        }
+
         exporter.borrow_mut().mark_end();
 
+        for value in counters.read()? {
+            exporter.borrow_mut().add_counter_total(
+                value.name(),
+                value.value());
+        }
+
         self.run_parsed_hooks(&exporter)?;
 
         Ok(exporter)
@@ -1666,4 +2525,228 @@ mod tests {
 
         assert!(count > 0);
     }
+
+    #[test]
+    fn pmu_sampling_settings() {
+        let cycles = RingBufBuilder::for_hardware(
+            crate::perf_event::abi::PERF_COUNT_HW_CPU_CYCLES);
+
+        let settings = ExportSettings::new(CallstackHelper::new())
+            .with_pmu_sampling("cycles", cycles);
+
+        assert_eq!(1, settings.os.pmu_events.len());
+        assert_eq!("cycles", settings.os.pmu_events[0].0);
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        /* Callstack help is applied during build() via hooks */
+        let pmu = builder.take_pmu_events().unwrap();
+
+        assert_eq!(1, pmu.len());
+        assert_eq!(crate::perf_event::abi::PERF_COUNT_HW_CPU_CYCLES, pmu[0].config());
+
+        let group = RingBufBuilder::for_pmu_group(
+            RingBufBuilder::for_hardware(
+                crate::perf_event::abi::PERF_COUNT_HW_CPU_CYCLES))
+            .with_member(RingBufBuilder::for_hardware(
+                crate::perf_event::abi::PERF_COUNT_HW_INSTRUCTIONS));
+
+        let settings = settings.with_pmu_group_sampling(
+            &["cycles", "instructions"],
+            group);
+
+        assert_eq!(vec!["cycles", "instructions"], settings.os.pmu_groups[0].0);
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        let groups = builder.take_pmu_groups().unwrap();
+
+        assert_eq!(1, groups.len());
+        assert_eq!(1, groups[0].members().len());
+    }
+
+    #[test]
+    fn software_sampling_settings() {
+        let settings = ExportSettings::new(CallstackHelper::new())
+            .with_page_faults()
+            .with_cpu_migrations()
+            .with_task_clock(100)
+            .with_alignment_faults();
+
+        let kinds: Vec<&str> = settings.os.pmu_events
+            .iter()
+            .map(|(kind, _)| kind.as_str())
+            .collect();
+
+        assert_eq!(vec!["page_fault", "cpu_migration", "task_clock", "alignment_fault"], kinds);
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        let pmu = builder.take_pmu_events().unwrap();
+
+        assert_eq!(4, pmu.len());
+        assert_eq!(crate::perf_event::abi::PERF_COUNT_SW_PAGE_FAULTS, pmu[0].config());
+        assert_eq!(crate::perf_event::abi::PERF_COUNT_SW_CPU_MIGRATIONS, pmu[1].config());
+        assert_eq!(crate::perf_event::abi::PERF_COUNT_SW_TASK_CLOCK, pmu[2].config());
+        assert_eq!(crate::perf_event::abi::PERF_COUNT_SW_ALIGNMENT_FAULTS, pmu[3].config());
+    }
+
+    #[test]
+    fn branch_stack_settings() {
+        let settings = ExportSettings::new(CallstackHelper::new())
+            .with_cpu_profiling(1000)
+            .with_page_faults();
+
+        /* Branch stacks are off by default */
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        assert!(!builder.take_profiling_events().unwrap().build().has_branch_stack());
+        assert!(!builder.take_pmu_events().unwrap()[0].build().has_branch_stack());
+
+        let settings = settings.with_branch_stacks(
+            crate::perf_event::abi::PERF_SAMPLE_BRANCH_USER |
+            crate::perf_event::abi::PERF_SAMPLE_BRANCH_ANY);
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        assert!(builder.take_profiling_events().unwrap().build().has_branch_stack());
+        assert!(builder.take_pmu_events().unwrap()[0].build().has_branch_stack());
+    }
+
+    #[test]
+    fn memory_access_settings() {
+        let loads = RingBufBuilder::for_pmu(
+            crate::perf_event::abi::PERF_TYPE_RAW,
+            0x1cd);
+
+        let settings = ExportSettings::new(CallstackHelper::new())
+            .with_memory_access_sampling(loads);
+
+        assert_eq!(1, settings.os.memory_events.len());
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        let pmu = builder.take_pmu_events().unwrap();
+
+        assert_eq!(1, pmu.len());
+
+        let common = pmu[0].build();
+        let sample_type = common.for_cpu(0).ancillary().sample_type();
+
+        assert!(sample_type & crate::perf_event::abi::PERF_SAMPLE_ADDR != 0);
+        assert!(sample_type & crate::perf_event::abi::PERF_SAMPLE_WEIGHT != 0);
+        assert!(sample_type & crate::perf_event::abi::PERF_SAMPLE_DATA_SRC != 0);
+    }
+
+    #[test]
+    fn counter_settings() {
+        let settings = ExportSettings::new(CallstackHelper::new())
+            .with_counter(
+                "cycles",
+                RingBufBuilder::for_hardware(crate::perf_event::abi::PERF_COUNT_HW_CPU_CYCLES));
+
+        assert_eq!(1, settings.os.counters.len());
+        assert_eq!("cycles", settings.os.counters[0].0);
+
+        /* Counters are kept out of the sampling session */
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        assert!(builder.take_pmu_events().is_none());
+    }
+
+    #[test]
+    fn ring_buffer_settings() {
+        let settings = ExportSettings::new(CallstackHelper::new());
+
+        /* Default wakeups and fixed sizes */
+        assert_eq!(0, settings.os.wakeup_watermark);
+        assert_eq!(0, settings.os.max_cpu_buf_bytes);
+
+        let settings = settings
+            .with_ring_buffer_watermark(16 * 1024)
+            .with_auto_ring_buffer_bytes(8 * 1024 * 1024);
+
+        assert_eq!(16 * 1024, settings.os.wakeup_watermark);
+        assert_eq!(8 * 1024 * 1024, settings.os.max_cpu_buf_bytes);
+    }
+
+    #[test]
+    fn cgroup_settings() {
+        let cgroup = crate::perf_event::abi::PERF_SAMPLE_CGROUP;
+
+        let settings = ExportSettings::new(CallstackHelper::new())
+            .with_cpu_profiling(1000)
+            .with_page_faults();
+
+        /* Cgroup labels are off by default */
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        let common = builder.take_profiling_events().unwrap().build();
+        assert!(common.for_cpu(0).ancillary().sample_type() & cgroup == 0);
+
+        let settings = settings.with_target_cgroup("/system.slice/test.service");
+
+        assert!(settings.os.cgroup_labels);
+        assert_eq!(Some("/system.slice/test.service"), settings.os.target_cgroup.as_deref());
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_exporter_events(&settings);
+
+        let common = builder.take_profiling_events().unwrap().build();
+        assert!(common.for_cpu(0).ancillary().sample_type() & cgroup != 0);
+
+        let common = builder.take_pmu_events().unwrap()[0].build();
+        assert!(common.for_cpu(0).ancillary().sample_type() & cgroup != 0);
+    }
+
+    #[test]
+    fn cgroup_labels() {
+        let id = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+        /* Common container runtime layouts */
+        assert_eq!(Some(id), cgroup_container_id(&format!("/system.slice/docker-{}.scope", id)));
+        assert_eq!(Some(id), cgroup_container_id(&format!("/docker/{}", id)));
+        assert_eq!(
+            Some(id),
+            cgroup_container_id(&format!(
+                "/kubepods.slice/kubepods-pod1.slice/cri-containerd-{}.scope", id)));
+
+        /* Non-container cgroups */
+        assert_eq!(None, cgroup_container_id("/system.slice/sshd.service"));
+        assert_eq!(None, cgroup_container_id("/"));
+
+        /* Labels are attached by cgroup ID */
+        let mut machine = ExportMachine::new(ExportSettings::new(CallstackHelper::new()));
+
+        OSExportMachine::add_cgroup(
+            &mut machine,
+            1234,
+            &format!("/docker/{}", id));
+
+        let attributes_id = machine.os.cgroup_attributes(1234);
+
+        assert_ne!(0, attributes_id);
+        assert_eq!(0, machine.os.cgroup_attributes(5678));
+
+        let mut sample = machine.make_sample(0, MetricValue::Count(1), 1, 0, 0, &[]);
+        sample.attach_attributes(attributes_id);
+
+        let mut walker = ExportAttributeWalker::default();
+        machine.sample_attributes(&sample, &mut walker);
+
+        let attributes = walker.attributes();
+
+        assert_eq!(2, attributes.len());
+        assert_eq!(Some("cgroup"), attributes[0].name_str(machine.strings()));
+        assert_eq!(Some("container.id"), attributes[1].name_str(machine.strings()));
+        assert_eq!(Some(id), attributes[1].label_str(machine.strings()));
+    }
 }
@@ -845,6 +845,11 @@ impl ExportMachineOSHooks for ExportMachine {
         /* TODO */
     }
 
+    fn os_unwind_user_stacks(&mut self) -> usize {
+        /* User stacks are not deferred on Windows */
+        0
+    }
+
     fn os_add_mmap_exec(
         &mut self,
         _pid: u32,
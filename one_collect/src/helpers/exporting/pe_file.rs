@@ -1,14 +1,16 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::fs::File;
 use std::mem::{zeroed, size_of};
 use std::string::{FromUtf8Error, FromUtf16Error};
 use std::slice;
 
 use crate::intern::InternedStrings;
+use super::snapshot::{SnapshotRead, SnapshotWrite};
 
+#[derive(Clone)]
 pub struct PEModuleMetadata {
     machine: u16,
     date_time: u32,
@@ -125,6 +127,49 @@ impl PEModuleMetadata {
         Ok(())
     }
 
+    pub(crate) fn write_snapshot(
+        &self,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_u16(self.machine)?;
+        writer.write_u32(self.date_time)?;
+        writer.write_u64(self.symbol_name_id as u64)?;
+        writer.write_u32(self.symbol_age)?;
+        writer.write_all(&self.symbol_sig)?;
+        writer.write_u64(self.version_name_id as u64)?;
+        writer.write_all(&self.perfmap_sig)?;
+        writer.write_u32(self.perfmap_version)?;
+        writer.write_u64(self.perfmap_name_id as u64)?;
+        writer.write_u64(self.text_loaded_layout_offset)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn read_snapshot(
+        reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut metadata = Self::new();
+
+        metadata.machine = SnapshotRead::read_u16(reader)?;
+        metadata.date_time = SnapshotRead::read_u32(reader)?;
+        metadata.symbol_name_id = reader.read_u64()? as usize;
+        metadata.symbol_age = SnapshotRead::read_u32(reader)?;
+        reader.read_exact(&mut metadata.symbol_sig)?;
+        metadata.version_name_id = reader.read_u64()? as usize;
+        reader.read_exact(&mut metadata.perfmap_sig)?;
+        metadata.perfmap_version = SnapshotRead::read_u32(reader)?;
+        metadata.perfmap_name_id = reader.read_u64()? as usize;
+        metadata.text_loaded_layout_offset = reader.read_u64()?;
+
+        Ok(metadata)
+    }
+
+    pub(crate) fn remap_strings(
+        &mut self,
+        remap: impl Fn(usize) -> usize) {
+        self.symbol_name_id = remap(self.symbol_name_id);
+        self.version_name_id = remap(self.version_name_id);
+        self.perfmap_name_id = remap(self.perfmap_name_id);
+    }
+
     pub fn reset(&mut self) {
         self.machine = 0;
         self.date_time = 0;
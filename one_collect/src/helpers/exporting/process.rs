@@ -331,6 +331,7 @@ pub struct ExportProcessSample {
     callstack_id: u32,
     record_id: u32,
     attributes_id: u32,
+    user_stack_id: u32,
 }
 
 impl ExportProcessSample {
@@ -354,6 +355,7 @@ impl ExportProcessSample {
             callstack_id,
             record_id: 0,
             attributes_id: 0,
+            user_stack_id: 0,
         }
     }
 
@@ -375,6 +377,12 @@ impl ExportProcessSample {
 
     pub fn callstack_id(&self) -> usize { self.callstack_id as usize }
 
+    pub(crate) fn set_callstack_id(
+        &mut self,
+        callstack_id: usize) {
+        self.callstack_id = callstack_id as u32;
+    }
+
     pub fn record_id(&self) -> usize { self.record_id as usize }
 
     pub fn has_record(&self) -> bool { self.record_id != 0 }
@@ -394,6 +402,16 @@ impl ExportProcessSample {
         attributes_id: usize) {
         self.attributes_id = attributes_id as u32;
     }
+
+    pub fn user_stack_id(&self) -> usize { self.user_stack_id as usize }
+
+    pub fn has_user_stack(&self) -> bool { self.user_stack_id != 0 }
+
+    pub fn attach_user_stack(
+        &mut self,
+        user_stack_id: usize) {
+        self.user_stack_id = user_stack_id as u32;
+    }
 }
 
 const EXPORT_PROCESS_FLAG_CREATED: u8 = 1 << 0;
@@ -544,6 +562,7 @@ pub struct ExportProcess {
     pub(crate) os: OSExportProcess,
     samples: Vec<ExportProcessSample>,
     mappings: ExportMappingLookup,
+    data_mappings: ExportMappingLookup,
     anon_maps: bool,
     create_time_qpc: Option<u64>,
     exit_time_qpc: Option<u64>,
@@ -574,6 +593,7 @@ impl ExportProcess {
             os: OSExportProcess::new(),
             samples: Vec::new(),
             mappings: ExportMappingLookup::default(),
+            data_mappings: ExportMappingLookup::default(),
             anon_maps: false,
             create_time_qpc: None,
             exit_time_qpc: None,
@@ -626,6 +646,23 @@ impl ExportProcess {
         self.mappings.mappings_mut().push(mapping);
     }
 
+    pub fn add_data_mapping(
+        &mut self,
+        mapping: ExportMapping) {
+        self.data_mappings.mappings_mut().push(mapping);
+    }
+
+    pub fn find_data_mapping(
+        &self,
+        address: u64,
+        time: Option<u64>) -> Option<&ExportMapping> {
+        /* Data may live within an executable mapping, IE: .rodata */
+        match self.mappings.find(address, time) {
+            Some(mapping) => { Some(mapping) },
+            None => { self.data_mappings.find(address, time) },
+        }
+    }
+
     pub fn needs_dynamic_symbol(
         &self,
         symbol: &DynamicSymbol,
@@ -761,6 +798,30 @@ impl ExportProcess {
         self.user_page_map = OnceCell::new();
     }
 
+    pub(crate) fn set_sample_callstacks(
+        &mut self,
+        callstacks: &[(usize, usize)]) {
+        for (index, callstack_id) in callstacks {
+            self.samples[*index].set_callstack_id(*callstack_id);
+        }
+
+        /* Clear page map */
+        self.user_page_map = OnceCell::new();
+    }
+
+    pub(crate) fn attach_sample_user_stack(
+        &mut self,
+        index: usize,
+        user_stack_id: usize) -> bool {
+        match self.samples.get_mut(index) {
+            Some(sample) => {
+                sample.attach_user_stack(user_stack_id);
+                true
+            },
+            None => { false },
+        }
+    }
+
     pub fn set_comm_id(
         &mut self,
         comm_id: usize) {
@@ -805,6 +866,8 @@ impl ExportProcess {
 
     pub fn mappings_mut(&mut self) -> &mut Vec<ExportMapping> { self.mappings.mappings_mut() }
 
+    pub fn data_mappings(&self) -> &Vec<ExportMapping> { self.data_mappings.mappings() }
+
     pub fn has_anon_mappings(&self) -> bool { self.anon_maps }
 
     pub fn get_unique_kernel_ips(
@@ -925,6 +988,7 @@ impl ExportProcess {
 
         fork.comm_id = self.comm_id;
         fork.mappings = self.mappings.clone();
+        fork.data_mappings = self.data_mappings.clone();
         fork.os = self.os.clone();
 
         fork
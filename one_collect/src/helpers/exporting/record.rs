@@ -1,7 +1,9 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::event::{Event, EventFormat};
+use crate::event::{Event, EventFormat, EventField, LocationType};
+
+use super::ExportMapping;
 
 pub struct ExportRecordData<'a> {
     record_type_id: u16,
@@ -30,7 +32,40 @@ impl<'a> ExportRecordData<'a> {
 
 const EXPORT_RECORD_FLAG_ORIG_DATA: u8 = 1;
 
-#[derive(PartialEq, Default)]
+pub const EXPORT_RECORD_BRANCH_STACK: &str = "branch_stack";
+pub const EXPORT_RECORD_MEMORY_ACCESS: &str = "memory_access";
+
+pub struct ExportMemoryAccess<'a> {
+    address: u64,
+    weight: u64,
+    data_src: u64,
+    mapping: Option<&'a ExportMapping>,
+}
+
+impl<'a> ExportMemoryAccess<'a> {
+    pub fn new(
+        address: u64,
+        weight: u64,
+        data_src: u64,
+        mapping: Option<&'a ExportMapping>) -> Self {
+        Self {
+            address,
+            weight,
+            data_src,
+            mapping,
+        }
+    }
+
+    pub fn address(&self) -> u64 { self.address }
+
+    pub fn weight(&self) -> u64 { self.weight }
+
+    pub fn data_src(&self) -> u64 { self.data_src }
+
+    pub fn mapping(&self) -> Option<&'a ExportMapping> { self.mapping }
+}
+
+#[derive(Clone, PartialEq, Default)]
 pub struct ExportRecordType {
     kind: u16,
     id: usize,
@@ -58,6 +93,10 @@ impl ExportRecordType {
         self.flags & EXPORT_RECORD_FLAG_ORIG_DATA != 0
     }
 
+    pub(crate) fn mark_original_data(&mut self) {
+        self.flags |= EXPORT_RECORD_FLAG_ORIG_DATA;
+    }
+
     pub fn from_event(
         kind: u16,
         event: &Event) -> Self {
@@ -72,8 +111,71 @@ impl ExportRecordType {
         }
     }
 
+    pub fn for_branch_stack(
+        kind: u16) -> Self {
+        /* Records are an array of entries in this format */
+        let mut format = EventFormat::new();
+
+        format.add_field(
+            EventField::new(
+                "from".into(), "u64".into(),
+                LocationType::Static, 0, 8));
+
+        format.add_field(
+            EventField::new(
+                "to".into(), "u64".into(),
+                LocationType::Static, 8, 8));
+
+        format.add_field(
+            EventField::new(
+                "flags".into(), "u64".into(),
+                LocationType::Static, 16, 8));
+
+        Self::new(
+            kind,
+            0,
+            EXPORT_RECORD_BRANCH_STACK.into(),
+            format)
+    }
+
+    pub fn is_branch_stack(&self) -> bool {
+        self.name == EXPORT_RECORD_BRANCH_STACK
+    }
+
+    pub fn for_memory_access(
+        kind: u16) -> Self {
+        let mut format = EventFormat::new();
+
+        format.add_field(
+            EventField::new(
+                "address".into(), "u64".into(),
+                LocationType::Static, 0, 8));
+
+        format.add_field(
+            EventField::new(
+                "weight".into(), "u64".into(),
+                LocationType::Static, 8, 8));
+
+        format.add_field(
+            EventField::new(
+                "data_src".into(), "u64".into(),
+                LocationType::Static, 16, 8));
+
+        Self::new(
+            kind,
+            0,
+            EXPORT_RECORD_MEMORY_ACCESS.into(),
+            format)
+    }
+
+    pub fn is_memory_access(&self) -> bool {
+        self.name == EXPORT_RECORD_MEMORY_ACCESS
+    }
+
     pub fn kind(&self) -> u16 { self.kind }
 
+    pub fn kind_mut(&mut self) -> &mut u16 { &mut self.kind }
+
     pub fn id(&self) -> usize { self.id }
 
     pub fn name(&self) -> &str { &self.name }
@@ -0,0 +1,1184 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::event::{EventField, EventFormat, LocationType};
+
+use super::*;
+
+/*
+ * Native snapshot format of an ExportMachine. All values are little
+ * endian. IDs (strings, callstacks, records, attributes, spans, etc.)
+ * are stored as-is and the tables are reloaded in ID order, so the
+ * IDs within a loaded machine match the saved machine exactly.
+ */
+const SNAPSHOT_MAGIC: &[u8; 8] = b"OCSNAP\0\0";
+const SNAPSHOT_VERSION: u32 = 6;
+
+const VALUE_COUNT: u8 = 0;
+const VALUE_DURATION: u8 = 1;
+const VALUE_BYTES: u8 = 2;
+const VALUE_SPAN: u8 = 3;
+
+const ATTRIBUTE_LABEL: u8 = 0;
+const ATTRIBUTE_VALUE: u8 = 1;
+
+pub(crate) trait SnapshotWrite: Write {
+    fn write_u8(&mut self, value: u8) -> anyhow::Result<()> {
+        self.write_all(&[value])?;
+        Ok(())
+    }
+
+    fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.write_all(&value.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_u32(&mut self, value: u32) -> anyhow::Result<()> {
+        self.write_all(&value.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_u64(&mut self, value: u64) -> anyhow::Result<()> {
+        self.write_all(&value.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_len(&mut self, len: usize) -> anyhow::Result<()> {
+        self.write_u64(len as u64)
+    }
+
+    fn write_bytes(&mut self, value: &[u8]) -> anyhow::Result<()> {
+        self.write_len(value.len())?;
+        self.write_all(value)?;
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &str) -> anyhow::Result<()> {
+        self.write_bytes(value.as_bytes())
+    }
+
+    fn write_opt_u32(&mut self, value: Option<u32>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => { self.write_u8(1)?; self.write_u32(value) },
+            None => { self.write_u8(0) },
+        }
+    }
+
+    fn write_opt_u64(&mut self, value: Option<u64>) -> anyhow::Result<()> {
+        match value {
+            Some(value) => { self.write_u8(1)?; self.write_u64(value) },
+            None => { self.write_u8(0) },
+        }
+    }
+}
+
+impl<W: Write> SnapshotWrite for W {}
+
+pub(crate) trait SnapshotRead: Read {
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        let mut buf = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let mut buf = [0; 2];
+        self.read_exact(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        let mut buf = [0; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        let mut buf = [0; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_len(&mut self) -> anyhow::Result<usize> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    fn read_bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = self.read_len()?;
+        let mut bytes = Vec::new();
+
+        /* Don't trust the length for allocations */
+        Read::take(&mut *self, len as u64).read_to_end(&mut bytes)?;
+
+        if bytes.len() != len {
+            anyhow::bail!("Snapshot is truncated.");
+        }
+
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.read_bytes()?)?)
+    }
+
+    fn read_opt_u32(&mut self) -> anyhow::Result<Option<u32>> {
+        match self.read_u8()? {
+            0 => { Ok(None) },
+            _ => { Ok(Some(self.read_u32()?)) },
+        }
+    }
+
+    fn read_opt_u64(&mut self) -> anyhow::Result<Option<u64>> {
+        match self.read_u8()? {
+            0 => { Ok(None) },
+            _ => { Ok(Some(self.read_u64()?)) },
+        }
+    }
+}
+
+impl<R: Read> SnapshotRead for R {}
+
+fn location_to_u8(location: LocationType) -> u8 {
+    match location {
+        LocationType::Static => { 0 },
+        LocationType::StaticString => { 1 },
+        LocationType::DynRelative => { 2 },
+        LocationType::DynAbsolute => { 3 },
+        LocationType::StaticUTF16String => { 4 },
+    }
+}
+
+fn location_from_u8(location: u8) -> anyhow::Result<LocationType> {
+    match location {
+        0 => { Ok(LocationType::Static) },
+        1 => { Ok(LocationType::StaticString) },
+        2 => { Ok(LocationType::DynRelative) },
+        3 => { Ok(LocationType::DynAbsolute) },
+        4 => { Ok(LocationType::StaticUTF16String) },
+        _ => { anyhow::bail!("Unknown field location {}.", location) },
+    }
+}
+
+/*
+ * QPC values are only meaningful with the frequency of the machine that
+ * captured them. Loaded times are rescaled to the local frequency, so
+ * the rest of the exporting code can keep using ExportMachine::qpc_freq().
+ */
+struct QpcScale {
+    from: u64,
+    to: u64,
+}
+
+impl QpcScale {
+    fn new(from: u64) -> Self {
+        Self {
+            from,
+            to: ExportMachine::qpc_freq(),
+        }
+    }
+
+    fn apply(
+        &self,
+        qpc: u64) -> u64 {
+        if self.from == self.to || self.from == 0 {
+            return qpc;
+        }
+
+        (qpc as u128 * self.to as u128 / self.from as u128) as u64
+    }
+}
+
+fn check_id(
+    id: usize,
+    count: usize,
+    what: &str) -> anyhow::Result<()> {
+    if id >= count {
+        anyhow::bail!("Snapshot {} ID {} is out of range.", what, id);
+    }
+
+    Ok(())
+}
+
+fn write_span(
+    writer: &mut impl Write,
+    span: &ExportSpan) -> anyhow::Result<()> {
+    writer.write_len(span.name_id())?;
+    writer.write_u64(span.start_time())?;
+    writer.write_u64(span.end_time())?;
+    writer.write_len(span.children().len())?;
+
+    for child in span.children() {
+        write_span(writer, child)?;
+    }
+
+    Ok(())
+}
+
+fn read_span(
+    reader: &mut impl Read,
+    scale: &QpcScale,
+    string_count: usize) -> anyhow::Result<ExportSpan> {
+    let name_id = reader.read_len()?;
+    let start_time = scale.apply(reader.read_u64()?);
+    let end_time = scale.apply(reader.read_u64()?);
+    let count = reader.read_len()?;
+
+    check_id(name_id, string_count, "span name")?;
+
+    let mut span = ExportSpan::start(name_id, start_time, 0);
+
+    for _ in 0..count {
+        span.add_child(read_span(reader, scale, string_count)?);
+    }
+
+    span.mark_end(end_time);
+
+    Ok(span)
+}
+
+fn write_mapping(
+    writer: &mut impl Write,
+    mapping: &ExportMapping) -> anyhow::Result<()> {
+    writer.write_u64(mapping.time())?;
+    writer.write_len(mapping.filename_id())?;
+    writer.write_u64(mapping.start())?;
+    writer.write_u64(mapping.end())?;
+    writer.write_u64(mapping.file_offset())?;
+    writer.write_u8(mapping.anon() as u8)?;
+    writer.write_len(mapping.id())?;
+
+    match mapping.unwind_type() {
+        UnwindType::DWARF => { writer.write_u8(0)?; },
+        UnwindType::Prolog => { writer.write_u8(1)?; },
+    }
+
+    match mapping.node() {
+        Some(node) => {
+            writer.write_u8(1)?;
+            writer.write_u64(node.dev())?;
+            writer.write_u64(node.ino())?;
+        },
+        None => { writer.write_u8(0)?; },
+    }
+
+    writer.write_len(mapping.symbols().len())?;
+
+    for symbol in mapping.symbols() {
+        writer.write_len(symbol.name_id())?;
+        writer.write_u64(symbol.start())?;
+        writer.write_u64(symbol.end())?;
+    }
+
+    Ok(())
+}
+
+fn read_mapping(
+    reader: &mut impl Read,
+    scale: &QpcScale,
+    string_count: usize,
+    map_count: usize) -> anyhow::Result<ExportMapping> {
+    let time = scale.apply(reader.read_u64()?);
+    let filename_id = reader.read_len()?;
+    let start = reader.read_u64()?;
+    let end = reader.read_u64()?;
+    let file_offset = reader.read_u64()?;
+    let anon = reader.read_u8()? != 0;
+    let id = reader.read_len()?;
+
+    check_id(filename_id, string_count, "mapping filename")?;
+    check_id(id, map_count, "mapping")?;
+
+    let unwind_type = match reader.read_u8()? {
+        0 => { UnwindType::DWARF },
+        _ => { UnwindType::Prolog },
+    };
+
+    let mut mapping = ExportMapping::new(
+        time,
+        filename_id,
+        start,
+        end,
+        file_offset,
+        anon,
+        id,
+        unwind_type);
+
+    if reader.read_u8()? != 0 {
+        let dev = reader.read_u64()?;
+        let ino = reader.read_u64()?;
+
+        mapping.set_node(ExportDevNode::new(dev, ino));
+    }
+
+    let count = reader.read_len()?;
+
+    for _ in 0..count {
+        let name_id = reader.read_len()?;
+        let start = reader.read_u64()?;
+        let end = reader.read_u64()?;
+
+        check_id(name_id, string_count, "symbol name")?;
+
+        mapping.add_symbol(ExportSymbol::new(name_id, start, end));
+    }
+
+    Ok(mapping)
+}
+
+fn write_sample(
+    writer: &mut impl Write,
+    sample: &ExportProcessSample) -> anyhow::Result<()> {
+    writer.write_u64(sample.time())?;
+
+    match sample.value() {
+        MetricValue::Count(value) => { writer.write_u8(VALUE_COUNT)?; writer.write_u64(value)?; },
+        MetricValue::Duration(value) => { writer.write_u8(VALUE_DURATION)?; writer.write_u64(value)?; },
+        MetricValue::Bytes(value) => { writer.write_u8(VALUE_BYTES)?; writer.write_u64(value)?; },
+        MetricValue::Span(id) => { writer.write_u8(VALUE_SPAN)?; writer.write_len(id)?; },
+    }
+
+    writer.write_u16(sample.cpu())?;
+    writer.write_u16(sample.kind())?;
+    writer.write_u32(sample.tid())?;
+    writer.write_u64(sample.ip())?;
+    writer.write_len(sample.callstack_id())?;
+    writer.write_len(sample.record_id())?;
+    writer.write_len(sample.attributes_id())?;
+
+    Ok(())
+}
+
+fn read_sample(
+    reader: &mut impl Read,
+    scale: &QpcScale) -> anyhow::Result<ExportProcessSample> {
+    let time = scale.apply(reader.read_u64()?);
+
+    let value = match reader.read_u8()? {
+        VALUE_COUNT => { MetricValue::Count(reader.read_u64()?) },
+        VALUE_DURATION => { MetricValue::Duration(scale.apply(reader.read_u64()?)) },
+        VALUE_BYTES => { MetricValue::Bytes(reader.read_u64()?) },
+        VALUE_SPAN => { MetricValue::Span(reader.read_len()?) },
+        kind => { anyhow::bail!("Unknown sample value kind {}.", kind) },
+    };
+
+    let cpu = reader.read_u16()?;
+    let kind = reader.read_u16()?;
+    let tid = reader.read_u32()?;
+    let ip = reader.read_u64()?;
+    let callstack_id = reader.read_len()?;
+    let record_id = reader.read_len()?;
+    let attributes_id = reader.read_len()?;
+
+    let mut sample = ExportProcessSample::new(
+        time,
+        value,
+        cpu,
+        kind,
+        tid,
+        ip,
+        callstack_id);
+
+    if record_id != 0 {
+        sample.attach_record(record_id);
+    }
+
+    if attributes_id != 0 {
+        sample.attach_attributes(attributes_id);
+    }
+
+    Ok(sample)
+}
+
+impl ExportMachine {
+    pub fn save_snapshot(
+        &self,
+        writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_u32(SNAPSHOT_VERSION)?;
+
+        /* QPC frequency (Version 6+) */
+        writer.write_u64(ExportMachine::qpc_freq())?;
+
+        /* Capture times */
+        match self.start_date {
+            Some(date) => {
+                writer.write_u8(1)?;
+                writer.write_u64(date.timestamp() as u64)?;
+                writer.write_u32(date.timestamp_subsec_nanos())?;
+            },
+            None => { writer.write_u8(0)?; },
+        }
+
+        writer.write_opt_u64(self.start_qpc)?;
+        writer.write_opt_u64(self.end_qpc)?;
+
+        match self.duration {
+            Some(duration) => {
+                writer.write_u8(1)?;
+                writer.write_u64(duration.as_secs())?;
+                writer.write_u32(duration.subsec_nanos())?;
+            },
+            None => { writer.write_u8(0)?; },
+        }
+
+        /* Interned strings */
+        let mut count = 0;
+        self.strings.for_each(|_, _| { count += 1; });
+        writer.write_len(count)?;
+
+        let mut result = Ok(());
+        self.strings.for_each(|_, value| {
+            if result.is_ok() {
+                result = writer.write_str(value);
+            }
+        });
+        result?;
+
+        /* Interned callstacks */
+        let mut count = 0;
+        self.callstacks.for_each(|_, _| { count += 1; });
+        writer.write_len(count)?;
+
+        let mut result = Ok(());
+        self.callstacks.for_each(|_, frames| {
+            if result.is_ok() {
+                result = writer.write_len(frames.len());
+
+                for frame in frames {
+                    if result.is_ok() {
+                        result = writer.write_u64(*frame);
+                    }
+                }
+            }
+        });
+        result?;
+
+        /* Sample kinds */
+        writer.write_len(self.kinds.len())?;
+
+        for kind in &self.kinds {
+            writer.write_str(kind)?;
+        }
+
+        /* Record types, type 0 is always default */
+        writer.write_len(self.record_types.len() - 1)?;
+
+        for record_type in self.record_types.iter().skip(1) {
+            writer.write_u16(record_type.kind())?;
+            writer.write_len(record_type.id())?;
+            writer.write_str(record_type.name())?;
+            writer.write_u8(record_type.is_original_data() as u8)?;
+
+            let fields = record_type.format().fields();
+            writer.write_len(fields.len())?;
+
+            for field in fields {
+                writer.write_str(&field.name)?;
+                writer.write_str(&field.type_name)?;
+                writer.write_u8(location_to_u8(field.location))?;
+                writer.write_len(field.offset)?;
+                writer.write_len(field.size)?;
+            }
+        }
+
+        /* Records, record 0 is always default */
+        writer.write_len(self.records.len() - 1)?;
+
+        for record in self.records.iter().skip(1) {
+            writer.write_u16(record.record_type())?;
+            writer.write_len(record.start())?;
+            writer.write_u32((record.end() - record.start()) as u32)?;
+        }
+
+        writer.write_bytes(&self.record_data)?;
+
+        /* Attributes, attribute 0 is always default */
+        writer.write_len(self.attributes.len() - 1)?;
+
+        for attributes in self.attributes.iter().skip(1) {
+            writer.write_len(attributes.attributes().len())?;
+
+            for pair in attributes.attributes() {
+                writer.write_len(pair.name())?;
+
+                match pair.attribute_value() {
+                    ExportAttributeValue::Label(id) => {
+                        writer.write_u8(ATTRIBUTE_LABEL)?;
+                        writer.write_len(id)?;
+                    },
+                    ExportAttributeValue::Value(value) => {
+                        writer.write_u8(ATTRIBUTE_VALUE)?;
+                        writer.write_u64(value)?;
+                    },
+                }
+            }
+
+            writer.write_len(attributes.associated_ids().len())?;
+
+            for id in attributes.associated_ids() {
+                writer.write_len(*id)?;
+            }
+        }
+
+        /* Spans */
+        writer.write_len(self.spans.len())?;
+
+        for span in &self.spans {
+            write_span(writer, span)?;
+        }
+
+        /* Module metadata */
+        let metadata: Vec<_> = self.module_metadata.iter().collect();
+        writer.write_len(metadata.len())?;
+
+        for (node, metadata) in metadata {
+            writer.write_u64(node.dev())?;
+            writer.write_u64(node.ino())?;
+            metadata.write_snapshot(writer)?;
+        }
+
+        /* Processes */
+        writer.write_len(self.map_index)?;
+        writer.write_len(self.procs.len())?;
+
+        for proc in self.procs.values() {
+            writer.write_u32(proc.pid())?;
+            writer.write_opt_u32(proc.ns_pid())?;
+            writer.write_opt_u64(proc.comm_id().map(|id| id as u64))?;
+            writer.write_opt_u64(proc.create_time_qpc())?;
+            writer.write_opt_u64(proc.exit_time_qpc())?;
+
+            writer.write_len(proc.mappings().len())?;
+
+            for mapping in proc.mappings() {
+                write_mapping(writer, mapping)?;
+            }
+
+            writer.write_len(proc.samples().len())?;
+
+            for sample in proc.samples() {
+                write_sample(writer, sample)?;
+            }
+        }
+
+        /* Counter totals (Version 2+) */
+        writer.write_len(self.counter_totals.len())?;
+
+        for (name_id, value) in &self.counter_totals {
+            writer.write_len(*name_id)?;
+            writer.write_u64(*value)?;
+        }
+
+        /* Lost samples per CPU (Version 3+) */
+        writer.write_len(self.lost_samples.len())?;
+
+        for count in &self.lost_samples {
+            writer.write_u64(*count)?;
+        }
+
+        /* Deferred user stacks (Version 4+) */
+        let stacks = &self.user_stacks.stacks()[1..];
+
+        writer.write_len(stacks.len())?;
+
+        for stack in stacks {
+            writer.write_u64(stack.ip())?;
+            writer.write_u64(stack.fp())?;
+            writer.write_u64(stack.sp())?;
+            writer.write_opt_u64(stack.link())?;
+            writer.write_len(stack.callstack_id())?;
+            writer.write_len(stack.offset())?;
+            writer.write_u32(stack.len() as u32)?;
+        }
+
+        writer.write_bytes(self.user_stacks.data())?;
+
+        let mut attached = Vec::new();
+
+        for proc in self.procs.values() {
+            for (index, sample) in proc.samples().iter().enumerate() {
+                if sample.has_user_stack() {
+                    attached.push((proc.pid(), index, sample.user_stack_id()));
+                }
+            }
+        }
+
+        writer.write_len(attached.len())?;
+
+        for (pid, index, id) in attached {
+            writer.write_u32(pid)?;
+            writer.write_len(index)?;
+            writer.write_len(id)?;
+        }
+
+        /* Sched switches (Version 5+) */
+        writer.write_len(self.sched_switches.len())?;
+
+        for switch in &self.sched_switches {
+            writer.write_u64(switch.time())?;
+            writer.write_u16(switch.cpu())?;
+            writer.write_u32(switch.prev_pid())?;
+            writer.write_u32(switch.prev_tid())?;
+            writer.write_u32(switch.next_pid())?;
+            writer.write_u32(switch.next_tid())?;
+            writer.write_u8(switch.preempted() as u8)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save_snapshot_file(
+        &self,
+        path: &str) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        self.save_snapshot(&mut writer)?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn load_snapshot(
+        reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != SNAPSHOT_MAGIC {
+            anyhow::bail!("Not an ExportMachine snapshot.");
+        }
+
+        let version = reader.read_u32()?;
+
+        if version == 0 || version > SNAPSHOT_VERSION {
+            anyhow::bail!("Unsupported snapshot version {}.", version);
+        }
+
+        /* QPC frequency (Version 6+), older snapshots assume ours */
+        let scale = match version >= 6 {
+            true => { QpcScale::new(reader.read_u64()?) },
+            false => { QpcScale::new(ExportMachine::qpc_freq()) },
+        };
+
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        /* Capture times */
+        if reader.read_u8()? != 0 {
+            let secs = reader.read_u64()? as i64;
+            let nanos = reader.read_u32()?;
+
+            machine.start_date = DateTime::from_timestamp(secs, nanos);
+        }
+
+        machine.start_qpc = reader.read_opt_u64()?.map(|qpc| scale.apply(qpc));
+        machine.end_qpc = reader.read_opt_u64()?.map(|qpc| scale.apply(qpc));
+
+        if reader.read_u8()? != 0 {
+            let secs = reader.read_u64()?;
+            let nanos = reader.read_u32()?;
+
+            machine.duration = Some(Duration::new(secs, nanos));
+        }
+
+        /* Interned strings, must come back with the same IDs */
+        let string_count = reader.read_len()?;
+
+        for i in 0..string_count {
+            let value = reader.read_string()?;
+
+            if machine.strings.to_id(&value) != i {
+                anyhow::bail!("Snapshot string IDs are inconsistent.");
+            }
+        }
+
+        /* Interned callstacks, must come back with the same IDs */
+        let callstack_count = reader.read_len()?;
+        let mut frames = Vec::new();
+
+        for i in 0..callstack_count {
+            let len = reader.read_len()?;
+
+            frames.clear();
+
+            for _ in 0..len {
+                frames.push(reader.read_u64()?);
+            }
+
+            if machine.callstacks.to_id(&frames) != i {
+                anyhow::bail!("Snapshot callstack IDs are inconsistent.");
+            }
+        }
+
+        /* Sample kinds */
+        let count = reader.read_len()?;
+
+        for _ in 0..count {
+            machine.kinds.push(reader.read_string()?);
+        }
+
+        let kind_count = machine.kinds.len();
+
+        /* Record types */
+        let count = reader.read_len()?;
+
+        for _ in 0..count {
+            let kind = reader.read_u16()?;
+            let id = reader.read_len()?;
+            let name = reader.read_string()?;
+            let original_data = reader.read_u8()? != 0;
+
+            check_id(kind as usize, kind_count, "record type kind")?;
+
+            let mut format = EventFormat::new();
+            let fields = reader.read_len()?;
+
+            for _ in 0..fields {
+                let name = reader.read_string()?;
+                let type_name = reader.read_string()?;
+                let location = location_from_u8(reader.read_u8()?)?;
+                let offset = reader.read_len()?;
+                let size = reader.read_len()?;
+
+                format.add_field(
+                    EventField::new(
+                        name,
+                        type_name,
+                        location,
+                        offset,
+                        size));
+            }
+
+            let mut record_type = ExportRecordType::new(kind, id, name, format);
+
+            if original_data {
+                record_type.mark_original_data();
+            }
+
+            machine.record_types.push(record_type);
+        }
+
+        /* Records */
+        let count = reader.read_len()?;
+
+        for _ in 0..count {
+            let record_type = reader.read_u16()?;
+            let offset = reader.read_len()?;
+            let length = reader.read_u32()?;
+
+            machine.records.push(ExportRecord::new(record_type, offset, length));
+        }
+
+        machine.record_data = reader.read_bytes()?;
+
+        for record in &machine.records {
+            if record.end() > machine.record_data.len() ||
+               record.record_type() as usize >= machine.record_types.len() {
+                anyhow::bail!("Snapshot record is out of range.");
+            }
+        }
+
+        /* Attributes, 0 is the default and is not saved */
+        let count = reader.read_len()?;
+        let attribute_count = count + 1;
+
+        for _ in 0..count {
+            let mut attributes = ExportAttributes::default();
+            let pairs = reader.read_len()?;
+
+            for _ in 0..pairs {
+                let name = reader.read_len()?;
+
+                check_id(name, string_count, "attribute name")?;
+
+                let value = match reader.read_u8()? {
+                    ATTRIBUTE_LABEL => {
+                        let label = reader.read_len()?;
+
+                        check_id(label, string_count, "attribute label")?;
+
+                        ExportAttributeValue::Label(label)
+                    },
+                    ATTRIBUTE_VALUE => { ExportAttributeValue::Value(reader.read_u64()?) },
+                    kind => { anyhow::bail!("Unknown attribute kind {}.", kind) },
+                };
+
+                attributes.push(ExportAttributePair::new(name, value));
+            }
+
+            let associations = reader.read_len()?;
+
+            for _ in 0..associations {
+                let associated_id = reader.read_len()?;
+
+                check_id(associated_id, attribute_count, "attribute association")?;
+
+                attributes.push_association(associated_id);
+            }
+
+            machine.push_unique_attributes(attributes);
+        }
+
+        /* Spans */
+        let count = reader.read_len()?;
+
+        for _ in 0..count {
+            let span = read_span(reader, &scale, string_count)?;
+
+            machine.spans.push(span);
+        }
+
+        /* Module metadata */
+        let count = reader.read_len()?;
+
+        for _ in 0..count {
+            let dev = reader.read_u64()?;
+            let ino = reader.read_u64()?;
+            let mut metadata = ModuleMetadata::read_snapshot(reader)?;
+
+            let in_range = std::cell::Cell::new(true);
+
+            metadata.remap_strings(|id| {
+                in_range.set(in_range.get() && id < string_count);
+                id
+            });
+
+            if !in_range.get() {
+                anyhow::bail!("Snapshot module metadata string is out of range.");
+            }
+
+            machine.module_metadata
+                .entry(ExportDevNode::new(dev, ino))
+                .or_insert(metadata);
+        }
+
+        /* Processes */
+        machine.map_index = reader.read_len()?;
+
+        let count = reader.read_len()?;
+
+        for _ in 0..count {
+            let pid = reader.read_u32()?;
+            let mut proc = ExportProcess::new(pid);
+
+            *proc.ns_pid_mut() = reader.read_opt_u32()?;
+
+            if let Some(comm_id) = reader.read_opt_u64()? {
+                check_id(comm_id as usize, string_count, "comm")?;
+
+                proc.set_comm_id(comm_id as usize);
+            }
+
+            if let Some(qpc) = reader.read_opt_u64()? {
+                proc.set_create_time_qpc(scale.apply(qpc));
+            }
+
+            if let Some(qpc) = reader.read_opt_u64()? {
+                proc.set_exit_time_qpc(scale.apply(qpc));
+            }
+
+            let mappings = reader.read_len()?;
+
+            for _ in 0..mappings {
+                proc.add_mapping(read_mapping(reader, &scale, string_count, machine.map_index)?);
+            }
+
+            let samples = reader.read_len()?;
+
+            for _ in 0..samples {
+                let sample = read_sample(reader, &scale)?;
+
+                check_id(sample.kind() as usize, kind_count, "sample kind")?;
+                check_id(sample.callstack_id(), callstack_count, "sample callstack")?;
+                check_id(sample.record_id(), machine.records.len(), "sample record")?;
+                check_id(sample.attributes_id(), attribute_count, "sample attributes")?;
+
+                if let MetricValue::Span(id) = sample.value() {
+                    check_id(id, machine.spans.len(), "sample span")?;
+                }
+
+                proc.add_sample(sample);
+            }
+
+            machine.procs.insert(pid, proc);
+        }
+
+        /* Counter totals (Version 2+) */
+        if version >= 2 {
+            let count = reader.read_len()?;
+
+            for _ in 0..count {
+                let name_id = reader.read_len()?;
+                let value = reader.read_u64()?;
+
+                if name_id >= string_count {
+                    anyhow::bail!("Snapshot counter name is out of range.");
+                }
+
+                machine.counter_totals.push((name_id, value));
+            }
+        }
+
+        /* Lost samples per CPU (Version 3+) */
+        if version >= 3 {
+            let count = reader.read_len()?;
+
+            for _ in 0..count {
+                machine.lost_samples.push(reader.read_u64()?);
+            }
+        }
+
+        /* Deferred user stacks (Version 4+) */
+        if version >= 4 {
+            let count = reader.read_len()?;
+            let mut stacks = Vec::new();
+
+            for _ in 0..count {
+                let ip = reader.read_u64()?;
+                let fp = reader.read_u64()?;
+                let sp = reader.read_u64()?;
+                let link = reader.read_opt_u64()?;
+                let callstack_id = reader.read_len()?;
+                let offset = reader.read_len()?;
+                let length = reader.read_u32()?;
+
+                stacks.push(
+                    ExportUserStack::new(
+                        ip,
+                        fp,
+                        sp,
+                        link,
+                        callstack_id,
+                        offset,
+                        length));
+            }
+
+            let data = reader.read_bytes()?;
+
+            for stack in &stacks {
+                if stack.end() > data.len() ||
+                   machine.callstacks.from_id(stack.callstack_id(), &mut frames).is_err() {
+                    anyhow::bail!("Snapshot user stack is out of range.");
+                }
+
+                machine.user_stacks.push(*stack);
+            }
+
+            machine.user_stacks.set_data(data);
+
+            let count = reader.read_len()?;
+
+            for _ in 0..count {
+                let pid = reader.read_u32()?;
+                let index = reader.read_len()?;
+                let id = reader.read_len()?;
+
+                let attached = id != 0 && id <= stacks.len() &&
+                    machine.procs
+                    .get_mut(&pid)
+                    .map(|proc| proc.attach_sample_user_stack(index, id))
+                    .unwrap_or(false);
+
+                if !attached {
+                    anyhow::bail!("Snapshot sample user stack is out of range.");
+                }
+            }
+        }
+
+        /* Sched switches (Version 5+) */
+        if version >= 5 {
+            let count = reader.read_len()?;
+
+            for _ in 0..count {
+                let time = scale.apply(reader.read_u64()?);
+                let cpu = reader.read_u16()?;
+                let prev_pid = reader.read_u32()?;
+                let prev_tid = reader.read_u32()?;
+                let next_pid = reader.read_u32()?;
+                let next_tid = reader.read_u32()?;
+                let preempted = reader.read_u8()? != 0;
+
+                machine.sched_switches.push(
+                    ExportSchedSwitch::new(
+                        time,
+                        cpu,
+                        prev_pid,
+                        prev_tid,
+                        next_pid,
+                        next_tid,
+                        preempted));
+            }
+        }
+
+        Ok(machine)
+    }
+
+    pub fn load_snapshot_file(
+        path: &str) -> anyhow::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        Self::load_snapshot(&mut reader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+
+    #[test]
+    fn round_trip() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        machine.mark_start_direct(Utc::now(), 100);
+
+        let cpu = machine.sample_kind("cpu");
+        machine.add_comm_exec(1, "app", 100).unwrap();
+        machine.add_mmap_exec(100, 1, 0x1000, 0x1000, 0, 8, 1, 42, "/usr/bin/app").unwrap();
+
+        let name_id = machine.intern("main");
+        machine.process_mut(1).mappings_mut()[0].add_symbol(
+            ExportSymbol::new(name_id, 0x1000, 0x10FF));
+
+        let mut e = Event::new(1, "test".into());
+
+        e.format_mut().add_field(
+            EventField::new(
+                "1".into(), "unsigned char".into(),
+                LocationType::Static, 0, 1));
+
+        let record_type = machine.record_type(ExportRecordType::from_event(cpu, &e));
+
+        let mut attributes = ExportAttributes::default();
+        attributes.push(machine.label_attribute("Host", "test"));
+        let attributes_id = machine.push_unique_attributes(attributes);
+
+        let mut span = ExportSpan::start(machine.intern("Span"), 110, 1);
+        span.add_child(ExportSpan::start(machine.intern("Child"), 112, 0));
+        span.mark_last_child_end(115);
+        span.mark_end(120);
+        let value = machine.span_to_value(span);
+
+        let mut sample = machine.make_sample(150, value, 2, 3, cpu, &[0x1010, 0x1020, 0x1030]);
+        sample.attach_attributes(attributes_id);
+
+        machine.add_custom_sample_with_record(1, sample, record_type, &[b'Z']).unwrap();
+        machine.user_stacks.defer(
+            1,
+            160,
+            ExportUserStack::from_regs(0x1040, 0x2010, 0x2000, None),
+            &[1, 2, 3, 4]);
+        machine.add_sample(160, MetricValue::Bytes(64), 1, 2, 0, cpu, &[0x1040]).unwrap();
+        machine.add_counter_total("cycles", 1234);
+        machine.add_lost_samples(1, 12);
+        machine.add_sched_switch(ExportSchedSwitch::new(170, 2, 1, 2, 0, 0, true));
+
+        let mut buffer = Vec::new();
+        machine.save_snapshot(&mut buffer).unwrap();
+
+        let loaded = ExportMachine::load_snapshot(&mut buffer.as_slice()).unwrap();
+
+        assert_eq!(machine.start_qpc(), loaded.start_qpc());
+        assert_eq!(machine.start_date(), loaded.start_date());
+        assert_eq!(machine.sample_kinds(), loaded.sample_kinds());
+        assert_eq!(machine.record_types().len(), loaded.record_types().len());
+        assert_eq!(machine.counter_totals(), loaded.counter_totals());
+        assert_eq!(machine.lost_samples_per_cpu(), loaded.lost_samples_per_cpu());
+        assert_eq!(machine.sched_switches(), loaded.sched_switches());
+
+        let proc = loaded.find_process(1).unwrap();
+        assert_eq!(Some("app"), proc.comm_id().map(|id| loaded.strings().from_id(id).unwrap()));
+        assert_eq!(2, proc.samples().len());
+
+        let mapping = &proc.mappings()[0];
+        assert_eq!("/usr/bin/app", loaded.strings().from_id(mapping.filename_id()).unwrap());
+        assert_eq!(0x1000, mapping.start());
+        let node = mapping.node().unwrap();
+        assert_eq!(ExportDevNode::from_parts(8, 1, 42).dev(), node.dev());
+        assert_eq!(42, node.ino());
+        assert_eq!(name_id, mapping.symbols()[0].name_id());
+
+        let sample = &proc.samples()[0];
+        assert_eq!(150, sample.time());
+        assert_eq!(0x1010, sample.ip());
+
+        let mut frames = Vec::new();
+        loaded.callstacks().from_id(sample.callstack_id(), &mut frames).unwrap();
+        assert_eq!(vec![0x1020, 0x1030], frames);
+
+        let data = loaded.sample_record_data(sample);
+        assert_eq!("test", data.record_type().name());
+        assert!(data.record_type().is_original_data());
+        assert_eq!(1, data.record_type().format().fields().len());
+        assert_eq!(&[b'Z'], data.record_data());
+
+        let mut walker = ExportAttributeWalker::default();
+        loaded.sample_attributes(sample, &mut walker);
+        assert_eq!(Some("test"), walker.attributes()[0].label_str(loaded.strings()));
+
+        let span = loaded.sample_span(sample).unwrap();
+        assert_eq!("Span", span.name(loaded.strings()));
+        assert_eq!(120, span.end_time());
+        assert_eq!("Child", span.children()[0].name(loaded.strings()));
+        assert_eq!(115, span.children()[0].end_time());
+
+        let sample = &proc.samples()[1];
+        assert!(matches!(sample.value(), MetricValue::Bytes(64)));
+        assert!(!sample.has_record());
+
+        let (stack, stack_data) = loaded.user_stack(sample).unwrap();
+        assert_eq!(0x2010, stack.fp());
+        assert_eq!(0x2000, stack.sp());
+        assert_eq!(&[1, 2, 3, 4], stack_data);
+        assert!(loaded.user_stack(&proc.samples()[0]).is_none());
+
+        /* Bad data should fail cleanly */
+        assert!(ExportMachine::load_snapshot(&mut &buffer[..buffer.len() / 2]).is_err());
+        assert!(ExportMachine::load_snapshot(&mut &b"NOTASNAPSHOT"[..]).is_err());
+    }
+
+    #[test]
+    fn qpc_freq() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        machine.mark_start_direct(Utc::now(), 100);
+
+        let cpu = machine.sample_kind("cpu");
+        machine.add_comm_exec(1, "app", 100).unwrap();
+        machine.add_sample(200, MetricValue::Duration(50), 1, 1, 0, cpu, &[0x1000]).unwrap();
+
+        let mut buffer = Vec::new();
+        machine.save_snapshot(&mut buffer).unwrap();
+
+        /* Captured at twice our frequency, after magic and version */
+        let freq = ExportMachine::qpc_freq() * 2;
+        buffer[12..20].copy_from_slice(&freq.to_le_bytes());
+
+        let loaded = ExportMachine::load_snapshot(&mut buffer.as_slice()).unwrap();
+
+        assert_eq!(Some(50), loaded.start_qpc());
+
+        let sample = &loaded.find_process(1).unwrap().samples()[0];
+        assert_eq!(100, sample.time());
+        assert!(matches!(sample.value(), MetricValue::Duration(25)));
+    }
+
+    #[test]
+    fn out_of_range_ids() {
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        let cpu = machine.sample_kind("cpu");
+        machine.add_comm_exec(1, "app", 100).unwrap();
+
+        /* Callstack that was never interned */
+        machine.process_mut(1).add_sample(
+            ExportProcessSample::new(200, MetricValue::Count(1), 0, cpu, 1, 0x1000, 1234));
+
+        let mut buffer = Vec::new();
+        machine.save_snapshot(&mut buffer).unwrap();
+
+        assert!(ExportMachine::load_snapshot(&mut buffer.as_slice()).is_err());
+    }
+}
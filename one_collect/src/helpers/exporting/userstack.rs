@@ -0,0 +1,377 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::*;
+use ruwind::{MachineUnwinder, ModuleAccessor, Unwindable, UnwindResult};
+
+/*
+ * Raw user registers and stack of a sample whose unwinding was deferred.
+ * The callstack the sample had when captured is kept, so unwinding can
+ * be repeated later, IE: on another machine or with better debug info.
+ */
+#[derive(Default, Clone, Copy)]
+pub struct ExportUserStack {
+    ip: u64,
+    fp: u64,
+    sp: u64,
+    link: Option<u64>,
+    callstack_id: usize,
+    offset: usize,
+    length: u32,
+}
+
+impl ExportUserStack {
+    pub(crate) fn new(
+        ip: u64,
+        fp: u64,
+        sp: u64,
+        link: Option<u64>,
+        callstack_id: usize,
+        offset: usize,
+        length: u32) -> Self {
+        Self {
+            ip,
+            fp,
+            sp,
+            link,
+            callstack_id,
+            offset,
+            length,
+        }
+    }
+
+    pub(crate) fn from_regs(
+        ip: u64,
+        fp: u64,
+        sp: u64,
+        link: Option<u64>) -> Self {
+        Self {
+            ip,
+            fp,
+            sp,
+            link,
+            ..Default::default()
+        }
+    }
+
+    pub fn ip(&self) -> u64 { self.ip }
+
+    pub fn fp(&self) -> u64 { self.fp }
+
+    pub fn sp(&self) -> u64 { self.sp }
+
+    pub fn link(&self) -> Option<u64> { self.link }
+
+    pub fn callstack_id(&self) -> usize { self.callstack_id }
+
+    pub fn offset(&self) -> usize { self.offset }
+
+    pub fn len(&self) -> usize { self.length as usize }
+
+    pub fn is_empty(&self) -> bool { self.length == 0 }
+
+    pub fn end(&self) -> usize { self.offset + self.len() }
+}
+
+pub(crate) struct ExportUserStacks {
+    stacks: Vec<ExportUserStack>,
+    data: Vec<u8>,
+    committed: usize,
+    pending: Option<(u32, u64, ExportUserStack)>,
+}
+
+impl ExportUserStacks {
+    pub fn new() -> Self {
+        Self {
+            /* Ensure user stack ID 0 is always empty/default */
+            stacks: vec![ExportUserStack::default()],
+            data: Vec::new(),
+            committed: 0,
+            pending: None,
+        }
+    }
+
+    pub fn len(&self) -> usize { self.stacks.len() }
+
+    pub fn stacks(&self) -> &[ExportUserStack] { &self.stacks }
+
+    pub fn get(
+        &self,
+        id: usize) -> Option<&ExportUserStack> {
+        self.stacks.get(id)
+    }
+
+    pub fn data(&self) -> &[u8] { &self.data[..self.committed] }
+
+    pub fn stack_data(
+        &self,
+        stack: &ExportUserStack) -> &[u8] {
+        &self.data[stack.offset..stack.end()]
+    }
+
+    pub fn defer(
+        &mut self,
+        pid: u32,
+        time: u64,
+        mut stack: ExportUserStack,
+        stack_data: &[u8]) {
+        /* Data of a pending stack that was never attached is reused */
+        self.data.truncate(self.committed);
+        self.data.extend_from_slice(stack_data);
+
+        stack.offset = self.committed;
+        stack.length = stack_data.len() as u32;
+
+        self.pending = Some((pid, time, stack));
+    }
+
+    pub fn take_pending(
+        &mut self,
+        pid: u32,
+        time: u64) -> Option<ExportUserStack> {
+        /* Only the sample of the record that deferred the stack matches */
+        match self.pending.take() {
+            Some((pending_pid, pending_time, stack))
+                if pending_pid == pid && pending_time == time => { Some(stack) },
+            _ => { None },
+        }
+    }
+
+    pub fn attach(
+        &mut self,
+        mut stack: ExportUserStack,
+        sample: &mut ExportProcessSample) {
+        stack.callstack_id = sample.callstack_id();
+
+        sample.attach_user_stack(self.push(stack));
+    }
+
+    pub fn push(
+        &mut self,
+        stack: ExportUserStack) -> usize {
+        let id = self.stacks.len();
+
+        self.committed = stack.end();
+        self.stacks.push(stack);
+
+        id
+    }
+
+    pub fn push_data(
+        &mut self,
+        stack: &ExportUserStack,
+        stack_data: &[u8],
+        callstack_id: usize) -> usize {
+        let offset = self.committed;
+
+        self.data.truncate(offset);
+        self.data.extend_from_slice(stack_data);
+
+        self.push(
+            ExportUserStack::new(
+                stack.ip,
+                stack.fp,
+                stack.sp,
+                stack.link,
+                callstack_id,
+                offset,
+                stack.length))
+    }
+
+    pub fn set_data(
+        &mut self,
+        data: Vec<u8>) {
+        self.committed = data.len();
+        self.data = data;
+    }
+}
+
+/* Mappings are looked up as they were at the time of the sample */
+struct ExportUserStackProcess<'a> {
+    process: &'a ExportProcess,
+    time: u64,
+}
+
+impl Unwindable for ExportUserStackProcess<'_> {
+    fn find(
+        &self,
+        ip: u64) -> Option<&dyn CodeSection> {
+        match self.process.find_mapping(ip, Some(self.time)) {
+            Some(mapping) => { Some(mapping) },
+            None => { None },
+        }
+    }
+}
+
+impl ExportMachine {
+    pub fn user_stack(
+        &self,
+        sample: &ExportProcessSample) -> Option<(&ExportUserStack, &[u8])> {
+        if !sample.has_user_stack() {
+            return None;
+        }
+
+        self.user_stacks
+            .get(sample.user_stack_id())
+            .map(|stack| (stack, self.user_stacks.stack_data(stack)))
+    }
+
+    pub fn has_user_stacks(&self) -> bool { self.user_stacks.len() > 1 }
+
+    pub fn unwind_user_stacks_with(
+        &mut self,
+        unwinder: &mut dyn MachineUnwinder,
+        accessor: &dyn ModuleAccessor) -> usize {
+        let mut frames = Vec::new();
+        let mut unwound = 0;
+
+        /* Stats only reflect the latest unwinding */
+        self.unwind_stats.clear();
+
+        for proc in self.procs.values_mut() {
+            let mut callstacks = Vec::new();
+
+            for (index, sample) in proc.samples().iter().enumerate() {
+                if !sample.has_user_stack() {
+                    continue;
+                }
+
+                let stack = match self.user_stacks.get(sample.user_stack_id()) {
+                    Some(stack) => { stack },
+                    None => { continue; },
+                };
+
+                /* Always start from the captured callstack, so this can be repeated */
+                if self.callstacks.from_id(stack.callstack_id(), &mut frames).is_err() {
+                    continue;
+                }
+
+                let process = ExportUserStackProcess {
+                    process: proc,
+                    time: sample.time(),
+                };
+
+                /* The user IP is already within the captured callstack */
+                let mut result = UnwindResult::new();
+                result.frames_pushed = 1;
+
+                unwinder.reset(
+                    stack.ip(),
+                    stack.fp(),
+                    stack.sp());
+
+                if let Some(lr) = stack.link() {
+                    unwinder.set_link_register(lr);
+                }
+
+                unwinder.unwind(
+                    &process,
+                    accessor,
+                    self.user_stacks.stack_data(stack),
+                    &mut frames,
+                    &mut result);
+
+                if result.frames_pushed > 1 {
+                    unwound += 1;
+                }
+
+                self.unwind_stats.add(&result);
+
+                callstacks.push((index, self.callstacks.to_id(&frames)));
+            }
+
+            proc.set_sample_callstacks(&callstacks);
+        }
+
+        unwound
+    }
+
+    /*
+     * Unwinds with the default unwinder, opening modules via the dev nodes
+     * seen during capture. Loaded snapshots have no dev nodes, so unwinding
+     * them on another machine must use unwind_user_stacks_with() and an
+     * accessor that can open the captured modules.
+     */
+    pub fn unwind_user_stacks(&mut self) -> usize {
+        self.os_unwind_user_stacks()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use ruwind::ModuleKey;
+
+    struct NoAccessor {
+    }
+
+    impl ModuleAccessor for NoAccessor {
+        fn open(
+            &self,
+            _key: &ModuleKey) -> Option<File> {
+            None
+        }
+    }
+
+    #[test]
+    fn deferred_unwind() {
+        let base: u64 = 0x555500000000;
+        let sp: u64 = 0x7ffff0000000;
+
+        let mut machine = ExportMachine::new(ExportSettings::default());
+
+        machine.process_mut(1).add_mapping(
+            ExportMapping::new(0, 0, base, base + 0x2000, 0, false, 0, UnwindType::Prolog));
+
+        /* Frame records of [previous FP, return address] */
+        let mut stack_data = vec![0; 16];
+        stack_data.extend_from_slice(&(sp + 32).to_ne_bytes());
+        stack_data.extend_from_slice(&(base + 0x1100).to_ne_bytes());
+        stack_data.extend_from_slice(&0u64.to_ne_bytes());
+        stack_data.extend_from_slice(&(base + 0x1200).to_ne_bytes());
+
+        let stack = ExportUserStack::from_regs(base + 0x1008, sp + 16, sp, None);
+
+        /* Only the user IP is captured with the kernel frames */
+        machine.user_stacks.defer(1, 0, stack, &stack_data);
+        machine.add_sample(0, MetricValue::Count(1), 1, 1, 0, 0, &[0xffff0010, base + 0x1008]).unwrap();
+
+        /* Pending stacks only go to samples of the same process */
+        machine.user_stacks.defer(1, 1, stack, &stack_data);
+        machine.add_sample(1, MetricValue::Count(1), 2, 2, 0, 0, &[base + 0x1008]).unwrap();
+
+        /* Pending stacks only go to the sample of the deferring record */
+        machine.user_stacks.defer(1, 2, stack, &stack_data);
+        machine.add_sample(3, MetricValue::Count(1), 1, 1, 0, 0, &[base + 0x1008]).unwrap();
+
+        let sample = machine.find_process(1).unwrap().samples()[0];
+        let (stack, data) = machine.user_stack(&sample).unwrap();
+
+        assert_eq!(base + 0x1008, stack.ip());
+        assert_eq!(stack_data, data);
+        assert!(!machine.find_process(2).unwrap().samples()[0].has_user_stack());
+        assert!(!machine.find_process(1).unwrap().samples()[1].has_user_stack());
+
+        let mut unwinder = ruwind::frame_pointer_unwinder();
+        let accessor = NoAccessor {};
+        let mut frames = Vec::new();
+
+        /* Unwinding again starts from the captured callstack */
+        for _ in 0..2 {
+            assert_eq!(1, machine.unwind_user_stacks_with(&mut unwinder, &accessor));
+
+            let sample = machine.find_process(1).unwrap().samples()[0];
+            machine.callstacks.from_id(sample.callstack_id(), &mut frames).unwrap();
+
+            assert_eq!(0xffff0010, sample.ip());
+            assert_eq!(vec![base + 0x1008, base + 0x1100, base + 0x1200], frames);
+        }
+
+        /* Stats are not doubled by unwinding again */
+        let total = machine.unwind_stats().total();
+        assert_eq!(1, total.unwinds());
+        assert_eq!(3, total.frames());
+        assert_eq!(0, total.truncated());
+    }
+}
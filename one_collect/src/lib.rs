@@ -43,6 +43,11 @@ pub mod tracefs;
 pub mod procfs;
 #[cfg(any(doc, target_os = "linux"))]
 pub mod perf_event;
+/* perf.data files can be written on any OS */
+#[cfg(not(any(doc, target_os = "linux")))]
+pub mod perf_event {
+    pub mod abi;
+}
 #[cfg(any(doc, target_os = "linux"))]
 pub mod openat;
 #[cfg(any(doc, target_os = "linux"))]
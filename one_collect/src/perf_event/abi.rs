@@ -59,12 +59,74 @@ pub const PERF_SAMPLE_REGS_INTR: u64 = 1 << 18;
 pub const PERF_SAMPLE_PHYS_ADDR: u64 = 1 << 19;
 pub const PERF_SAMPLE_AUX: u64 = 1 << 20;
 pub const PERF_SAMPLE_CGROUP: u64 = 1 << 21;
+pub const PERF_SAMPLE_WEIGHT_STRUCT: u64 = 1 << 24;
 
 pub const PERF_SAMPLE_REGS_ABI_NONE: u64 = 0;
 pub const PERF_SAMPLE_REGS_ABI_32: u64 = 1;
 pub const PERF_SAMPLE_REGS_ABI_64: u64 = 2;
 
+// Branch sample types (branch_sample_type)
+pub const PERF_SAMPLE_BRANCH_USER: u64 = 1 << 0;
+pub const PERF_SAMPLE_BRANCH_KERNEL: u64 = 1 << 1;
+pub const PERF_SAMPLE_BRANCH_HV: u64 = 1 << 2;
+pub const PERF_SAMPLE_BRANCH_ANY: u64 = 1 << 3;
+pub const PERF_SAMPLE_BRANCH_ANY_CALL: u64 = 1 << 4;
+pub const PERF_SAMPLE_BRANCH_ANY_RETURN: u64 = 1 << 5;
+pub const PERF_SAMPLE_BRANCH_IND_CALL: u64 = 1 << 6;
+pub const PERF_SAMPLE_BRANCH_COND: u64 = 1 << 10;
+pub const PERF_SAMPLE_BRANCH_CALL_STACK: u64 = 1 << 11;
+pub const PERF_SAMPLE_BRANCH_IND_JUMP: u64 = 1 << 12;
+pub const PERF_SAMPLE_BRANCH_CALL: u64 = 1 << 13;
+pub const PERF_SAMPLE_BRANCH_NO_FLAGS: u64 = 1 << 14;
+pub const PERF_SAMPLE_BRANCH_NO_CYCLES: u64 = 1 << 15;
+pub const PERF_SAMPLE_BRANCH_TYPE_SAVE: u64 = 1 << 16;
+
+// Data source bit fields (union perf_mem_data_src)
+pub const PERF_MEM_OP_SHIFT: u64 = 0;
+pub const PERF_MEM_OP_LOAD: u64 = 0x02;
+pub const PERF_MEM_OP_STORE: u64 = 0x04;
+pub const PERF_MEM_OP_PFETCH: u64 = 0x08;
+pub const PERF_MEM_OP_EXEC: u64 = 0x10;
+
+pub const PERF_MEM_LVL_SHIFT: u64 = 5;
+pub const PERF_MEM_LVL_HIT: u64 = 0x02;
+pub const PERF_MEM_LVL_MISS: u64 = 0x04;
+pub const PERF_MEM_LVL_L1: u64 = 0x08;
+pub const PERF_MEM_LVL_LFB: u64 = 0x10;
+pub const PERF_MEM_LVL_L2: u64 = 0x20;
+pub const PERF_MEM_LVL_L3: u64 = 0x40;
+pub const PERF_MEM_LVL_LOC_RAM: u64 = 0x80;
+pub const PERF_MEM_LVL_REM_RAM1: u64 = 0x100;
+pub const PERF_MEM_LVL_REM_RAM2: u64 = 0x200;
+pub const PERF_MEM_LVL_REM_CCE1: u64 = 0x400;
+pub const PERF_MEM_LVL_REM_CCE2: u64 = 0x800;
+pub const PERF_MEM_LVL_IO: u64 = 0x1000;
+pub const PERF_MEM_LVL_UNC: u64 = 0x2000;
+
+pub const PERF_MEM_LOCK_SHIFT: u64 = 24;
+pub const PERF_MEM_LOCK_LOCKED: u64 = 0x02;
+
+pub const PERF_MEM_TLB_SHIFT: u64 = 26;
+pub const PERF_MEM_TLB_HIT: u64 = 0x02;
+pub const PERF_MEM_TLB_MISS: u64 = 0x04;
+
+pub const PERF_MEM_LVLNUM_SHIFT: u64 = 33;
+pub const PERF_MEM_LVLNUM_L1: u64 = 0x01;
+pub const PERF_MEM_LVLNUM_L2: u64 = 0x02;
+pub const PERF_MEM_LVLNUM_L3: u64 = 0x03;
+pub const PERF_MEM_LVLNUM_L4: u64 = 0x04;
+pub const PERF_MEM_LVLNUM_ANY_CACHE: u64 = 0x0b;
+pub const PERF_MEM_LVLNUM_LFB: u64 = 0x0c;
+pub const PERF_MEM_LVLNUM_RAM: u64 = 0x0d;
+pub const PERF_MEM_LVLNUM_PMEM: u64 = 0x0e;
+
+pub const PERF_MEM_REMOTE_SHIFT: u64 = 37;
+
+// Size of struct perf_branch_entry { from, to, flags }
+pub const PERF_BRANCH_ENTRY_SIZE: usize = 24;
+
 // Supported record types (header.entry_type)
+pub const PERF_RECORD_MMAP: u32 = 1;
 pub const PERF_RECORD_LOST: u32 = 2;
 pub const PERF_RECORD_COMM: u32 = 3;
 pub const PERF_RECORD_EXIT: u32 = 4;
@@ -73,6 +135,30 @@ pub const PERF_RECORD_SAMPLE: u32 = 9;
 pub const PERF_RECORD_MMAP2: u32 = 10;
 pub const PERF_RECORD_LOST_SAMPLES: u32 = 13;
 pub const PERF_RECORD_SWITCH_CPU_WIDE: u32 = 15;
+pub const PERF_RECORD_CGROUP: u32 = 19;
+
+// perf.data file format
+/* "PERFILE2" as a little endian u64 */
+pub const PERF_FILE_MAGIC: u64 = 0x32454c4946524550;
+pub const PERF_FILE_HEADER_SIZE: u64 = 104;
+
+/* Synthesized records, only present in files */
+pub const PERF_RECORD_USER_TYPE_START: u32 = 64;
+pub const PERF_RECORD_FINISHED_ROUND: u32 = 68;
+
+/* Feature section bits */
+pub const HEADER_HOSTNAME: usize = 3;
+pub const HEADER_OSRELEASE: usize = 4;
+pub const HEADER_VERSION: usize = 5;
+pub const HEADER_ARCH: usize = 6;
+pub const HEADER_NRCPUS: usize = 7;
+pub const HEADER_CPUDESC: usize = 8;
+pub const HEADER_CMDLINE: usize = 11;
+pub const HEADER_EVENT_DESC: usize = 12;
+pub const HEADER_FEAT_BITS: usize = 256;
+
+// perf_event_open() flags
+pub const PERF_FLAG_PID_CGROUP: usize = 1 << 2;
 
 // Known read formats
 pub const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
@@ -119,16 +205,52 @@ pub const FLAG_TEXT_POKE: u64 = 1 << 33;
 pub const PERF_TYPE_HARDWARE: u32 = 0;
 pub const PERF_TYPE_SOFTWARE: u32 = 1;
 pub const PERF_TYPE_TRACEPOINT: u32 = 2;
+pub const PERF_TYPE_HW_CACHE: u32 = 3;
+pub const PERF_TYPE_RAW: u32 = 4;
 
 pub const PERF_EVENT_IOC_ENABLE: i32 = 9216;
 pub const PERF_EVENT_IOC_DISABLE: i32 = 9217;
 pub const PERF_EVENT_IOC_SET_OUTPUT: i32 = 9221;
+pub const PERF_EVENT_IOC_ID: u64 = 0x80082407;
 
 pub const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+pub const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+pub const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
 pub const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+pub const PERF_COUNT_SW_CPU_MIGRATIONS: u64 = 4;
+pub const PERF_COUNT_SW_PAGE_FAULTS_MIN: u64 = 5;
+pub const PERF_COUNT_SW_PAGE_FAULTS_MAJ: u64 = 6;
+pub const PERF_COUNT_SW_ALIGNMENT_FAULTS: u64 = 7;
+pub const PERF_COUNT_SW_EMULATION_FAULTS: u64 = 8;
 pub const PERF_COUNT_SW_BPF_OUTPUT: u64 = 10;
 pub const PERF_COUNT_SW_DUMMY: u64 = 9;
 
+pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+pub const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+pub const PERF_COUNT_HW_CACHE_REFERENCES: u64 = 2;
+pub const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+pub const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+pub const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+pub const PERF_COUNT_HW_BUS_CYCLES: u64 = 6;
+pub const PERF_COUNT_HW_STALLED_CYCLES_FRONTEND: u64 = 7;
+pub const PERF_COUNT_HW_STALLED_CYCLES_BACKEND: u64 = 8;
+pub const PERF_COUNT_HW_REF_CPU_CYCLES: u64 = 9;
+
+pub const PERF_COUNT_HW_CACHE_L1D: u64 = 0;
+pub const PERF_COUNT_HW_CACHE_L1I: u64 = 1;
+pub const PERF_COUNT_HW_CACHE_LL: u64 = 2;
+pub const PERF_COUNT_HW_CACHE_DTLB: u64 = 3;
+pub const PERF_COUNT_HW_CACHE_ITLB: u64 = 4;
+pub const PERF_COUNT_HW_CACHE_BPU: u64 = 5;
+pub const PERF_COUNT_HW_CACHE_NODE: u64 = 6;
+
+pub const PERF_COUNT_HW_CACHE_OP_READ: u64 = 0;
+pub const PERF_COUNT_HW_CACHE_OP_WRITE: u64 = 1;
+pub const PERF_COUNT_HW_CACHE_OP_PREFETCH: u64 = 2;
+
+pub const PERF_COUNT_HW_CACHE_RESULT_ACCESS: u64 = 0;
+pub const PERF_COUNT_HW_CACHE_RESULT_MISS: u64 = 1;
+
 pub const PERF_ATTR_SIZE_VER4: u32 = 104;
 
 /* X86_64 Common Registers */
@@ -141,12 +263,20 @@ pub const PERF_REG_IP: u64 = 1 << 8u64;
 
 /* ARM64 Common Registers */
 #[cfg(target_arch = "aarch64")]
-pub const PERF_REG_BP: u64 = 1 << 30u64;
+pub const PERF_REG_BP: u64 = 1 << 29u64;
+#[cfg(target_arch = "aarch64")]
+pub const PERF_REG_LR: u64 = 1 << 30u64;
 #[cfg(target_arch = "aarch64")]
 pub const PERF_REG_SP: u64 = 1 << 31u64;
 #[cfg(target_arch = "aarch64")]
 pub const PERF_REG_IP: u64 = 1 << 32u64;
 
+/* Registers needed to unwind user stacks */
+#[cfg(target_arch = "x86_64")]
+pub const PERF_REGS_UNWIND: u64 = PERF_REG_BP | PERF_REG_SP | PERF_REG_IP;
+#[cfg(target_arch = "aarch64")]
+pub const PERF_REGS_UNWIND: u64 = PERF_REG_BP | PERF_REG_LR | PERF_REG_SP | PERF_REG_IP;
+
 pub const PERF_CONTEXT_HV: u64 = 0xFFFFFFFFFFFFFFE0;
 pub const PERF_CONTEXT_KERNEL: u64 = 0xFFFFFFFFFFFFFF80;
 pub const PERF_CONTEXT_USER: u64 = 0xFFFFFFFFFFFFFE00;
@@ -155,6 +285,9 @@ pub const PERF_CONTEXT_GUEST_KERNEL: u64 = 0xFFFFFFFFFFFFF780;
 pub const PERF_CONTEXT_GUEST_USER: u64 = 0xFFFFFFFFFFFFF600;
 pub const PERF_CONTEXT_MAX: u64 = 0xFFFFFFFFFFFFF001;
 
+pub const PERF_RECORD_MISC_KERNEL: u16 = 1;
+pub const PERF_RECORD_MISC_USER: u16 = 2;
+
 pub const PERF_RECORD_MISC_MMAP_DATA: u16 = 1 << 13u16;
 pub const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13u16;
 pub const PERF_RECORD_MISC_FORK_EXEC: u16 = 1 << 13u16;
@@ -330,6 +463,12 @@ impl Sample {
         output.extend_from_slice(&time.to_ne_bytes());
     }
 
+    pub fn write_period(
+        period: u64,
+        output: &mut Vec<u8>) {
+        output.extend_from_slice(&period.to_ne_bytes());
+    }
+
     pub fn write_raw(
         data: &[u8],
         output: &mut Vec<u8>) {
@@ -0,0 +1,134 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::abi;
+
+const BRANCH_FLAG_MISPRED: u64 = 1 << 0;
+const BRANCH_FLAG_PREDICTED: u64 = 1 << 1;
+const BRANCH_FLAG_IN_TX: u64 = 1 << 2;
+const BRANCH_FLAG_ABORT: u64 = 1 << 3;
+const BRANCH_CYCLES_SHIFT: u64 = 4;
+const BRANCH_CYCLES_MASK: u64 = 0xFFFF;
+const BRANCH_TYPE_SHIFT: u64 = 20;
+const BRANCH_TYPE_MASK: u64 = 0xF;
+
+/*
+ * Decoded struct perf_branch_entry from PERF_SAMPLE_BRANCH_STACK.
+ * Entries are ordered newest branch first, so the code between the
+ * target of an entry and the source of the entry before it is a
+ * basic block that ran straight through.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BranchEntry {
+    from: u64,
+    to: u64,
+    flags: u64,
+}
+
+impl BranchEntry {
+    pub fn new(
+        from: u64,
+        to: u64,
+        flags: u64) -> Self {
+        Self {
+            from,
+            to,
+            flags,
+        }
+    }
+
+    pub fn decode(
+        data: &[u8],
+        entries: &mut Vec<BranchEntry>) -> anyhow::Result<()> {
+        entries.clear();
+
+        if !data.len().is_multiple_of(abi::PERF_BRANCH_ENTRY_SIZE) {
+            anyhow::bail!("Branch stack data is truncated.");
+        }
+
+        for entry in data.chunks_exact(abi::PERF_BRANCH_ENTRY_SIZE) {
+            entries.push(
+                Self::new(
+                    u64::from_ne_bytes(entry[0..8].try_into()?),
+                    u64::from_ne_bytes(entry[8..16].try_into()?),
+                    u64::from_ne_bytes(entry[16..24].try_into()?)));
+        }
+
+        Ok(())
+    }
+
+    pub fn write(
+        &self,
+        data: &mut Vec<u8>) {
+        data.extend_from_slice(&self.from.to_ne_bytes());
+        data.extend_from_slice(&self.to.to_ne_bytes());
+        data.extend_from_slice(&self.flags.to_ne_bytes());
+    }
+
+    pub fn from_ip(&self) -> u64 { self.from }
+
+    pub fn to_ip(&self) -> u64 { self.to }
+
+    pub fn flags(&self) -> u64 { self.flags }
+
+    pub fn mispredicted(&self) -> bool { self.flags & BRANCH_FLAG_MISPRED != 0 }
+
+    pub fn predicted(&self) -> bool { self.flags & BRANCH_FLAG_PREDICTED != 0 }
+
+    pub fn in_tx(&self) -> bool { self.flags & BRANCH_FLAG_IN_TX != 0 }
+
+    pub fn abort(&self) -> bool { self.flags & BRANCH_FLAG_ABORT != 0 }
+
+    pub fn cycles(&self) -> u16 {
+        ((self.flags >> BRANCH_CYCLES_SHIFT) & BRANCH_CYCLES_MASK) as u16
+    }
+
+    pub fn branch_type(&self) -> u8 {
+        ((self.flags >> BRANCH_TYPE_SHIFT) & BRANCH_TYPE_MASK) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let mut data = Vec::new();
+        let mut entries = Vec::new();
+
+        /* Mispredicted, 12 cycles */
+        BranchEntry::new(0x1000, 0x2000, 1 | (12 << 4)).write(&mut data);
+
+        /* Predicted, in transaction, type 3 */
+        BranchEntry::new(0x2010, 0x3000, 2 | 4 | (3 << 20)).write(&mut data);
+
+        BranchEntry::decode(&data, &mut entries).unwrap();
+
+        assert_eq!(2, entries.len());
+
+        let entry = &entries[0];
+        assert_eq!(0x1000, entry.from_ip());
+        assert_eq!(0x2000, entry.to_ip());
+        assert!(entry.mispredicted());
+        assert!(!entry.predicted());
+        assert_eq!(12, entry.cycles());
+        assert_eq!(0, entry.branch_type());
+
+        let entry = &entries[1];
+        assert_eq!(0x2010, entry.from_ip());
+        assert_eq!(0x3000, entry.to_ip());
+        assert!(!entry.mispredicted());
+        assert!(entry.predicted());
+        assert!(entry.in_tx());
+        assert!(!entry.abort());
+        assert_eq!(3, entry.branch_type());
+
+        /* Empty stacks are valid */
+        BranchEntry::decode(&[], &mut entries).unwrap();
+        assert!(entries.is_empty());
+
+        /* Truncated data should fail */
+        assert!(BranchEntry::decode(&data[..30], &mut entries).is_err());
+    }
+}
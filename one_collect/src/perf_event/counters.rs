@@ -0,0 +1,288 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::*;
+use super::rb::{CpuRingBuf, PerfCounterRead, pmu_open_error};
+
+pub struct PerfCounterValue {
+    name: String,
+    raw_value: u64,
+    value: u64,
+    time_enabled: u64,
+    time_running: u64,
+}
+
+impl PerfCounterValue {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            raw_value: 0,
+            value: 0,
+            time_enabled: 0,
+            time_running: 0,
+        }
+    }
+
+    fn add(
+        &mut self,
+        read: &PerfCounterRead) {
+        self.raw_value += read.value;
+        self.time_enabled += read.time_enabled;
+        self.time_running += read.time_running;
+        self.value += Self::scale(read);
+    }
+
+    fn scale(read: &PerfCounterRead) -> u64 {
+        /*
+         * When more counters are requested than the PMU has, the
+         * kernel multiplexes them. Scale the raw value by the time
+         * the counter was enabled vs actually counting.
+         */
+        if read.time_running == 0 {
+            return 0;
+        }
+
+        if read.time_running >= read.time_enabled {
+            return read.value;
+        }
+
+        let scaled =
+            read.value as u128 *
+            read.time_enabled as u128 /
+            read.time_running as u128;
+
+        scaled.min(u64::MAX as u128) as u64
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn raw_value(&self) -> u64 { self.raw_value }
+
+    pub fn value(&self) -> u64 { self.value }
+
+    pub fn time_enabled(&self) -> u64 { self.time_enabled }
+
+    pub fn time_running(&self) -> u64 { self.time_running }
+
+    pub fn is_multiplexed(&self) -> bool { self.time_running < self.time_enabled }
+}
+
+struct PerfCounter {
+    name: String,
+    bufs: Vec<CpuRingBuf>,
+}
+
+pub struct PerfCountersBuilder {
+    target_pids: Option<Vec<i32>>,
+    counters: Vec<(String, RingBufBuilder<Pmu>)>,
+}
+
+impl Default for PerfCountersBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PerfCountersBuilder {
+    pub fn new() -> Self {
+        Self {
+            target_pids: None,
+            counters: Vec::new(),
+        }
+    }
+
+    pub fn with_target_pid(
+        &mut self,
+        pid: i32) -> Self {
+        let mut pids = self.target_pids.take().unwrap_or_default();
+
+        pids.push(pid);
+
+        Self {
+            target_pids: Some(pids),
+            counters: std::mem::take(&mut self.counters),
+        }
+    }
+
+    pub fn with_counter(
+        &mut self,
+        name: &str,
+        builder: RingBufBuilder<Pmu>) -> Self {
+        let mut counters = std::mem::take(&mut self.counters);
+
+        counters.push((name.to_owned(), builder));
+
+        Self {
+            target_pids: self.target_pids.take(),
+            counters,
+        }
+    }
+
+    pub fn build(&mut self) -> IOResult<PerfCounters> {
+        let mut counters = Vec::new();
+
+        for (name, builder) in &self.counters {
+            let common = builder.build_counter();
+            let mut bufs = Vec::new();
+
+            /* Counters are per-CPU, either system wide or per-pid */
+            for cpu in 0..cpu_count() {
+                match &self.target_pids {
+                    Some(pids) => {
+                        for pid in pids {
+                            let mut buf = common.for_cpu(cpu);
+                            buf.open(Some(*pid)).map_err(pmu_open_error)?;
+                            bufs.push(buf);
+                        }
+                    },
+                    None => {
+                        let mut buf = common.for_cpu(cpu);
+                        buf.open(None).map_err(pmu_open_error)?;
+                        bufs.push(buf);
+                    },
+                }
+            }
+
+            counters.push(
+                PerfCounter {
+                    name: name.clone(),
+                    bufs,
+                });
+        }
+
+        Ok(PerfCounters {
+            counters,
+        })
+    }
+}
+
+pub struct PerfCounters {
+    counters: Vec<PerfCounter>,
+}
+
+impl PerfCounters {
+    pub fn enable(&mut self) -> IOResult<()> {
+        for counter in &self.counters {
+            for buf in &counter.bufs {
+                buf.enable()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn disable(&mut self) -> IOResult<()> {
+        for counter in &self.counters {
+            for buf in &counter.bufs {
+                buf.disable()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&self) -> IOResult<Vec<PerfCounterValue>> {
+        let mut values = Vec::new();
+
+        for counter in &self.counters {
+            let mut value = PerfCounterValue::new(&counter.name);
+
+            for buf in &counter.bufs {
+                value.add(&buf.read_counter()?);
+            }
+
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(
+        value: u64,
+        time_enabled: u64,
+        time_running: u64) -> PerfCounterRead {
+        PerfCounterRead {
+            value,
+            time_enabled,
+            time_running,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn scaling() {
+        let mut value = PerfCounterValue::new("cycles");
+
+        /* Full time counting is not scaled */
+        value.add(&read(1000, 100, 100));
+        assert_eq!(1000, value.value());
+        assert!(!value.is_multiplexed());
+
+        /* Half time counting doubles */
+        value.add(&read(1000, 100, 50));
+        assert_eq!(3000, value.value());
+        assert_eq!(2000, value.raw_value());
+        assert_eq!(200, value.time_enabled());
+        assert_eq!(150, value.time_running());
+        assert!(value.is_multiplexed());
+
+        /* Never scheduled has no value */
+        value.add(&read(0, 100, 0));
+        assert_eq!(3000, value.value());
+        assert_eq!("cycles", value.name());
+    }
+
+    #[test]
+    fn builder() {
+        let builder = PerfCountersBuilder::new()
+            .with_counter(
+                "cycles",
+                RingBufBuilder::for_hardware(abi::PERF_COUNT_HW_CPU_CYCLES))
+            .with_counter(
+                "cpu-clock",
+                RingBufBuilder::for_pmu(PERF_TYPE_SOFTWARE, abi::PERF_COUNT_SW_CPU_CLOCK))
+            .with_target_pid(1);
+
+        assert_eq!(2, builder.counters.len());
+        assert_eq!(Some(vec![1]), builder.target_pids);
+
+        let common = builder.counters[0].1.build_counter();
+        let attributes = common.for_cpu(0).ancillary();
+
+        assert!(attributes.read_format() & abi::PERF_FORMAT_TOTAL_TIME_ENABLED != 0);
+        assert!(attributes.read_format() & abi::PERF_FORMAT_TOTAL_TIME_RUNNING != 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn cpu_clock() {
+        let mut counters = PerfCountersBuilder::new()
+            .with_counter(
+                "cpu-clock",
+                RingBufBuilder::for_pmu(PERF_TYPE_SOFTWARE, abi::PERF_COUNT_SW_CPU_CLOCK))
+            .with_target_pid(0)
+            .build()
+            .unwrap();
+
+        counters.enable().unwrap();
+
+        /* Spin for 10 ms */
+        let now = std::time::Instant::now();
+
+        while now.elapsed().as_millis() < 10 {
+            /* Nothing */
+        }
+
+        counters.disable().unwrap();
+
+        let values = counters.read().unwrap();
+
+        assert_eq!(1, values.len());
+        assert!(values[0].value() > 0);
+    }
+}
@@ -221,3 +221,21 @@ pub fn cswitch() -> Event {
 
     event
 }
+
+pub fn cgroup() -> Event {
+    let mut event = Event::new(0, "__cgroup".into());
+    let mut offset: usize = 0;
+    let len: usize = 8;
+    let format = event.format_mut();
+
+    format.add_field(EventField::new(
+        "id".into(), "u64".into(),
+        LocationType::Static, offset, len));
+    offset += len;
+
+    format.add_field(EventField::new(
+        "path[]".into(), "char".into(),
+        LocationType::StaticString, offset, 0));
+
+    event
+}
@@ -0,0 +1,888 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::io::{Read, Seek, SeekFrom, BufReader};
+use std::path::Path;
+
+use super::*;
+
+pub use super::abi::{
+    PERF_FILE_MAGIC,
+    PERF_FILE_HEADER_SIZE,
+    PERF_RECORD_USER_TYPE_START,
+    PERF_RECORD_FINISHED_ROUND,
+    HEADER_HOSTNAME,
+    HEADER_OSRELEASE,
+    HEADER_VERSION,
+    HEADER_ARCH,
+    HEADER_NRCPUS,
+    HEADER_CPUDESC,
+    HEADER_CMDLINE,
+    HEADER_FEAT_BITS,
+};
+
+#[derive(Default)]
+pub struct PerfFileFeatures {
+    hostname: Option<String>,
+    os_release: Option<String>,
+    version: Option<String>,
+    arch: Option<String>,
+    cpu_desc: Option<String>,
+    cpus_available: Option<u32>,
+    cpus_online: Option<u32>,
+    cmdline: Vec<String>,
+}
+
+impl PerfFileFeatures {
+    pub fn hostname(&self) -> Option<&str> { self.hostname.as_deref() }
+
+    pub fn os_release(&self) -> Option<&str> { self.os_release.as_deref() }
+
+    pub fn version(&self) -> Option<&str> { self.version.as_deref() }
+
+    pub fn arch(&self) -> Option<&str> { self.arch.as_deref() }
+
+    pub fn cpu_desc(&self) -> Option<&str> { self.cpu_desc.as_deref() }
+
+    pub fn cpus_available(&self) -> Option<u32> { self.cpus_available }
+
+    pub fn cpus_online(&self) -> Option<u32> { self.cpus_online }
+
+    pub fn cmdline(&self) -> &[String] { &self.cmdline }
+}
+
+#[derive(Clone, Copy)]
+struct FileSection {
+    offset: u64,
+    size: u64,
+}
+
+impl FileSection {
+    fn from_slice(slice: &[u8]) -> Self {
+        Self {
+            offset: read_u64(slice, 0),
+            size: read_u64(slice, 8),
+        }
+    }
+
+    fn within(
+        &self,
+        file_len: u64) -> bool {
+        match self.offset.checked_add(self.size) {
+            Some(end) => { end <= file_len },
+            None => { false },
+        }
+    }
+}
+
+struct RoundEntry {
+    time: u64,
+    start: usize,
+    len: usize,
+    attr: usize,
+    cpu: u32,
+}
+
+pub struct PerfFileDataSource<R: Read + Seek> {
+    reader: R,
+    attrs: Vec<Rc<perf_event_attr>>,
+    attr_ids: HashMap<u64, usize>,
+    features: PerfFileFeatures,
+    data_remaining: u64,
+    round: Vec<u8>,
+    entries: Vec<RoundEntry>,
+    index: usize,
+    last_time: u64,
+    max_time: u64,
+    flush_time: u64,
+    pending: Vec<u8>,
+    pending_entries: Vec<RoundEntry>,
+    unknown_samples: u64,
+    read_error: Writable<Option<IOError>>,
+}
+
+impl PerfFileDataSource<BufReader<File>> {
+    pub fn open(
+        path: impl AsRef<Path>) -> IOResult<Self> {
+        let file = File::open(path)?;
+
+        Self::new(BufReader::new(file))
+    }
+}
+
+fn read_u64(
+    slice: &[u8],
+    offset: usize) -> u64 {
+    match slice.get(offset .. offset + 8) {
+        Some(slice) => { u64::from_ne_bytes(slice.try_into().unwrap()) },
+        None => { 0 },
+    }
+}
+
+fn read_u32(
+    slice: &[u8],
+    offset: usize) -> u32 {
+    match slice.get(offset .. offset + 4) {
+        Some(slice) => { u32::from_ne_bytes(slice.try_into().unwrap()) },
+        None => { 0 },
+    }
+}
+
+fn read_string(
+    slice: &[u8],
+    offset: &mut usize) -> Option<String> {
+    let len = slice.get(*offset .. *offset + 4)?;
+    let len = u32::from_ne_bytes(len.try_into().unwrap()) as usize;
+    *offset += 4;
+
+    let data = slice.get(*offset .. *offset + len)?;
+    *offset += len;
+
+    /* Strings are NULL padded to alignment */
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+
+    Some(String::from_utf8_lossy(&data[..end]).to_string())
+}
+
+fn parse_attr(slice: &[u8]) -> perf_event_attr {
+    /* Older or newer files may have different sizes, missing fields are 0 */
+    perf_event_attr {
+        event_type: read_u32(slice, 0),
+        size: read_u32(slice, 4),
+        config: read_u64(slice, 8),
+        sample_period_freq: read_u64(slice, 16),
+        sample_type: read_u64(slice, 24),
+        read_format: read_u64(slice, 32),
+        flags: read_u64(slice, 40),
+        wakeup_events_watermark: read_u32(slice, 48),
+        bp_type: read_u32(slice, 52),
+        bp_addr: read_u64(slice, 56),
+        bp_len: read_u64(slice, 64),
+        branch_sample_type: read_u64(slice, 72),
+        sample_regs_user: read_u64(slice, 80),
+        sample_stack_user: read_u32(slice, 88),
+        clockid: read_u32(slice, 92) as i32,
+        sample_regs_intr: read_u64(slice, 96),
+    }
+}
+
+fn sample_id_offset(attr: &perf_event_attr) -> Option<usize> {
+    let mut offset = abi::Header::data_offset();
+
+    if attr.has_format(abi::PERF_SAMPLE_IDENTIFIER) {
+        return Some(offset);
+    }
+
+    if !attr.has_format(abi::PERF_SAMPLE_ID) {
+        return None;
+    }
+
+    if attr.has_format(abi::PERF_SAMPLE_IP) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_TID) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_TIME) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_ADDR) { offset += 8; }
+
+    Some(offset)
+}
+
+fn sample_time_cpu_offsets(attr: &perf_event_attr) -> (Option<usize>, Option<usize>) {
+    let mut offset = abi::Header::data_offset();
+    let mut time = None;
+    let mut cpu = None;
+
+    if attr.has_format(abi::PERF_SAMPLE_IDENTIFIER) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_IP) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_TID) { offset += 8; }
+
+    if attr.has_format(abi::PERF_SAMPLE_TIME) {
+        time = Some(offset);
+        offset += 8;
+    }
+
+    if attr.has_format(abi::PERF_SAMPLE_ADDR) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_ID) { offset += 8; }
+    if attr.has_format(abi::PERF_SAMPLE_STREAM_ID) { offset += 8; }
+
+    if attr.has_format(abi::PERF_SAMPLE_CPU) {
+        cpu = Some(offset);
+    }
+
+    (time, cpu)
+}
+
+impl<R: Read + Seek> PerfFileDataSource<R> {
+    pub fn new(
+        mut reader: R) -> IOResult<Self> {
+        let mut header = [0u8; PERF_FILE_HEADER_SIZE as usize];
+
+        /* Sizes within the file are untrusted, bound them to its length */
+        let file_len = reader.seek(SeekFrom::End(0))?;
+
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header)?;
+
+        let magic = read_u64(&header, 0);
+
+        if magic != PERF_FILE_MAGIC {
+            if magic == PERF_FILE_MAGIC.swap_bytes() {
+                return Err(io_error("Cross-endian perf.data files are not supported"));
+            }
+
+            return Err(io_error("Not a perf.data file"));
+        }
+
+        if read_u64(&header, 8) != PERF_FILE_HEADER_SIZE {
+            return Err(io_error("Unsupported perf.data header size (pipe mode?)"));
+        }
+
+        let attr_size = read_u64(&header, 16) as usize;
+        let attr_section = FileSection::from_slice(&header[24..40]);
+        let data_section = FileSection::from_slice(&header[40..56]);
+        let feature_bits = &header[72..104];
+
+        if attr_size <= 16 {
+            return Err(io_error("Invalid perf.data attribute size"));
+        }
+
+        if !attr_section.within(file_len) || !data_section.within(file_len) {
+            return Err(io_error("perf.data section is outside of the file"));
+        }
+
+        /* Attributes, each is followed by an ID file section */
+        let mut attrs = Vec::new();
+        let mut attr_ids = HashMap::new();
+        let mut buffer = vec![0u8; attr_section.size as usize];
+
+        reader.seek(SeekFrom::Start(attr_section.offset))?;
+        reader.read_exact(&mut buffer)?;
+
+        for entry in buffer.chunks_exact(attr_size) {
+            let attr = parse_attr(&entry[..attr_size - 16]);
+            let ids = FileSection::from_slice(&entry[attr_size - 16..]);
+            let index = attrs.len();
+
+            if !ids.within(file_len) {
+                return Err(io_error("perf.data ID section is outside of the file"));
+            }
+
+            let mut id_data = vec![0u8; ids.size as usize];
+            reader.seek(SeekFrom::Start(ids.offset))?;
+            reader.read_exact(&mut id_data)?;
+
+            for id in id_data.chunks_exact(8) {
+                attr_ids.insert(read_u64(id, 0), index);
+            }
+
+            attrs.push(Rc::new(attr));
+        }
+
+        if attrs.is_empty() {
+            return Err(io_error("No attributes within perf.data file"));
+        }
+
+        /* Feature sections are located after data */
+        let mut features = PerfFileFeatures::default();
+        let mut sections = Vec::new();
+
+        for bit in 0..HEADER_FEAT_BITS {
+            if feature_bits[bit / 8] & (1 << (bit % 8)) != 0 {
+                sections.push(bit);
+            }
+        }
+
+        if !sections.is_empty() {
+            let mut table = vec![0u8; sections.len() * 16];
+
+            reader.seek(SeekFrom::Start(data_section.offset + data_section.size))?;
+
+            /* Features are optional, tolerate truncated files */
+            if reader.read_exact(&mut table).is_ok() {
+                for (i, bit) in sections.iter().enumerate() {
+                    let section = FileSection::from_slice(&table[i * 16..]);
+
+                    if !section.within(file_len) {
+                        break;
+                    }
+
+                    let mut data = vec![0u8; section.size as usize];
+                    reader.seek(SeekFrom::Start(section.offset))?;
+
+                    if reader.read_exact(&mut data).is_err() {
+                        break;
+                    }
+
+                    Self::parse_feature(*bit, &data, &mut features);
+                }
+            }
+        }
+
+        reader.seek(SeekFrom::Start(data_section.offset))?;
+
+        Ok(Self {
+            reader,
+            attrs,
+            attr_ids,
+            features,
+            data_remaining: data_section.size,
+            round: Vec::new(),
+            entries: Vec::new(),
+            index: 0,
+            last_time: 0,
+            max_time: 0,
+            flush_time: 0,
+            pending: Vec::new(),
+            pending_entries: Vec::new(),
+            unknown_samples: 0,
+            read_error: Writable::new(None),
+        })
+    }
+
+    pub fn features(&self) -> &PerfFileFeatures { &self.features }
+
+    pub fn unknown_samples(&self) -> u64 { self.unknown_samples }
+
+    /*
+     * Reading stops at the first truncated or corrupt record, the
+     * error is kept here so it can be checked after parsing.
+     */
+    pub fn read_error(&self) -> ReadOnly<Option<IOError>> {
+        self.read_error.read_only()
+    }
+
+    pub fn attributes(&self) -> &[Rc<perf_event_attr>] { &self.attrs }
+
+    fn parse_feature(
+        bit: usize,
+        data: &[u8],
+        features: &mut PerfFileFeatures) {
+        let mut offset = 0;
+
+        match bit {
+            HEADER_HOSTNAME => { features.hostname = read_string(data, &mut offset); },
+            HEADER_OSRELEASE => { features.os_release = read_string(data, &mut offset); },
+            HEADER_VERSION => { features.version = read_string(data, &mut offset); },
+            HEADER_ARCH => { features.arch = read_string(data, &mut offset); },
+            HEADER_CPUDESC => { features.cpu_desc = read_string(data, &mut offset); },
+            HEADER_NRCPUS if data.len() >= 8 => {
+                features.cpus_available = Some(read_u32(data, 0));
+                features.cpus_online = Some(read_u32(data, 4));
+            },
+            HEADER_CMDLINE => {
+                let count = read_u32(data, 0);
+                offset += 4;
+
+                for _ in 0..count {
+                    match read_string(data, &mut offset) {
+                        Some(arg) => { features.cmdline.push(arg); },
+                        None => { break; },
+                    }
+                }
+            },
+            _ => {
+                /* Unused feature */
+            },
+        }
+    }
+
+    fn find_attr(
+        &self,
+        record: &[u8],
+        entry_type: u32) -> Option<usize> {
+        if self.attrs.len() == 1 {
+            return Some(0);
+        }
+
+        /* perf requires ID positions to be the same across attributes */
+        let first = &self.attrs[0];
+
+        let offset = if entry_type == abi::PERF_RECORD_SAMPLE {
+            sample_id_offset(first)
+        } else {
+            match first.non_sampled_id_offsets() {
+                Some(offsets) => {
+                    let start = record.len().saturating_sub(offsets.size);
+
+                    match offsets.identifier {
+                        Some(identifier) => { Some(start + identifier) },
+                        None => { offsets.id.map(|id| start + id) },
+                    }
+                },
+                None => { None },
+            }
+        };
+
+        match offset {
+            Some(offset) => {
+                self.attr_ids
+                    .get(&read_u64(record, offset))
+                    .copied()
+            },
+            None => { Some(0) },
+        }
+    }
+
+    fn time_and_cpu(
+        &self,
+        record: &[u8],
+        entry_type: u32,
+        attr: usize) -> (Option<u64>, u32) {
+        let attr = &self.attrs[attr];
+
+        let (time, cpu) = if entry_type == abi::PERF_RECORD_SAMPLE {
+            sample_time_cpu_offsets(attr)
+        } else {
+            match attr.non_sampled_id_offsets() {
+                Some(offsets) => {
+                    let start = record.len().saturating_sub(offsets.size);
+
+                    (offsets.time.map(|time| start + time),
+                     offsets.cpu.map(|cpu| start + cpu))
+                },
+                None => { (None, None) },
+            }
+        };
+
+        (time.map(|time| read_u64(record, time)),
+         cpu.map_or(0, |cpu| read_u32(record, cpu)))
+    }
+
+    fn flush_pending(
+        &mut self,
+        limit: Option<u64>) {
+        /* Stable to keep equal times in file order */
+        self.pending_entries.sort_by_key(|entry| entry.time);
+
+        let mut held = Vec::new();
+        let mut held_entries = Vec::new();
+
+        for entry in self.pending_entries.drain(..) {
+            let data = &self.pending[entry.start .. entry.start + entry.len];
+
+            let (output, entries) = match limit {
+                Some(limit) if entry.time > limit => { (&mut held, &mut held_entries) },
+                _ => { (&mut self.round, &mut self.entries) },
+            };
+
+            let start = output.len();
+            output.extend_from_slice(data);
+
+            entries.push(
+                RoundEntry {
+                    start,
+                    ..entry
+                });
+        }
+
+        self.pending = held;
+        self.pending_entries = held_entries;
+    }
+
+    fn load_round(&mut self) -> IOResult<()> {
+        self.round.clear();
+        self.entries.clear();
+        self.index = 0;
+
+        let mut header = [0u8; 8];
+
+        while self.data_remaining >= 8 {
+            self.reader.read_exact(&mut header)?;
+
+            let entry_type = read_u32(&header, 0);
+            let size = u16::from_ne_bytes(header[6..8].try_into().unwrap()) as u64;
+
+            if size < 8 || size > self.data_remaining {
+                return Err(io_error("Corrupt perf.data record"));
+            }
+
+            self.data_remaining -= size;
+
+            let start = self.pending.len();
+            self.pending.extend_from_slice(&header);
+            self.pending.resize(start + size as usize, 0);
+            self.reader.read_exact(&mut self.pending[start + 8..])?;
+
+            /*
+             * Rounds only guarantee that data up to the prior round's
+             * max time is complete, later data may still be written
+             * out of order in the next round, so hold it back.
+             */
+            if entry_type == PERF_RECORD_FINISHED_ROUND {
+                self.pending.truncate(start);
+
+                let limit = self.flush_time;
+                self.flush_time = self.max_time;
+                self.flush_pending(Some(limit));
+
+                if self.entries.is_empty() {
+                    continue;
+                }
+
+                return Ok(());
+            }
+
+            /* Skip perf tool synthesized records */
+            if entry_type >= PERF_RECORD_USER_TYPE_START {
+                self.pending.truncate(start);
+                continue;
+            }
+
+            let record = &self.pending[start..];
+
+            let attr = match self.find_attr(record, entry_type) {
+                Some(attr) => { attr },
+                None => {
+                    /* Samples cannot be parsed without their attribute */
+                    if entry_type == abi::PERF_RECORD_SAMPLE {
+                        self.pending.truncate(start);
+                        self.unknown_samples += 1;
+                        continue;
+                    }
+
+                    /* Non-sample ID positions are the same across attributes */
+                    0
+                },
+            };
+
+            let (time, cpu) = self.time_and_cpu(record, entry_type, attr);
+
+            /* Keep untimed records in place relative to neighbors */
+            let time = time.unwrap_or(self.last_time);
+            self.last_time = time;
+            self.max_time = self.max_time.max(time);
+
+            self.pending_entries.push(
+                RoundEntry {
+                    time,
+                    start,
+                    len: size as usize,
+                    attr,
+                    cpu,
+                });
+        }
+
+        /* End of data, everything left is complete */
+        self.flush_pending(None);
+
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> PerfDataSource for PerfFileDataSource<R> {
+    fn enable(&mut self) -> IOResult<()> { Ok(()) }
+
+    fn disable(&mut self) -> IOResult<()> { Ok(()) }
+
+    fn target_pids(&self) -> Option<&[i32]> { None }
+
+    fn create_bpf_files(
+        &mut self,
+        _event: Option<&Event>) -> IOResult<Vec<PerfDataFile>> {
+        Err(io_error("BPF is not supported with perf.data files"))
+    }
+
+    fn add_event(
+        &mut self,
+        _event: &Event) -> IOResult<()> {
+        /* Events are already within the file */
+        Ok(())
+    }
+
+    fn begin_reading(&mut self) { }
+
+    fn read(
+        &mut self,
+        _timeout: Duration) -> Option<PerfData<'_>> {
+        while self.index >= self.entries.len() {
+            if self.data_remaining < 8 &&
+               self.pending_entries.is_empty() {
+                return None;
+            }
+
+            if let Err(err) = self.load_round() {
+                /* Records read before the error are still delivered */
+                *self.read_error.borrow_mut() = Some(err);
+                self.data_remaining = 0;
+            }
+        }
+
+        let entry = &self.entries[self.index];
+        self.index += 1;
+
+        Some(PerfData {
+            ancillary: AncillaryData {
+                cpu: entry.cpu,
+                attributes: self.attrs[entry.attr].clone(),
+            },
+            raw_data: &self.round[entry.start .. entry.start + entry.len],
+        })
+    }
+
+    fn end_reading(&mut self) { }
+
+    fn more(&self) -> bool {
+        self.index < self.entries.len() ||
+        !self.pending_entries.is_empty() ||
+        self.data_remaining >= 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::cell::RefCell;
+
+    fn write_section(
+        offset: u64,
+        size: u64,
+        output: &mut Vec<u8>) {
+        output.extend_from_slice(&offset.to_ne_bytes());
+        output.extend_from_slice(&size.to_ne_bytes());
+    }
+
+    fn write_attr(
+        attr: &perf_event_attr,
+        output: &mut Vec<u8>) {
+        output.extend_from_slice(&attr.event_type.to_ne_bytes());
+        output.extend_from_slice(&attr.size.to_ne_bytes());
+        output.extend_from_slice(&attr.config.to_ne_bytes());
+        output.extend_from_slice(&attr.sample_period_freq.to_ne_bytes());
+        output.extend_from_slice(&attr.sample_type.to_ne_bytes());
+        output.extend_from_slice(&attr.read_format.to_ne_bytes());
+        output.extend_from_slice(&attr.flags.to_ne_bytes());
+        output.resize(output.len() + 56, 0);
+    }
+
+    fn sample(
+        pid: u32,
+        time: u64,
+        cpu: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut record = Vec::new();
+
+        data.extend_from_slice(&pid.to_ne_bytes());
+        data.extend_from_slice(&pid.to_ne_bytes());
+        data.extend_from_slice(&time.to_ne_bytes());
+        data.extend_from_slice(&cpu.to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+
+        abi::Header::write(abi::PERF_RECORD_SAMPLE, 0, &data, &mut record);
+
+        record
+    }
+
+    fn comm(
+        pid: u32,
+        comm: &str,
+        time: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut record = Vec::new();
+
+        data.extend_from_slice(&pid.to_ne_bytes());
+        data.extend_from_slice(&pid.to_ne_bytes());
+        data.extend_from_slice(comm.as_bytes());
+        data.resize(data.len() + 8 - (comm.len() % 8), 0);
+
+        /* sample_id_all: TID, TIME, CPU */
+        data.extend_from_slice(&pid.to_ne_bytes());
+        data.extend_from_slice(&pid.to_ne_bytes());
+        data.extend_from_slice(&time.to_ne_bytes());
+        data.extend_from_slice(&1u32.to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+
+        abi::Header::write(abi::PERF_RECORD_COMM, 0, &data, &mut record);
+
+        record
+    }
+
+    fn perf_file(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut attr = perf_event_attr::default();
+        attr.event_type = abi::PERF_TYPE_SOFTWARE;
+        attr.size = abi::PERF_ATTR_SIZE_VER4;
+        attr.config = abi::PERF_COUNT_SW_CPU_CLOCK;
+        attr.sample_type =
+            abi::PERF_SAMPLE_TID |
+            abi::PERF_SAMPLE_TIME |
+            abi::PERF_SAMPLE_CPU;
+        attr.flags = abi::FLAG_SAMPLE_ID_ALL;
+
+        let hostname = b"testhost\0\0\0\0\0\0\0\0";
+
+        let mut data = Vec::new();
+        for record in records {
+            data.extend_from_slice(record);
+        }
+
+        let attr_offset = PERF_FILE_HEADER_SIZE;
+        let attr_size = abi::PERF_ATTR_SIZE_VER4 as u64 + 16;
+        let ids_offset = attr_offset + attr_size;
+        let data_offset = ids_offset + 8;
+        let features_offset = data_offset + data.len() as u64;
+        let hostname_offset = features_offset + 16;
+
+        let mut file = Vec::new();
+
+        /* Header */
+        file.extend_from_slice(&PERF_FILE_MAGIC.to_ne_bytes());
+        file.extend_from_slice(&PERF_FILE_HEADER_SIZE.to_ne_bytes());
+        file.extend_from_slice(&attr_size.to_ne_bytes());
+        write_section(attr_offset, attr_size, &mut file);
+        write_section(data_offset, data.len() as u64, &mut file);
+        write_section(0, 0, &mut file);
+        let mut bits = [0u8; 32];
+        bits[HEADER_HOSTNAME / 8] |= 1 << (HEADER_HOSTNAME % 8);
+        file.extend_from_slice(&bits);
+
+        /* Attrs */
+        write_attr(&attr, &mut file);
+        write_section(ids_offset, 8, &mut file);
+        file.extend_from_slice(&1u64.to_ne_bytes());
+
+        /* Data */
+        file.extend_from_slice(&data);
+
+        /* Features */
+        write_section(hostname_offset, 4 + hostname.len() as u64, &mut file);
+        file.extend_from_slice(&(hostname.len() as u32).to_ne_bytes());
+        file.extend_from_slice(hostname);
+
+        file
+    }
+
+    #[test]
+    fn it_works() {
+        let mut round_end = Vec::new();
+        abi::Header::write(PERF_RECORD_FINISHED_ROUND, 0, &[], &mut round_end);
+
+        let records = vec![
+            sample(1, 30, 2),
+            comm(1, "test", 10),
+            sample(1, 20, 3),
+            round_end,
+            sample(1, 40, 0),
+        ];
+
+        let source = PerfFileDataSource::new(
+            Cursor::new(perf_file(&records))).unwrap();
+
+        assert_eq!(Some("testhost"), source.features().hostname());
+        assert_eq!(1, source.attributes().len());
+
+        let mut session = PerfSession::new(Box::new(source));
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let time_data = session.time_data_ref();
+        let ancillary = session.ancillary_data();
+
+        let comm_seen = seen.clone();
+        let comm_time = time_data.clone();
+        session.comm_event().add_callback(move |data| {
+            let time = comm_time.get_u64(data.full_data())?;
+            comm_seen.borrow_mut().push((time, u32::MAX));
+            Ok(())
+        });
+
+        let sample_seen = seen.clone();
+        session.cpu_profile_event().add_callback(move |data| {
+            let time = time_data.get_u64(data.full_data())?;
+            let cpu = ancillary.borrow().cpu();
+            sample_seen.borrow_mut().push((time, cpu));
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        let seen = seen.borrow();
+        assert_eq!(4, seen.len());
+        assert_eq!((10, u32::MAX), seen[0]);
+        assert_eq!((20, 3), seen[1]);
+        assert_eq!((30, 2), seen[2]);
+        assert_eq!((40, 0), seen[3]);
+    }
+
+    #[test]
+    fn cross_round_order() {
+        let mut round_end = Vec::new();
+        abi::Header::write(PERF_RECORD_FINISHED_ROUND, 0, &[], &mut round_end);
+
+        /* Later rounds may contain data older than the prior round */
+        let records = vec![
+            sample(1, 10, 0),
+            sample(1, 30, 0),
+            round_end.clone(),
+            sample(1, 20, 1),
+            sample(1, 50, 1),
+            round_end,
+            sample(1, 40, 2),
+        ];
+
+        let source = PerfFileDataSource::new(
+            Cursor::new(perf_file(&records))).unwrap();
+
+        let mut session = PerfSession::new(Box::new(source));
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let time_data = session.time_data_ref();
+
+        let sample_seen = seen.clone();
+        session.cpu_profile_event().add_callback(move |data| {
+            let time = time_data.get_u64(data.full_data())?;
+            sample_seen.borrow_mut().push(time);
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(vec![10, 20, 30, 40, 50], *seen.borrow());
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut corrupt = sample(1, 20, 0);
+        corrupt[6..8].copy_from_slice(&4u16.to_ne_bytes());
+
+        let records = vec![
+            sample(1, 10, 0),
+            corrupt,
+        ];
+
+        let source = PerfFileDataSource::new(
+            Cursor::new(perf_file(&records))).unwrap();
+
+        let read_error = source.read_error();
+        let mut session = PerfSession::new(Box::new(source));
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let time_data = session.time_data_ref();
+
+        let sample_seen = seen.clone();
+        session.cpu_profile_event().add_callback(move |data| {
+            let time = time_data.get_u64(data.full_data())?;
+            sample_seen.borrow_mut().push(time);
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        /* Prior records are kept, the error is not swallowed */
+        assert_eq!(vec![10], *seen.borrow());
+        assert!(read_error.borrow().is_some());
+
+        /* Sections past the end of the file are rejected */
+        let mut file = perf_file(&[sample(1, 10, 0)]);
+        file[32..40].copy_from_slice(&u64::MAX.to_ne_bytes());
+        assert!(PerfFileDataSource::new(Cursor::new(file)).is_err());
+
+        let mut file = perf_file(&[sample(1, 10, 0)]);
+        let len = file.len() as u64;
+        file[48..56].copy_from_slice(&len.to_ne_bytes());
+        assert!(PerfFileDataSource::new(Cursor::new(file)).is_err());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let data = vec![0u8; PERF_FILE_HEADER_SIZE as usize];
+
+        assert!(PerfFileDataSource::new(Cursor::new(data)).is_err());
+    }
+}
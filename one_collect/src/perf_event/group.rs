@@ -0,0 +1,189 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::collections::HashMap;
+
+use super::abi;
+
+/*
+ * Decodes PERF_SAMPLE_READ data into per-sample counter deltas.
+ * The kernel reports running totals for each counter, so the
+ * previous value of each counter is kept by ID to produce the
+ * amount counted since the last sample of that counter.
+ */
+#[derive(Default)]
+pub struct GroupReadDecoder {
+    last_values: HashMap<u64, u64>,
+}
+
+impl GroupReadDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reset(&mut self) {
+        self.last_values.clear();
+    }
+
+    pub fn decode(
+        &mut self,
+        read_format: u64,
+        data: &[u8],
+        deltas: &mut Vec<u64>) -> anyhow::Result<()> {
+        deltas.clear();
+
+        let mut offset = 0;
+
+        if read_format & abi::PERF_FORMAT_GROUP == 0 {
+            /* Single reads lead with the value, then the times */
+            let value = Self::read_u64(data, &mut offset)?;
+
+            Self::skip_times(read_format, data, &mut offset)?;
+
+            let delta = self.read_value(read_format, 0, value, data, &mut offset)?;
+
+            deltas.push(delta);
+
+            return Ok(());
+        }
+
+        /* Group reads lead with the count and times, then the values */
+        let count = Self::read_u64(data, &mut offset)?;
+
+        Self::skip_times(read_format, data, &mut offset)?;
+
+        for i in 0..count {
+            let value = Self::read_u64(data, &mut offset)?;
+
+            let delta = self.read_value(read_format, i, value, data, &mut offset)?;
+
+            deltas.push(delta);
+        }
+
+        Ok(())
+    }
+
+    fn skip_times(
+        read_format: u64,
+        data: &[u8],
+        offset: &mut usize) -> anyhow::Result<()> {
+        if read_format & abi::PERF_FORMAT_TOTAL_TIME_ENABLED != 0 {
+            Self::read_u64(data, offset)?;
+        }
+
+        if read_format & abi::PERF_FORMAT_TOTAL_TIME_RUNNING != 0 {
+            Self::read_u64(data, offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_value(
+        &mut self,
+        read_format: u64,
+        index: u64,
+        value: u64,
+        data: &[u8],
+        offset: &mut usize) -> anyhow::Result<u64> {
+        /* Without IDs, fall back to the position in the group */
+        let id = if read_format & abi::PERF_FORMAT_ID != 0 {
+            Self::read_u64(data, offset)?
+        } else {
+            index
+        };
+
+        if read_format & abi::PERF_FORMAT_LOST != 0 {
+            Self::read_u64(data, offset)?;
+        }
+
+        let last = self.last_values.insert(id, value).unwrap_or(0);
+
+        Ok(value.saturating_sub(last))
+    }
+
+    fn read_u64(
+        data: &[u8],
+        offset: &mut usize) -> anyhow::Result<u64> {
+        let start = *offset;
+        let end = start + 8;
+
+        if end > data.len() {
+            anyhow::bail!("Read data is truncated.");
+        }
+
+        *offset = end;
+
+        Ok(u64::from_ne_bytes(data[start..end].try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_u64(
+        data: &mut Vec<u8>,
+        value: u64) {
+        data.extend_from_slice(&value.to_ne_bytes());
+    }
+
+    #[test]
+    fn group_deltas() {
+        let mut decoder = GroupReadDecoder::new();
+        let mut deltas = Vec::new();
+        let mut data = Vec::new();
+
+        let read_format =
+            abi::PERF_FORMAT_GROUP |
+            abi::PERF_FORMAT_ID;
+
+        /* nr, { value, id } * nr */
+        push_u64(&mut data, 2);
+        push_u64(&mut data, 1000);
+        push_u64(&mut data, 10);
+        push_u64(&mut data, 2500);
+        push_u64(&mut data, 11);
+
+        decoder.decode(read_format, &data, &mut deltas).unwrap();
+        assert_eq!(vec![1000, 2500], deltas);
+
+        data.clear();
+        push_u64(&mut data, 2);
+        push_u64(&mut data, 1500);
+        push_u64(&mut data, 10);
+        push_u64(&mut data, 4000);
+        push_u64(&mut data, 11);
+
+        decoder.decode(read_format, &data, &mut deltas).unwrap();
+        assert_eq!(vec![500, 1500], deltas);
+
+        /* Truncated data should fail */
+        assert!(decoder.decode(read_format, &data[..16], &mut deltas).is_err());
+
+        /* Reset starts over */
+        decoder.reset();
+        decoder.decode(read_format, &data, &mut deltas).unwrap();
+        assert_eq!(vec![1500, 4000], deltas);
+    }
+
+    #[test]
+    fn single_deltas() {
+        let mut decoder = GroupReadDecoder::new();
+        let mut deltas = Vec::new();
+        let mut data = Vec::new();
+
+        let read_format =
+            abi::PERF_FORMAT_TOTAL_TIME_ENABLED |
+            abi::PERF_FORMAT_TOTAL_TIME_RUNNING |
+            abi::PERF_FORMAT_ID;
+
+        /* value, enabled, running, id */
+        push_u64(&mut data, 42);
+        push_u64(&mut data, 100);
+        push_u64(&mut data, 100);
+        push_u64(&mut data, 7);
+
+        decoder.decode(read_format, &data, &mut deltas).unwrap();
+        assert_eq!(vec![42], deltas);
+    }
+}
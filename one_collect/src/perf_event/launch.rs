@@ -0,0 +1,439 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::ffi::CString;
+use std::io::{Error as IOError, Result as IOResult};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use libc::*;
+
+/*
+ * Launches a command that is held right before exec until started.
+ * This allows per-task perf events to be opened with inherit and
+ * enable on exec against the child PID, so capture begins exactly
+ * at exec and follows all of its descendants.
+ */
+pub struct LaunchedProcess {
+    pid: i32,
+    start_fd: Option<i32>,
+    error_fd: Option<i32>,
+    exit_code: Option<i32>,
+}
+
+impl LaunchedProcess {
+    pub fn spawn(
+        program: &str,
+        args: &[String]) -> IOResult<Self> {
+        /*
+         * Allocate everything up front, the child must not allocate.
+         * PATH is searched here as well, since execvp is not async
+         * signal safe and we fork from a multi-threaded process.
+         */
+        let path = Self::resolve_path(program)?;
+        let mut c_args = Vec::new();
+
+        c_args.push(CString::new(program)?);
+
+        for arg in args {
+            c_args.push(CString::new(arg.as_str())?);
+        }
+
+        let mut argv: Vec<*const c_char> = c_args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .collect();
+
+        argv.push(std::ptr::null());
+
+        let mut start_fds = [0i32; 2];
+        let mut error_fds = [0i32; 2];
+
+        unsafe {
+            if pipe2(start_fds.as_mut_ptr(), O_CLOEXEC) != 0 {
+                return Err(IOError::last_os_error());
+            }
+
+            if pipe2(error_fds.as_mut_ptr(), O_CLOEXEC) != 0 {
+                let error = IOError::last_os_error();
+                close(start_fds[0]);
+                close(start_fds[1]);
+                return Err(error);
+            }
+
+            match fork() {
+                -1 => {
+                    let error = IOError::last_os_error();
+
+                    for fd in start_fds.iter().chain(error_fds.iter()) {
+                        close(*fd);
+                    }
+
+                    Err(error)
+                },
+                0 => {
+                    close(start_fds[1]);
+                    close(error_fds[0]);
+
+                    /* Wait for the parent to start us, EOF means abandoned */
+                    let mut byte = 0u8;
+
+                    let read_len = loop {
+                        let result = read(start_fds[0], &mut byte as *mut u8 as *mut c_void, 1);
+
+                        if result == -1 && *__errno_location() == EINTR {
+                            continue;
+                        }
+
+                        break result;
+                    };
+
+                    if read_len == 1 {
+                        execv(path.as_ptr(), argv.as_ptr());
+
+                        /* Only get here on failure, report errno to parent */
+                        let errno = *__errno_location();
+
+                        write(
+                            error_fds[1],
+                            &errno as *const i32 as *const c_void,
+                            std::mem::size_of::<i32>());
+                    }
+
+                    _exit(127);
+                },
+                pid => {
+                    close(start_fds[0]);
+                    close(error_fds[1]);
+
+                    Ok(Self {
+                        pid,
+                        start_fd: Some(start_fds[1]),
+                        error_fd: Some(error_fds[0]),
+                        exit_code: None,
+                    })
+                },
+            }
+        }
+    }
+
+    fn resolve_path(program: &str) -> IOResult<CString> {
+        /* Like execvp, names with a slash are used as-is */
+        if program.contains('/') {
+            return Ok(CString::new(program)?);
+        }
+
+        if let Some(paths) = std::env::var_os("PATH") {
+            for dir in std::env::split_paths(&paths) {
+                let path = dir.join(program);
+
+                if Self::is_executable(&path) {
+                    return Ok(CString::new(path.into_os_string().into_vec())?);
+                }
+            }
+        }
+
+        /* Not found, exec reports the error once started */
+        Ok(CString::new(program)?)
+    }
+
+    fn is_executable(path: &Path) -> bool {
+        match std::fs::metadata(path) {
+            Ok(metadata) => {
+                metadata.is_file() &&
+                metadata.permissions().mode() & 0o111 != 0
+            },
+            Err(_) => { false },
+        }
+    }
+
+    pub fn pid(&self) -> i32 { self.pid }
+
+    pub fn start(&mut self) -> IOResult<()> {
+        let start_fd = match self.start_fd.take() {
+            Some(fd) => { fd },
+            None => { return Ok(()); },
+        };
+
+        let error_fd = self.error_fd.take().unwrap_or(-1);
+
+        unsafe {
+            let byte = 1u8;
+
+            let written = write(start_fd, &byte as *const u8 as *const c_void, 1);
+            let write_error = IOError::last_os_error();
+
+            close(start_fd);
+
+            if written != 1 {
+                close(error_fd);
+                return Err(write_error);
+            }
+
+            /* Closed on successful exec, otherwise we get errno */
+            let mut errno = 0i32;
+
+            let read_len = loop {
+                let result = read(
+                    error_fd,
+                    &mut errno as *mut i32 as *mut c_void,
+                    std::mem::size_of::<i32>());
+
+                if result == -1 && *__errno_location() == EINTR {
+                    continue;
+                }
+
+                break result;
+            };
+
+            close(error_fd);
+
+            if read_len == std::mem::size_of::<i32>() as isize {
+                return Err(IOError::from_raw_os_error(errno));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn exit_code(&self) -> Option<i32> { self.exit_code }
+
+    pub fn exit_waiter(&self) -> impl FnOnce() -> IOResult<()> + Send + 'static {
+        let pid = self.pid;
+
+        /*
+         * Blocks until the process exits but leaves it to be reaped
+         * by wait(), so the PID stays ours and can still be killed.
+         */
+        move || {
+            let mut info: siginfo_t = unsafe { std::mem::zeroed() };
+
+            loop {
+                let result = unsafe {
+                    waitid(
+                        P_PID,
+                        pid as id_t,
+                        &mut info,
+                        WEXITED | WNOWAIT)
+                };
+
+                if result == 0 {
+                    return Ok(());
+                }
+
+                let error = IOError::last_os_error();
+
+                if error.raw_os_error() != Some(EINTR) {
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    pub fn kill(&mut self) -> IOResult<i32> {
+        /* Already reaped, the PID may belong to someone else now */
+        if let Some(code) = self.exit_code {
+            return Ok(code);
+        }
+
+        unsafe {
+            if libc::kill(self.pid, SIGKILL) != 0 {
+                return Err(IOError::last_os_error());
+            }
+        }
+
+        self.wait()
+    }
+
+    pub fn try_wait(&mut self) -> IOResult<Option<i32>> {
+        self.wait_with_options(WNOHANG)
+    }
+
+    pub fn wait(&mut self) -> IOResult<i32> {
+        match self.wait_with_options(0)? {
+            Some(code) => { Ok(code) },
+            None => { Err(IOError::other("Process did not exit.")) },
+        }
+    }
+
+    fn wait_with_options(
+        &mut self,
+        options: i32) -> IOResult<Option<i32>> {
+        if self.exit_code.is_some() {
+            return Ok(self.exit_code);
+        }
+
+        let mut status = 0i32;
+
+        unsafe {
+            loop {
+                match waitpid(self.pid, &mut status, options) {
+                    -1 => {
+                        let error = IOError::last_os_error();
+
+                        if error.raw_os_error() == Some(EINTR) {
+                            continue;
+                        }
+
+                        return Err(error);
+                    },
+                    0 => { return Ok(None); },
+                    _ => { break; },
+                }
+            }
+        }
+
+        /* Signals are reported like shells do, 128 + signal */
+        let code = if WIFEXITED(status) {
+            WEXITSTATUS(status)
+        } else if WIFSIGNALED(status) {
+            128 + WTERMSIG(status)
+        } else {
+            return Ok(None);
+        };
+
+        self.exit_code = Some(code);
+
+        Ok(self.exit_code)
+    }
+}
+
+impl Drop for LaunchedProcess {
+    fn drop(&mut self) {
+        /* Never started children see EOF and exit */
+        let started = self.start_fd.is_none();
+
+        unsafe {
+            if let Some(fd) = self.start_fd.take() {
+                close(fd);
+            }
+
+            if let Some(fd) = self.error_fd.take() {
+                close(fd);
+            }
+        }
+
+        /* Reap so the child is not left as a zombie */
+        if !started {
+            let _ = self.wait();
+        } else if let Ok(None) = self.try_wait() {
+            let _ = self.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launch() {
+        let mut process = LaunchedProcess::spawn(
+            "sh",
+            &["-c".into(), "exit 3".into()]).unwrap();
+
+        assert!(process.pid() > 0);
+
+        /* Held until started */
+        assert_eq!(None, process.try_wait().unwrap());
+
+        process.start().unwrap();
+
+        assert_eq!(3, process.wait().unwrap());
+        assert_eq!(Some(3), process.exit_code());
+    }
+
+    #[test]
+    fn launch_missing() {
+        let mut process = LaunchedProcess::spawn(
+            "/does/not/exist",
+            &[]).unwrap();
+
+        let error = process.start().unwrap_err();
+
+        assert_eq!(Some(ENOENT), error.raw_os_error());
+        assert_eq!(127, process.wait().unwrap());
+    }
+
+    #[test]
+    fn launch_path() {
+        let path = LaunchedProcess::resolve_path("sh").unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!(path.ends_with("/sh"));
+
+        let path = LaunchedProcess::resolve_path("/does/not/exist").unwrap();
+        assert_eq!("/does/not/exist", path.to_str().unwrap());
+    }
+
+    #[test]
+    fn launch_exit_waiter() {
+        let mut process = LaunchedProcess::spawn(
+            "sh",
+            &["-c".into(), "exit 3".into()]).unwrap();
+
+        process.start().unwrap();
+
+        let waiter = process.exit_waiter();
+        std::thread::spawn(waiter).join().unwrap().unwrap();
+
+        /* Not reaped by the waiter */
+        assert_eq!(Some(3), process.try_wait().unwrap());
+    }
+
+    #[test]
+    fn launch_kill() {
+        let mut process = LaunchedProcess::spawn(
+            "sleep",
+            &["60".into()]).unwrap();
+
+        process.start().unwrap();
+
+        assert_eq!(None, process.try_wait().unwrap());
+        assert_eq!(128 + SIGKILL, process.kill().unwrap());
+
+        /* Reaped, so killing again is a no-op */
+        assert_eq!(128 + SIGKILL, process.kill().unwrap());
+    }
+
+    #[test]
+    fn launch_abandoned() {
+        let process = LaunchedProcess::spawn(
+            "sh",
+            &["-c".into(), "exit 0".into()]).unwrap();
+
+        let pid = process.pid();
+
+        drop(process);
+
+        /* Never exec'd, exits on its own and is reaped by drop */
+        let mut status = 0;
+
+        unsafe {
+            assert_eq!(-1, waitpid(pid, &mut status, WNOHANG));
+            assert_eq!(Some(ECHILD), IOError::last_os_error().raw_os_error());
+        }
+    }
+
+    #[test]
+    fn launch_dropped() {
+        let mut process = LaunchedProcess::spawn(
+            "sleep",
+            &["60".into()]).unwrap();
+
+        process.start().unwrap();
+
+        let pid = process.pid();
+
+        drop(process);
+
+        /* Still running children are killed and reaped by drop */
+        let mut status = 0;
+
+        unsafe {
+            assert_eq!(-1, waitpid(pid, &mut status, WNOHANG));
+            assert_eq!(Some(ECHILD), IOError::last_os_error().raw_os_error());
+        }
+    }
+}
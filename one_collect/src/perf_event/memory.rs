@@ -0,0 +1,226 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::abi;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryLevel {
+    Unknown,
+    L1,
+    Lfb,
+    L2,
+    L3,
+    L4,
+    Cache,
+    Ram,
+    RemoteRam,
+    RemoteCache,
+    Pmem,
+    Io,
+    Uncached,
+}
+
+impl MemoryLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryLevel::Unknown => { "Unknown" },
+            MemoryLevel::L1 => { "L1" },
+            MemoryLevel::Lfb => { "LFB" },
+            MemoryLevel::L2 => { "L2" },
+            MemoryLevel::L3 => { "L3" },
+            MemoryLevel::L4 => { "L4" },
+            MemoryLevel::Cache => { "Cache" },
+            MemoryLevel::Ram => { "DRAM" },
+            MemoryLevel::RemoteRam => { "Remote DRAM" },
+            MemoryLevel::RemoteCache => { "Remote Cache" },
+            MemoryLevel::Pmem => { "PMEM" },
+            MemoryLevel::Io => { "IO" },
+            MemoryLevel::Uncached => { "Uncached" },
+        }
+    }
+}
+
+/*
+ * Decodes the union perf_mem_data_src from PERF_SAMPLE_DATA_SRC.
+ * Newer kernels report the level number, older ones only report
+ * the legacy level bits, so both are checked.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDataSource {
+    data_src: u64,
+}
+
+impl MemoryDataSource {
+    pub fn new(data_src: u64) -> Self {
+        Self {
+            data_src,
+        }
+    }
+
+    fn field(
+        &self,
+        shift: u64,
+        bits: u64) -> u64 {
+        (self.data_src >> shift) & ((1 << bits) - 1)
+    }
+
+    fn op(&self) -> u64 { self.field(abi::PERF_MEM_OP_SHIFT, 5) }
+
+    fn lvl(&self) -> u64 { self.field(abi::PERF_MEM_LVL_SHIFT, 14) }
+
+    fn lvl_num(&self) -> u64 { self.field(abi::PERF_MEM_LVLNUM_SHIFT, 4) }
+
+    fn tlb(&self) -> u64 { self.field(abi::PERF_MEM_TLB_SHIFT, 7) }
+
+    pub fn data_src(&self) -> u64 { self.data_src }
+
+    pub fn is_load(&self) -> bool { self.op() & abi::PERF_MEM_OP_LOAD != 0 }
+
+    pub fn is_store(&self) -> bool { self.op() & abi::PERF_MEM_OP_STORE != 0 }
+
+    pub fn is_prefetch(&self) -> bool { self.op() & abi::PERF_MEM_OP_PFETCH != 0 }
+
+    pub fn is_exec(&self) -> bool { self.op() & abi::PERF_MEM_OP_EXEC != 0 }
+
+    pub fn is_hit(&self) -> bool { self.lvl() & abi::PERF_MEM_LVL_HIT != 0 }
+
+    pub fn is_miss(&self) -> bool { self.lvl() & abi::PERF_MEM_LVL_MISS != 0 }
+
+    pub fn is_remote(&self) -> bool { self.field(abi::PERF_MEM_REMOTE_SHIFT, 1) != 0 }
+
+    pub fn is_locked(&self) -> bool {
+        self.field(abi::PERF_MEM_LOCK_SHIFT, 2) & abi::PERF_MEM_LOCK_LOCKED != 0
+    }
+
+    pub fn is_tlb_hit(&self) -> bool { self.tlb() & abi::PERF_MEM_TLB_HIT != 0 }
+
+    pub fn is_tlb_miss(&self) -> bool { self.tlb() & abi::PERF_MEM_TLB_MISS != 0 }
+
+    pub fn level(&self) -> MemoryLevel {
+        match self.lvl_num() {
+            abi::PERF_MEM_LVLNUM_L1 => { return MemoryLevel::L1; },
+            abi::PERF_MEM_LVLNUM_L2 => { return MemoryLevel::L2; },
+            abi::PERF_MEM_LVLNUM_L3 => { return MemoryLevel::L3; },
+            abi::PERF_MEM_LVLNUM_L4 => { return MemoryLevel::L4; },
+            abi::PERF_MEM_LVLNUM_ANY_CACHE => {
+                if self.is_remote() {
+                    return MemoryLevel::RemoteCache;
+                }
+
+                return MemoryLevel::Cache;
+            },
+            abi::PERF_MEM_LVLNUM_LFB => { return MemoryLevel::Lfb; },
+            abi::PERF_MEM_LVLNUM_RAM => {
+                if self.is_remote() {
+                    return MemoryLevel::RemoteRam;
+                }
+
+                return MemoryLevel::Ram;
+            },
+            abi::PERF_MEM_LVLNUM_PMEM => { return MemoryLevel::Pmem; },
+            _ => { },
+        }
+
+        /* Fallback to legacy level bits */
+        let lvl = self.lvl();
+
+        if lvl & abi::PERF_MEM_LVL_L1 != 0 {
+            MemoryLevel::L1
+        } else if lvl & abi::PERF_MEM_LVL_LFB != 0 {
+            MemoryLevel::Lfb
+        } else if lvl & abi::PERF_MEM_LVL_L2 != 0 {
+            MemoryLevel::L2
+        } else if lvl & abi::PERF_MEM_LVL_L3 != 0 {
+            MemoryLevel::L3
+        } else if lvl & abi::PERF_MEM_LVL_LOC_RAM != 0 {
+            MemoryLevel::Ram
+        } else if lvl & (abi::PERF_MEM_LVL_REM_RAM1 | abi::PERF_MEM_LVL_REM_RAM2) != 0 {
+            MemoryLevel::RemoteRam
+        } else if lvl & (abi::PERF_MEM_LVL_REM_CCE1 | abi::PERF_MEM_LVL_REM_CCE2) != 0 {
+            MemoryLevel::RemoteCache
+        } else if lvl & abi::PERF_MEM_LVL_IO != 0 {
+            MemoryLevel::Io
+        } else if lvl & abi::PERF_MEM_LVL_UNC != 0 {
+            MemoryLevel::Uncached
+        } else {
+            MemoryLevel::Unknown
+        }
+    }
+
+    pub fn description(&self) -> String {
+        let result = if self.is_hit() {
+            " hit"
+        } else if self.is_miss() {
+            " miss"
+        } else {
+            ""
+        };
+
+        format!("{}{}", self.level().name(), result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_src(
+        op: u64,
+        lvl: u64,
+        lvl_num: u64) -> MemoryDataSource {
+        MemoryDataSource::new(
+            (op << abi::PERF_MEM_OP_SHIFT) |
+            (lvl << abi::PERF_MEM_LVL_SHIFT) |
+            (lvl_num << abi::PERF_MEM_LVLNUM_SHIFT))
+    }
+
+    #[test]
+    fn decode() {
+        /* Legacy L1 load hit */
+        let src = data_src(
+            abi::PERF_MEM_OP_LOAD,
+            abi::PERF_MEM_LVL_L1 | abi::PERF_MEM_LVL_HIT,
+            0);
+
+        assert!(src.is_load());
+        assert!(!src.is_store());
+        assert!(src.is_hit());
+        assert!(!src.is_miss());
+        assert_eq!(MemoryLevel::L1, src.level());
+        assert_eq!("L1 hit", src.description());
+
+        /* Legacy local DRAM load, after L3 miss */
+        let src = data_src(
+            abi::PERF_MEM_OP_LOAD,
+            abi::PERF_MEM_LVL_LOC_RAM | abi::PERF_MEM_LVL_HIT,
+            0);
+
+        assert_eq!(MemoryLevel::Ram, src.level());
+        assert_eq!("DRAM hit", src.description());
+
+        /* Level numbers take priority, remote DRAM */
+        let src = MemoryDataSource::new(
+            data_src(
+                abi::PERF_MEM_OP_STORE,
+                abi::PERF_MEM_LVL_MISS,
+                abi::PERF_MEM_LVLNUM_RAM).data_src() |
+            (1 << abi::PERF_MEM_REMOTE_SHIFT) |
+            (abi::PERF_MEM_TLB_MISS << abi::PERF_MEM_TLB_SHIFT) |
+            (abi::PERF_MEM_LOCK_LOCKED << abi::PERF_MEM_LOCK_SHIFT));
+
+        assert!(src.is_store());
+        assert!(src.is_miss());
+        assert!(src.is_remote());
+        assert!(src.is_tlb_miss());
+        assert!(!src.is_tlb_hit());
+        assert!(src.is_locked());
+        assert_eq!(MemoryLevel::RemoteRam, src.level());
+        assert_eq!("Remote DRAM miss", src.description());
+
+        /* Nothing known */
+        let src = MemoryDataSource::new(0);
+
+        assert_eq!(MemoryLevel::Unknown, src.level());
+        assert_eq!("Unknown", src.description());
+    }
+}
@@ -18,17 +18,51 @@ use std::os::fd::FromRawFd;
 
 pub mod abi;
 pub mod rb;
+pub mod file;
 mod events;
 mod bpf;
+mod group;
+mod branch;
+mod memory;
+mod launch;
+mod counters;
 
 use abi::*;
 
 pub use rb::source::RingBufSessionBuilder;
-pub use rb::{RingBufOptions, RingBufBuilder};
+pub use rb::{RingBufOptions, RingBufBuilder, RingBufGroupBuilder, Pmu};
 pub use rb::cpu_count;
+pub use file::PerfFileDataSource;
+pub use group::GroupReadDecoder;
+pub use branch::BranchEntry;
+pub use memory::{MemoryDataSource, MemoryLevel};
+pub use launch::LaunchedProcess;
+pub use counters::{PerfCountersBuilder, PerfCounters, PerfCounterValue};
 
 static EMPTY: &[u8] = &[];
 
+/// How a PMU profile event was configured.  The same (type, config)
+/// can be opened as a plain sample, a group leader or a memory access
+/// event, so this is part of the lookup key for profile samples.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PmuEventKind {
+    Sample,
+    Group,
+    Memory,
+}
+
+impl PmuEventKind {
+    fn from_attributes(attributes: &perf_event_attr) -> Self {
+        if attributes.has_read_format(abi::PERF_FORMAT_GROUP) {
+            PmuEventKind::Group
+        } else if attributes.has_format(abi::PERF_SAMPLE_DATA_SRC) {
+            PmuEventKind::Memory
+        } else {
+            PmuEventKind::Sample
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct AncillaryData {
     cpu: u32,
@@ -101,11 +135,17 @@ impl<'a> PerfData<'a> {
         self.ancillary.attributes.sample_regs_user.count_ones() as usize
     }
 
+    fn regs_intr_count(&self) -> usize {
+        self.ancillary.attributes.sample_regs_intr.count_ones() as usize
+    }
+
     fn non_sampled_id_offsets(&self) -> Option<SampleIdOffsets> {
         self.ancillary.non_sampled_id_offsets()
     }
 
-    fn read_format_size(&self) -> usize {
+    fn read_format_size(
+        &self,
+        offset: usize) -> Result<usize, TryFromSliceError> {
         let mut size: usize = 0;
 
         if self.has_read_format(abi::PERF_FORMAT_TOTAL_TIME_ENABLED) {
@@ -116,19 +156,28 @@ impl<'a> PerfData<'a> {
             size += 8;
         }
 
+        /* Each value is followed by optional ID and lost counts */
+        let mut value_size: usize = 8;
+
         if self.has_read_format(abi::PERF_FORMAT_ID) {
-            size += 8;
+            value_size += 8;
         }
 
-        if self.has_read_format(abi::PERF_FORMAT_GROUP) {
-            size += 8;
+        if self.has_read_format(abi::PERF_FORMAT_LOST) {
+            value_size += 8;
         }
 
-        if self.has_read_format(abi::PERF_FORMAT_LOST) {
+        if self.has_read_format(abi::PERF_FORMAT_GROUP) {
+            /* Group reads lead with the number of values */
+            let count = self.read_u64(offset)? as usize;
+
             size += 8;
+            size += count * value_size;
+        } else {
+            size += value_size;
         }
 
-        size
+        Ok(size)
     }
 
     fn read_u64(
@@ -225,6 +274,9 @@ pub struct PerfSession {
     branch_stack_field: DataFieldRef,
     regs_user_field: DataFieldRef,
     stack_user_field: DataFieldRef,
+    weight_field: DataFieldRef,
+    data_src_field: DataFieldRef,
+    cgroup_field: DataFieldRef,
 
     /* Options */
     read_timeout: Duration,
@@ -232,6 +284,7 @@ pub struct PerfSession {
     /* Events */
     cpu_profile_event: Event,
     cswitch_profile_event: Event,
+    pmu_profile_events: HashMap<(u32, u64, PmuEventKind), Event>,
     lost_event: Event,
     comm_event: Event,
     exit_event: Event,
@@ -239,6 +292,7 @@ pub struct PerfSession {
     mmap_event: Event,
     lost_samples_event: Event,
     cswitch_event: Event,
+    cgroup_event: Event,
     drop_event: Event,
 
     /* BPF */
@@ -291,6 +345,9 @@ impl PerfSession {
             branch_stack_field: DataFieldRef::new(),
             regs_user_field: DataFieldRef::new(),
             stack_user_field: DataFieldRef::new(),
+            weight_field: DataFieldRef::new(),
+            data_src_field: DataFieldRef::new(),
+            cgroup_field: DataFieldRef::new(),
 
             /* BPF */
             bpf_events: HashMap::new(),
@@ -302,6 +359,7 @@ impl PerfSession {
             /* Events */
             cpu_profile_event: Event::new(0, "__cpu_profile".into()),
             cswitch_profile_event: Event::new(0, "__cswitch_profile".into()),
+            pmu_profile_events: HashMap::new(),
             lost_event: events::lost(),
             comm_event: events::comm(),
             exit_event: events::exit(),
@@ -309,6 +367,7 @@ impl PerfSession {
             mmap_event: events::mmap(),
             lost_samples_event: events::lost_samples(),
             cswitch_event: events::cswitch(),
+            cgroup_event: events::cgroup(),
             drop_event: Event::new(0, "__session_drop".into()),
 
             /* Ancillary data */
@@ -344,6 +403,16 @@ impl PerfSession {
         &mut self.cswitch_profile_event
     }
 
+    pub fn pmu_profile_event(
+        &mut self,
+        event_type: u32,
+        config: u64,
+        kind: PmuEventKind) -> &mut Event {
+        self.pmu_profile_events
+            .entry((event_type, config, kind))
+            .or_insert_with(|| Event::new(0, "__pmu_profile".into()))
+    }
+
     pub fn lost_event(&mut self) -> &mut Event {
         &mut self.lost_event
     }
@@ -372,6 +441,10 @@ impl PerfSession {
         &mut self.cswitch_event
     }
 
+    pub fn cgroup_event(&mut self) -> &mut Event {
+        &mut self.cgroup_event
+    }
+
     pub fn drop_event(&mut self) -> &mut Event {
         &mut self.drop_event
     }
@@ -444,6 +517,18 @@ impl PerfSession {
         self.stack_user_field.clone()
     }
 
+    pub fn weight_data_ref(&self) -> DataFieldRef {
+        self.weight_field.clone()
+    }
+
+    pub fn data_src_data_ref(&self) -> DataFieldRef {
+        self.data_src_field.clone()
+    }
+
+    pub fn cgroup_data_ref(&self) -> DataFieldRef {
+        self.cgroup_field.clone()
+    }
+
     pub fn set_read_timeout(
         &mut self,
         timeout: Duration) {
@@ -715,7 +800,7 @@ impl PerfSession {
 
                 /* PERF_SAMPLE_READ */
                 if perf_data.has_format(abi::PERF_SAMPLE_READ) {
-                    let read_size = perf_data.read_format_size();
+                    let read_size = perf_data.read_format_size(offset)?;
                     offset += self.read_field.update(offset, read_size);
                 } else {
                     self.read_field.reset();
@@ -745,7 +830,7 @@ impl PerfSession {
                 if perf_data.has_format(abi::PERF_SAMPLE_BRANCH_STACK) {
                     let count = perf_data.read_u64(offset)? as usize;
                     offset += 8;
-                    let size = count * 24;
+                    let size = count * abi::PERF_BRANCH_ENTRY_SIZE;
                     offset += self.branch_stack_field.update(offset, size);
                 } else {
                     self.branch_stack_field.reset();
@@ -786,6 +871,53 @@ impl PerfSession {
                     self.stack_user_field.reset();
                 }
 
+                /* PERF_SAMPLE_WEIGHT or PERF_SAMPLE_WEIGHT_STRUCT */
+                if perf_data.has_format(abi::PERF_SAMPLE_WEIGHT) ||
+                   perf_data.has_format(abi::PERF_SAMPLE_WEIGHT_STRUCT) {
+                    offset += self.weight_field.update(offset, 8);
+                } else {
+                    self.weight_field.reset();
+                }
+
+                /* PERF_SAMPLE_DATA_SRC */
+                if perf_data.has_format(abi::PERF_SAMPLE_DATA_SRC) {
+                    offset += self.data_src_field.update(offset, 8);
+                } else {
+                    self.data_src_field.reset();
+                }
+
+                /* PERF_SAMPLE_TRANSACTION */
+                if perf_data.has_format(abi::PERF_SAMPLE_TRANSACTION) {
+                    offset += 8;
+                }
+
+                /* PERF_SAMPLE_REGS_INTR */
+                if perf_data.has_format(abi::PERF_SAMPLE_REGS_INTR) {
+                    let abi = perf_data.read_u64(offset)?;
+                    offset += 8;
+                    let count = perf_data.regs_intr_count();
+                    offset += count * (abi * 4) as usize;
+                }
+
+                /* PERF_SAMPLE_PHYS_ADDR */
+                if perf_data.has_format(abi::PERF_SAMPLE_PHYS_ADDR) {
+                    offset += 8;
+                }
+
+                /* PERF_SAMPLE_AUX */
+                if perf_data.has_format(abi::PERF_SAMPLE_AUX) {
+                    let size = perf_data.read_u64(offset)? as usize;
+                    offset += 8;
+                    offset += size;
+                }
+
+                /* PERF_SAMPLE_CGROUP */
+                if perf_data.has_format(abi::PERF_SAMPLE_CGROUP) {
+                    offset += self.cgroup_field.update(offset, 8);
+                } else {
+                    self.cgroup_field.reset();
+                }
+
                 /* TODO: Remaining abi format types */
 
                 /* For now print warning if we see this */
@@ -833,37 +965,52 @@ impl PerfSession {
                     }
                 } else {
                     /* Non-event profile sample */
-                    match perf_data.ancillary.event_type() {
-                        /* Software */
-                        PERF_TYPE_SOFTWARE => {
-                            match perf_data.ancillary.config() {
-                                /* CPU */
-                                PERF_COUNT_SW_CPU_CLOCK => {
-                                    self.cpu_profile_event.process(
-                                        perf_data.raw_data,
-                                        perf_data.raw_data,
-                                        &mut self.errors);
-
-                                    self.log_errors(&self.cpu_profile_event);
-                                },
-
-                                /* CSWITCH */
-                                PERF_COUNT_SW_CONTEXT_SWITCHES => {
-                                    self.cswitch_profile_event.process(
-                                        perf_data.raw_data,
-                                        perf_data.raw_data,
-                                        &mut self.errors);
-
-                                    self.log_errors(&self.cswitch_profile_event);
-                                },
-
-                                /* Unsupported */
-                                _ => { },
-                            }
+                    let key = (
+                        perf_data.ancillary.event_type(),
+                        perf_data.ancillary.config());
+
+                    match key {
+                        /* CPU */
+                        (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_CLOCK) => {
+                            self.cpu_profile_event.process(
+                                perf_data.raw_data,
+                                perf_data.raw_data,
+                                &mut self.errors);
+
+                            self.log_errors(&self.cpu_profile_event);
+                        },
+
+                        /* CSWITCH */
+                        (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CONTEXT_SWITCHES) => {
+                            self.cswitch_profile_event.process(
+                                perf_data.raw_data,
+                                perf_data.raw_data,
+                                &mut self.errors);
+
+                            self.log_errors(&self.cswitch_profile_event);
                         },
 
-                        /* Unsupported */
-                        _ => { },
+                        /* Hardware, cache, raw and other software events */
+                        (event_type, config) => {
+                            let key = (
+                                event_type,
+                                config,
+                                PmuEventKind::from_attributes(
+                                    &perf_data.ancillary.attributes));
+
+                            if let Some(event) = self.pmu_profile_events.get_mut(&key) {
+                                event.process(
+                                    perf_data.raw_data,
+                                    perf_data.raw_data,
+                                    &mut self.errors);
+                            }
+
+                            if !self.errors.is_empty() {
+                                if let Some(event) = self.pmu_profile_events.get(&key) {
+                                    self.log_errors(event);
+                                }
+                            }
+                        },
                     }
                 }
             },
@@ -945,6 +1092,17 @@ impl PerfSession {
                 self.log_errors(&self.cswitch_event);
             },
 
+            abi::PERF_RECORD_CGROUP => {
+                let offset = abi::Header::data_offset();
+
+                self.cgroup_event.process(
+                    perf_data.raw_data,
+                    &perf_data.raw_data[offset..],
+                    &mut self.errors);
+
+                self.log_errors(&self.cgroup_event);
+            },
+
             _ => {
                 /* TODO: Remaining abi record types */
             },
@@ -1423,4 +1581,354 @@ mod tests {
         /* Ensure we only saw 1 event and our assert checks ran */
         assert_eq!(count.load(Ordering::Relaxed), 1);
     }
+
+    #[test]
+    fn mock_data_pmu_profile() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let sample_format =
+            abi::PERF_SAMPLE_TIME |
+            abi::PERF_SAMPLE_PERIOD;
+
+        /* Create our mock data as hardware cycle samples */
+        let mut mock = MockData::new(sample_format, 0);
+        let attr = Rc::get_mut(&mut mock.attr).unwrap();
+
+        attr.event_type = PERF_TYPE_HARDWARE;
+        attr.config = abi::PERF_COUNT_HW_CPU_CYCLES;
+
+        let mut perf_data = Vec::new();
+        let mut raw_data = Vec::new();
+
+        Sample::write_time(4321, &mut raw_data);
+        Sample::write_period(100000, &mut raw_data);
+
+        Header::write(abi::PERF_RECORD_SAMPLE, 0, raw_data.as_slice(), &mut perf_data);
+        mock.push(perf_data.as_slice());
+
+        let mut session = PerfSession::new(Box::new(mock));
+
+        let callback_count = Arc::clone(&count);
+        let period_data = session.period_data_ref();
+
+        /* Unrelated PMU events should not be called */
+        session.pmu_profile_event(
+            PERF_TYPE_HARDWARE,
+            abi::PERF_COUNT_HW_INSTRUCTIONS,
+            PmuEventKind::Sample).add_callback(|_| {
+            panic!("Unexpected PMU event");
+        });
+
+        /* Same config opened as a group leader is a different event */
+        session.pmu_profile_event(
+            PERF_TYPE_HARDWARE,
+            abi::PERF_COUNT_HW_CPU_CYCLES,
+            PmuEventKind::Group).add_callback(|_| {
+            panic!("Unexpected PMU group event");
+        });
+
+        session.pmu_profile_event(
+            PERF_TYPE_HARDWARE,
+            abi::PERF_COUNT_HW_CPU_CYCLES,
+            PmuEventKind::Sample).add_callback(move |data| {
+            let period = period_data.try_get_u64(data.full_data()).unwrap();
+
+            assert_eq!(100000, period);
+
+            callback_count.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn mock_data_branch_stack() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let sample_format =
+            abi::PERF_SAMPLE_TIME |
+            abi::PERF_SAMPLE_PERIOD |
+            abi::PERF_SAMPLE_BRANCH_STACK;
+
+        /* Create our mock data as hardware cycle samples with LBR */
+        let mut mock = MockData::new(sample_format, 0);
+        let attr = Rc::get_mut(&mut mock.attr).unwrap();
+
+        attr.event_type = PERF_TYPE_HARDWARE;
+        attr.config = abi::PERF_COUNT_HW_CPU_CYCLES;
+        attr.branch_sample_type = abi::PERF_SAMPLE_BRANCH_ANY;
+
+        let mut perf_data = Vec::new();
+        let mut raw_data = Vec::new();
+
+        Sample::write_time(4321, &mut raw_data);
+        Sample::write_period(100000, &mut raw_data);
+
+        /* bnr, then entries newest first */
+        raw_data.extend_from_slice(&2u64.to_ne_bytes());
+        BranchEntry::new(0x1100, 0x2000, 1).write(&mut raw_data);
+        BranchEntry::new(0x1000, 0x1080, 2).write(&mut raw_data);
+
+        Header::write(abi::PERF_RECORD_SAMPLE, 0, raw_data.as_slice(), &mut perf_data);
+        mock.push(perf_data.as_slice());
+
+        let mut session = PerfSession::new(Box::new(mock));
+
+        let callback_count = Arc::clone(&count);
+        let branch_data = session.branch_stack_data_ref();
+        let mut entries = Vec::new();
+
+        session.pmu_profile_event(
+            PERF_TYPE_HARDWARE,
+            abi::PERF_COUNT_HW_CPU_CYCLES,
+            PmuEventKind::Sample).add_callback(move |data| {
+            BranchEntry::decode(
+                branch_data.get_data(data.full_data()),
+                &mut entries)?;
+
+            assert_eq!(2, entries.len());
+            assert_eq!(0x1100, entries[0].from_ip());
+            assert_eq!(0x2000, entries[0].to_ip());
+            assert!(entries[0].mispredicted());
+            assert_eq!(0x1000, entries[1].from_ip());
+            assert_eq!(0x1080, entries[1].to_ip());
+            assert!(entries[1].predicted());
+
+            callback_count.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn mock_data_memory_access() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let sample_format =
+            abi::PERF_SAMPLE_TIME |
+            abi::PERF_SAMPLE_ADDR |
+            abi::PERF_SAMPLE_PERIOD |
+            abi::PERF_SAMPLE_WEIGHT |
+            abi::PERF_SAMPLE_DATA_SRC;
+
+        /* Create our mock data as raw memory load samples */
+        let mut mock = MockData::new(sample_format, 0);
+        let attr = Rc::get_mut(&mut mock.attr).unwrap();
+
+        attr.event_type = abi::PERF_TYPE_RAW;
+        attr.config = 0x1cd;
+
+        let data_src =
+            (abi::PERF_MEM_OP_LOAD << abi::PERF_MEM_OP_SHIFT) |
+            ((abi::PERF_MEM_LVL_L2 | abi::PERF_MEM_LVL_HIT) << abi::PERF_MEM_LVL_SHIFT);
+
+        let mut perf_data = Vec::new();
+        let mut raw_data = Vec::new();
+
+        Sample::write_time(4321, &mut raw_data);
+        raw_data.extend_from_slice(&0x7f001000u64.to_ne_bytes());
+        Sample::write_period(1, &mut raw_data);
+        raw_data.extend_from_slice(&42u64.to_ne_bytes());
+        raw_data.extend_from_slice(&data_src.to_ne_bytes());
+
+        Header::write(abi::PERF_RECORD_SAMPLE, 0, raw_data.as_slice(), &mut perf_data);
+        mock.push(perf_data.as_slice());
+
+        let mut session = PerfSession::new(Box::new(mock));
+
+        let callback_count = Arc::clone(&count);
+        let address_data = session.address_data_ref();
+        let weight_data = session.weight_data_ref();
+        let data_src_data = session.data_src_data_ref();
+
+        session.pmu_profile_event(
+            abi::PERF_TYPE_RAW,
+            0x1cd,
+            PmuEventKind::Memory).add_callback(move |data| {
+            let full_data = data.full_data();
+
+            assert_eq!(0x7f001000, address_data.get_u64(full_data)?);
+            assert_eq!(42, weight_data.get_u64(full_data)?);
+
+            let src = MemoryDataSource::new(data_src_data.get_u64(full_data)?);
+
+            assert!(src.is_load());
+            assert_eq!(MemoryLevel::L2, src.level());
+
+            callback_count.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn mock_data_cgroup() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let cgroup_count = Arc::new(AtomicUsize::new(0));
+
+        let sample_format =
+            abi::PERF_SAMPLE_TIME |
+            abi::PERF_SAMPLE_TRANSACTION |
+            abi::PERF_SAMPLE_REGS_INTR |
+            abi::PERF_SAMPLE_PHYS_ADDR |
+            abi::PERF_SAMPLE_CGROUP;
+
+        /* Create our mock data as raw samples with skipped fields */
+        let mut mock = MockData::new(sample_format, 0);
+        let attr = Rc::get_mut(&mut mock.attr).unwrap();
+
+        attr.event_type = abi::PERF_TYPE_RAW;
+        attr.config = 0x3c;
+        attr.sample_regs_intr = 0b11;
+
+        let mut perf_data = Vec::new();
+        let mut raw_data = Vec::new();
+
+        Sample::write_time(4321, &mut raw_data);
+        raw_data.extend_from_slice(&1u64.to_ne_bytes());
+        raw_data.extend_from_slice(&abi::PERF_SAMPLE_REGS_ABI_64.to_ne_bytes());
+        raw_data.extend_from_slice(&2u64.to_ne_bytes());
+        raw_data.extend_from_slice(&3u64.to_ne_bytes());
+        raw_data.extend_from_slice(&4u64.to_ne_bytes());
+        raw_data.extend_from_slice(&1234u64.to_ne_bytes());
+
+        Header::write(abi::PERF_RECORD_SAMPLE, 0, raw_data.as_slice(), &mut perf_data);
+        mock.push(perf_data.as_slice());
+
+        /* New cgroup record */
+        perf_data.clear();
+        raw_data.clear();
+
+        raw_data.extend_from_slice(&1234u64.to_ne_bytes());
+        raw_data.extend_from_slice(b"/system.slice/test.service\0");
+
+        Header::write(abi::PERF_RECORD_CGROUP, 0, raw_data.as_slice(), &mut perf_data);
+        mock.push(perf_data.as_slice());
+
+        let mut session = PerfSession::new(Box::new(mock));
+
+        let callback_count = Arc::clone(&count);
+        let time_data = session.time_data_ref();
+        let cgroup_data = session.cgroup_data_ref();
+
+        session.pmu_profile_event(
+            abi::PERF_TYPE_RAW,
+            0x3c,
+            PmuEventKind::Sample).add_callback(move |data| {
+            let full_data = data.full_data();
+
+            assert_eq!(4321, time_data.get_u64(full_data)?);
+            assert_eq!(1234, cgroup_data.get_u64(full_data)?);
+
+            callback_count.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        let callback_count = Arc::clone(&cgroup_count);
+        let event = session.cgroup_event();
+        let format = event.format();
+        let id = format.get_field_ref_unchecked("id");
+        let path = format.get_field_ref_unchecked("path[]");
+
+        event.add_callback(move |data| {
+            let format = data.format();
+            let event_data = data.event_data();
+
+            assert_eq!(1234, format.get_u64(id, event_data)?);
+            assert_eq!("/system.slice/test.service", format.get_str(path, event_data)?);
+
+            callback_count.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert_eq!(cgroup_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn mock_data_group_read() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let sample_format =
+            abi::PERF_SAMPLE_TIME |
+            abi::PERF_SAMPLE_READ |
+            abi::PERF_SAMPLE_CALLCHAIN;
+
+        let read_format =
+            abi::PERF_FORMAT_GROUP |
+            abi::PERF_FORMAT_ID;
+
+        /* Create our mock data as grouped hardware samples */
+        let mut mock = MockData::new(sample_format, read_format);
+        let attr = Rc::get_mut(&mut mock.attr).unwrap();
+
+        attr.event_type = PERF_TYPE_HARDWARE;
+        attr.config = abi::PERF_COUNT_HW_CPU_CYCLES;
+
+        let mut perf_data = Vec::new();
+        let mut raw_data = Vec::new();
+
+        Sample::write_time(4321, &mut raw_data);
+
+        /* nr, { value, id } * nr */
+        for value in [2u64, 1000, 1, 3000, 2] {
+            raw_data.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        /* Callchain follows the variable read data */
+        for value in [1u64, 0x1234] {
+            raw_data.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        Header::write(abi::PERF_RECORD_SAMPLE, 0, raw_data.as_slice(), &mut perf_data);
+        mock.push(perf_data.as_slice());
+
+        let mut session = PerfSession::new(Box::new(mock));
+
+        let callback_count = Arc::clone(&count);
+        let read_data = session.read_data_ref();
+        let callchain_data = session.callchain_data_ref();
+        let mut decoder = GroupReadDecoder::new();
+        let mut deltas = Vec::new();
+
+        session.pmu_profile_event(
+            PERF_TYPE_HARDWARE,
+            abi::PERF_COUNT_HW_CPU_CYCLES,
+            PmuEventKind::Group).add_callback(move |data| {
+            let full_data = data.full_data();
+            let read = read_data.get_data(full_data);
+
+            decoder.decode(read_format, read, &mut deltas)?;
+
+            assert_eq!(vec![1000, 3000], deltas);
+
+            let callchain = callchain_data.get_data(full_data);
+
+            assert_eq!(&0x1234u64.to_ne_bytes(), callchain);
+
+            callback_count.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.parse_all().unwrap();
+
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
 }
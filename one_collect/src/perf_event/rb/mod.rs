@@ -4,6 +4,7 @@
 use std::marker::PhantomData;
 use std::arch::asm;
 use std::rc::Rc;
+use std::path::Path;
 
 #[cfg(target_os = "linux")]
 use libc::*;
@@ -12,6 +13,7 @@ use super::abi;
 use super::*;
 
 pub mod source;
+mod poll;
 
 /* Arch: X64 */
 #[cfg(target_arch = "x86_64")]
@@ -100,6 +102,41 @@ pub trait RingBufOptions {
 
         clone
     }
+
+    fn with_branch_stack_data(
+        &self,
+        branch_sample_type: u64) -> Self where Self: Sized {
+        let mut clone = self.clone_options();
+        let attributes = clone.attributes_mut();
+
+        attributes.sample_type |= abi::PERF_SAMPLE_BRANCH_STACK;
+        attributes.branch_sample_type = branch_sample_type;
+
+        clone
+    }
+
+    fn with_cgroup_data(&self) -> Self where Self: Sized {
+        let mut clone = self.clone_options();
+        let attributes = clone.attributes_mut();
+
+        /* Cgroup ID (cgroupfs inode) the sample happened in */
+        attributes.sample_type |= abi::PERF_SAMPLE_CGROUP;
+
+        clone
+    }
+
+    fn with_memory_access_data(&self) -> Self where Self: Sized {
+        let mut clone = self.clone_options();
+        let attributes = clone.attributes_mut();
+
+        /* Data address, access latency and where it was satisfied */
+        attributes.sample_type |= abi::PERF_SAMPLE_ADDR;
+        attributes.sample_type |= abi::PERF_SAMPLE_WEIGHT;
+        attributes.sample_type |= abi::PERF_SAMPLE_DATA_SRC;
+        attributes.flags |= FLAG_PRECISE_IP;
+
+        clone
+    }
 }
 
 pub fn cpu_count() -> u32 {
@@ -156,6 +193,9 @@ pub struct ContextSwitches;
 pub struct Tracepoint;
 pub struct Kernel;
 pub struct Bpf;
+pub struct Pmu;
+
+const SYSFS_PMU_PATH: &str = "/sys/bus/event_source/devices";
 
 pub struct RingBufBuilder<T = Profiling> {
     attributes: perf_event_attr,
@@ -249,6 +289,243 @@ impl RingBufBuilder {
             _type: PhantomData::<Bpf>,
         }
     }
+
+    pub fn for_pmu(
+        event_type: u32,
+        config: u64) -> RingBufBuilder<Pmu> {
+        let mut attributes = Self::common_attributes();
+
+        attributes.event_type = event_type;
+        attributes.config = config;
+        attributes.sample_period_freq = 1000;
+        attributes.flags |= FLAG_FREQ;
+
+        /* Period is the amount of events each sample represents */
+        attributes.sample_type |= abi::PERF_SAMPLE_PERIOD;
+
+        RingBufBuilder::<Pmu> {
+            attributes,
+            _type: PhantomData::<Pmu>,
+        }
+    }
+
+    pub fn for_pmu_group(
+        leader: RingBufBuilder<Pmu>) -> RingBufGroupBuilder {
+        RingBufGroupBuilder {
+            leader,
+            members: Vec::new(),
+        }
+    }
+
+    pub fn for_hardware(
+        config: u64) -> RingBufBuilder<Pmu> {
+        Self::for_pmu(
+            PERF_TYPE_HARDWARE,
+            config)
+    }
+
+    pub fn for_hw_cache(
+        cache: u64,
+        op: u64,
+        result: u64) -> RingBufBuilder<Pmu> {
+        Self::for_pmu(
+            PERF_TYPE_HW_CACHE,
+            cache | (op << 8) | (result << 16))
+    }
+
+    pub fn for_software(
+        config: u64) -> RingBufBuilder<Pmu> {
+        Self::for_pmu(
+            PERF_TYPE_SOFTWARE,
+            config)
+    }
+
+    pub fn for_page_faults() -> RingBufBuilder<Pmu> {
+        /* Faults are discrete events, sample each one by default */
+        Self::for_software(PERF_COUNT_SW_PAGE_FAULTS)
+            .with_period(1)
+    }
+
+    pub fn for_minor_faults() -> RingBufBuilder<Pmu> {
+        Self::for_software(PERF_COUNT_SW_PAGE_FAULTS_MIN)
+            .with_period(1)
+    }
+
+    pub fn for_major_faults() -> RingBufBuilder<Pmu> {
+        Self::for_software(PERF_COUNT_SW_PAGE_FAULTS_MAJ)
+            .with_period(1)
+    }
+
+    pub fn for_alignment_faults() -> RingBufBuilder<Pmu> {
+        Self::for_software(PERF_COUNT_SW_ALIGNMENT_FAULTS)
+            .with_period(1)
+    }
+
+    pub fn for_cpu_migrations() -> RingBufBuilder<Pmu> {
+        Self::for_software(PERF_COUNT_SW_CPU_MIGRATIONS)
+            .with_period(1)
+    }
+
+    pub fn for_task_clock(
+        sampling_frequency: u64) -> RingBufBuilder<Pmu> {
+        Self::for_software(PERF_COUNT_SW_TASK_CLOCK)
+            .with_frequency(sampling_frequency)
+    }
+
+    pub fn for_mem_loads() -> IOResult<RingBufBuilder<Pmu>> {
+        Ok(Self::for_sysfs_pmu_event("cpu", "mem-loads")?
+            .with_memory_access_data())
+    }
+
+    pub fn for_mem_stores() -> IOResult<RingBufBuilder<Pmu>> {
+        Ok(Self::for_sysfs_pmu_event("cpu", "mem-stores")?
+            .with_memory_access_data())
+    }
+
+    pub fn for_sysfs_pmu_event(
+        pmu: &str,
+        event: &str) -> IOResult<RingBufBuilder<Pmu>> {
+        Self::for_sysfs_pmu_event_at(
+            Path::new(SYSFS_PMU_PATH),
+            pmu,
+            event)
+    }
+
+    fn for_sysfs_pmu_event_at(
+        root: &Path,
+        pmu: &str,
+        event: &str) -> IOResult<RingBufBuilder<Pmu>> {
+        let pmu_path = root.join(pmu);
+
+        if !pmu_path.exists() {
+            return Err(pmu_unsupported(
+                &format!("PMU '{}' is not present on this machine.", pmu)));
+        }
+
+        let event_type = std::fs::read_to_string(pmu_path.join("type"))?
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| io_error("Invalid PMU type."))?;
+
+        let event_path = pmu_path.join("events").join(event);
+
+        if !event_path.exists() {
+            return Err(pmu_unsupported(
+                &format!("PMU '{}' has no event '{}'.", pmu, event)));
+        }
+
+        let terms = std::fs::read_to_string(event_path)?;
+        let mut configs = [0u64; 3];
+
+        for term in terms.trim().split(',') {
+            let term = term.trim();
+
+            if term.is_empty() {
+                continue;
+            }
+
+            /* Terms without a value are flags, IE: "edge" */
+            let (name, value) = match term.split_once('=') {
+                Some((name, value)) => { (name, parse_pmu_value(value)?) },
+                None => { (term, 1) },
+            };
+
+            let format = match name {
+                "config" => { "config:0-63".to_owned() },
+                "config1" => { "config1:0-63".to_owned() },
+                "config2" => { "config2:0-63".to_owned() },
+                _ => { std::fs::read_to_string(pmu_path.join("format").join(name))? },
+            };
+
+            apply_pmu_format(
+                format.trim(),
+                value,
+                &mut configs)?;
+        }
+
+        let mut builder = Self::for_pmu(event_type, configs[0]);
+
+        /* config1 and config2 share space with bp_addr and bp_len */
+        builder.attributes.bp_addr = configs[1];
+        builder.attributes.bp_len = configs[2];
+
+        Ok(builder)
+    }
+}
+
+fn pmu_unsupported(message: &str) -> IOError {
+    IOError::new(
+        std::io::ErrorKind::Unsupported,
+        message)
+}
+
+pub(crate) fn pmu_open_error(error: IOError) -> IOError {
+    /*
+     * Machines without a hardware PMU (IE: most VMs) fail to open
+     * hardware events in a few ways depending on the kernel version.
+     */
+    match error.raw_os_error() {
+        Some(ENOENT) | Some(EOPNOTSUPP) | Some(ENODEV) => {
+            pmu_unsupported("PMU event is not supported on this machine.")
+        },
+        _ => { error },
+    }
+}
+
+fn parse_pmu_value(value: &str) -> IOResult<u64> {
+    let value = value.trim();
+
+    let result = match value.strip_prefix("0x") {
+        Some(hex) => { u64::from_str_radix(hex, 16) },
+        None => { value.parse::<u64>() },
+    };
+
+    result.map_err(|_| io_error("Invalid PMU event value."))
+}
+
+fn apply_pmu_format(
+    format: &str,
+    mut value: u64,
+    configs: &mut [u64; 3]) -> IOResult<()> {
+    /* Format is like "config:0-7,32-35" or "config1:5" */
+    let (config, ranges) = match format.split_once(':') {
+        Some(parts) => { parts },
+        None => { return Err(io_error("Invalid PMU format.")); },
+    };
+
+    let index = match config {
+        "config" => { 0 },
+        "config1" => { 1 },
+        "config2" => { 2 },
+        _ => { return Err(io_error("Invalid PMU format config.")); },
+    };
+
+    for range in ranges.split(',') {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => { (start, end) },
+            None => { (range, range) },
+        };
+
+        let start = start.trim().parse::<u32>()
+            .map_err(|_| io_error("Invalid PMU format range."))?;
+
+        let end = end.trim().parse::<u32>()
+            .map_err(|_| io_error("Invalid PMU format range."))?;
+
+        if start > end || end > 63 {
+            return Err(io_error("Invalid PMU format range."));
+        }
+
+        let bits = end - start + 1;
+
+        let mask = if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 };
+
+        configs[index] |= (value & mask) << start;
+
+        value = value.checked_shr(bits).unwrap_or(0);
+    }
+
+    Ok(())
 }
 
 impl RingBufOptions for RingBufBuilder<Profiling> {
@@ -338,6 +615,137 @@ impl RingBufBuilder<Bpf> {
     }
 }
 
+impl RingBufOptions for RingBufBuilder<Pmu> {
+    fn clone_options(&self) -> Self {
+        Self {
+            attributes: self.attributes,
+            _type: self._type,
+        }
+    }
+
+    fn attributes_mut(&mut self) -> &mut perf_event_attr {
+        &mut self.attributes
+    }
+}
+
+impl RingBufBuilder<Pmu> {
+    pub fn with_frequency(
+        &self,
+        frequency: u64) -> Self {
+        let mut attributes = self.attributes;
+
+        attributes.sample_period_freq = frequency;
+        attributes.flags |= FLAG_FREQ;
+
+        Self {
+            attributes,
+            _type: self._type,
+        }
+    }
+
+    pub fn with_period(
+        &self,
+        period: u64) -> Self {
+        let mut attributes = self.attributes;
+
+        attributes.sample_period_freq = period;
+        attributes.flags &= !FLAG_FREQ;
+
+        Self {
+            attributes,
+            _type: self._type,
+        }
+    }
+
+    pub fn event_type(&self) -> u32 { self.attributes.event_type }
+
+    pub fn config(&self) -> u64 { self.attributes.config }
+
+    pub(crate) fn build(&self) -> CommonRingBuf {
+        CommonRingBuf::new(self.attributes)
+    }
+
+    pub(crate) fn build_counter(&self) -> CommonRingBuf {
+        let mut attributes = self.attributes;
+
+        /* Counting only, no samples are generated */
+        attributes.sample_period_freq = 0;
+        attributes.flags &= !FLAG_FREQ;
+
+        /* Like perf stat, children of a target pid are counted too */
+        attributes.flags |= FLAG_INHERIT;
+        attributes.read_format =
+            abi::PERF_FORMAT_TOTAL_TIME_ENABLED |
+            abi::PERF_FORMAT_TOTAL_TIME_RUNNING |
+            abi::PERF_FORMAT_ID;
+
+        CommonRingBuf::new(attributes)
+    }
+}
+
+pub struct RingBufGroupBuilder {
+    leader: RingBufBuilder<Pmu>,
+    members: Vec<RingBufBuilder<Pmu>>,
+}
+
+impl RingBufOptions for RingBufGroupBuilder {
+    fn clone_options(&self) -> Self {
+        Self {
+            leader: self.leader.clone_options(),
+            members: self.members
+                .iter()
+                .map(|member| member.clone_options())
+                .collect(),
+        }
+    }
+
+    fn attributes_mut(&mut self) -> &mut perf_event_attr {
+        /* Only the leader samples, members are read with it */
+        self.leader.attributes_mut()
+    }
+}
+
+impl RingBufGroupBuilder {
+    pub fn with_member(
+        &self,
+        member: RingBufBuilder<Pmu>) -> Self {
+        let mut clone = self.clone_options();
+
+        clone.members.push(member);
+
+        clone
+    }
+
+    pub fn leader(&self) -> &RingBufBuilder<Pmu> { &self.leader }
+
+    pub fn members(&self) -> &[RingBufBuilder<Pmu>] { &self.members }
+
+    pub(crate) fn build(&self) -> (CommonRingBuf, Vec<CommonRingBuf>) {
+        let mut leader = self.leader.attributes;
+
+        /* Leader samples carry the values of the whole group */
+        leader.sample_type |= abi::PERF_SAMPLE_READ;
+        leader.read_format |= abi::PERF_FORMAT_GROUP | abi::PERF_FORMAT_ID;
+
+        let members = self.members
+            .iter()
+            .map(|member| {
+                let mut attributes = member.attributes;
+
+                /* Members only count, they follow the leader schedule */
+                attributes.sample_period_freq = 0;
+                attributes.sample_type = leader.sample_type;
+                attributes.read_format = leader.read_format;
+                attributes.flags &= !(FLAG_FREQ | FLAG_DISABLED);
+
+                CommonRingBuf::new(attributes)
+            })
+            .collect();
+
+        (CommonRingBuf::new(leader), members)
+    }
+}
+
 impl RingBufBuilder<Kernel> {
     pub fn with_mmap_records(&self) -> Self {
         let mut attributes = self.attributes;
@@ -350,6 +758,30 @@ impl RingBufBuilder<Kernel> {
         }
     }
 
+    pub fn with_mmap_data_records(&self) -> Self {
+        let mut attributes = self.attributes;
+
+        /* Non-executable mmaps, IE: heaps and data files */
+        attributes.flags |= FLAG_MMAP | FLAG_MMAP2 | FLAG_MMAP_DATA;
+
+        Self {
+            attributes,
+            _type: self._type,
+        }
+    }
+
+    pub fn with_cgroup_records(&self) -> Self {
+        let mut attributes = self.attributes;
+
+        /* Path of cgroups created during the session */
+        attributes.flags |= FLAG_CGROUP;
+
+        Self {
+            attributes,
+            _type: self._type,
+        }
+    }
+
     pub fn with_comm_records(&self) -> Self {
         let mut attributes = self.attributes;
 
@@ -395,8 +827,18 @@ struct read_format {
     id: u64,
 }
 
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub(crate) struct PerfCounterRead {
+    pub value: u64,
+    pub time_enabled: u64,
+    pub time_running: u64,
+    pub id: u64,
+}
+
 pub(crate) struct CommonRingBuf {
     attributes: Rc<perf_event_attr>,
+    open_flags: usize,
 }
 
 impl CommonRingBuf {
@@ -404,6 +846,7 @@ impl CommonRingBuf {
         attributes: perf_event_attr) -> Self {
         Self {
             attributes: Rc::new(attributes),
+            open_flags: 0,
         }
     }
 
@@ -430,12 +873,84 @@ impl CommonRingBuf {
         clone
     }
 
+    pub fn with_task_flags(
+        self,
+        flags: u64) -> Self {
+        if flags == 0 {
+            return self;
+        }
+
+        let mut clone = self;
+        let mut attributes = *clone.attributes;
+
+        attributes.flags |= flags;
+
+        /* Enable on exec only works for initially disabled events */
+        if attributes.flags & FLAG_DISABLED == 0 {
+            attributes.flags &= !FLAG_ENABLE_ON_EXEC;
+        }
+
+        clone.attributes = Rc::new(attributes);
+        clone
+    }
+
+    pub fn with_open_flags(
+        self,
+        flags: usize) -> Self {
+        /* IE: PERF_FLAG_PID_CGROUP, target is a cgroup fd, not a PID */
+        let mut clone = self;
+        clone.open_flags |= flags;
+        clone
+    }
+
+    pub fn with_wakeup_watermark(
+        self,
+        bytes: u32) -> Self {
+        /* Wake readers once this many bytes are in the buffer */
+        if bytes == 0 {
+            return self;
+        }
+
+        let mut clone = self;
+        let mut attributes = *clone.attributes;
+
+        attributes.flags |= FLAG_WATERMARK;
+        attributes.wakeup_events_watermark = bytes;
+
+        clone.attributes = Rc::new(attributes);
+        clone
+    }
+
+    pub fn has_branch_stack(&self) -> bool {
+        self.attributes.has_format(PERF_SAMPLE_BRANCH_STACK)
+    }
+
+    pub fn without_branch_stack(
+        self) -> Self {
+        if !self.has_branch_stack() {
+            return self;
+        }
+
+        let mut clone = self;
+        let mut attributes = *clone.attributes;
+
+        attributes.sample_type &= !PERF_SAMPLE_BRANCH_STACK;
+        attributes.branch_sample_type = 0;
+
+        clone.attributes = Rc::new(attributes);
+        clone
+    }
+
     pub fn for_cpu(
         &self,
         cpu: u32) -> CpuRingBuf {
-        CpuRingBuf::new(
+        let mut cpu_buf = CpuRingBuf::new(
             cpu,
-            self.attributes.clone())
+            self.attributes.clone());
+
+        cpu_buf.open_flags = self.open_flags;
+
+        cpu_buf
     }
 }
 
@@ -626,8 +1141,10 @@ pub(crate) struct CpuRingBuf {
     cpu: u32,
     attributes: Rc<perf_event_attr>,
     sample_time_offset: u16,
+    open_flags: usize,
     fd: Option<i32>,
     id: Option<u64>,
+    redirected: bool,
 }
 
 impl CpuRingBuf {
@@ -652,8 +1169,10 @@ impl CpuRingBuf {
             cpu,
             attributes,
             sample_time_offset,
+            open_flags: 0,
             fd: None,
             id: None,
+            redirected: false,
         }
     }
 
@@ -666,6 +1185,24 @@ impl CpuRingBuf {
 
     fn read_id(&self) -> IOResult<u64> {
         match &self.fd {
+            Some(fd) if self.attributes.read_format != abi::PERF_FORMAT_ID => {
+                /* Other read formats change the layout, ask for the ID directly */
+                let mut id: u64 = 0;
+
+                unsafe {
+                    let result = ioctl(
+                        *fd,
+                        abi::PERF_EVENT_IOC_ID as _,
+                        &mut id as *mut u64);
+
+                    if result == -1 {
+                        return Err(IOError::last_os_error());
+                    }
+                }
+
+                Ok(id)
+            },
+
             Some(fd) => {
                 let mut id = read_format::default();
 
@@ -688,6 +1225,46 @@ impl CpuRingBuf {
         }
     }
 
+    pub fn read_counter(&self) -> IOResult<PerfCounterRead> {
+        if !self.attributes.has_read_format(
+            abi::PERF_FORMAT_TOTAL_TIME_ENABLED |
+            abi::PERF_FORMAT_TOTAL_TIME_RUNNING |
+            abi::PERF_FORMAT_ID) {
+            return Err(io_error(
+                "Ring buffer is not a counter."));
+        }
+
+        match &self.fd {
+            Some(fd) => {
+                let mut counter = PerfCounterRead::default();
+
+                unsafe {
+                    let result = read(
+                        *fd,
+                        &mut counter as *mut PerfCounterRead as *mut c_void,
+                        std::mem::size_of::<PerfCounterRead>());
+
+                    if result == -1 {
+                        return Err(IOError::last_os_error());
+                    }
+                }
+
+                Ok(counter)
+            },
+
+            None => Err(io_error(
+                "Ring buffer is not open."))
+        }
+    }
+
+    pub fn cpu(&self) -> u32 {
+        self.cpu
+    }
+
+    pub fn is_redirected(&self) -> bool {
+        self.redirected
+    }
+
     pub fn sample_time_offset(&self) -> u16 {
         self.sample_time_offset
     }
@@ -699,14 +1276,34 @@ impl CpuRingBuf {
     pub fn open(
         &mut self,
         target_pid: Option<i32>) -> IOResult<()> {
+        self.open_with_group(target_pid, -1)
+    }
+
+    pub fn open_in_group(
+        &mut self,
+        target_pid: Option<i32>,
+        leader: &Self) -> IOResult<()> {
+        match leader.fd {
+            Some(fd) => { self.open_with_group(target_pid, fd) },
+            None => {
+                Err(io_error(
+                    "Group leader is not open."))
+            },
+        }
+    }
+
+    fn open_with_group(
+        &mut self,
+        target_pid: Option<i32>,
+        group_fd: i32) -> IOResult<()> {
         let pid = target_pid.unwrap_or(-1);
 
         let fd = perf_event_open(
             &self.attributes,
             pid,
             self.cpu as i32,
-            -1,
-            0)?;
+            group_fd,
+            self.open_flags)?;
 
         self.fd = Some(fd as i32);
         self.id = Some(self.read_id()?);
@@ -787,7 +1384,7 @@ impl CpuRingBuf {
     }
 
     pub fn redirect_to(
-        &self, 
+        &mut self,
         target: &Self) -> IOResult<()> {
         if self.fd.is_none() || target.fd.is_none() {
             return Err(io_error(
@@ -803,9 +1400,11 @@ impl CpuRingBuf {
             if result == -1 {
                 return Err(IOError::last_os_error());
             }
-
-            Ok(())
         }
+
+        self.redirected = true;
+
+        Ok(())
     }
 }
 
@@ -999,4 +1598,54 @@ mod tests {
         rb.redirect_to(&rb_head).unwrap();
         rb.enable().unwrap();
     }
+
+    #[test]
+    fn sysfs_pmu_event() {
+        let root = std::env::temp_dir().join(
+            format!("one_collect_pmu_{}", std::process::id()));
+
+        let pmu = root.join("test_pmu");
+
+        std::fs::create_dir_all(pmu.join("events")).unwrap();
+        std::fs::create_dir_all(pmu.join("format")).unwrap();
+
+        std::fs::write(pmu.join("type"), "42\n").unwrap();
+        std::fs::write(pmu.join("format/event"), "config:0-7\n").unwrap();
+        std::fs::write(pmu.join("format/umask"), "config:8-15\n").unwrap();
+        std::fs::write(pmu.join("format/edge"), "config:18\n").unwrap();
+        std::fs::write(pmu.join("format/split"), "config1:0-3,8-11\n").unwrap();
+        std::fs::write(pmu.join("events/test"), "event=0x3c,umask=0x01,edge\n").unwrap();
+        std::fs::write(pmu.join("events/split"), "split=0xab,config2=7\n").unwrap();
+        std::fs::write(pmu.join("events/bad"), "unknown=1\n").unwrap();
+
+        let builder = RingBufBuilder::for_sysfs_pmu_event_at(
+            &root, "test_pmu", "test").unwrap();
+
+        assert_eq!(42, builder.event_type());
+        assert_eq!(0x3c | (0x01 << 8) | (1 << 18), builder.config());
+
+        let builder = RingBufBuilder::for_sysfs_pmu_event_at(
+            &root, "test_pmu", "split").unwrap();
+
+        assert_eq!(0, builder.config());
+        assert_eq!(0xb | (0xa << 8), builder.attributes.bp_addr);
+        assert_eq!(7, builder.attributes.bp_len);
+
+        /* Unknown terms fail */
+        assert!(RingBufBuilder::for_sysfs_pmu_event_at(
+            &root, "test_pmu", "bad").is_err());
+
+        /* Missing PMUs and events fail cleanly as unsupported */
+        let err = RingBufBuilder::for_sysfs_pmu_event_at(
+            &root, "missing_pmu", "test").err().unwrap();
+
+        assert_eq!(std::io::ErrorKind::Unsupported, err.kind());
+
+        let err = RingBufBuilder::for_sysfs_pmu_event_at(
+            &root, "test_pmu", "missing").err().unwrap();
+
+        assert_eq!(std::io::ErrorKind::Unsupported, err.kind());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,157 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::time::Duration;
+use std::io::{Error as IOError, Result as IOResult};
+
+use libc::*;
+
+/*
+ * Waits on the per-CPU leader fds instead of sleeping for the full
+ * read timeout. The kernel wakes us once a buffer passes its wakeup
+ * watermark, so readers only spin up when there is data to parse.
+ */
+pub(crate) struct CpuRingPoll {
+    fd: i32,
+    count: usize,
+    events: Vec<epoll_event>,
+}
+
+impl CpuRingPoll {
+    pub fn new() -> IOResult<Self> {
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+
+        if fd == -1 {
+            return Err(IOError::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            count: 0,
+            events: Vec::new(),
+        })
+    }
+
+    pub fn add(
+        &mut self,
+        fd: i32) -> IOResult<()> {
+        let mut event = epoll_event {
+            events: EPOLLIN as u32,
+            u64: fd as u64,
+        };
+
+        unsafe {
+            if epoll_ctl(self.fd, EPOLL_CTL_ADD, fd, &mut event) == -1 {
+                return Err(IOError::last_os_error());
+            }
+        }
+
+        self.count += 1;
+        self.events.push(event);
+
+        Ok(())
+    }
+
+    pub fn remove(
+        &mut self,
+        fd: i32) {
+        let result = unsafe {
+            epoll_ctl(self.fd, EPOLL_CTL_DEL, fd, std::ptr::null_mut())
+        };
+
+        if result == 0 {
+            self.count -= 1;
+        }
+    }
+
+    pub fn wait(
+        &mut self,
+        timeout: Duration) -> usize {
+        /* Nothing to wait on, act like a sleep */
+        if self.count == 0 {
+            std::thread::sleep(timeout);
+            return 0;
+        }
+
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+
+        let ready = unsafe {
+            epoll_wait(
+                self.fd,
+                self.events.as_mut_ptr(),
+                self.events.len() as i32,
+                timeout)
+        };
+
+        if ready <= 0 {
+            return 0;
+        }
+
+        let ready = ready as usize;
+
+        for i in 0..ready {
+            let event = self.events[i];
+
+            /*
+             * Per-task events hang up once the task exits, which would
+             * wake us on every wait. The buffer is still mapped and read,
+             * so only stop waiting on it.
+             */
+            if event.events & EPOLLHUP as u32 != 0 {
+                self.remove(event.u64 as i32);
+            }
+        }
+
+        ready
+    }
+}
+
+impl Drop for CpuRingPoll {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait() {
+        let mut poll = CpuRingPoll::new().unwrap();
+        let mut fds = [0i32; 2];
+
+        unsafe {
+            assert_eq!(0, pipe2(fds.as_mut_ptr(), O_CLOEXEC));
+        }
+
+        poll.add(fds[0]).unwrap();
+
+        /* Nothing written, times out */
+        assert_eq!(0, poll.wait(Duration::from_millis(1)));
+
+        let byte = 1u8;
+
+        unsafe {
+            assert_eq!(1, write(fds[1], &byte as *const u8 as *const c_void, 1));
+        }
+
+        assert_eq!(1, poll.wait(Duration::from_millis(1000)));
+
+        /* Writer closed, hang up stops further waits on it */
+        unsafe {
+            close(fds[1]);
+        }
+
+        assert_eq!(1, poll.wait(Duration::from_millis(1000)));
+
+        /* Unread data is still there, but no longer waited on */
+        assert_eq!(0, poll.wait(Duration::from_millis(1)));
+
+        unsafe {
+            close(fds[0]);
+        }
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+use std::os::fd::AsRawFd;
+
 use super::*;
 
 type BoxedBuilderHook = Box<dyn FnOnce(&mut RingBufSessionBuilder)>;
@@ -38,7 +40,13 @@ pub struct RingBufSessionBuilder {
     profiling_builder: Option<RingBufBuilder<Profiling>>,
     cswitch_builder: Option<RingBufBuilder<ContextSwitches>>,
     bpf_builder: Option<RingBufBuilder<Bpf>>,
+    pmu_builders: Option<Vec<RingBufBuilder<Pmu>>>,
+    pmu_groups: Option<Vec<RingBufGroupBuilder>>,
     hooks: Option<Vec<RingBufSessionHook>>,
+    task_flags: u64,
+    target_cgroup: Option<String>,
+    wakeup_watermark: u32,
+    max_pages: usize,
 }
 
 impl Default for RingBufSessionBuilder {
@@ -57,7 +65,13 @@ impl RingBufSessionBuilder {
             profiling_builder: None,
             cswitch_builder: None,
             bpf_builder: None,
+            pmu_builders: None,
+            pmu_groups: None,
             hooks: None,
+            task_flags: 0,
+            target_cgroup: None,
+            wakeup_watermark: 0,
+            max_pages: 0,
         }
     }
 
@@ -84,7 +98,66 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
+        }
+    }
+
+    pub fn with_inherit(&mut self) -> Self {
+        /* Target PIDs also follow any children they create */
+        Self {
+            pages: self.pages,
+            target_pids: self.target_pids.take(),
+            kernel_builder: self.kernel_builder.take(),
+            event_builder: self.event_builder.take(),
+            profiling_builder: self.profiling_builder.take(),
+            cswitch_builder: self.cswitch_builder.take(),
+            bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
+            hooks: self.hooks.take(),
+            task_flags: self.task_flags | FLAG_INHERIT,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
+        }
+    }
+
+    pub fn with_launched_process(
+        &mut self,
+        process: &LaunchedProcess) -> Self {
+        /* Capture starts when the process execs, not when enabled */
+        let mut builder = self.with_target_pid(process.pid());
+
+        builder.task_flags |= FLAG_INHERIT | FLAG_ENABLE_ON_EXEC;
+
+        builder
+    }
+
+    pub fn with_target_cgroup(
+        &mut self,
+        path: &str) -> Self {
+        /* Only tasks within the cgroup (and below) are captured */
+        Self {
+            pages: self.pages,
+            target_pids: self.target_pids.take(),
+            kernel_builder: self.kernel_builder.take(),
+            event_builder: self.event_builder.take(),
+            profiling_builder: self.profiling_builder.take(),
+            cswitch_builder: self.cswitch_builder.take(),
+            bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
+            hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: Some(path.to_owned()),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -99,7 +172,57 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
+            hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
+        }
+    }
+
+    pub fn with_wakeup_watermark(
+        &mut self,
+        bytes: u32) -> Self {
+        /* Readers are woken once a CPU buffer holds this many bytes */
+        Self {
+            pages: self.pages,
+            target_pids: self.target_pids.take(),
+            kernel_builder: self.kernel_builder.take(),
+            event_builder: self.event_builder.take(),
+            profiling_builder: self.profiling_builder.take(),
+            cswitch_builder: self.cswitch_builder.take(),
+            bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
+            hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: bytes,
+            max_pages: self.max_pages,
+        }
+    }
+
+    pub fn with_auto_page_count(
+        &mut self,
+        max_pages: usize) -> Self {
+        /* CPU buffers that lose records double in size, up to max_pages */
+        Self {
+            pages: self.pages,
+            target_pids: self.target_pids.take(),
+            kernel_builder: self.kernel_builder.take(),
+            event_builder: self.event_builder.take(),
+            profiling_builder: self.profiling_builder.take(),
+            cswitch_builder: self.cswitch_builder.take(),
+            bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages,
         }
     }
 
@@ -114,7 +237,13 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -140,7 +269,13 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -166,7 +301,13 @@ impl RingBufSessionBuilder {
             profiling_builder: Some(builder),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -192,7 +333,13 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: Some(builder),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -218,7 +365,13 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: Some(builder),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -233,6 +386,78 @@ impl RingBufSessionBuilder {
         self.bpf_builder.replace(builder)
     }
 
+    pub fn with_pmu_events(
+        &mut self,
+        builder: RingBufBuilder<Pmu>) -> Self {
+        let mut pmu_builders = self.pmu_builders.take().unwrap_or_default();
+
+        pmu_builders.push(builder);
+
+        Self {
+            pages: self.pages,
+            target_pids: self.target_pids.take(),
+            kernel_builder: self.kernel_builder.take(),
+            event_builder: self.event_builder.take(),
+            profiling_builder: self.profiling_builder.take(),
+            cswitch_builder: self.cswitch_builder.take(),
+            bpf_builder: self.bpf_builder.take(),
+            pmu_builders: Some(pmu_builders),
+            pmu_groups: self.pmu_groups.take(),
+            hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
+        }
+    }
+
+    pub fn take_pmu_events(
+        &mut self) -> Option<Vec<RingBufBuilder<Pmu>>> {
+        self.pmu_builders.take()
+    }
+
+    pub fn replace_pmu_events(
+        &mut self,
+        builders: Vec<RingBufBuilder<Pmu>>) -> Option<Vec<RingBufBuilder<Pmu>>> {
+        self.pmu_builders.replace(builders)
+    }
+
+    pub fn with_pmu_group(
+        &mut self,
+        builder: RingBufGroupBuilder) -> Self {
+        let mut pmu_groups = self.pmu_groups.take().unwrap_or_default();
+
+        pmu_groups.push(builder);
+
+        Self {
+            pages: self.pages,
+            target_pids: self.target_pids.take(),
+            kernel_builder: self.kernel_builder.take(),
+            event_builder: self.event_builder.take(),
+            profiling_builder: self.profiling_builder.take(),
+            cswitch_builder: self.cswitch_builder.take(),
+            bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: Some(pmu_groups),
+            hooks: self.hooks.take(),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
+        }
+    }
+
+    pub fn take_pmu_groups(
+        &mut self) -> Option<Vec<RingBufGroupBuilder>> {
+        self.pmu_groups.take()
+    }
+
+    pub fn replace_pmu_groups(
+        &mut self,
+        builders: Vec<RingBufGroupBuilder>) -> Option<Vec<RingBufGroupBuilder>> {
+        self.pmu_groups.replace(builders)
+    }
+
     pub fn with_hooks(
         &mut self,
         builder_hook: impl FnOnce(&mut RingBufSessionBuilder) + 'static,
@@ -252,7 +477,13 @@ impl RingBufSessionBuilder {
             profiling_builder: self.profiling_builder.take(),
             cswitch_builder: self.cswitch_builder.take(),
             bpf_builder: self.bpf_builder.take(),
+            pmu_builders: self.pmu_builders.take(),
+            pmu_groups: self.pmu_groups.take(),
             hooks: Some(hooks),
+            task_flags: self.task_flags,
+            target_cgroup: self.target_cgroup.take(),
+            wakeup_watermark: self.wakeup_watermark,
+            max_pages: self.max_pages,
         }
     }
 
@@ -267,14 +498,32 @@ impl RingBufSessionBuilder {
             }
         }
 
+        let target_cgroup = match self.target_cgroup.take() {
+            Some(path) => { Some(TargetCgroup::open(&path)?) },
+            None => { None },
+        };
+
+        if target_cgroup.is_some() && self.target_pids.is_some() {
+            return Err(io_error(
+                "Target PIDs cannot be used with a target cgroup."));
+        }
+
         let mut source = RingBufDataSource::new(
-            self.pages,
-            self.target_pids.take(),
-            self.kernel_builder.take(),
-            self.event_builder.take(),
-            self.profiling_builder.take(),
-            self.cswitch_builder.take(),
-            self.bpf_builder.take());
+            RingBufSourceOptions {
+                pages: self.pages,
+                target_pids: self.target_pids.take(),
+                target_cgroup,
+                kernel_builder: self.kernel_builder.take(),
+                event_builder: self.event_builder.take(),
+                profiling_builder: self.profiling_builder.take(),
+                cswitch_builder: self.cswitch_builder.take(),
+                bpf_builder: self.bpf_builder.take(),
+                pmu_builders: self.pmu_builders.take(),
+                pmu_groups: self.pmu_groups.take(),
+                task_flags: self.task_flags,
+                wakeup_watermark: self.wakeup_watermark,
+                max_pages: self.max_pages,
+            });
 
         source.build()?;
 
@@ -292,49 +541,180 @@ impl RingBufSessionBuilder {
     }
 }
 
+struct TargetCgroup {
+    dir: File,
+    pids: Vec<i32>,
+}
+
+impl TargetCgroup {
+    fn open(path: &str) -> IOResult<Self> {
+        /* Paths are either absolute or relative to the cgroup v2 mount */
+        let mount = procfs::cgroup2_mount();
+
+        let path = if Path::new(path).starts_with(&mount) {
+            PathBuf::from(path)
+        } else {
+            mount.join(path.trim_start_matches('/'))
+        };
+
+        let dir = File::open(&path)?;
+        let mut pids = Vec::new();
+
+        Self::add_pids(&path, &mut pids);
+
+        Ok(Self {
+            dir,
+            pids,
+        })
+    }
+
+    fn add_pids(
+        path: &Path,
+        pids: &mut Vec<i32>) {
+        /* Existing processes in the cgroup and any child cgroups */
+        if let Ok(procs) = std::fs::read_to_string(path.join("cgroup.procs")) {
+            for line in procs.lines() {
+                if let Ok(pid) = line.trim().parse::<i32>() {
+                    pids.push(pid);
+                }
+            }
+        }
+
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    Self::add_pids(&entry.path(), pids);
+                }
+            }
+        }
+    }
+}
+
+/* Everything a data source is built from, taken from the session builder */
+#[derive(Default)]
+struct RingBufSourceOptions {
+    pages: usize,
+    target_pids: Option<Vec<i32>>,
+    target_cgroup: Option<TargetCgroup>,
+    kernel_builder: Option<RingBufBuilder<Kernel>>,
+    event_builder: Option<RingBufBuilder<Tracepoint>>,
+    profiling_builder: Option<RingBufBuilder<Profiling>>,
+    cswitch_builder: Option<RingBufBuilder<ContextSwitches>>,
+    bpf_builder: Option<RingBufBuilder<Bpf>>,
+    pmu_builders: Option<Vec<RingBufBuilder<Pmu>>>,
+    pmu_groups: Option<Vec<RingBufGroupBuilder>>,
+    task_flags: u64,
+    wakeup_watermark: u32,
+    max_pages: usize,
+}
+
+struct PendingRing {
+    reader: CpuRingReader,
+    id: u64,
+    pages: usize,
+}
+
 pub struct RingBufDataSource {
     readers: Vec<CpuRingReader>,
     cursors: Vec<CpuRingCursor>,
+    reader_pages: Vec<usize>,
+    pending_rings: HashMap<usize, PendingRing>,
+    lost_cpus: HashSet<usize>,
+    poll: Option<poll::CpuRingPoll>,
     temp: Vec<u8>,
     leader_ids: HashMap<u32, u64>,
     ring_bufs: HashMap<u64, CpuRingBuf>,
     pages: usize,
     enabled: bool,
     target_pids: Option<Vec<i32>>,
+    target_cgroup: Option<TargetCgroup>,
     kernel_builder: Option<RingBufBuilder<Kernel>>,
     event_builder: Option<RingBufBuilder<Tracepoint>>,
     profiling_builder: Option<RingBufBuilder<Profiling>>,
     cswitch_builder: Option<RingBufBuilder<ContextSwitches>>,
     bpf_builder: Option<RingBufBuilder<Bpf>>,
+    pmu_builders: Option<Vec<RingBufBuilder<Pmu>>>,
+    pmu_groups: Option<Vec<RingBufGroupBuilder>>,
     next_time: Option<u64>,
     oldest_cpu: Option<usize>,
+    task_flags: u64,
+    open_flags: usize,
+    exec_pending: bool,
+    wakeup_watermark: u32,
+    max_pages: usize,
+    leader_common: Option<CommonRingBuf>,
 }
 
 impl RingBufDataSource {
-    fn new(
-        pages: usize,
-        target_pids: Option<Vec<i32>>,
-        kernel_builder: Option<RingBufBuilder<Kernel>>,
-        event_builder: Option<RingBufBuilder<Tracepoint>>,
-        profiling_builder: Option<RingBufBuilder<Profiling>>,
-        cswitch_builder: Option<RingBufBuilder<ContextSwitches>>,
-        bpf_builder: Option<RingBufBuilder<Bpf>>) -> Self {
+    fn new(options: RingBufSourceOptions) -> Self {
+        let RingBufSourceOptions {
+            pages,
+            target_pids,
+            target_cgroup,
+            kernel_builder,
+            event_builder,
+            profiling_builder,
+            cswitch_builder,
+            bpf_builder,
+            pmu_builders,
+            pmu_groups,
+            task_flags,
+            wakeup_watermark,
+            max_pages,
+        } = options;
+
+        /* Task flags only apply to per-PID buffers */
+        let task_flags = match target_pids {
+            Some(_) => { task_flags },
+            None => { 0 },
+        };
+
+        /* Cgroup buffers are opened per-CPU using the cgroup fd */
+        let (target_pids, open_flags) = match &target_cgroup {
+            Some(cgroup) => {
+                (Some(vec![cgroup.dir.as_raw_fd()]), PERF_FLAG_PID_CGROUP)
+            },
+            None => { (target_pids, 0) },
+        };
+
+        /*
+         * Growing re-opens the leader for the first target only, children
+         * of inherited targets would stop reporting their kernel records.
+         */
+        let max_pages = match task_flags & FLAG_INHERIT {
+            0 => { max_pages },
+            _ => { 0 },
+        };
+
         Self {
             readers: Vec::new(),
             cursors: Vec::new(),
+            reader_pages: Vec::new(),
+            pending_rings: HashMap::new(),
+            lost_cpus: HashSet::new(),
+            poll: None,
             temp: Vec::new(),
             leader_ids: HashMap::new(),
             ring_bufs: HashMap::new(),
             pages,
             target_pids,
+            target_cgroup,
             kernel_builder,
             event_builder,
             profiling_builder,
             cswitch_builder,
             bpf_builder,
+            pmu_builders,
+            pmu_groups,
             next_time: None,
             oldest_cpu: None,
             enabled: false,
+            task_flags,
+            open_flags,
+            exec_pending: task_flags & FLAG_ENABLE_ON_EXEC != 0,
+            wakeup_watermark,
+            max_pages,
+            leader_common: None,
         }
     }
 
@@ -379,11 +759,88 @@ impl RingBufDataSource {
         Ok(())
     }
 
+    fn add_cpu_group_bufs(
+        target_pid: Option<i32>,
+        leader_ids: &HashMap<u32, u64>,
+        ring_bufs: &mut HashMap<u64, CpuRingBuf>,
+        group_leader: &CommonRingBuf,
+        group_members: &[CommonRingBuf]) -> IOResult<()> {
+        /*
+         * Utility function to allocate per-cpu counter groups.
+         * The group leader is redirected to the kernel leader
+         * buffers, the members are only read along with it.
+         */
+        for i in 0..cpu_count() {
+            let leader_id = leader_ids[&i];
+            let leader = &ring_bufs[&leader_id];
+            let mut cpu_buf = group_leader.for_cpu(i);
+
+            cpu_buf.open(target_pid)?;
+            cpu_buf.redirect_to(leader)?;
+
+            for member in group_members {
+                let mut member_buf = member.for_cpu(i);
+
+                member_buf.open_in_group(target_pid, &cpu_buf)?;
+
+                match member_buf.id() {
+                    Some(id) => { ring_bufs.insert(id, member_buf); },
+                    None => {
+                        return Err(io_error(
+                            "Internal error getting buffer ID."));
+                    }
+                }
+            }
+
+            match cpu_buf.id() {
+                Some(id) => { ring_bufs.insert(id, cpu_buf); },
+                None => {
+                    return Err(io_error(
+                        "Internal error getting buffer ID."));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn without_unsupported_branch_stack(
+        common: CommonRingBuf,
+        target_pid: Option<i32>) -> CommonRingBuf {
+        if !common.has_branch_stack() {
+            return common;
+        }
+
+        /*
+         * Branch stacks need LBR (or similar) from the CPU and are not
+         * supported by software events. Probe the first CPU and drop the
+         * branch stack when unsupported, instead of failing the session.
+         */
+        let mut probe = common.for_cpu(0);
+
+        match probe.open(target_pid) {
+            Ok(()) => { common },
+            Err(error) => {
+                /* Other errors are real attr mistakes, let them surface */
+                match error.raw_os_error() {
+                    Some(EOPNOTSUPP) => {
+                        eprintln!("WARN: Branch stacks are not supported, capturing without them");
+                        common.without_branch_stack()
+                    },
+                    _ => { common },
+                }
+            },
+        }
+    }
+
     fn build(&mut self) -> IOResult<()> {
         /* Always required */
         let common = self.kernel_builder
             .get_or_insert_with(RingBufBuilder::for_kernel)
-            .build();
+            .build()
+            .with_task_flags(self.task_flags)
+            .with_open_flags(self.open_flags)
+            .with_wakeup_watermark(self.wakeup_watermark);
 
         let empty_pids = Vec::new();
 
@@ -410,6 +867,7 @@ impl RingBufDataSource {
                     let reader = cpu_buf.create_reader(self.pages)?;
                     self.readers.push(reader);
                     self.cursors.push(CpuRingCursor::default());
+                    self.reader_pages.push(self.pages);
 
                     self.ring_bufs.insert(id, cpu_buf);
                 },
@@ -433,9 +891,20 @@ impl RingBufDataSource {
             }
         }
 
+        /* Kept to re-open leaders when they need to grow */
+        self.leader_common = Some(common);
+
+        /* Wait on the leaders for data, otherwise we sleep */
+        self.poll = self.create_poll();
+
         /* Add in profiling samples and redirect to kernel outputs */
         if let Some(profiling_builder) = self.profiling_builder.as_mut() {
-            let common = profiling_builder.build();
+            let common = Self::without_unsupported_branch_stack(
+                profiling_builder
+                    .build()
+                    .with_task_flags(self.task_flags)
+                    .with_open_flags(self.open_flags),
+                pids.first().copied());
 
             if pids.is_empty() {
                 Self::add_cpu_bufs(
@@ -458,7 +927,10 @@ impl RingBufDataSource {
 
         /* Add in cswitch samples and redirect to kernel outputs */
         if let Some(cswitch_builder) = self.cswitch_builder.as_mut() {
-            let common = cswitch_builder.build();
+            let common = cswitch_builder
+                .build()
+                .with_task_flags(self.task_flags)
+                .with_open_flags(self.open_flags);
 
             if pids.is_empty() {
                 Self::add_cpu_bufs(
@@ -479,10 +951,238 @@ impl RingBufDataSource {
             }
         }
 
+        /* Add in PMU samples and redirect to kernel outputs */
+        if let Some(pmu_builders) = self.pmu_builders.as_mut() {
+            for pmu_builder in pmu_builders {
+                let common = Self::without_unsupported_branch_stack(
+                    pmu_builder
+                        .build()
+                        .with_task_flags(self.task_flags)
+                        .with_open_flags(self.open_flags),
+                    pids.first().copied());
+
+                if pids.is_empty() {
+                    Self::add_cpu_bufs(
+                        None,
+                        &self.leader_ids,
+                        &mut self.ring_bufs,
+                        &common,
+                        None).map_err(pmu_open_error)?;
+                } else {
+                    for pid in pids {
+                        Self::add_cpu_bufs(
+                            Some(*pid),
+                            &self.leader_ids,
+                            &mut self.ring_bufs,
+                            &common,
+                            None).map_err(pmu_open_error)?;
+                    }
+                }
+            }
+        }
+
+        /* Add in PMU counter groups and redirect to kernel outputs */
+        if let Some(pmu_groups) = self.pmu_groups.as_mut() {
+            for pmu_group in pmu_groups {
+                let (leader, members) = pmu_group.build();
+
+                let leader = leader
+                    .with_task_flags(self.task_flags)
+                    .with_open_flags(self.open_flags);
+
+                let members: Vec<CommonRingBuf> = members
+                    .into_iter()
+                    .map(|member| {
+                        member
+                            .with_task_flags(self.task_flags)
+                            .with_open_flags(self.open_flags)
+                    })
+                    .collect();
+
+                if pids.is_empty() {
+                    Self::add_cpu_group_bufs(
+                        None,
+                        &self.leader_ids,
+                        &mut self.ring_bufs,
+                        &leader,
+                        &members).map_err(pmu_open_error)?;
+                } else {
+                    for pid in pids {
+                        Self::add_cpu_group_bufs(
+                            Some(*pid),
+                            &self.leader_ids,
+                            &mut self.ring_bufs,
+                            &leader,
+                            &members).map_err(pmu_open_error)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_poll(&self) -> Option<poll::CpuRingPoll> {
+        let mut poll = poll::CpuRingPoll::new().ok()?;
+
+        for id in self.leader_ids.values() {
+            if let Some(fd) = self.ring_bufs[id].fd {
+                poll.add(fd).ok()?;
+            }
+        }
+
+        Some(poll)
+    }
+
+    fn redirect_cpu_bufs(
+        &mut self,
+        ids: &[u64],
+        target_id: u64) -> IOResult<()> {
+        for id in ids {
+            /* Take out to redirect, target lives in the same map */
+            let mut rb = match self.ring_bufs.remove(id) {
+                Some(rb) => { rb },
+                None => { continue; },
+            };
+
+            let result = rb.redirect_to(&self.ring_bufs[&target_id]);
+
+            self.ring_bufs.insert(*id, rb);
+
+            result?;
+        }
+
         Ok(())
     }
 
+    fn grow_cpu_buf(
+        &mut self,
+        cpu: usize) -> IOResult<()> {
+        let pages = self.reader_pages[cpu].next_power_of_two() * 2;
+
+        if pages > self.max_pages {
+            return Ok(());
+        }
+
+        let common = match &self.leader_common {
+            Some(common) => { common },
+            None => { return Ok(()); },
+        };
+
+        let target_pid = match &self.target_pids {
+            Some(pids) => { pids.first().copied() },
+            None => { None },
+        };
+
+        let mut leader = common.for_cpu(cpu as u32);
+
+        leader.open(target_pid)?;
+
+        let id = match leader.id() {
+            Some(id) => { id },
+            None => {
+                return Err(io_error(
+                    "Internal error getting buffer ID."));
+            }
+        };
+
+        let reader = leader.create_reader(pages)?;
+        let old_id = self.leader_ids[&(cpu as u32)];
+
+        let ids: Vec<u64> = self.ring_bufs
+            .iter()
+            .filter(|(_, rb)| rb.cpu() == cpu as u32 && rb.is_redirected())
+            .map(|(id, _)| *id)
+            .collect();
+
+        self.ring_bufs.insert(id, leader);
+
+        /* Move everything over to the new buffer, or put it all back */
+        if let Err(error) = self.redirect_cpu_bufs(&ids, id) {
+            let _ = self.redirect_cpu_bufs(&ids, old_id);
+            self.ring_bufs.remove(&id);
+
+            return Err(error);
+        }
+
+        /*
+         * New leader comes up before the old one goes down, so kernel
+         * records may be duplicated for a moment but are never missed.
+         */
+        if self.enabled {
+            self.ring_bufs[&id].enable()?;
+            self.ring_bufs[&old_id].disable()?;
+        }
+
+        if let (Some(poll), Some(fd)) = (self.poll.as_mut(), self.ring_bufs[&id].fd) {
+            let _ = poll.add(fd);
+        }
+
+        /* Old buffer is drained before the new one is read */
+        self.pending_rings.insert(
+            cpu,
+            PendingRing {
+                reader,
+                id,
+                pages,
+            });
+
+        Ok(())
+    }
+
+    fn grow_lost_bufs(&mut self) {
+        let cpus: Vec<usize> = self.lost_cpus.drain().collect();
+
+        for cpu in cpus {
+            if self.pending_rings.contains_key(&cpu) {
+                continue;
+            }
+
+            /* IE: Over the mlock limit, keep the current sizes */
+            if self.grow_cpu_buf(cpu).is_err() {
+                self.max_pages = 0;
+            }
+        }
+    }
+
+    fn swap_pending_bufs(&mut self) {
+        let cpus: Vec<usize> = self.pending_rings.keys().copied().collect();
+
+        for cpu in cpus {
+            let cursor = &mut self.cursors[cpu];
+
+            self.readers[cpu].begin_reading(cursor);
+
+            /* Still data from before the redirect */
+            if cursor.more() {
+                continue;
+            }
+
+            let pending = self.pending_rings.remove(&cpu).unwrap();
+
+            if let Some(old_id) = self.leader_ids.insert(cpu as u32, pending.id) {
+                if let Some(old) = self.ring_bufs.remove(&old_id) {
+                    if let (Some(poll), Some(fd)) = (self.poll.as_mut(), old.fd) {
+                        poll.remove(fd);
+                    }
+                }
+            }
+
+            self.readers[cpu] = pending.reader;
+            self.cursors[cpu] = CpuRingCursor::default();
+            self.reader_pages[cpu] = pending.pages;
+        }
+    }
+
     fn enable(&mut self) -> IOResult<()> {
+        /* The kernel enables these once the launched process execs */
+        if self.exec_pending {
+            self.exec_pending = false;
+            self.enabled = true;
+
+            return Ok(());
+        }
+
         for rb in self.ring_bufs.values() {
             rb.enable()?;
         }
@@ -613,6 +1313,11 @@ impl PerfDataSource for RingBufDataSource {
     }
 
     fn target_pids(&self) -> Option<&[i32]> {
+        /* Cgroup targets are fds, give back the processes within */
+        if let Some(cgroup) = &self.target_cgroup {
+            return Some(&cgroup.pids);
+        }
+
         match &self.target_pids {
             Some(pids) => { Some(&pids) },
             None => { None },
@@ -625,7 +1330,10 @@ impl PerfDataSource for RingBufDataSource {
         let mut files = Vec::new();
 
         if let Some(bpf_builder) = self.bpf_builder.as_mut() {
-            let mut common = bpf_builder.build();
+            let mut common = bpf_builder
+                .build()
+                .with_task_flags(self.task_flags)
+                .with_open_flags(self.open_flags);
 
             if let Some(event) = &event {
                 if event.has_no_callstack_flag() {
@@ -663,7 +1371,10 @@ impl PerfDataSource for RingBufDataSource {
         event: &Event) -> IOResult<()> {
         /* Add in all the events and redirect to kernel outputs */
         if let Some(event_builder) = self.event_builder.as_mut() {
-            let mut common = event_builder.build(event.id() as u64);
+            let mut common = event_builder
+                .build(event.id() as u64)
+                .with_task_flags(self.task_flags)
+                .with_open_flags(self.open_flags);
 
             /* Mutate attributes based on flags */
             if event.has_no_callstack_flag() {
@@ -696,6 +1407,11 @@ impl PerfDataSource for RingBufDataSource {
     }
 
     fn begin_reading(&mut self) {
+        if self.max_pages != 0 {
+            self.swap_pending_bufs();
+            self.grow_lost_bufs();
+        }
+
         for i in 0..self.readers.len() {
             let reader = &mut self.readers[i];
             let cursor = &mut self.cursors[i];
@@ -711,7 +1427,11 @@ impl PerfDataSource for RingBufDataSource {
         timeout: Duration) -> Option<PerfData<'_>> {
         /* Bail if we couldn't find a current buffer */
         if self.oldest_cpu.is_none() {
-            std::thread::sleep(timeout);
+            match self.poll.as_mut() {
+                Some(poll) => { poll.wait(timeout); },
+                None => { std::thread::sleep(timeout); },
+            }
+
             return None;
         }
 
@@ -748,6 +1468,15 @@ impl PerfDataSource for RingBufDataSource {
             cursor,
             &mut self.temp) {
             Ok(raw_data) => {
+                /* Lost records mean this CPU buffer is too small */
+                if self.max_pages != 0 {
+                    if let Ok(header) = abi::Header::from_slice(raw_data) {
+                        if header.entry_type == abi::PERF_RECORD_LOST {
+                            self.lost_cpus.insert(cpu);
+                        }
+                    }
+                }
+
                 let perf_data = PerfData {
                     ancillary,
                     raw_data,
@@ -784,6 +1513,35 @@ mod tests {
     use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
+    fn touch_fresh_pages(count: usize) {
+        /*
+         * Heap memory may be reused and already faulted in, so map
+         * fresh anonymous pages to be sure each touch faults.
+         */
+        unsafe {
+            let page_size = libc::sysconf(libc::_SC_PAGE_SIZE) as usize;
+            let len = count * page_size;
+
+            let addr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0);
+
+            assert_ne!(libc::MAP_FAILED, addr);
+
+            let pages = addr as *mut u8;
+
+            for i in (0..len).step_by(page_size) {
+                std::ptr::write_volatile(pages.add(i), 1);
+            }
+
+            libc::munmap(addr, len);
+        }
+    }
+
     #[test]
     fn config() {
         let kernel = RingBufBuilder::for_kernel()
@@ -802,6 +1560,67 @@ mod tests {
             .with_page_count(1)
             .with_kernel_events(kernel)
             .with_profiling_events(profiling);
+
+        let cycles = RingBufBuilder::for_hardware(
+            abi::PERF_COUNT_HW_CPU_CYCLES)
+            .with_period(100000);
+
+        assert_eq!(PERF_TYPE_HARDWARE, cycles.event_type());
+        assert_eq!(abi::PERF_COUNT_HW_CPU_CYCLES, cycles.config());
+
+        let misses = RingBufBuilder::for_hw_cache(
+            abi::PERF_COUNT_HW_CACHE_LL,
+            abi::PERF_COUNT_HW_CACHE_OP_READ,
+            abi::PERF_COUNT_HW_CACHE_RESULT_MISS);
+
+        assert_eq!(PERF_TYPE_HW_CACHE, misses.event_type());
+        assert_eq!(0x10002, misses.config());
+
+        let mut builder = RingBufSessionBuilder::new()
+            .with_pmu_events(cycles)
+            .with_pmu_events(misses);
+
+        assert_eq!(2, builder.take_pmu_events().unwrap().len());
+
+        let group = RingBufBuilder::for_pmu_group(
+            RingBufBuilder::for_hardware(abi::PERF_COUNT_HW_CPU_CYCLES))
+            .with_member(RingBufBuilder::for_hardware(abi::PERF_COUNT_HW_INSTRUCTIONS))
+            .with_callchain_data();
+
+        let (leader, members) = group.build();
+
+        assert_eq!(1, members.len());
+        assert!(leader.attributes.has_format(abi::PERF_SAMPLE_READ));
+        assert!(leader.attributes.has_format(abi::PERF_SAMPLE_CALLCHAIN));
+        assert!(leader.attributes.has_read_format(abi::PERF_FORMAT_GROUP));
+        assert_eq!(0, members[0].attributes.sample_period_freq);
+        assert_eq!(0, members[0].attributes.flags & FLAG_DISABLED);
+        assert_eq!(abi::PERF_COUNT_HW_INSTRUCTIONS, members[0].attributes.config);
+
+        let mut builder = builder.with_pmu_group(group);
+
+        assert_eq!(1, builder.take_pmu_groups().unwrap().len());
+
+        let builder = RingBufSessionBuilder::new()
+            .with_wakeup_watermark(4096)
+            .with_auto_page_count(64);
+
+        assert_eq!(4096, builder.wakeup_watermark);
+        assert_eq!(64, builder.max_pages);
+
+        let common = RingBufBuilder::for_kernel()
+            .build()
+            .with_wakeup_watermark(4096);
+
+        assert!(common.attributes.flags & FLAG_WATERMARK != 0);
+        assert_eq!(4096, common.attributes.wakeup_events_watermark);
+
+        /* Zero keeps the default wakeups */
+        let common = RingBufBuilder::for_kernel()
+            .build()
+            .with_wakeup_watermark(0);
+
+        assert_eq!(0, common.attributes.flags & FLAG_WATERMARK);
     }
 
     #[test]
@@ -878,4 +1697,229 @@ mod tests {
         println!("Got {} samples", count);
         assert!(count >= 100);
     }
+
+    #[test]
+    #[ignore]
+    fn page_faults() {
+        let mut session = RingBufSessionBuilder::new()
+            .with_page_count(8)
+            .with_target_pid(unsafe { libc::gettid() })
+            .with_pmu_events(RingBufBuilder::for_page_faults())
+            .build()
+            .unwrap();
+
+        session.set_read_timeout(Duration::from_millis(0));
+
+        let samples = Arc::new(AtomicUsize::new(0));
+        let callback_samples = samples.clone();
+        let period_data = session.period_data_ref();
+
+        session.pmu_profile_event(
+            PERF_TYPE_SOFTWARE,
+            abi::PERF_COUNT_SW_PAGE_FAULTS,
+            PmuEventKind::Sample).add_callback(move |data| {
+            assert_eq!(1, period_data.try_get_u64(data.full_data()).unwrap());
+
+            callback_samples.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.enable().unwrap();
+
+        /* Touch fresh pages to fault them in */
+        touch_fresh_pages(64);
+
+        session.disable().unwrap();
+        session.parse_all().unwrap();
+
+        assert!(samples.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn branch_stack_fallback() {
+        /* Software events never have branch stacks, so this must fall back */
+        let mut session = RingBufSessionBuilder::new()
+            .with_page_count(8)
+            .with_target_pid(unsafe { libc::gettid() })
+            .with_pmu_events(
+                RingBufBuilder::for_page_faults()
+                    .with_branch_stack_data(
+                        abi::PERF_SAMPLE_BRANCH_USER |
+                        abi::PERF_SAMPLE_BRANCH_ANY))
+            .build()
+            .unwrap();
+
+        session.set_read_timeout(Duration::from_millis(0));
+
+        let samples = Arc::new(AtomicUsize::new(0));
+        let callback_samples = samples.clone();
+        let branch_data = session.branch_stack_data_ref();
+
+        session.pmu_profile_event(
+            PERF_TYPE_SOFTWARE,
+            abi::PERF_COUNT_SW_PAGE_FAULTS,
+            PmuEventKind::Sample).add_callback(move |data| {
+            assert!(branch_data.get_data(data.full_data()).is_empty());
+
+            callback_samples.fetch_add(1, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        session.enable().unwrap();
+
+        touch_fresh_pages(64);
+
+        session.disable().unwrap();
+        session.parse_all().unwrap();
+
+        assert!(samples.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn auto_page_count() {
+        let mut source = RingBufDataSource::new(
+            RingBufSourceOptions {
+                pages: 1,
+                target_pids: Some(vec![unsafe { libc::gettid() }]),
+                pmu_builders: Some(vec![RingBufBuilder::for_page_faults()]),
+                max_pages: 8,
+                ..Default::default()
+            });
+
+        source.build().unwrap();
+        source.enable().unwrap();
+
+        for _ in 0..8 {
+            /* Fault in far more samples than a single page holds */
+            touch_fresh_pages(1024);
+
+            for _ in 0..1024 {
+                source.begin_reading();
+                while source.read(Duration::from_millis(0)).is_some() {}
+                source.end_reading();
+            }
+        }
+
+        source.disable().unwrap();
+
+        /* Lost records grew the buffer, capped at the max */
+        assert!(source.reader_pages.iter().any(|pages| *pages > 1));
+        assert!(source.reader_pages.iter().all(|pages| *pages <= 8));
+    }
+
+    #[test]
+    #[ignore]
+    fn launched_process() {
+        /* Child shell runs another process, both must be captured */
+        let mut process = LaunchedProcess::spawn(
+            "sh",
+            &["-c".into(), "ls / > /dev/null; exit 0".into()]).unwrap();
+
+        let mut session = RingBufSessionBuilder::new()
+            .with_page_count(8)
+            .with_launched_process(&process)
+            .with_pmu_events(RingBufBuilder::for_page_faults())
+            .build()
+            .unwrap();
+
+        session.set_read_timeout(Duration::from_millis(0));
+
+        let pids = crate::Writable::new(HashSet::new());
+        let callback_pids = pids.clone();
+        let pid_data = session.pid_field_ref();
+
+        session.pmu_profile_event(
+            PERF_TYPE_SOFTWARE,
+            abi::PERF_COUNT_SW_PAGE_FAULTS,
+            PmuEventKind::Sample).add_callback(move |data| {
+            let pid = pid_data.get_u32(data.full_data())?;
+
+            callback_pids.borrow_mut().insert(pid);
+
+            Ok(())
+        });
+
+        session.enable().unwrap();
+        process.start().unwrap();
+
+        assert_eq!(0, process.wait().unwrap());
+
+        session.disable().unwrap();
+        session.parse_all().unwrap();
+
+        let pids = pids.borrow();
+
+        assert!(pids.len() >= 2);
+        assert!(pids.contains(&(process.pid() as u32)));
+        assert!(!pids.contains(&std::process::id()));
+    }
+
+    #[test]
+    #[ignore]
+    fn target_cgroup() {
+        use std::os::unix::fs::MetadataExt;
+
+        let name = format!("one_collect_test_{}", std::process::id());
+        let path = procfs::cgroup2_mount().join(&name);
+
+        std::fs::create_dir(&path).unwrap();
+
+        let id = std::fs::metadata(&path).unwrap().ino();
+
+        /* Child moves itself into the cgroup before running ls */
+        let mut process = LaunchedProcess::spawn(
+            "sh",
+            &["-c".into(), format!(
+                "echo $$ > {}/cgroup.procs; ls / > /dev/null; exit 0",
+                path.display())]).unwrap();
+
+        let mut session = RingBufSessionBuilder::new()
+            .with_page_count(8)
+            .with_target_cgroup(&name)
+            .with_pmu_events(
+                RingBufBuilder::for_page_faults()
+                    .with_cgroup_data())
+            .build()
+            .unwrap();
+
+        session.set_read_timeout(Duration::from_millis(0));
+
+        let pids = crate::Writable::new(HashSet::new());
+        let callback_pids = pids.clone();
+        let pid_data = session.pid_field_ref();
+        let cgroup_data = session.cgroup_data_ref();
+
+        session.pmu_profile_event(
+            PERF_TYPE_SOFTWARE,
+            abi::PERF_COUNT_SW_PAGE_FAULTS,
+            PmuEventKind::Sample).add_callback(move |data| {
+            let full_data = data.full_data();
+
+            assert_eq!(id, cgroup_data.get_u64(full_data)?);
+
+            callback_pids.borrow_mut().insert(pid_data.get_u32(full_data)?);
+
+            Ok(())
+        });
+
+        session.enable().unwrap();
+        process.start().unwrap();
+
+        assert_eq!(0, process.wait().unwrap());
+
+        session.disable().unwrap();
+        session.parse_all().unwrap();
+
+        std::fs::remove_dir(&path).unwrap();
+
+        let pids = pids.borrow();
+
+        /* Only the ls run after the move is guaranteed to fault */
+        assert!(!pids.is_empty());
+        assert!(!pids.contains(&std::process::id()));
+    }
 }
@@ -265,6 +265,34 @@ pub fn ns_pid(
     None
 }
 
+/// Gets the mount point of the unified (v2) cgroup hierarchy.
+///
+/// The function reads the `/proc/self/mounts` file to find the `cgroup2` filesystem. Hybrid
+/// systems mount it below the v1 controllers, IE: `/sys/fs/cgroup/unified`.
+///
+/// # Returns
+///
+/// The mount point of the `cgroup2` filesystem, or `/sys/fs/cgroup` if it could not be found.
+pub fn cgroup2_mount() -> PathBuf {
+    if let Ok(file) = File::open("/proc/self/mounts") {
+        for line in BufReader::new(file).lines() {
+            match line {
+                Ok(line) => {
+                    let mut parts = line.split_whitespace();
+                    let mount = parts.nth(1);
+
+                    if let (Some(mount), Some("cgroup2")) = (mount, parts.next()) {
+                        return PathBuf::from(mount);
+                    }
+                },
+                Err(_) => { break; },
+            }
+        }
+    }
+
+    PathBuf::from("/sys/fs/cgroup")
+}
+
 /// Iterates over the memory modules of a process and applies a callback function to each module.
 ///
 /// The function reads the `/proc/{pid}/maps` file to get the list of memory modules.
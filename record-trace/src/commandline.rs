@@ -7,7 +7,9 @@ use std::fmt;
 use std::path::PathBuf;
 use std::process;
 
-use crate::export::{Exporter, NetTraceExporter, PerfViewExporter};
+use one_collect::helpers::exporting::formats::otlp::OTLP_DEFAULT_ENDPOINT;
+
+use crate::export::{Exporter, NetTraceExporter, PerfViewExporter, PerfDataExporter, FoldedExporter, SpeedscopeExporter, ChromeTraceExporter, PerfettoExporter, OtlpExporter, SnapshotExporter};
 
 #[derive(Parser)]
 #[command(version = crate_version!(), about, long_about = None)]
@@ -24,23 +26,77 @@ struct Args {
     #[arg(long, help = "Capture context switches")]
     off_cpu: bool,
 
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture page fault samples")]
+    page_faults: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture minor page fault samples")]
+    minor_faults: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture major page fault samples")]
+    major_faults: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture alignment fault samples")]
+    alignment_faults: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture CPU migration samples")]
+    cpu_migrations: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture task clock samples.  Like --on-cpu, but only counts time while the target tasks run")]
+    task_clock: bool,
+
     #[arg(long, help = "Display samples live")]
     live: bool,
 
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Unwind user stacks by walking frame pointers instead of DWARF.  Cheaper, but requires binaries built with frame pointers")]
+    frame_pointers: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Store raw user stacks while recording and unwind them once recording stops.  Keeps collection overhead low, snapshots keep the raw stacks")]
+    deferred_unwind: bool,
+
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Report why user stack unwinding stopped, per module.  Helps explain truncated stacks")]
+    unwind_stats: bool,
+
     #[arg(long = "pid", help = "Capture data for the specified process ID.  Multiple pids can be specified, one per usage of --pid")]
     target_pids: Option<Vec<i32>>,
 
+    #[cfg(target_os = "linux")]
+    #[arg(long, help = "Capture data only for processes within the specified cgroup (v2), for example /system.slice/docker-<id>.scope.  Samples are labeled with their cgroup and container ID")]
+    cgroup: Option<String>,
+
     #[arg(long, help = "Script snippet to run to enable complex configurations")]
     script: Option<String>,
 
     #[arg(long, help = "Script file to run to enable complex configurations")]
     script_file: Option<String>,
+
+    #[arg(long, num_args = 0..=1, default_missing_value = OTLP_DEFAULT_ENDPOINT, help = "OTLP/HTTP endpoint to post profiles to when using --format otlp, for example http://localhost:4318.  Without a value the local collector default is used")]
+    otlp_endpoint: Option<String>,
+
+    #[cfg(target_os = "linux")]
+    #[arg(last = true, help = "Command to launch and capture, including all of its child processes, until it exits")]
+    command: Vec<String>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Format {
     Nettrace,
     PerfviewXML,
+    PerfData,
+    Folded,
+    Speedscope,
+    ChromeTrace,
+    Perfetto,
+    Otlp,
+    Snapshot,
 }
 
 impl fmt::Display for Format {
@@ -48,6 +104,13 @@ impl fmt::Display for Format {
         match self {
             Format::Nettrace => write!(f, "nettrace"),
             Format::PerfviewXML => write!(f, "perfview-xml"),
+            Format::PerfData => write!(f, "perf-data"),
+            Format::Folded => write!(f, "folded"),
+            Format::Speedscope => write!(f, "speedscope"),
+            Format::ChromeTrace => write!(f, "chrome-trace"),
+            Format::Perfetto => write!(f, "perfetto"),
+            Format::Otlp => write!(f, "otlp"),
+            Format::Snapshot => write!(f, "snapshot"),
         }
     }
 }
@@ -58,9 +121,32 @@ pub (crate) struct RecordArgs {
     format: Format,
     on_cpu: bool,
     off_cpu: bool,
+    #[cfg(target_os = "linux")]
+    page_faults: bool,
+    #[cfg(target_os = "linux")]
+    minor_faults: bool,
+    #[cfg(target_os = "linux")]
+    major_faults: bool,
+    #[cfg(target_os = "linux")]
+    alignment_faults: bool,
+    #[cfg(target_os = "linux")]
+    cpu_migrations: bool,
+    #[cfg(target_os = "linux")]
+    task_clock: bool,
     live: bool,
+    #[cfg(target_os = "linux")]
+    frame_pointers: bool,
+    #[cfg(target_os = "linux")]
+    deferred_unwind: bool,
+    #[cfg(target_os = "linux")]
+    unwind_stats: bool,
     target_pids: Option<Vec<i32>>,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<String>,
     script: Option<String>,
+    otlp_endpoint: Option<String>,
+    #[cfg(target_os = "linux")]
+    command: Vec<String>,
 }
 
 impl RecordArgs {
@@ -93,20 +179,74 @@ impl RecordArgs {
             format: command_args.format,
             on_cpu: command_args.on_cpu,
             off_cpu: command_args.off_cpu,
+            #[cfg(target_os = "linux")]
+            page_faults: command_args.page_faults,
+            #[cfg(target_os = "linux")]
+            minor_faults: command_args.minor_faults,
+            #[cfg(target_os = "linux")]
+            major_faults: command_args.major_faults,
+            #[cfg(target_os = "linux")]
+            alignment_faults: command_args.alignment_faults,
+            #[cfg(target_os = "linux")]
+            cpu_migrations: command_args.cpu_migrations,
+            #[cfg(target_os = "linux")]
+            task_clock: command_args.task_clock,
             live: command_args.live,
+            #[cfg(target_os = "linux")]
+            frame_pointers: command_args.frame_pointers,
+            #[cfg(target_os = "linux")]
+            deferred_unwind: command_args.deferred_unwind,
+            #[cfg(target_os = "linux")]
+            unwind_stats: command_args.unwind_stats,
             target_pids: command_args.target_pids,
+            #[cfg(target_os = "linux")]
+            cgroup: command_args.cgroup,
             script,
+            otlp_endpoint: command_args.otlp_endpoint,
+            #[cfg(target_os = "linux")]
+            command: command_args.command,
         };
 
         // Cross-argument validation.
-        if !args.on_cpu && !args.off_cpu && args.script.is_none() {
+        if !args.has_events() {
             eprintln!("No events or scripts selected. Exiting.");
             process::exit(1);
         }
 
+        #[cfg(target_os = "linux")]
+        args.validate_linux();
+
         args
     }
 
+    fn has_events(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.page_faults || self.minor_faults || self.major_faults ||
+           self.alignment_faults || self.cpu_migrations || self.task_clock {
+            return true;
+        }
+
+        self.on_cpu || self.off_cpu || self.script.is_some()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn validate_linux(&self) {
+        if self.deferred_unwind && (self.live || self.frame_pointers) {
+            eprintln!("--deferred-unwind cannot be used with --live or --frame-pointers. Exiting.");
+            process::exit(1);
+        }
+
+        if self.target_pids.is_some() && !self.command.is_empty() {
+            eprintln!("--pid cannot be used when launching a command. Exiting.");
+            process::exit(1);
+        }
+
+        if self.cgroup.is_some() && (self.target_pids.is_some() || !self.command.is_empty()) {
+            eprintln!("--cgroup cannot be used with --pid or when launching a command. Exiting.");
+            process::exit(1);
+        }
+    }
+
     pub (crate) fn output_path(&self) -> &PathBuf {
         &self.output_path
     }
@@ -115,6 +255,13 @@ impl RecordArgs {
         match self.format {
             Format::Nettrace => Box::new(NetTraceExporter::new()),
             Format::PerfviewXML => Box::new(PerfViewExporter::new()),
+            Format::PerfData => Box::new(PerfDataExporter::new()),
+            Format::Folded => Box::new(FoldedExporter::new()),
+            Format::Speedscope => Box::new(SpeedscopeExporter::new()),
+            Format::ChromeTrace => Box::new(ChromeTraceExporter::new()),
+            Format::Perfetto => Box::new(PerfettoExporter::new()),
+            Format::Otlp => Box::new(OtlpExporter::new()),
+            Format::Snapshot => Box::new(SnapshotExporter::new()),
         }
     }
 
@@ -126,15 +273,112 @@ impl RecordArgs {
         self.off_cpu
     }
 
+    /* Enabled sample kinds with their export file tag and description */
+    pub (crate) fn sample_kinds(&self) -> Vec<(&'static str, &'static str, &'static str)> {
+        let mut kinds = Vec::new();
+
+        if self.on_cpu {
+            kinds.push(("cpu", "CPU", "CPU Samples"));
+        }
+
+        if self.off_cpu {
+            kinds.push(("cswitch", "CSwitch", "Wait Time"));
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let linux_kinds = [
+                (self.page_faults, "page_fault", "PageFault", "Page Faults"),
+                (self.minor_faults, "minor_fault", "MinorFault", "Minor Page Faults"),
+                (self.major_faults, "major_fault", "MajorFault", "Major Page Faults"),
+                (self.alignment_faults, "alignment_fault", "AlignmentFault", "Alignment Faults"),
+                (self.cpu_migrations, "cpu_migration", "CPUMigration", "CPU Migrations"),
+                (self.task_clock, "task_clock", "TaskClock", "Task Clock Samples"),
+            ];
+
+            for (enabled, name, tag, desc) in linux_kinds {
+                if enabled {
+                    kinds.push((name, tag, desc));
+                }
+            }
+        }
+
+        kinds
+    }
+
+    pub (crate) fn sched_switches(&self) -> bool {
+        /* Only Perfetto shows who switched in and out */
+        self.off_cpu && self.format == Format::Perfetto
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn page_faults(&self) -> bool {
+        self.page_faults
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn minor_faults(&self) -> bool {
+        self.minor_faults
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn major_faults(&self) -> bool {
+        self.major_faults
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn alignment_faults(&self) -> bool {
+        self.alignment_faults
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn cpu_migrations(&self) -> bool {
+        self.cpu_migrations
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn task_clock(&self) -> bool {
+        self.task_clock
+    }
+
     pub (crate) fn live(&self) -> bool {
         self.live
     }
 
+    #[cfg(target_os = "linux")]
+    pub (crate) fn frame_pointers(&self) -> bool {
+        self.frame_pointers
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn deferred_unwind(&self) -> bool {
+        self.deferred_unwind
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn unwind_stats(&self) -> bool {
+        self.unwind_stats
+    }
+
     pub (crate) fn target_pids(&self) -> &Option<Vec<i32>> {
         &self.target_pids
     }
 
+    #[cfg(target_os = "linux")]
+    pub (crate) fn cgroup(&self) -> &Option<String> {
+        &self.cgroup
+    }
+
     pub (crate) fn script(&self) -> &Option<String> {
         &self.script
     }
+
+    pub (crate) fn otlp_endpoint(&self) -> &Option<String> {
+        &self.otlp_endpoint
+    }
+
+    #[cfg(target_os = "linux")]
+    pub (crate) fn command(&self) -> &[String] {
+        &self.command
+    }
 }
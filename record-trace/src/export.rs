@@ -4,6 +4,12 @@
 use one_collect::helpers::exporting::ExportMachine;
 use one_collect::helpers::exporting::formats::nettrace::*;
 use one_collect::helpers::exporting::formats::perf_view::*;
+use one_collect::helpers::exporting::formats::perf_data::*;
+use one_collect::helpers::exporting::formats::folded::*;
+use one_collect::helpers::exporting::formats::speedscope::*;
+use one_collect::helpers::exporting::formats::chrome_trace::*;
+use one_collect::helpers::exporting::formats::perfetto::*;
+use one_collect::helpers::exporting::formats::otlp::*;
 use one_collect::helpers::exporting::graph::{ExportGraph, ExportGraphMetricValueConverter};
 use one_collect::helpers::exporting::process::MetricValue;
 
@@ -91,27 +97,17 @@ impl Exporter for PerfViewExporter {
         /* Split by comm name */
         let comm_map = machine.split_processes_by_comm();
 
-        let cpu = match machine.find_sample_kind("cpu") {
-            Some(cpu) => { cpu },
-            None => {
-                if args.on_cpu() {
-                    return Err(anyhow!("CPU sample kind should be known."));
-                }
+        let mut kinds = Vec::new();
 
-                0
-            }
-        };
-
-        let cswitch = match machine.find_sample_kind("cswitch") {
-            Some(cswitch) => { cswitch },
-            None => {
-                if args.off_cpu() {
-                    return Err(anyhow!("CSwitch sample kind should be known."));
+        /* Kinds to export with their file tag and description */
+        for (name, tag, desc) in args.sample_kinds() {
+            match machine.find_sample_kind(name) {
+                Some(kind) => { kinds.push((kind, tag, desc)); },
+                None => {
+                    return Err(anyhow!("{} sample kind should be known.", tag));
                 }
-
-                0
             }
-        };
+        }
 
         let mut graph = ExportGraph::new();
         let mut buf: String;
@@ -122,30 +118,17 @@ impl Exporter for PerfViewExporter {
                     for pid in pids {
                         let single_pid = vec![pid];
 
-                        if args.on_cpu() {
-                            let path = format!("{}/t.Unknown.{}.CPU.PerfView.xml", args.output_path().display(), pid);
-
-                            Self::export_pids(
-                                machine,
-                                &mut graph,
-                                &converter,
-                                &single_pid,
-                                cpu,
-                                &path,
-                                "CPU Samples");
-                        }
-
-                        if args.off_cpu() {
-                            let path = format!("{}/t.Unknown.{}.CSwitch.PerfView.xml", args.output_path().display(), pid);
+                        for (kind, tag, desc) in &kinds {
+                            let path = format!("{}/t.Unknown.{}.{}.PerfView.xml", args.output_path().display(), pid, tag);
 
                             Self::export_pids(
                                 machine,
                                 &mut graph,
                                 &converter,
                                 &single_pid,
-                                cswitch,
+                                *kind,
                                 &path,
-                                "Wait Time");
+                                desc);
                         }
                     }
                 },
@@ -163,30 +146,17 @@ impl Exporter for PerfViewExporter {
                         Err(_) => { "Unknown" },
                     };
 
-                    if args.on_cpu() {
-                        let path = format!("{}/t.{}.CPU.PerfView.xml", args.output_path().display(), comm);
+                    for (kind, tag, desc) in &kinds {
+                        let path = format!("{}/t.{}.{}.PerfView.xml", args.output_path().display(), comm, tag);
 
                         Self::export_pids(
                             machine,
                             &mut graph,
                             &converter,
                             &pids,
-                            cpu,
+                            *kind,
                             &path,
-                            "CPU Samples");
-                    }
-
-                    if args.off_cpu() {
-                        let path = format!("{}/t.{}.CSwitch.PerfView.xml", args.output_path().display(), comm);
-
-                        Self::export_pids(
-                            machine,
-                            &mut graph,
-                            &converter,
-                            &pids,
-                            cswitch,
-                            &path,
-                            "Wait Time");
+                            desc);
                     }
                 }
             }
@@ -269,3 +239,292 @@ impl Exporter for NetTraceExporter {
         Ok(())
     }
 }
+
+pub (crate) struct PerfDataExporter {
+    output_path: PathBuf,
+}
+
+impl PerfDataExporter {
+    pub fn new() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+        }
+    }
+}
+
+impl Exporter for PerfDataExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        self.output_path.push(args.output_path());
+
+        if output_path.exists() && output_path.is_dir() {
+            self.output_path.push("perf.data");
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        _args: &RecordArgs) -> anyhow::Result<()> {
+        machine.to_perf_data_file(|_proc| { true }, self.output_path.to_str().unwrap())?;
+
+        println!("{}: perf.data written", self.output_path.display());
+
+        Ok(())
+    }
+}
+
+pub (crate) struct FoldedExporter {
+}
+
+impl FoldedExporter {
+    pub fn new() -> Self {
+        Self {
+        }
+    }
+}
+
+impl Exporter for FoldedExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        if output_path.exists() && !output_path.is_dir() {
+            return Err(anyhow!("{} is not a directory.", output_path.display()));
+        }
+        else if !output_path.exists() {
+            return Err(anyhow!("{} does not exist.", output_path.display()));
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let converter = PerfViewExportGraphMetricValueConverter::new(ExportMachine::qpc_freq());
+
+        for (name, _, desc) in args.sample_kinds() {
+            if let Some(kind) = machine.find_sample_kind(name) {
+                let path = format!("{}/{}.folded", args.output_path().display(), name);
+
+                machine.to_folded_file(
+                    FoldedMachineOptions::new(kind).with_converter(&converter),
+                    &path)?;
+
+                println!("{}: {}", path, desc);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub (crate) struct SpeedscopeExporter {
+    output_path: PathBuf,
+}
+
+impl SpeedscopeExporter {
+    pub fn new() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+        }
+    }
+}
+
+impl Exporter for SpeedscopeExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        self.output_path.push(args.output_path());
+
+        if output_path.exists() && output_path.is_dir() {
+            self.output_path.push("trace.speedscope.json");
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        _args: &RecordArgs) -> anyhow::Result<()> {
+        let converter = PerfViewExportGraphMetricValueConverter::new(ExportMachine::qpc_freq());
+
+        machine.to_speedscope_file(
+            |_proc| { true },
+            Some(&converter),
+            self.output_path.to_str().unwrap())?;
+
+        println!("{}: Speedscope profiles", self.output_path.display());
+
+        Ok(())
+    }
+}
+
+pub (crate) struct ChromeTraceExporter {
+    output_path: PathBuf,
+}
+
+impl ChromeTraceExporter {
+    pub fn new() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+        }
+    }
+}
+
+impl Exporter for ChromeTraceExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        self.output_path.push(args.output_path());
+
+        if output_path.exists() && output_path.is_dir() {
+            self.output_path.push("trace.json");
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        _args: &RecordArgs) -> anyhow::Result<()> {
+        machine.to_chrome_trace_file(|_proc| { true }, self.output_path.to_str().unwrap())?;
+
+        println!("{}: Chrome trace events", self.output_path.display());
+
+        Ok(())
+    }
+}
+
+pub (crate) struct PerfettoExporter {
+    output_path: PathBuf,
+}
+
+impl PerfettoExporter {
+    pub fn new() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+        }
+    }
+}
+
+impl Exporter for PerfettoExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        self.output_path.push(args.output_path());
+
+        if output_path.exists() && output_path.is_dir() {
+            self.output_path.push("trace.perfetto-trace");
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        _args: &RecordArgs) -> anyhow::Result<()> {
+        machine.to_perfetto_file(|_proc| { true }, self.output_path.to_str().unwrap())?;
+
+        println!("{}: Perfetto trace", self.output_path.display());
+
+        Ok(())
+    }
+}
+
+pub (crate) struct OtlpExporter {
+    output_path: PathBuf,
+}
+
+impl OtlpExporter {
+    pub fn new() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+        }
+    }
+}
+
+impl Exporter for OtlpExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        self.output_path.push(args.output_path());
+
+        if output_path.exists() && output_path.is_dir() {
+            self.output_path.push("profiles.otlp.pb");
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        match args.otlp_endpoint() {
+            Some(endpoint) => {
+                machine.to_otlp_endpoint(|_proc| { true }, endpoint)?;
+
+                println!("{}: OTLP profiles posted", endpoint);
+            },
+            None => {
+                machine.to_otlp_file(|_proc| { true }, self.output_path.to_str().unwrap())?;
+
+                println!("{}: OTLP profiles", self.output_path.display());
+            },
+        }
+
+        Ok(())
+    }
+}
+
+pub (crate) struct SnapshotExporter {
+    output_path: PathBuf,
+}
+
+impl SnapshotExporter {
+    pub fn new() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+        }
+    }
+}
+
+impl Exporter for SnapshotExporter {
+    fn validate(
+        &mut self,
+        args: &RecordArgs) -> anyhow::Result<()> {
+        let output_path = args.output_path();
+        self.output_path.push(args.output_path());
+
+        if output_path.exists() && output_path.is_dir() {
+            self.output_path.push("trace.ocsnap");
+        }
+
+        Ok(())
+    }
+
+    fn run(
+        &self,
+        machine: &mut ExportMachine,
+        _args: &RecordArgs) -> anyhow::Result<()> {
+        machine.save_snapshot_file(self.output_path.to_str().unwrap())?;
+
+        println!("{}: ExportMachine snapshot", self.output_path.display());
+
+        Ok(())
+    }
+}
@@ -5,14 +5,22 @@ use crate::commandline::RecordArgs;
 use one_collect::helpers::dotnet::UniversalDotNetHelp;
 use one_collect::helpers::{dotnet::universal::UniversalDotNetHelper, exporting::ExportSettings};
 use one_collect::helpers::exporting::universal::UniversalExporter;
+#[cfg(target_os = "linux")]
+use one_collect::helpers::exporting::ExportSettingsLinuxExt;
+#[cfg(target_os = "linux")]
+use one_collect::helpers::callstack::CallstackHelper;
 
 use one_collect::helpers::dotnet::DotNetScripting;
 use one_collect::helpers::exporting::{
     ExportMachine,
     ExportFilterAction,
     ExportSampleFilterContext,
-    ScriptedUniversalExporter
+    ScriptedUniversalExporter,
 };
+#[cfg(target_os = "linux")]
+use one_collect::helpers::exporting::UnwindStop;
+#[cfg(target_os = "linux")]
+use one_collect::perf_event::LaunchedProcess;
 use one_collect::Writable;
 
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -22,6 +30,9 @@ use std::process;
 
 const DEFAULT_CPU_FREQUENCY: u64 = 1000;
 
+/* Warn when more than 1% of samples were lost */
+const LOST_SAMPLES_WARN_RATIO: f64 = 0.01;
+
 pub (crate) struct Recorder {
     args: RecordArgs,
 }
@@ -42,13 +53,20 @@ impl Recorder {
 
         let mut settings = ExportSettings::default();
 
+        #[cfg(target_os = "linux")]
+        {
+            settings = self.linux_settings(settings);
+        }
+
         // CPU sampling.
         if self.args.on_cpu() {
             settings = settings.with_cpu_profiling(DEFAULT_CPU_FREQUENCY);
         }
 
         // Context switches.
-        if self.args.off_cpu() {
+        if self.args.sched_switches() {
+            settings = settings.with_sched_switches();
+        } else if self.args.off_cpu() {
             settings = settings.with_cswitches();
         }
 
@@ -191,6 +209,18 @@ impl Recorder {
             }
         }
 
+        // Filter cgroup and launch command.
+        #[cfg(target_os = "linux")]
+        let launching = !self.args.command().is_empty();
+
+        #[cfg(not(target_os = "linux"))]
+        let launching = false;
+
+        #[cfg(target_os = "linux")]
+        {
+            settings = self.linux_targets(settings);
+        }
+
         let dotnet = UniversalDotNetHelper::default()
             .with_dynamic_symbols();
 
@@ -230,7 +260,12 @@ impl Recorder {
             // Print the banner telling the user that recording has started.
             if print_banner.load(Ordering::SeqCst) {
                 print_banner.store(false, Ordering::SeqCst);
-                println!("Recording started.  Press CTRL+C to stop.");
+
+                if launching {
+                    println!("Recording started.  Stops when the command exits or on CTRL+C.");
+                } else {
+                    println!("Recording started.  Press CTRL+C to stop.");
+                }
             }
 
             // When the user hits CTRL+C this will flip to true.
@@ -248,6 +283,19 @@ impl Recorder {
         println!("\nRecording stopped.");
         let mut exporter = exporter.borrow_mut();
 
+        Self::report_lost_samples(&exporter);
+
+        // Unwind user stacks that were stored while recording.
+        if exporter.has_user_stacks() {
+            println!("Unwinding user stacks.");
+            exporter.unwind_user_stacks();
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.args.unwind_stats() {
+            Self::report_unwind_stats(&exporter);
+        }
+
         // Capture binary metdata and resolve symbols.
         println!("Resolving symbols.");
         exporter.capture_and_resolve_symbols();
@@ -260,4 +308,148 @@ impl Recorder {
         println!("Finished recording trace.");
         println!("Trace written to {}", self.args.output_path().display());
     }
+
+    #[cfg(target_os = "linux")]
+    fn linux_settings(
+        &self,
+        settings: ExportSettings) -> ExportSettings {
+        // Frame pointer or DWARF unwinding.
+        let mut settings = if self.args.frame_pointers() {
+            ExportSettings::new(
+                CallstackHelper::new()
+                .with_frame_pointer_unwinding())
+        } else {
+            settings
+        };
+
+        // Deferred unwinding.
+        if self.args.deferred_unwind() {
+            settings = settings.with_deferred_unwinding();
+        }
+
+        // Page faults.
+        if self.args.page_faults() {
+            settings = settings.with_page_faults();
+        }
+
+        if self.args.minor_faults() {
+            settings = settings.with_minor_faults();
+        }
+
+        if self.args.major_faults() {
+            settings = settings.with_major_faults();
+        }
+
+        if self.args.alignment_faults() {
+            settings = settings.with_alignment_faults();
+        }
+
+        // CPU migrations.
+        if self.args.cpu_migrations() {
+            settings = settings.with_cpu_migrations();
+        }
+
+        // Task clock.
+        if self.args.task_clock() {
+            settings = settings.with_task_clock(DEFAULT_CPU_FREQUENCY);
+        }
+
+        settings
+    }
+
+    #[cfg(target_os = "linux")]
+    fn linux_targets(
+        &self,
+        mut settings: ExportSettings) -> ExportSettings {
+        // Filter cgroup.
+        if let Some(cgroup) = self.args.cgroup() {
+            settings = settings.with_target_cgroup(cgroup);
+        }
+
+        // Launch command.
+        let command = self.args.command();
+
+        if !command.is_empty() {
+            match LaunchedProcess::spawn(&command[0], &command[1..]) {
+                Ok(process) => {
+                    settings = settings.with_launched_process(process);
+                },
+                Err(e) => {
+                    eprintln!("Error: Unable to launch {}: {}", command[0], e);
+                    process::exit(1);
+                }
+            }
+        }
+
+        settings
+    }
+
+    fn report_lost_samples(exporter: &ExportMachine) {
+        let lost = exporter.lost_samples();
+
+        if lost == 0 {
+            return;
+        }
+
+        let ratio = exporter.lost_sample_ratio();
+
+        println!("Lost {} samples ({:.2}%).", lost, ratio * 100.0);
+
+        if ratio > LOST_SAMPLES_WARN_RATIO {
+            eprintln!(
+                "Warning: The kernel dropped {:.2}% of samples, the trace may not be representative.",
+                ratio * 100.0);
+
+            eprintln!(
+                "Consider capturing fewer sample kinds or narrowing the capture with --pid, --cgroup or a command.");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn report_unwind_stats(exporter: &ExportMachine) {
+        let stats = exporter.unwind_stats();
+
+        if stats.is_empty() {
+            println!("No user stacks were unwound.");
+            return;
+        }
+
+        let total = stats.total();
+
+        println!(
+            "Unwind stats: {} unwinds, {} frames, {} truncated.",
+            total.unwinds(),
+            total.frames(),
+            total.truncated());
+
+        // Modules with the most truncated stacks first.
+        let mut modules: Vec<_> = stats.modules().collect();
+        modules.sort_by_key(|(_, module)| std::cmp::Reverse(module.truncated()));
+
+        for (key, module) in modules {
+            let name = match key {
+                None => "[unknown]",
+                Some(key) if key.dev() == 0 && key.ino() == 0 => "[anon]",
+                Some(key) => exporter.dev_node_filename(&key).unwrap_or("[unknown]"),
+            };
+
+            let mut stops = String::new();
+
+            for stop in UnwindStop::ALL {
+                let count = module.stops(stop);
+
+                if count != 0 && stop.is_truncated() {
+                    let _ = write!(stops, " {}={}", stop.name(), count);
+                }
+            }
+
+            println!(
+                "  {}: {} unwinds, {} frames, {} truncated{}",
+                name,
+                module.unwinds(),
+                module.frames(),
+                module.truncated(),
+                stops);
+        }
+    }
 }
@@ -0,0 +1,490 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::*;
+use crate::dwarf::*;
+
+#[derive(Default)]
+pub struct Unwinder {
+    frame_cache: HashMap<ModuleKey, FrameOffsets>,
+    frame_table: FrameHeaderTable,
+    registers: Vec<u64>,
+    offsets: Vec<i16>,
+    link: Option<u64>,
+    pc: u64,
+    sp: u64,
+}
+
+impl Unwinder {
+    pub fn new() -> Self { Self::default() }
+
+    fn stack_value(
+        sp: u64,
+        address: u64,
+        off: i64,
+        stack_data: &[u8]) -> Option<u64> {
+        if address < sp {
+            return None;
+        }
+
+        let offset = (address - sp) as i64 + off;
+        let max_offset = stack_data.len() as i64 - 8;
+
+        if offset < 0 || offset > max_offset {
+            return None;
+        }
+
+        let start = offset as usize;
+        let end = start + 8;
+
+        Some(u64::from_ne_bytes(
+            stack_data[start..end]
+            .try_into()
+            .unwrap()))
+    }
+
+    fn tracked_register(
+        &self,
+        reg: i16) -> Option<u64> {
+        /* Only FP and SP are known past the first frame */
+        match reg as usize {
+            REG_FP | REG_SP => { Some(self.registers[reg as usize]) },
+            _ => { None },
+        }
+    }
+
+    fn unwind_frame_record(
+        &mut self,
+        stack_data: &[u8],
+        result: &mut UnwindResult) -> Option<u64> {
+        /*
+         * JIT code has no unwind info, but AAPCS64 code keeps a frame
+         * record of the previous FP and LR where FP points. Follow it.
+         */
+        let fp = self.registers[REG_FP];
+
+        if fp < self.registers[REG_SP] {
+            result.stopped(UnwindStop::BadStackRead, "Frame record would go backwards");
+            return None;
+        }
+
+        let prev_fp = Unwinder::stack_value(self.sp, fp, 0, stack_data);
+        let lr = Unwinder::stack_value(self.sp, fp, 8, stack_data);
+
+        match (prev_fp, lr) {
+            (Some(prev_fp), Some(lr)) => {
+                /* Frame records are pushed at the top of the frame */
+                self.registers[REG_FP] = prev_fp;
+                self.registers[REG_SP] = fp + 16;
+
+                Some(lr)
+            },
+            _ => {
+                result.stopped(
+                    UnwindStop::stack_read(self.sp, fp + 8, stack_data),
+                    "Bad stack frame record read");
+                None
+            },
+        }
+    }
+
+    fn unwind_module(
+        &mut self,
+        key: &ModuleKey,
+        accessor: &dyn ModuleAccessor,
+        rva: u64,
+        link: Option<u64>,
+        stack_data: &[u8],
+        result: &mut UnwindResult) -> Option<u64> {
+        /* Lookup offset by RVA */
+        if let Some(offset) = self.frame_cache
+            .entry(*key)
+            .or_default()
+            .get_frame_offset(
+                key,
+                accessor,
+                &mut self.frame_table,
+                rva) {
+            let cfa_data = offset.unwind_to_cfa(
+                &mut self.offsets,
+                rva);
+
+            if cfa_data.reg as usize > REG_SP {
+                result.stopped(UnwindStop::InvalidRule, "Register out of range");
+                return None;
+            }
+
+            let cfa = match self.tracked_register(cfa_data.reg as i16) {
+                Some(value) => { (value as i64 + cfa_data.off as i64) as u64 },
+                None => {
+                    result.stopped(UnwindStop::UnsupportedRegister, "CFA register not tracked");
+                    return None;
+                },
+            };
+
+            /* Values held in other registers are read before FP/SP change */
+            let mut reg_fp = None;
+            let mut reg_ra = None;
+
+            if cfa_data.reg_mask & REG_FP_BIT != 0 {
+                reg_fp = self.tracked_register(self.offsets[REG_FP]);
+
+                if reg_fp.is_none() {
+                    result.stopped(UnwindStop::UnsupportedRegister, "FP register not tracked");
+                    return None;
+                }
+            }
+
+            if cfa_data.reg_mask & REG_LR_BIT != 0 {
+                reg_ra = self.tracked_register(self.offsets[REG_LR]);
+
+                if reg_ra.is_none() {
+                    result.stopped(UnwindStop::UnsupportedRegister, "Return address register not tracked");
+                    return None;
+                }
+            }
+
+            /* Leaf functions can keep the return address in LR */
+            let saved_ra = cfa_data.off_mask & REG_LR_BIT != 0 && reg_ra.is_none();
+
+            if !saved_ra && reg_ra.is_none() && link.is_none() {
+                /* Past the first frame this is the outermost frame, IE: _start */
+                if result.frames_pushed > 1 {
+                    result.stop = UnwindStop::Complete;
+                } else {
+                    result.stopped(UnwindStop::InvalidRule, "No return address register");
+                }
+
+                return None;
+            }
+
+            /* Unexpected backwards access, leafs may not move SP */
+            if self.registers[REG_SP] > cfa ||
+               (self.registers[REG_SP] == cfa && saved_ra) {
+                result.stopped(UnwindStop::InvalidRule, "CFA would go backwards");
+                return None;
+            }
+
+            /* Update FP */
+            if let Some(value) = reg_fp {
+                self.registers[REG_FP] = value;
+            } else if cfa_data.off_mask & REG_FP_BIT != 0 {
+                match Unwinder::stack_value(
+                    self.sp,
+                    cfa,
+                    self.offsets[REG_FP] as i64,
+                    stack_data) {
+                    Some(value) => {
+                        self.registers[REG_FP] = value;
+                    },
+                    None => {
+                        result.stopped(
+                            UnwindStop::stack_read(
+                                self.sp,
+                                (cfa as i64 + self.offsets[REG_FP] as i64) as u64,
+                                stack_data),
+                            "Bad stack FP read");
+                        return None;
+                    },
+                }
+            }
+
+            /* Update SP */
+            self.registers[REG_SP] = cfa;
+
+            if reg_ra.is_some() {
+                return reg_ra;
+            }
+
+            if !saved_ra {
+                return link;
+            }
+
+            /* Read IP */
+            match Unwinder::stack_value(
+                self.sp,
+                cfa,
+                self.offsets[REG_LR] as i64,
+                stack_data) {
+                Some(value) => {
+                    return Some(value);
+                },
+                None => {
+                    result.stopped(
+                        UnwindStop::stack_read(
+                            self.sp,
+                            (cfa as i64 + self.offsets[REG_LR] as i64) as u64,
+                            stack_data),
+                        "Bad stack IP read");
+                    return None;
+                }
+            }
+        }
+
+        result.stopped(UnwindStop::NoFrameInfo, "No module found");
+        None
+    }
+}
+
+/* DWARF register values */
+const REG_FP: usize = 29;
+const REG_LR: usize = 30;
+const REG_SP: usize = 31;
+
+/* Matching bits to DWARF */
+const REG_FP_BIT: u64 = 1 << REG_FP;
+const REG_LR_BIT: u64 = 1 << REG_LR;
+
+/*
+ * Pointer authentication codes live above the 48-bit user VA.
+ * NOTE: This assumes 48-bit VAs, kernels with 52-bit user VAs
+ * can hand out addresses above this, which would be truncated.
+ */
+const ADDRESS_MASK: u64 = (1 << 48) - 1;
+
+impl MachineUnwinder for Unwinder {
+    fn reset(
+        &mut self,
+        rip: u64,
+        rbp: u64,
+        rsp: u64) {
+        /* Force 0 values for registers */
+        self.registers.clear();
+        self.registers.resize(REG_SP + 1, 0);
+
+        /* Force enough slots for offsets */
+        self.offsets.clear();
+        self.offsets.resize(REG_SP + 1, 0);
+
+        /* Set initial values */
+        self.registers[REG_FP] = rbp;
+        self.registers[REG_SP] = rsp;
+        self.pc = rip;
+        self.sp = rsp;
+    }
+
+    fn set_link_register(
+        &mut self,
+        lr: u64) {
+        self.link = Some(lr);
+    }
+
+    fn unwind(
+        &mut self,
+        process: &dyn Unwindable,
+        accessor: &dyn ModuleAccessor,
+        stack_data: &[u8],
+        stack_frames: &mut Vec<u64>,
+        result: &mut UnwindResult) {
+        /* LR only holds the return address for the first frame */
+        let mut link = self.link.take().map(|lr| lr & ADDRESS_MASK);
+        let mut lookup_pc = self.pc;
+
+        loop {
+            let module = match process.find(lookup_pc) {
+                Some(module) => { module },
+                None => {
+                    /* Return address outside of any module */
+                    result.stop = UnwindStop::NoModule;
+                    break;
+                },
+            };
+
+            result.module = Some(module.key());
+
+            let ip = if module.unwind_type() == UnwindType::Prolog {
+                /* Anonymous and JIT */
+                self.unwind_frame_record(
+                    stack_data,
+                    result)
+            } else {
+                /* Default to DWARF */
+                let rva = module.rva(lookup_pc);
+
+                self.unwind_module(
+                    &module.key(),
+                    accessor,
+                    rva,
+                    link,
+                    stack_data,
+                    result)
+            };
+
+            link = None;
+
+            /* Add ip to stack or stop */
+            match ip {
+                Some(next_ip) => {
+                    self.pc = next_ip & ADDRESS_MASK;
+
+                    stack_frames.push(self.pc);
+                    result.frames_pushed += 1;
+
+                    /* Hard cap of frames */
+                    if result.frames_pushed > 128 {
+                        result.stop = UnwindStop::FrameLimit;
+                        break;
+                    }
+
+                    /* IP of 0 means we are done. */
+                    if self.pc == 0 {
+                        break;
+                    }
+
+                    /* Return addresses follow the call, which may end the function */
+                    lookup_pc = self.pc.saturating_sub(4);
+                },
+                None => {
+                    return;
+                },
+            }
+        }
+
+        if result.frames_pushed > 1 {
+            stack_frames.pop();
+            result.frames_pushed -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    struct SingleAccessor {
+    }
+
+    impl ModuleAccessor for SingleAccessor {
+        fn open(
+            &self,
+            _key: &ModuleKey) -> Option<File> {
+            File::open("test_assets/arm64_test").ok()
+        }
+    }
+
+    #[test]
+    fn it_works() {
+        /*
+         * Captured at SP in a leaf with its return address only in LR.
+         * The leaf is called by a PAC signed frame, which is called by
+         * JIT code with just a frame record, called by an SP based frame.
+         */
+        let base: u64 = 0xaaaa00000000;
+        let jit: u64 = 0xffff80001000;
+        let sp: u64 = 0xfffff0000000;
+
+        let mut unwinder = Unwinder::new();
+        let mut machine = Machine::new();
+        let mut proc = Process::new();
+
+        proc.add_module(Module::new(base, base + 0x2000, 0, 0, 1, UnwindType::DWARF));
+        proc.add_module(Module::new_anon(jit - 0x1000, jit + 0x1000));
+        assert!(machine.add_process(0, proc));
+
+        let accessor = SingleAccessor {};
+        let stack_data = fs::read("test_assets/arm64_test.data").unwrap();
+        let mut stack_frames: Vec<u64> = Vec::new();
+
+        unwinder.set_link_register(base + 0x1124);
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            sp,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(None, result.error);
+        assert_eq!(
+            vec![base + 0x1008, base + 0x1124, jit + 0x14, base + 0x121c],
+            stack_frames);
+
+        /* Link register is consumed, leaf cannot unwind without it */
+        stack_frames.clear();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            sp,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(Some("No return address register"), result.error);
+        assert_eq!(UnwindStop::InvalidRule, result.stop);
+        assert_eq!(vec![base + 0x1008], stack_frames);
+
+        /* Within the signed frame, after the frame record is setup */
+        stack_frames.clear();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1120,
+            sp,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(None, result.error);
+        assert_eq!(
+            vec![base + 0x1120, jit + 0x14, base + 0x121c],
+            stack_frames);
+    }
+
+    #[test]
+    fn corrupt_return_address() {
+        let jit: u64 = 0xffff80001000;
+        let sp: u64 = 0xfffff0000000;
+
+        let mut unwinder = Unwinder::new();
+        let mut machine = Machine::new();
+        let mut proc = Process::new();
+
+        proc.add_module(Module::new_anon(jit - 0x1000, jit + 0x1000));
+        assert!(machine.add_process(0, proc));
+
+        /* Frame record with a return address below 4 */
+        let mut stack_data = Vec::new();
+        stack_data.extend_from_slice(&0u64.to_ne_bytes());
+        stack_data.extend_from_slice(&2u64.to_ne_bytes());
+
+        let accessor = SingleAccessor {};
+        let mut stack_frames: Vec<u64> = Vec::new();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            jit + 0x10,
+            sp,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(UnwindStop::NoModule, result.stop);
+        assert_eq!(vec![jit + 0x10], stack_frames);
+    }
+
+    #[test]
+    fn untracked_registers() {
+        let mut unwinder = Unwinder::new();
+
+        unwinder.reset(0x1000, 0x2000, 0x3000);
+
+        assert_eq!(Some(0x2000), unwinder.tracked_register(REG_FP as i16));
+        assert_eq!(Some(0x3000), unwinder.tracked_register(REG_SP as i16));
+
+        /* Callee saved and LR are never recovered, so reading them is wrong */
+        assert_eq!(None, unwinder.tracked_register(19));
+        assert_eq!(None, unwinder.tracked_register(REG_LR as i16));
+        assert_eq!(None, unwinder.tracked_register(-1));
+    }
+}
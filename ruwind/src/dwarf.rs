@@ -1,10 +1,12 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::fmt;
+use std::fs::File;
 
 use crate::elf::*;
+use crate::{ModuleKey, ModuleAccessor};
 
 const VALUE_TYPE_OFFSET: u8 = 0;
 const VALUE_TYPE_REG: u8 = 1;
@@ -16,6 +18,7 @@ pub struct UnwindCFA {
     pub reg: u8,
     pub off: i16,
     pub off_mask: u64,
+    pub reg_mask: u64,
 }
 
 impl UnwindCFA {
@@ -24,6 +27,7 @@ impl UnwindCFA {
             reg: 0,
             off: 0,
             off_mask: 0,
+            reg_mask: 0,
         }
     }
 }
@@ -171,8 +175,11 @@ impl FrameOptions {
 pub struct FrameOffset {
     pub rva: u64,
     pub fde: u64,
+    pub size: u64,
     state: u8,
     ret_reg: u8,
+    debug: bool,
+    address_size: u8,
     frame_states: Vec<FrameState>,
 }
 
@@ -183,12 +190,28 @@ impl FrameOffset {
         Self {
             rva,
             fde,
+            size: 0,
             state: STATE_UNPARSED,
             ret_reg: 0,
+            debug: false,
+            address_size: 8,
             frame_states: Vec::new(),
         }
     }
 
+    fn new_debug(
+        rva: u64,
+        fde: u64,
+        address_size: u8) -> Self {
+        let mut offset = Self::new(rva, fde);
+
+        /* FDE is within .debug_frame section data */
+        offset.debug = true;
+        offset.address_size = address_size;
+
+        offset
+    }
+
     pub fn unwind_to_cfa(
         &self,
         reg_offsets: &mut Vec<i16>,
@@ -206,13 +229,33 @@ impl FrameOffset {
             cfa_data.off = state.cfa_off;
 
             for reg_state in &state.reg_states {
-                if reg_state.reg >= max_reg || 
-                   reg_state.val_type != VALUE_TYPE_OFFSET {
+                if reg_state.reg >= max_reg {
+                    continue;
+                }
+
+                let bit = 1 << reg_state.reg as u64;
+
+                /* Undefined return address marks the outermost frame */
+                if reg_state.val_type == VALUE_TYPE_UNDEFINED {
+                    cfa_data.off_mask &= !bit;
+                    cfa_data.reg_mask &= !bit;
+                    continue;
+                }
+
+                /* Held in another register, offset slot has its number */
+                if reg_state.val_type == VALUE_TYPE_REG {
+                    reg_offsets[reg_state.reg as usize] = reg_state.val;
+                    cfa_data.reg_mask |= bit;
+                    continue;
+                }
+
+                if reg_state.val_type != VALUE_TYPE_OFFSET {
                     continue;
                 }
 
                 reg_offsets[reg_state.reg as usize] = reg_state.val;
-                cfa_data.off_mask |= 1 << reg_state.reg as u64;
+                cfa_data.off_mask |= bit;
+                cfa_data.reg_mask &= !bit;
             }
         }
 
@@ -227,6 +270,16 @@ impl FrameOffset {
         self.state == STATE_VALID
     }
 
+    pub fn is_debug(&self) -> bool {
+        self.debug
+    }
+
+    pub fn contains(
+        &self,
+        rva: u64) -> bool {
+        rva >= self.rva && rva - self.rva < self.size
+    }
+
     pub fn mark_invalid(&mut self) {
         self.state = STATE_INVALID;
     }
@@ -247,8 +300,14 @@ impl FrameOffset {
         let cie_id = u32::from_ne_bytes(
             entry[4..8].try_into().unwrap());
 
+        let expected_id = if self.debug {
+            DEBUG_FRAME_CIE_ID
+        } else {
+            0
+        };
+
         /* Not valid */
-        if cie_id != 0 {
+        if cie_id != expected_id {
             return Err(
                 error("Invalid CIE"));
         }
@@ -257,7 +316,14 @@ impl FrameOffset {
 
         let version = read_byte(entry, &mut cursor)?;
 
-        if version != 1 {
+        /* .debug_frame uses the DWARF version of the producer */
+        let valid_version = match version {
+            1 => { true },
+            3 | 4 => { self.debug },
+            _ => { false },
+        };
+
+        if !valid_version {
             return Err(
                 error("Invalid Version"));
         }
@@ -265,6 +331,16 @@ impl FrameOffset {
         let aug_len = read_string(entry, &mut cursor)?;
         let aug = &entry[9..(9 + aug_len)];
 
+        if version == 4 {
+            let address_size = read_byte(entry, &mut cursor)?;
+            let _segment_size = read_byte(entry, &mut cursor)?;
+
+            if address_size == 4 {
+                options.enc = DW_EH_PE_UDATA4 |
+                              DW_EH_PE_ABSPTR;
+            }
+        }
+
         let code_align = read_uleb(entry, &mut cursor)?;
         let data_align = read_sleb(entry, &mut cursor)?;
         let ret_reg = read_uleb(entry, &mut cursor)?;
@@ -281,7 +357,7 @@ impl FrameOffset {
         options.data_align = data_align as i16;
         self.ret_reg = ret_reg as u8;
 
-        if aug[0] == b'z' {
+        if aug.first() == Some(&b'z') {
             let _aug_len = read_uleb(entry, &mut cursor)?;
             options.has_aug_data = true;
 
@@ -375,14 +451,19 @@ impl FrameOffset {
         let cie_offset = u32::from_ne_bytes(
             fde_slice[4..8].try_into().unwrap());
 
-        /* Not valid */
-        if cie_offset == 0 {
-            return Err(
-                error("Invalid CIE offset"));
-        }
+        let cie_pos = if self.debug {
+            /* CIE is an offset from the section start */
+            cie_offset as u64
+        } else {
+            /* Not valid */
+            if cie_offset == 0 {
+                return Err(
+                    error("Invalid CIE offset"));
+            }
 
-        /* CIE is back from current pos */
-        let cie_pos = (self.fde + 4) - cie_offset as u64;
+            /* CIE is back from current pos */
+            (self.fde + 4) - cie_offset as u64
+        };
 
         /* Move to CIE and load */
         reader.seek(SeekFrom::Start(cie_pos))?;
@@ -392,13 +473,27 @@ impl FrameOffset {
         let cie_slice = &cie_buf[..cie_len];
 
         let mut options = FrameOptions::new();
+
+        if self.address_size == 4 {
+            /* 32-bit .debug_frame, a version 4 CIE may still override */
+            options.enc = DW_EH_PE_UDATA4 |
+                          DW_EH_PE_ABSPTR;
+        }
+
         let cie_cursor = self.parse_cie(
             cie_slice,
             &mut options)?;
 
         let mut cursor: usize = 8;
-        let pc_start = read_value(options.enc, self.fde as i64, fde_slice, &mut cursor)?;
-        let _pc_size = read_value(options.enc, -12, fde_slice, &mut cursor)?;
+        let mut pc_start = read_value(options.enc, self.fde as i64, fde_slice, &mut cursor)?;
+        let pc_size = read_value(options.enc, -12, fde_slice, &mut cursor)?;
+
+        if self.debug {
+            /* Addresses are virtual, RVA was already translated */
+            pc_start = self.rva as i64;
+        }
+
+        self.size = pc_size as u64;
 
         if options.has_aug_data {
             /* Skip augmentation data */
@@ -707,6 +802,11 @@ impl FrameOffset {
                         let _size = read_uleb(slice, cursor)?;
                     },
 
+                    /* Return address signed (ARM64 PAC), stripped on read */
+                    DW_CFA_AARCH64_NEGATE_RA_STATE => {
+                        /* Nothing */
+                    },
+
                     /* Unknown */
                     _ => {
                         return Err(
@@ -828,6 +928,115 @@ impl FrameOffset {
 
         Ok(())
     }
+
+    fn address_to_rva(
+        sections: &[SectionMetadata],
+        address: u64) -> Option<u64> {
+        for sec in sections {
+            if sec.address != 0 &&
+               address >= sec.address &&
+               address < sec.address + sec.size {
+                return Some((address - sec.address) + sec.offset);
+            }
+        }
+
+        None
+    }
+
+    fn debug_address_size(
+        data: &[u8],
+        cie_pos: usize,
+        class_size: usize) -> usize {
+        /* Version 4 CIEs give the address size after the augmentation */
+        let mut cursor = cie_pos + 8;
+
+        if data.get(cursor) != Some(&4) {
+            return class_size;
+        }
+
+        cursor += 1;
+
+        while let Some(byte) = data.get(cursor) {
+            cursor += 1;
+
+            if *byte == 0 {
+                break;
+            }
+        }
+
+        match data.get(cursor) {
+            Some(4) => { 4 },
+            Some(8) => { 8 },
+            _ => { class_size },
+        }
+    }
+
+    fn parse_debug_table(
+        data: &[u8],
+        class: u8,
+        sections: &[SectionMetadata],
+        offsets: &mut Vec<FrameOffset>) {
+        let mut pos: usize = 0;
+
+        /* Addresses are the ELF class size unless the CIE says otherwise */
+        let class_size = if class == ELFCLASS32 { 4 } else { 8 };
+
+        while pos + 4 <= data.len() {
+            let len = u32::from_ne_bytes(
+                data[pos..pos + 4].try_into().unwrap());
+
+            /* 64-bit DWARF, not supported */
+            if len == u32::MAX {
+                break;
+            }
+
+            let next = pos + 4 + len as usize;
+
+            if next > data.len() {
+                break;
+            }
+
+            /* FDEs have a CIE pointer, then the PC start and range */
+            if len >= 4 {
+                let cie_id = u32::from_ne_bytes(
+                    data[pos + 4..pos + 8].try_into().unwrap());
+
+                let address_size = if cie_id != DEBUG_FRAME_CIE_ID {
+                    Self::debug_address_size(
+                        data,
+                        cie_id as usize,
+                        class_size)
+                } else {
+                    0
+                };
+
+                if address_size != 0 && len as usize >= 4 + address_size * 2 {
+                    let start = pos + 8;
+
+                    let address = if address_size == 4 {
+                        u32::from_ne_bytes(
+                            data[start..start + 4].try_into().unwrap()) as u64
+                    } else {
+                        u64::from_ne_bytes(
+                            data[start..start + 8].try_into().unwrap())
+                    };
+
+                    if let Some(rva) = Self::address_to_rva(sections, address) {
+                        offsets.push(
+                            FrameOffset::new_debug(
+                                rva,
+                                pos as u64,
+                                address_size as u8));
+                    }
+                }
+            }
+
+            pos = next;
+        }
+
+        /* Unlike .eh_frame_hdr, FDEs are in link order */
+        offsets.sort_by_key(|offset| offset.rva);
+    }
 }
 
 impl fmt::Debug for FrameOffset {
@@ -845,6 +1054,161 @@ impl fmt::Debug for FrameOffset {
     }
 }
 
+#[derive(Default)]
+pub(crate) struct FrameOffsets {
+    frame_offsets: Vec<FrameOffset>,
+    debug_offsets: Vec<FrameOffset>,
+    debug_frame: Vec<u8>,
+    filled: bool,
+    debug_filled: bool,
+}
+
+impl FrameOffsets {
+    fn load_debug_frame(
+        &mut self,
+        key: &ModuleKey,
+        accessor: &dyn ModuleAccessor,
+        table: &mut FrameHeaderTable,
+        file: &mut File) -> Result<(), Error> {
+        /* Addresses always map via the module, debug files have no code */
+        let mut sections = Vec::new();
+
+        get_section_metadata(
+            file,
+            None,
+            SHT_PROGBITS,
+            &mut sections)?;
+
+        table.parse_debug_frame(
+            file,
+            &sections,
+            &mut self.debug_frame,
+            &mut self.debug_offsets)?;
+
+        if !self.debug_offsets.is_empty() {
+            return Ok(());
+        }
+
+        /* Stripped, try the debug file with the same build-id */
+        let mut build_id = [0; 20];
+        let mut debug_id = [0; 20];
+
+        if let Some(build_id) = get_build_id(file, &mut build_id)? {
+            if let Some(mut debug_file) = accessor.open_debug(key, build_id) {
+                if let Some(debug_id) = get_build_id(&mut debug_file, &mut debug_id)? {
+                    if build_id_equals(build_id, debug_id) {
+                        table.parse_debug_frame(
+                            &mut debug_file,
+                            &sections,
+                            &mut self.debug_frame,
+                            &mut self.debug_offsets)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find_offset(
+        offsets: &mut Vec<FrameOffset>,
+        debug_frame: &[u8],
+        key: &ModuleKey,
+        accessor: &dyn ModuleAccessor,
+        table: &mut FrameHeaderTable,
+        rva: u64) -> Option<usize> {
+        /* Find frame offset by RVA */
+        let index = FrameOffset::find(
+            rva,
+            offsets)?;
+
+        let offset = &mut offsets[index];
+
+        /* Ensure parsed */
+        if offset.is_unparsed() {
+            if offset.is_debug() {
+                /* Parse from the loaded section data */
+                let _result = table.parse_offset(
+                    &mut Cursor::new(debug_frame),
+                    offset);
+            } else if let Some(mut file) = accessor.open(key) {
+                /* Parse, determines if valid */
+                let _result = table.parse_offset(
+                    &mut file,
+                    offset);
+            } else {
+                /* Cannot access file */
+                offset.mark_invalid();
+            }
+        }
+
+        /* Ensure valid and actually covers the RVA */
+        if offset.is_valid() && offset.contains(rva) {
+            return Some(index);
+        }
+
+        None
+    }
+
+    pub(crate) fn get_frame_offset(
+        &mut self,
+        key: &ModuleKey,
+        accessor: &dyn ModuleAccessor,
+        table: &mut FrameHeaderTable,
+        rva: u64) -> Option<&FrameOffset> {
+        if !self.filled {
+            /* Initial find, load offsets */
+            if let Some(mut file) = accessor.open(key) {
+                let _result = table.parse(
+                    &mut file,
+                    &mut self.frame_offsets);
+            }
+
+            /* Don't attempt any more loads */
+            self.filled = true;
+        }
+
+        if let Some(index) = Self::find_offset(
+            &mut self.frame_offsets,
+            &self.debug_frame,
+            key,
+            accessor,
+            table,
+            rva) {
+            return Some(&self.frame_offsets[index]);
+        }
+
+        /*
+         * Code built without async unwind tables only has .eh_frame for
+         * the CRT objects, the rest is only within .debug_frame.
+         */
+        if !self.debug_filled {
+            if let Some(mut file) = accessor.open(key) {
+                let _result = self.load_debug_frame(
+                    key,
+                    accessor,
+                    table,
+                    &mut file);
+            }
+
+            /* Don't attempt any more loads */
+            self.debug_filled = true;
+        }
+
+        if let Some(index) = Self::find_offset(
+            &mut self.debug_offsets,
+            &self.debug_frame,
+            key,
+            accessor,
+            table,
+            rva) {
+            return Some(&self.debug_offsets[index]);
+        }
+
+        None
+    }
+}
+
 #[derive(Default)]
 pub struct FrameHeaderTable {
     metadata_buf: Vec<SectionMetadata>,
@@ -907,8 +1271,47 @@ impl FrameHeaderTable {
 
         Ok(())
     }
+
+    pub fn parse_debug_frame(
+        &mut self,
+        reader: &mut (impl Read + Seek),
+        sections: &[SectionMetadata],
+        frame_data: &mut Vec<u8>,
+        frame_offsets: &mut Vec<FrameOffset>) -> Result<(), Error> {
+        self.metadata_buf.clear();
+        get_section_metadata(
+            reader,
+            None,
+            SHT_PROGBITS,
+            &mut self.metadata_buf)?;
+
+        for sec in &self.metadata_buf {
+            if let Ok(true) = sec.name_equals(
+                reader,
+                ".debug_frame",
+                &mut self.cie_buf) {
+                /* Possibly compressed, keep uncompressed for FDEs */
+                read_section_data(
+                    reader,
+                    sec,
+                    frame_data)?;
+
+                FrameOffset::parse_debug_table(
+                    frame_data,
+                    sec.class,
+                    sections,
+                    frame_offsets);
+                break;
+            }
+        }
+
+        Ok(())
+    }
 }
 
+/* .debug_frame CIE marker */
+const DEBUG_FRAME_CIE_ID: u32 = 0xFFFFFFFF;
+
 /* Internal state */
 const STATE_UNPARSED: u8 = 0;
 const STATE_INVALID: u8 = 1;
@@ -962,6 +1365,7 @@ const DW_CFA_VAL_OFFSET: u8 = 0x14;
 const DW_CFA_VAL_OFFSET_SF: u8 = 0x15;
 const DW_CFA_VAL_EXPRESSION: u8 = 0x16;
 const DW_CFA_GNU_ARGS_SIZE: u8 = 0x2e;
+const DW_CFA_AARCH64_NEGATE_RA_STATE: u8 = 0x2d;
 
 fn read_byte(
     slice: &[u8],
@@ -1140,3 +1544,244 @@ fn error(
         ErrorKind::Other,
         error)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestAccessor {
+        path: &'static str,
+        debug_path: Option<&'static str>,
+    }
+
+    impl ModuleAccessor for TestAccessor {
+        fn open(
+            &self,
+            _key: &ModuleKey) -> Option<File> {
+            File::open(self.path).ok()
+        }
+
+        fn open_debug(
+            &self,
+            _key: &ModuleKey,
+            _build_id: &[u8; 20]) -> Option<File> {
+            File::open(self.debug_path?).ok()
+        }
+    }
+
+    fn check_debug_frame(
+        accessor: &TestAccessor) {
+        /*
+         * Built with -fno-asynchronous-unwind-tables, so only the CRT
+         * _start has .eh_frame. The rest is within .debug_frame only.
+         */
+        let key = ModuleKey { dev: 0, ino: 0 };
+        let mut offsets = FrameOffsets::default();
+        let mut table = FrameHeaderTable::new();
+        let mut reg_offsets = vec![0; 17];
+
+        let offset = offsets.get_frame_offset(
+            &key,
+            accessor,
+            &mut table,
+            0x1040).unwrap();
+
+        assert!(!offset.is_debug());
+
+        /* leaf: push rbp, mov rbp, rsp */
+        let offset = offsets.get_frame_offset(
+            &key,
+            accessor,
+            &mut table,
+            0x1129).unwrap();
+
+        assert!(offset.is_debug());
+        assert_eq!(0x1129, offset.rva);
+        assert_eq!(0x1b, offset.size);
+
+        let cfa = offset.unwind_to_cfa(&mut reg_offsets, 0x1129);
+        assert_eq!(7, cfa.reg);
+        assert_eq!(8, cfa.off);
+
+        let cfa = offset.unwind_to_cfa(&mut reg_offsets, 0x112a);
+        assert_eq!(7, cfa.reg);
+        assert_eq!(16, cfa.off);
+        assert_eq!(-16, reg_offsets[6]);
+
+        let cfa = offset.unwind_to_cfa(&mut reg_offsets, 0x1130);
+        assert_eq!(6, cfa.reg);
+        assert_eq!(16, cfa.off);
+
+        /* Past the end of main, nothing covers it */
+        assert!(offsets.get_frame_offset(
+            &key,
+            accessor,
+            &mut table,
+            0x1160).is_none());
+    }
+
+    fn push_entry(
+        data: &mut Vec<u8>,
+        body: &[u8]) -> u32 {
+        let pos = data.len() as u32;
+
+        data.extend_from_slice(&(body.len() as u32).to_ne_bytes());
+        data.extend_from_slice(body);
+
+        pos
+    }
+
+    fn push_debug_cie(
+        data: &mut Vec<u8>,
+        version: u8,
+        address_size: u8) -> u32 {
+        let mut body = Vec::new();
+
+        body.extend_from_slice(&DEBUG_FRAME_CIE_ID.to_ne_bytes());
+        body.push(version);
+        body.push(0);
+
+        if version == 4 {
+            body.push(address_size);
+            body.push(0);
+        }
+
+        /* Code align 1, data align -4, return register 8 */
+        body.extend_from_slice(&[1, 0x7c, 8]);
+
+        push_entry(data, &body)
+    }
+
+    fn push_debug_fde(
+        data: &mut Vec<u8>,
+        cie: u32,
+        address_size: u8,
+        start: u64,
+        size: u64) {
+        let mut body = Vec::new();
+
+        body.extend_from_slice(&cie.to_ne_bytes());
+
+        if address_size == 4 {
+            body.extend_from_slice(&(start as u32).to_ne_bytes());
+            body.extend_from_slice(&(size as u32).to_ne_bytes());
+        } else {
+            body.extend_from_slice(&start.to_ne_bytes());
+            body.extend_from_slice(&size.to_ne_bytes());
+        }
+
+        push_entry(data, &body);
+    }
+
+    #[test]
+    fn debug_frame_address_size() {
+        let sections = vec![
+            SectionMetadata {
+                sec_type: SHT_PROGBITS,
+                address: 0x1000,
+                offset: 0x400,
+                size: 0x100,
+                entry_size: 0,
+                name_offset: 0,
+                link: 0,
+                flags: 0,
+                class: ELFCLASS32,
+            }];
+
+        /* 32-bit ELF class with a version 1 CIE */
+        let mut data = Vec::new();
+        let cie = push_debug_cie(&mut data, 1, 0);
+        push_debug_fde(&mut data, cie, 4, 0x1010, 0x20);
+
+        /* Version 4 CIE with a 64-bit address size */
+        let cie = push_debug_cie(&mut data, 4, 8);
+        push_debug_fde(&mut data, cie, 8, 0x1040, 0x20);
+
+        let mut offsets = Vec::new();
+        FrameOffset::parse_debug_table(&data, ELFCLASS32, &sections, &mut offsets);
+
+        assert_eq!(2, offsets.len());
+        assert_eq!(0x410, offsets[0].rva);
+        assert_eq!(4, offsets[0].address_size);
+        assert_eq!(0x440, offsets[1].rva);
+        assert_eq!(8, offsets[1].address_size);
+
+        /* 64-bit ELF class with a version 4 CIE of 32-bit addresses */
+        let mut data = Vec::new();
+        let cie = push_debug_cie(&mut data, 4, 4);
+        push_debug_fde(&mut data, cie, 4, 0x1080, 0x20);
+
+        let mut offsets = Vec::new();
+        FrameOffset::parse_debug_table(&data, ELFCLASS64, &sections, &mut offsets);
+
+        assert_eq!(1, offsets.len());
+        assert_eq!(0x480, offsets[0].rva);
+        assert_eq!(4, offsets[0].address_size);
+    }
+
+    #[test]
+    fn debug_frame_compressed() {
+        let accessor = TestAccessor {
+            path: "test_assets/debug_frame_test",
+            debug_path: None,
+        };
+
+        check_debug_frame(&accessor);
+    }
+
+    #[test]
+    fn debug_frame_debug_file() {
+        let mut accessor = TestAccessor {
+            path: "test_assets/debug_frame_stripped",
+            debug_path: None,
+        };
+
+        /* Stripped, nothing beyond the CRT */
+        let key = ModuleKey { dev: 0, ino: 0 };
+        let mut offsets = FrameOffsets::default();
+        let mut table = FrameHeaderTable::new();
+
+        assert!(offsets.get_frame_offset(
+            &key,
+            &accessor,
+            &mut table,
+            0x1129).is_none());
+
+        /* Build-id matched debug file */
+        accessor.debug_path = Some("test_assets/debug_frame_stripped.debug");
+
+        check_debug_frame(&accessor);
+    }
+
+    #[test]
+    fn register_rules() {
+        let mut offset = FrameOffset::new(0, 0);
+        let mut reg_offsets = vec![0; 32];
+
+        /* LR saved on the stack, then moved to x19, then undefined */
+        let mut state = FrameState::new(0, 31, 16);
+        state.add_reg_value(30, -8, VALUE_TYPE_OFFSET).unwrap();
+        offset.frame_states.push(state);
+
+        let mut state = FrameState::new(4, 31, 16);
+        state.add_reg_value(30, 19, VALUE_TYPE_REG).unwrap();
+        offset.frame_states.push(state);
+
+        let mut state = FrameState::new(8, 31, 16);
+        state.add_reg_value(30, 0, VALUE_TYPE_UNDEFINED).unwrap();
+        offset.frame_states.push(state);
+
+        let cfa = offset.unwind_to_cfa(&mut reg_offsets, 0);
+        assert_eq!(1 << 30, cfa.off_mask);
+        assert_eq!(0, cfa.reg_mask);
+        assert_eq!(-8, reg_offsets[30]);
+
+        let cfa = offset.unwind_to_cfa(&mut reg_offsets, 4);
+        assert_eq!(1 << 30, cfa.reg_mask);
+        assert_eq!(19, reg_offsets[30]);
+
+        let cfa = offset.unwind_to_cfa(&mut reg_offsets, 8);
+        assert_eq!(0, cfa.off_mask);
+        assert_eq!(0, cfa.reg_mask);
+    }
+}
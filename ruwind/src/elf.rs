@@ -2,12 +2,14 @@
 // Licensed under the MIT license.
 
 use std::fs::File;
-use std::io::{BufReader, Error, Read, Seek, SeekFrom};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::marker::PhantomData;
 use std::mem::{zeroed, size_of};
+use std::path::PathBuf;
 use std::slice;
 use cpp_demangle::{DemangleOptions, Symbol};
 use rustc_demangle::try_demangle;
+use flate2::read::ZlibDecoder;
 
 pub const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
 
@@ -17,6 +19,8 @@ pub const SHT_NOTE: ElfWord = 7;
 pub const SHT_NOBITS: ElfWord = 8;
 pub const SHT_DYNSYM: ElfWord = 11;
 
+pub const SHF_COMPRESSED: u64 = 0x800;
+
 // Symbol type flags that can be or'd together to keep track of
 // which types of symbols are present in a binary.
 pub const SYMBOL_TYPE_ELF_SYMTAB: u32 = 1;
@@ -64,6 +68,7 @@ pub struct SectionMetadata {
     pub entry_size: u64,
     pub name_offset: u64,
     pub link: u32,
+    pub flags: u64,
     pub class: u8,
 }
 
@@ -620,6 +625,85 @@ pub fn get_build_id<'a>(
     read_build_id(reader, &sections, &section_offsets, buf)
 }
 
+pub fn build_id_debug_path(
+    build_id: &[u8]) -> PathBuf {
+    let mut path = String::from("/usr/lib/debug/.build-id/");
+
+    for (i, byte) in build_id.iter().enumerate() {
+        if i == 1 {
+            path.push('/');
+        }
+
+        path.push_str(&format!("{:02x}", byte));
+    }
+
+    path.push_str(".debug");
+
+    PathBuf::from(path)
+}
+
+pub fn read_section_data(
+    reader: &mut (impl Read + Seek),
+    section: &SectionMetadata,
+    buf: &mut Vec<u8>) -> Result<(), Error> {
+    reader.seek(SeekFrom::Start(section.offset))?;
+    buf.clear();
+
+    if section.flags & SHF_COMPRESSED == 0 {
+        buf.resize(section.size as usize, 0);
+        return reader.read_exact(buf);
+    }
+
+    /* Compressed sections start with a header of the real size */
+    let (ch_type, ch_size, header_size) = match section.class {
+        ELFCLASS32 => {
+            let mut header = ElfCompressionHeader32::default();
+
+            unsafe {
+                reader.read_exact(
+                    slice::from_raw_parts_mut(
+                        &mut header as *mut _ as *mut u8,
+                        size_of::<ElfCompressionHeader32>()))?;
+            }
+
+            (header.ch_type, header.ch_size as u64, size_of::<ElfCompressionHeader32>())
+        },
+        _ => {
+            let mut header = ElfCompressionHeader64::default();
+
+            unsafe {
+                reader.read_exact(
+                    slice::from_raw_parts_mut(
+                        &mut header as *mut _ as *mut u8,
+                        size_of::<ElfCompressionHeader64>()))?;
+            }
+
+            (header.ch_type, header.ch_size, size_of::<ElfCompressionHeader64>())
+        },
+    };
+
+    if ch_type != ELFCOMPRESS_ZLIB {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unsupported section compression"));
+    }
+
+    let data = reader.by_ref().take(
+        section.size.saturating_sub(header_size as u64));
+
+    ZlibDecoder::new(data)
+        .take(ch_size)
+        .read_to_end(buf)?;
+
+    if buf.len() as u64 != ch_size {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Truncated compressed section"));
+    }
+
+    Ok(())
+}
+
 fn seek_to_note_data(
     reader: &mut (impl Read + Seek),
     section: &SectionMetadata) -> Result<usize, Error> {
@@ -731,8 +815,8 @@ pub fn get_load_header(
 
 const EI_CLASS: usize = 4;
 
-const ELFCLASS32: u8 = 1;
-const ELFCLASS64: u8 = 2;
+pub(crate) const ELFCLASS32: u8 = 1;
+pub(crate) const ELFCLASS64: u8 = 2;
 
 const STT_FUNC: u8 = 2;
 
@@ -740,6 +824,8 @@ const PT_LOAD: u32 = 1;
 
 const PF_X: u32 = 1;
 
+const ELFCOMPRESS_ZLIB: u32 = 1;
+
 type Elf32Addr = u32;
 type Elf32Off = u32;
 type Elf64Addr = u64;
@@ -840,6 +926,23 @@ struct ElfSectionHeader64 {
     sh_entsize: ElfXWord,
 }
 
+#[repr(C)]
+#[derive(Default)]
+struct ElfCompressionHeader32 {
+    ch_type: ElfWord,
+    ch_size: ElfWord,
+    ch_addralign: ElfWord,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ElfCompressionHeader64 {
+    ch_type: ElfWord,
+    ch_reserved: ElfWord,
+    ch_size: ElfXWord,
+    ch_addralign: ElfXWord,
+}
+
 #[repr(C)]
 #[derive(Default)]
 struct ElfSymbol32 {
@@ -1109,6 +1212,7 @@ fn get_section_metadata32(
                     entry_size: sec.sh_entsize as u64,
                     name_offset,
                     link: sec.sh_link,
+                    flags: sec.sh_flags as u64,
                 });
         }
     }
@@ -1184,6 +1288,7 @@ fn get_section_metadata64(
                     entry_size: sec.sh_entsize,
                     name_offset,
                     link: sec.sh_link,
+                    flags: sec.sh_flags,
                 });
         }
     }
@@ -0,0 +1,275 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::*;
+
+/*
+ * Walks frame records of [previous FP, return address] that X64 RBP
+ * and ARM64 X29 based code keeps. No unwind info is read, so every
+ * return address is validated against the process modules instead.
+ *
+ * NOTE: ARM64 leaf functions keep their return address only in LR
+ * and push no frame record. Without unwind info we cannot tell if
+ * LR is still valid, so it is not used and the caller of a leaf
+ * is missing from ARM64 stacks. Use the DWARF unwinder for those.
+ */
+#[derive(Default)]
+pub struct Unwinder {
+    ip: u64,
+    fp: u64,
+    sp: u64,
+}
+
+impl Unwinder {
+    pub fn new() -> Self { Self::default() }
+
+    fn stack_value(
+        &self,
+        address: u64,
+        stack_data: &[u8]) -> Option<u64> {
+        if address < self.sp {
+            return None;
+        }
+
+        let start = (address - self.sp) as usize;
+        let end = start.checked_add(8)?;
+
+        if end > stack_data.len() {
+            return None;
+        }
+
+        Some(u64::from_ne_bytes(
+            stack_data[start..end]
+            .try_into()
+            .unwrap()))
+    }
+}
+
+/*
+ * Pointer authentication codes live above the 48-bit user VA.
+ * NOTE: Addresses above 48 bits (5-level paging or 52-bit VA)
+ * are truncated.
+ */
+const ADDRESS_MASK: u64 = (1 << 48) - 1;
+
+impl MachineUnwinder for Unwinder {
+    fn reset(
+        &mut self,
+        rip: u64,
+        rbp: u64,
+        rsp: u64) {
+        self.ip = rip;
+        self.fp = rbp;
+        self.sp = rsp;
+    }
+
+    fn unwind(
+        &mut self,
+        process: &dyn Unwindable,
+        _accessor: &dyn ModuleAccessor,
+        stack_data: &[u8],
+        stack_frames: &mut Vec<u64>,
+        result: &mut UnwindResult) {
+        let mut fp = self.fp;
+
+        result.module = process.find(self.ip).map(|module| module.key());
+
+        /* FP of 0 means we are done. */
+        while fp != 0 {
+            if fp & 7 != 0 {
+                result.stopped(UnwindStop::BadStackRead, "Unaligned frame pointer");
+                return;
+            }
+
+            let ip_address = match fp.checked_add(8) {
+                Some(address) => { address },
+                None => {
+                    result.stopped(UnwindStop::BadStackRead, "Frame pointer out of range");
+                    return;
+                },
+            };
+
+            let prev_fp = self.stack_value(fp, stack_data);
+            let ip = self.stack_value(ip_address, stack_data);
+
+            let (prev_fp, ip) = match (prev_fp, ip) {
+                (Some(prev_fp), Some(ip)) => { (prev_fp, ip & ADDRESS_MASK) },
+                _ => {
+                    result.stopped(
+                        UnwindStop::stack_read(self.sp, ip_address, stack_data),
+                        "Bad stack frame record read");
+                    return;
+                },
+            };
+
+            /* IP of 0 means we are done. */
+            if ip == 0 {
+                break;
+            }
+
+            /* Without unwind info, only trust addresses within modules */
+            match process.find(ip) {
+                Some(module) => { result.module = Some(module.key()); },
+                None => {
+                    result.stopped(UnwindStop::NoModule, "Return address not in a module");
+                    return;
+                },
+            }
+
+            stack_frames.push(ip);
+            result.frames_pushed += 1;
+
+            /* Hard cap of frames */
+            if result.frames_pushed > 128 {
+                result.stop = UnwindStop::FrameLimit;
+                break;
+            }
+
+            /* Stack grows down, callers must be above us */
+            if prev_fp != 0 && prev_fp <= fp {
+                result.stopped(UnwindStop::BadStackRead, "Frame pointer would go backwards");
+                return;
+            }
+
+            fp = prev_fp;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    struct NoAccessor {
+    }
+
+    impl ModuleAccessor for NoAccessor {
+        fn open(
+            &self,
+            _key: &ModuleKey) -> Option<File> {
+            None
+        }
+    }
+
+    fn push_record(
+        stack_data: &mut Vec<u8>,
+        fp: u64,
+        ip: u64) {
+        stack_data.extend_from_slice(&fp.to_ne_bytes());
+        stack_data.extend_from_slice(&ip.to_ne_bytes());
+    }
+
+    #[test]
+    fn it_works() {
+        let base: u64 = 0x555500000000;
+        let jit: u64 = 0x7fff00001000;
+        let sp: u64 = 0x7ffff0000000;
+
+        let mut unwinder = Unwinder::new();
+        let mut machine = Machine::new();
+        let mut proc = Process::new();
+
+        proc.add_module(Module::new(base, base + 0x2000, 0, 0, 1, UnwindType::DWARF));
+        proc.add_module(Module::new_anon(jit - 0x1000, jit + 0x1000));
+        assert!(machine.add_process(0, proc));
+
+        /* Locals, then records up the stack through JIT code */
+        let mut stack_data = vec![0; 16];
+        push_record(&mut stack_data, sp + 48, base + 0x1100);
+        push_record(&mut stack_data, 0, 0);
+        push_record(&mut stack_data, sp + 64, jit + 0x20);
+        push_record(&mut stack_data, 0, base + 0x1200);
+
+        let accessor = NoAccessor {};
+        let mut stack_frames: Vec<u64> = Vec::new();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            sp + 16,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(None, result.error);
+        assert_eq!(UnwindStop::Complete, result.stop);
+        assert_eq!(
+            vec![base + 0x1008, base + 0x1100, jit + 0x20, base + 0x1200],
+            stack_frames);
+
+        /* RBP used as a general register, garbage is not pushed */
+        let mut stack_data = vec![0; 16];
+        push_record(&mut stack_data, sp + 48, 0x1234);
+        stack_frames.clear();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            sp + 16,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(Some("Return address not in a module"), result.error);
+        assert_eq!(UnwindStop::NoModule, result.stop);
+        assert!(result.module == Some(ModuleKey::new(0, 1)));
+        assert_eq!(vec![base + 0x1008], stack_frames);
+
+        /* Loops are stopped */
+        let mut stack_data = vec![0; 16];
+        push_record(&mut stack_data, sp + 16, base + 0x1100);
+        stack_frames.clear();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            sp + 16,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(Some("Frame pointer would go backwards"), result.error);
+        assert_eq!(vec![base + 0x1008, base + 0x1100], stack_frames);
+
+        /* Records beyond the copied stack */
+        stack_frames.clear();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            sp + 4096,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(Some("Bad stack frame record read"), result.error);
+        assert_eq!(UnwindStop::StackExhausted, result.stop);
+        assert_eq!(vec![base + 0x1008], stack_frames);
+
+        /* Garbage at the very top of the address space */
+        stack_frames.clear();
+
+        let result = machine.unwind_process(
+            0,
+            &mut unwinder,
+            &accessor,
+            base + 0x1008,
+            u64::MAX - 7,
+            sp,
+            &stack_data[..],
+            &mut stack_frames);
+
+        assert_eq!(Some("Frame pointer out of range"), result.error);
+        assert_eq!(UnwindStop::BadStackRead, result.stop);
+        assert_eq!(vec![base + 0x1008], stack_frames);
+    }
+}
@@ -12,6 +12,9 @@ pub mod dwarf;
 mod module;
 mod process;
 mod machine;
+mod arm64unwinder;
+mod fpunwinder;
+mod stats;
 
 pub trait Unwindable {
     fn find<'a>(
@@ -46,9 +49,26 @@ impl Hash for ModuleKey {
     }
 }
 
+/* Why an unwind stopped, explains truncated stacks */
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum UnwindStop {
+    #[default]
+    Complete,
+    FrameLimit,
+    NoModule,
+    NoFrameInfo,
+    InvalidRule,
+    UnsupportedRegister,
+    BadStackRead,
+    StackExhausted,
+    AnonScanFailed,
+}
+
 pub struct UnwindResult {
     pub frames_pushed: usize,
     pub error: Option<&'static str>,
+    pub stop: UnwindStop,
+    pub module: Option<ModuleKey>,
 }
 
 impl UnwindResult {
@@ -56,8 +76,31 @@ impl UnwindResult {
         Self {
             frames_pushed: 0,
             error: None,
+            stop: UnwindStop::Complete,
+            module: None,
         }
     }
+
+    pub fn stopped(
+        &mut self,
+        stop: UnwindStop,
+        error: &'static str) {
+        self.stop = stop;
+        self.error = Some(error);
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct ModuleUnwindStats {
+    stops: HashMap<UnwindStop, u64>,
+    unwinds: u64,
+    frames: u64,
+}
+
+/* Unwind results aggregated by the module unwinding stopped in */
+#[derive(Default, Clone)]
+pub struct UnwindStats {
+    modules: HashMap<Option<ModuleKey>, ModuleUnwindStats>,
 }
 
 impl Default for UnwindResult {
@@ -73,6 +116,12 @@ pub trait MachineUnwinder {
         rbp: u64,
         rsp: u64);
 
+    fn set_link_register(
+        &mut self,
+        _lr: u64) {
+        /* Only for machines that return via a link register */
+    }
+
     fn unwind(
         &mut self,
         process: &dyn Unwindable,
@@ -86,6 +135,13 @@ pub trait ModuleAccessor {
     fn open(
         &self,
         key: &ModuleKey) -> Option<File>;
+
+    fn open_debug(
+        &self,
+        _key: &ModuleKey,
+        build_id: &[u8; 20]) -> Option<File> {
+        File::open(elf::build_id_debug_path(build_id)).ok()
+    }
 }
 
 #[derive(Eq, Clone, Copy, PartialEq)]
@@ -122,6 +178,20 @@ pub fn default_unwinder() -> impl MachineUnwinder {
     unwinder::Unwinder::new()
 }
 
+#[cfg(target_arch = "aarch64")]
+pub fn default_unwinder() -> impl MachineUnwinder {
+    arm64_unwinder()
+}
+
+pub fn arm64_unwinder() -> impl MachineUnwinder {
+    /* Link register must be set before each unwind, it is consumed */
+    arm64unwinder::Unwinder::new()
+}
+
+pub fn frame_pointer_unwinder() -> impl MachineUnwinder {
+    fpunwinder::Unwinder::new()
+}
+
 #[cfg(test)]
 #[cfg(target_arch = "x86_64")]
 mod tests {
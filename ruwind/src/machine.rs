@@ -88,7 +88,7 @@ impl Machine {
             },
             None => {
                 /* Process not mapped */
-                result.error = Some("Process not mapped");
+                result.stopped(UnwindStop::NoModule, "Process not mapped");
             },
         }
 
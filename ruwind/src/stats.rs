@@ -0,0 +1,174 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::*;
+
+impl UnwindStop {
+    pub const ALL: [UnwindStop; 9] = [
+        UnwindStop::Complete,
+        UnwindStop::FrameLimit,
+        UnwindStop::NoModule,
+        UnwindStop::NoFrameInfo,
+        UnwindStop::InvalidRule,
+        UnwindStop::UnsupportedRegister,
+        UnwindStop::BadStackRead,
+        UnwindStop::StackExhausted,
+        UnwindStop::AnonScanFailed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UnwindStop::Complete => { "complete" },
+            UnwindStop::FrameLimit => { "frame_limit" },
+            UnwindStop::NoModule => { "no_module" },
+            UnwindStop::NoFrameInfo => { "no_fde" },
+            UnwindStop::InvalidRule => { "invalid_cfa_rule" },
+            UnwindStop::UnsupportedRegister => { "unsupported_register" },
+            UnwindStop::BadStackRead => { "bad_stack_read" },
+            UnwindStop::StackExhausted => { "stack_exhausted" },
+            UnwindStop::AnonScanFailed => { "anon_scan_failed" },
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool { *self != UnwindStop::Complete }
+
+    pub(crate) fn stack_read(
+        sp: u64,
+        address: u64,
+        stack_data: &[u8]) -> Self {
+        /* Reads past the end of the copy mean the copy was too small */
+        if address >= sp && address - sp + 8 >= stack_data.len() as u64 {
+            UnwindStop::StackExhausted
+        } else {
+            UnwindStop::BadStackRead
+        }
+    }
+}
+
+impl ModuleUnwindStats {
+    pub fn unwinds(&self) -> u64 { self.unwinds }
+
+    pub fn frames(&self) -> u64 { self.frames }
+
+    pub fn stops(
+        &self,
+        stop: UnwindStop) -> u64 {
+        self.stops.get(&stop).copied().unwrap_or(0)
+    }
+
+    pub fn truncated(&self) -> u64 {
+        self.unwinds - self.stops(UnwindStop::Complete)
+    }
+
+    fn merge(
+        &mut self,
+        other: &ModuleUnwindStats) {
+        for (stop, count) in &other.stops {
+            *self.stops.entry(*stop).or_insert(0) += count;
+        }
+
+        self.unwinds += other.unwinds;
+        self.frames += other.frames;
+    }
+}
+
+impl UnwindStats {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn add(
+        &mut self,
+        result: &UnwindResult) {
+        let stats = self.modules
+            .entry(result.module)
+            .or_default();
+
+        *stats.stops.entry(result.stop).or_insert(0) += 1;
+        stats.unwinds += 1;
+        stats.frames += result.frames_pushed as u64;
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &UnwindStats) {
+        self.merge_with(other, |key| key);
+    }
+
+    pub fn merge_with(
+        &mut self,
+        other: &UnwindStats,
+        remap: impl Fn(ModuleKey) -> ModuleKey) {
+        for (key, stats) in &other.modules {
+            self.modules
+                .entry(key.map(&remap))
+                .or_default()
+                .merge(stats);
+        }
+    }
+
+    pub fn clear(&mut self) { self.modules.clear(); }
+
+    pub fn is_empty(&self) -> bool { self.modules.is_empty() }
+
+    pub fn module(
+        &self,
+        key: Option<ModuleKey>) -> Option<&ModuleUnwindStats> {
+        self.modules.get(&key)
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = (Option<ModuleKey>, &ModuleUnwindStats)> {
+        self.modules
+            .iter()
+            .map(|(key, stats)| (*key, stats))
+    }
+
+    pub fn total(&self) -> ModuleUnwindStats {
+        let mut total = ModuleUnwindStats::default();
+
+        for stats in self.modules.values() {
+            total.merge(stats);
+        }
+
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let module = ModuleKey::new(1, 2);
+        let mut stats = UnwindStats::new();
+        let mut result = UnwindResult::new();
+
+        result.frames_pushed = 4;
+        result.module = Some(module);
+        stats.add(&result);
+
+        result.frames_pushed = 1;
+        result.stopped(UnwindStop::NoFrameInfo, "No module found");
+        stats.add(&result);
+
+        let mut result = UnwindResult::new();
+        result.stopped(UnwindStop::NoModule, "Process not mapped");
+        stats.add(&result);
+
+        let found = stats.module(Some(module)).unwrap();
+        assert_eq!(2, found.unwinds());
+        assert_eq!(5, found.frames());
+        assert_eq!(1, found.truncated());
+        assert_eq!(1, found.stops(UnwindStop::NoFrameInfo));
+        assert_eq!(0, found.stops(UnwindStop::BadStackRead));
+
+        let total = stats.total();
+        assert_eq!(3, total.unwinds());
+        assert_eq!(2, total.truncated());
+        assert_eq!(2, stats.modules().count());
+
+        /* Reads beyond the copied stack vs below SP */
+        let stack_data = [0; 64];
+        assert_eq!(UnwindStop::StackExhausted, UnwindStop::stack_read(0x1000, 0x1038, &stack_data));
+        assert_eq!(UnwindStop::BadStackRead, UnwindStop::stack_read(0x1000, 0xff8, &stack_data));
+    }
+}
@@ -4,60 +4,6 @@
 use super::*;
 use crate::dwarf::*;
 
-#[derive(Default)]
-struct FrameOffsets {
-    frame_offsets: Vec<FrameOffset>,
-    filled: bool,
-}
-
-impl FrameOffsets {
-    fn get_frame_offset(
-        &mut self,
-        key: &ModuleKey,
-        accessor: &dyn ModuleAccessor,
-        table: &mut FrameHeaderTable,
-        rva: u64) -> Option<&FrameOffset> {
-        if !self.filled {
-            /* Initial find, load offsets */
-            if let Some(mut file) = accessor.open(key) {
-                let _result = table.parse(
-                    &mut file,
-                    &mut self.frame_offsets);
-            }
-
-            /* Don't attempt any more loads */
-            self.filled = true;
-        }
-
-        /* Find frame offset by RVA */
-        if let Some(index) = FrameOffset::find(
-            rva,
-            &self.frame_offsets) {
-            let offset = &mut self.frame_offsets[index];
-
-            /* Ensure parsed */
-            if offset.is_unparsed() {
-                if let Some(mut file) = accessor.open(key) {
-                    /* Parse, determines if valid */
-                    let _result = table.parse_offset(
-                        &mut file,
-                        offset);
-                } else {
-                    /* Cannot access file */
-                    offset.mark_invalid();
-                }
-            }
-
-            /* Ensure valid */
-            if offset.is_valid() {
-                return Some(offset);
-            }
-        }
-
-        None
-    }
-}
-
 #[derive(Default)]
 pub struct Unwinder {
     frame_cache: HashMap<ModuleKey, FrameOffsets>,
@@ -106,7 +52,13 @@ impl Unwinder {
         let len = stack_data.len();
 
         /* Ensure valid enough to start scan */
-        if cfa < self.rsp || len < 16 {
+        if cfa < self.rsp {
+            result.stopped(UnwindStop::BadStackRead, "Anon prolog below stack");
+            return None;
+        }
+
+        if len < 16 {
+            result.stopped(UnwindStop::StackExhausted, "Anon prolog stack too small");
             return None;
         }
 
@@ -118,6 +70,7 @@ impl Unwinder {
         let max_offset = len - 8;
 
         if offset > max_offset {
+            result.stopped(UnwindStop::StackExhausted, "Anon prolog beyond stack");
             return None;
         }
 
@@ -159,7 +112,12 @@ impl Unwinder {
             count += 1;
         }
 
-        result.error = Some("Anon prolog not found");
+        /* Running out of copied stack is not a failed scan */
+        if count < max_count {
+            result.stopped(UnwindStop::StackExhausted, "Anon prolog not found");
+        } else {
+            result.stopped(UnwindStop::AnonScanFailed, "Anon prolog not found");
+        }
 
         None
     }
@@ -185,21 +143,21 @@ impl Unwinder {
                 rva);
 
             if cfa_data.reg as usize > REG_RA {
-                result.error = Some("Register out of range");
+                result.stopped(UnwindStop::InvalidRule, "Register out of range");
                 return None;
             }
                 
             let cfa = (self.registers[cfa_data.reg as usize] as i64 + cfa_data.off as i64) as u64;
 
-            /* No return address, unexpected */
+            /* No return address, the outermost frame, IE: _start */
             if cfa_data.off_mask & REG_RA_BIT == 0 {
-                result.error = Some("No return address register");
+                result.stop = UnwindStop::Complete;
                 return None;
             }
 
             /* Unexpected backwards access */
             if self.registers[REG_RSP] >= cfa {
-                result.error = Some("CFA would go backwards");
+                result.stopped(UnwindStop::InvalidRule, "CFA would go backwards");
                 return None;
             }
 
@@ -214,7 +172,12 @@ impl Unwinder {
                         self.registers[REG_RBP] = value;
                     },
                     None => {
-                        result.error = Some("Bad stack RBP read");
+                        result.stopped(
+                            UnwindStop::stack_read(
+                                self.rsp,
+                                (cfa as i64 + self.offsets[REG_RBP] as i64) as u64,
+                                stack_data),
+                            "Bad stack RBP read");
                         return None;
                     },
                 }
@@ -233,13 +196,18 @@ impl Unwinder {
                     return Some(value);
                 },
                 None => {
-                    result.error = Some("Bad stack IP read");
+                    result.stopped(
+                        UnwindStop::stack_read(
+                            self.rsp,
+                            (cfa as i64 + self.offsets[REG_RA] as i64) as u64,
+                            stack_data),
+                        "Bad stack IP read");
                     return None;
                 }
             }
         }
 
-        result.error = Some("No module found");
+        result.stopped(UnwindStop::NoFrameInfo, "No module found");
         None
     }
 }
@@ -281,7 +249,18 @@ impl MachineUnwinder for Unwinder {
         stack_data: &[u8],
         stack_frames: &mut Vec<u64>,
         result: &mut UnwindResult) {
-        while let Some(module) = process.find(self.rip) {
+        loop {
+            let module = match process.find(self.rip) {
+                Some(module) => { module },
+                None => {
+                    /* Return address outside of any module */
+                    result.stop = UnwindStop::NoModule;
+                    break;
+                },
+            };
+
+            result.module = Some(module.key());
+
             let ip = if module.unwind_type() == UnwindType::Prolog {
                 /* Anonymous and PE */
                 self.unwind_prolog(
@@ -310,6 +289,7 @@ impl MachineUnwinder for Unwinder {
 
                     /* Hard cap of frames */
                     if result.frames_pushed > 128 {
+                        result.stop = UnwindStop::FrameLimit;
                         break;
                     }
 